@@ -1,6 +1,7 @@
 using System;
 using System.Collections.Generic;
 using System.Security.Policy;
+using System.Text.Json;
 using System.Threading.Tasks;
 using Karenia.Rurikawa.Coordinator.Services;
 using Karenia.Rurikawa.Models.Account;
@@ -80,6 +81,23 @@ namespace Karenia.Rurikawa.Coordinator.Controllers.Admin {
             return await judgerService.QueryJudger(tags, start, take);
         }
 
+        /// <summary>
+        /// Change the configuration of all connected judgers at runtime.
+        /// Only fields that are safe to change at runtime are applied by judgers.
+        /// </summary>
+        /// <param name="coordinatorService"/>
+        /// <param name="config">Config fields to change, keyed by their names in the judger's config file</param>
+        /// <returns>The number of judgers the update was sent to</returns>
+        [HttpPost("config")]
+        public async Task<int> UpdateJudgerConfig(
+            [FromServices] JudgerCoordinatorService coordinatorService,
+            [FromBody] Dictionary<string, JsonElement> config
+        ) {
+            return await coordinatorService.BroadcastConfigUpdate(new UpdateConfigServerMsg {
+                Config = config
+            });
+        }
+
         [HttpDelete("{id}")]
         public async Task<ActionResult> DeleteJudger(
             [FromServices] JudgerService judgerService,
@@ -62,6 +62,10 @@ namespace Karenia.Rurikawa.Coordinator.Controllers {
             public string? Ref { get; set; }
             public FlowSnake TestSuite { get; set; }
             public List<string> Tests { get; set; }
+            /// <summary>
+            /// Credentials for cloning the repo if it's private.
+            /// </summary>
+            public GitCredentials? Credentials { get; set; }
         }
 #pragma warning restore
 
@@ -82,6 +86,7 @@ namespace Karenia.Rurikawa.Coordinator.Controllers {
                 TestSuite = m.TestSuite,
                 Tests = m.Tests,
                 Stage = JobStage.Queued,
+                RepoCredentials = m.Credentials,
             };
             try {
                 var result = await GetRevision(job);
@@ -143,6 +148,8 @@ namespace Karenia.Rurikawa.Coordinator.Controllers {
                 "-q",
                 "--exit-code"
             })
+                .WithEnvironmentVariables(
+                    job.RepoCredentials?.GitEnv(job.Repo) ?? new Dictionary<string, string?>())
                 .WithValidation(CommandResultValidation.None)
                 .ExecuteBufferedAsync(cancel.Token);
 
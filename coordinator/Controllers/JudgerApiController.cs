@@ -1,5 +1,7 @@
 ﻿using System;
 using System.Collections.Generic;
+using System.IO;
+using System.IO.Compression;
 using System.Linq;
 using System.Threading.Tasks;
 using Karenia.Rurikawa.Coordinator.Services;
@@ -7,9 +9,11 @@ using Karenia.Rurikawa.Helpers;
 using Karenia.Rurikawa.Models;
 using Karenia.Rurikawa.Models.Judger;
 using Microsoft.AspNetCore.Authorization;
+using Microsoft.AspNetCore.Http;
 using Microsoft.AspNetCore.Mvc;
 using Microsoft.EntityFrameworkCore;
 using Microsoft.Extensions.Logging;
+using Microsoft.Net.Http.Headers;
 
 namespace Karenia.Rurikawa.Coordinator.Controllers {
     [ApiController]
@@ -53,6 +57,15 @@ namespace Karenia.Rurikawa.Coordinator.Controllers {
             return NoContent();
         }
 
+        /// <summary>
+        /// Maximum size of a compressed test result after decompression.
+        /// </summary>
+        const long MaxDecompressedResultSize = 256L << 20;
+
+        /// <summary>
+        /// Stores a test result. Results may be compressed with one of
+        /// <see cref="ServerHelloMsg.SupportedUploadEncodings"/>.
+        /// </summary>
         [HttpPost("upload")]
         public async Task<IActionResult> UploadJudgerResult(
             [FromQuery] FlowSnake jobId,
@@ -63,29 +76,61 @@ namespace Karenia.Rurikawa.Coordinator.Controllers {
                     "ContentLength must be specified!"));
 
             var filename = $"results/{jobId}/{testId}.json";
-            await fs.UploadFile(filename, Request.Body, Request.ContentLength.Value, true);
+            var encoding = Request.Headers[HeaderNames.ContentEncoding].ToString();
+            if (string.IsNullOrEmpty(encoding)) {
+                await fs.UploadFile(filename, Request.Body, Request.ContentLength.Value, true);
+            } else if (encoding.Equals("gzip", StringComparison.OrdinalIgnoreCase)) {
+                // Results are stored uncompressed, since they are served to
+                // browsers as they are
+                using var decompressed = new MemoryStream();
+                using (var gzip = new GZipStream(Request.Body, CompressionMode.Decompress)) {
+                    var buffer = new byte[81920];
+                    int read;
+                    while ((read = await gzip.ReadAsync(buffer, 0, buffer.Length)) > 0) {
+                        if (decompressed.Length + read > MaxDecompressedResultSize)
+                            return StatusCode(
+                                StatusCodes.Status413PayloadTooLarge,
+                                new ErrorResponse(
+                                    ErrorCodes.CONTENT_TOO_LARGE,
+                                    "Decompressed result is too large"));
+                        await decompressed.WriteAsync(buffer, 0, read);
+                    }
+                }
+                decompressed.Position = 0;
+                await fs.UploadFile(filename, decompressed, decompressed.Length, true);
+            } else {
+                return StatusCode(
+                    StatusCodes.Status415UnsupportedMediaType,
+                    new ErrorResponse(
+                        ErrorCodes.UNSUPPORTED_CONTENT_ENCODING,
+                        $"Content-Encoding {encoding} is not supported"));
+            }
             return Ok(filename);
         }
 
         /// <summary>
         /// This is a backup method for sending job results. This endpoint only
-        /// accepts <c>JobResultMsg</c> and <c>JobProgressMsg</c>.
+        /// accepts <c>JobResultMsg</c>, <c>JobResultChunkMsg</c> and
+        /// <c>JobProgressMsg</c>.
         /// </summary>
         /// <returns></returns>
         [HttpPost("result")]
-        public ActionResult SendJobResult(
+        public async Task<ActionResult> SendJobResult(
             [FromBody] IClientResultMsg resultMsg,
             [FromServices] JudgerCoordinatorService coordinator) {
             var judger = AuthHelper.ExtractUsername(HttpContext.User);
             switch (resultMsg) {
                 case JobResultMsg msg:
                     coordinator.OnJobResultMessage(judger!, msg); break;
+                case JobResultChunkMsg msg:
+                    // Chunks must be stored before the final message arrives
+                    await coordinator.OnJobResultChunkMessage(judger!, msg); break;
                 case JobProgressMsg msg:
                     coordinator.OnJobProgressMessage(judger!, msg); break;
                 default:
                     return BadRequest(new ErrorResponse(
                         ErrorCodes.INVALID_MESSAGE_TYPE,
-                        "This endpoint only accepts JobResultMsg and JobProgressMsg"));
+                        "This endpoint only accepts JobResultMsg, JobResultChunkMsg and JobProgressMsg"));
             }
             return NoContent();
         }
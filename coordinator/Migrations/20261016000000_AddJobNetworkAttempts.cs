@@ -0,0 +1,24 @@
+﻿using System.Collections.Generic;
+using Karenia.Rurikawa.Models;
+using Microsoft.EntityFrameworkCore.Infrastructure;
+using Microsoft.EntityFrameworkCore.Migrations;
+
+namespace Karenia.Rurikawa.Coordinator.Migrations {
+    [DbContext(typeof(RurikawaDb))]
+    [Migration("20261016000000_AddJobNetworkAttempts")]
+    public partial class AddJobNetworkAttempts : Migration {
+        protected override void Up(MigrationBuilder migrationBuilder) {
+            migrationBuilder.AddColumn<List<string>>(
+                name: "network_attempts",
+                table: "jobs",
+                type: "text[]",
+                nullable: true);
+        }
+
+        protected override void Down(MigrationBuilder migrationBuilder) {
+            migrationBuilder.DropColumn(
+                name: "network_attempts",
+                table: "jobs");
+        }
+    }
+}
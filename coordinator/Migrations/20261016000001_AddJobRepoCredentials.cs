@@ -0,0 +1,24 @@
+﻿using Karenia.Rurikawa.Models;
+using Karenia.Rurikawa.Models.Judger;
+using Microsoft.EntityFrameworkCore.Infrastructure;
+using Microsoft.EntityFrameworkCore.Migrations;
+
+namespace Karenia.Rurikawa.Coordinator.Migrations {
+    [DbContext(typeof(RurikawaDb))]
+    [Migration("20261016000001_AddJobRepoCredentials")]
+    public partial class AddJobRepoCredentials : Migration {
+        protected override void Up(MigrationBuilder migrationBuilder) {
+            migrationBuilder.AddColumn<GitCredentials>(
+                name: "repo_credentials",
+                table: "jobs",
+                type: "jsonb",
+                nullable: true);
+        }
+
+        protected override void Down(MigrationBuilder migrationBuilder) {
+            migrationBuilder.DropColumn(
+                name: "repo_credentials",
+                table: "jobs");
+        }
+    }
+}
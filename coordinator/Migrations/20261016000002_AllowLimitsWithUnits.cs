@@ -0,0 +1,49 @@
+﻿using Karenia.Rurikawa.Models;
+using Microsoft.EntityFrameworkCore.Infrastructure;
+using Microsoft.EntityFrameworkCore.Migrations;
+
+namespace Karenia.Rurikawa.Coordinator.Migrations {
+    [DbContext(typeof(RurikawaDb))]
+    [Migration("20261016000002_AllowLimitsWithUnits")]
+    public partial class AllowLimitsWithUnits : Migration {
+        protected override void Up(MigrationBuilder migrationBuilder) {
+            migrationBuilder.AlterColumn<double>(
+                name: "time_limit",
+                table: "test_suites",
+                type: "double precision",
+                nullable: true,
+                oldClrType: typeof(int),
+                oldType: "integer",
+                oldNullable: true);
+
+            migrationBuilder.AlterColumn<long>(
+                name: "memory_limit",
+                table: "test_suites",
+                type: "bigint",
+                nullable: true,
+                oldClrType: typeof(int),
+                oldType: "integer",
+                oldNullable: true);
+        }
+
+        protected override void Down(MigrationBuilder migrationBuilder) {
+            migrationBuilder.AlterColumn<int>(
+                name: "time_limit",
+                table: "test_suites",
+                type: "integer",
+                nullable: true,
+                oldClrType: typeof(double),
+                oldType: "double precision",
+                oldNullable: true);
+
+            migrationBuilder.AlterColumn<int>(
+                name: "memory_limit",
+                table: "test_suites",
+                type: "integer",
+                nullable: true,
+                oldClrType: typeof(long),
+                oldType: "bigint",
+                oldNullable: true);
+        }
+    }
+}
@@ -0,0 +1,23 @@
+﻿using Karenia.Rurikawa.Models;
+using Microsoft.EntityFrameworkCore.Infrastructure;
+using Microsoft.EntityFrameworkCore.Migrations;
+
+namespace Karenia.Rurikawa.Coordinator.Migrations {
+    [DbContext(typeof(RurikawaDb))]
+    [Migration("20261016000003_AddJobSeed")]
+    public partial class AddJobSeed : Migration {
+        protected override void Up(MigrationBuilder migrationBuilder) {
+            migrationBuilder.AddColumn<ulong>(
+                name: "seed",
+                table: "jobs",
+                type: "numeric(20,0)",
+                nullable: true);
+        }
+
+        protected override void Down(MigrationBuilder migrationBuilder) {
+            migrationBuilder.DropColumn(
+                name: "seed",
+                table: "jobs");
+        }
+    }
+}
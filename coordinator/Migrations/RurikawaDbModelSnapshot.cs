@@ -2,6 +2,7 @@
 using System;
 using System.Collections.Generic;
 using Karenia.Rurikawa.Models;
+using Karenia.Rurikawa.Models.Judger;
 using Karenia.Rurikawa.Models.Test;
 using Microsoft.EntityFrameworkCore;
 using Microsoft.EntityFrameworkCore.Infrastructure;
@@ -314,11 +315,19 @@ namespace Karenia.Rurikawa.Coordinator.Migrations
                         .HasColumnName("judger")
                         .HasColumnType("text");
 
+                    b.Property<List<string>>("NetworkAttempts")
+                        .HasColumnName("network_attempts")
+                        .HasColumnType("text[]");
+
                     b.Property<string>("Repo")
                         .IsRequired()
                         .HasColumnName("repo")
                         .HasColumnType("text");
 
+                    b.Property<GitCredentials>("RepoCredentials")
+                        .HasColumnName("repo_credentials")
+                        .HasColumnType("jsonb");
+
                     b.Property<int?>("ResultKind")
                         .HasColumnName("result_kind")
                         .HasColumnType("integer");
@@ -337,6 +346,10 @@ namespace Karenia.Rurikawa.Coordinator.Migrations
                         .HasColumnName("revision")
                         .HasColumnType("text");
 
+                    b.Property<ulong?>("Seed")
+                        .HasColumnName("seed")
+                        .HasColumnType("numeric(20,0)");
+
                     b.Property<int>("Stage")
                         .HasColumnName("stage")
                         .HasColumnType("integer");
@@ -431,9 +444,9 @@ namespace Karenia.Rurikawa.Coordinator.Migrations
                         .HasColumnName("is_public")
                         .HasColumnType("boolean");
 
-                    b.Property<int?>("MemoryLimit")
+                    b.Property<long?>("MemoryLimit")
                         .HasColumnName("memory_limit")
-                        .HasColumnType("integer");
+                        .HasColumnType("bigint");
 
                     b.Property<string>("Name")
                         .IsRequired()
@@ -462,9 +475,9 @@ namespace Karenia.Rurikawa.Coordinator.Migrations
                         .HasColumnName("test_groups")
                         .HasColumnType("jsonb");
 
-                    b.Property<int?>("TimeLimit")
+                    b.Property<double?>("TimeLimit")
                         .HasColumnName("time_limit")
-                        .HasColumnType("integer");
+                        .HasColumnType("double precision");
 
                     b.Property<string>("Title")
                         .IsRequired()
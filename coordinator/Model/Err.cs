@@ -27,6 +27,8 @@ namespace Karenia.Rurikawa.Models {
 
         public const string JUDGER_NO_SUCH_REGISTER_TOKEN = "judger_no_such_register_token";
         public const string UNSPECIFIED_CONTENT_LENGTH = "unspecified_content_length";
+        public const string UNSUPPORTED_CONTENT_ENCODING = "unsupported_content_encoding";
+        public const string CONTENT_TOO_LARGE = "content_too_large";
         public const string INVALID_MESSAGE_TYPE = "invalid_message_type";
     }
 }
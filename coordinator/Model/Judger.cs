@@ -117,6 +117,19 @@ namespace Karenia.Rurikawa.Models.Judger {
         [Column(TypeName = "jsonb")]
         public Dictionary<string, TestResult> Results { get; set; } = new Dictionary<string, TestResult>();
 
+        /// <summary>
+        /// Network access attempts recorded while running this job, if the
+        /// test suite audits network access.
+        /// </summary>
+        public List<string>? NetworkAttempts { get; set; }
+
+        /// <summary>
+        /// The seed randomized tests were run with, as reported by the judger.
+        /// Sent back to judgers when the job is dispatched again, so that the
+        /// same tests are reproduced.
+        /// </summary>
+        public ulong? Seed { get; set; }
+
         /// <summary>
         /// The time when this job gets dispatched onto a judger.
         /// <p>
@@ -137,6 +150,29 @@ namespace Karenia.Rurikawa.Models.Judger {
         [JsonIgnore]
         public string? Judger { get; set; }
 
+        /// <summary>
+        /// Tags a judger must have to run this job, i.e. the tags of its test
+        /// suite. Only filled in when dispatching, so that judgers can
+        /// decline jobs they are not suitable for.
+        /// </summary>
+        [NotMapped]
+        public List<string>? RequiredTags { get; set; }
+
+        /// <summary>
+        /// Credentials for cloning <c>Repo</c> if it's private. Never sent
+        /// to users; judgers receive them in <c>Credentials</c>.
+        /// </summary>
+        [JsonIgnore]
+        [Column(TypeName = "jsonb")]
+        public GitCredentials? RepoCredentials { get; set; }
+
+        /// <summary>
+        /// Credentials for cloning <c>Repo</c>. Only filled in when
+        /// dispatching, so that they only reach judgers.
+        /// </summary>
+        [NotMapped]
+        public GitCredentials? Credentials { get; set; }
+
         public double Score {
             get {
                 var s = 0.0;
@@ -159,9 +195,63 @@ namespace Karenia.Rurikawa.Models.Judger {
         }
     }
 
+    /// <summary>
+    /// Credentials of a private git repository, sent as HTTP basic
+    /// authentication when cloning.
+    /// </summary>
+    public class GitCredentials {
+        /// <summary>
+        /// User name. Most hosts accept any non-empty name with access tokens.
+        /// </summary>
+        public string? Username { get; set; }
+
+        /// <summary>
+        /// Password or access token.
+        /// </summary>
+        public string Password { get; set; }
+
+        /// <summary>
+        /// Environment variables making git send these credentials with
+        /// requests to <c>repo</c> only.
+        /// </summary>
+        public Dictionary<string, string?> GitEnv(string repo) {
+            var auth = Convert.ToBase64String(
+                System.Text.Encoding.UTF8.GetBytes($"{Username ?? "git"}:{Password}"));
+            return new Dictionary<string, string?> {
+                ["GIT_CONFIG_COUNT"] = "1",
+                ["GIT_CONFIG_KEY_0"] = $"http.{repo}.extraHeader",
+                ["GIT_CONFIG_VALUE_0"] = $"Authorization: Basic {auth}",
+                ["GIT_TERMINAL_PROMPT"] = "0",
+            };
+        }
+    }
+
     public class JobBuildOutput {
         public string Output { get; set; }
         public string Error { get; set; }
+
+        /// <summary>
+        /// Dockerfile steps of the build in the order they started, if the
+        /// judger reported them.
+        /// </summary>
+        public List<BuildStageMark>? Stages { get; set; }
+    }
+
+    /// <summary>
+    /// The start of a Dockerfile step in the build output of a job.
+    /// </summary>
+    public class BuildStageMark {
+        /// <summary>
+        /// Position of the output message starting this step.
+        /// </summary>
+        public ulong? Seq { get; set; }
+
+        /// <summary>
+        /// Milliseconds since the build started.
+        /// </summary>
+        public ulong? ElapsedMs { get; set; }
+
+        public BuildStage Stage { get; set; }
     }
 
     /// <summary>
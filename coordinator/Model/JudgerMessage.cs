@@ -1,5 +1,7 @@
 using System;
 using System.Collections.Generic;
+using System.Linq;
+using System.Text.Json;
 using System.Text.Json.Serialization;
 using Dahomey.Json.Attributes;
 using Karenia.Rurikawa.Helpers;
@@ -13,7 +15,18 @@ namespace Karenia.Rurikawa.Models.Judger {
     public class ServerMsg { }
 
     [JsonDiscriminator("server_hello")]
-    public class ServerHelloMsg : ServerMsg { }
+    public class ServerHelloMsg : ServerMsg {
+        /// <summary>
+        /// <c>Content-Encoding</c>s the upload endpoint of test results can
+        /// decompress.
+        /// </summary>
+        public static readonly IReadOnlyList<string> SupportedUploadEncodings = new[] { "gzip" };
+
+        /// <summary>
+        /// <c>Content-Encoding</c>s accepted for result uploads.
+        /// </summary>
+        public List<string> AcceptEncodings { get; set; } = SupportedUploadEncodings.ToList();
+    }
 
     /// <summary>
     /// Message that provides a new job to judger with given id and specification.
@@ -67,6 +80,41 @@ namespace Karenia.Rurikawa.Models.Judger {
         public bool AsCancel { get; set; }
     }
 
+    /// <summary>
+    /// Message that requests the given client to abort every running job of
+    /// a revision of a repository, with the same semantics of <c>AsCancel</c>
+    /// as <c>AbortJobServerMsg</c>. The client replies with a
+    /// <c>RevisionAbortedMsg</c>.
+    /// </summary>
+    [JsonDiscriminator("abort_revision")]
+    public class AbortRevisionServerMsg : ServerMsg {
+        public string Repo { get; set; }
+        public string Revision { get; set; }
+        public bool AsCancel { get; set; }
+    }
+
+    /// <summary>
+    /// Message that changes the configuration of the judger at runtime.
+    /// <br/>
+    /// Keys are field names in the judger's config file. The judger only
+    /// applies fields that are safe to change at runtime, and ignores others.
+    /// </summary>
+    [JsonDiscriminator("update_config")]
+    public class UpdateConfigServerMsg : ServerMsg {
+        public Dictionary<string, JsonElement> Config { get; set; }
+    }
+
+    /// <summary>
+    /// Message that requests the given client to upload the log of a finished
+    /// job, for debugging it after the fact. Logs are only available if the
+    /// judger retained the folder of the job. The client replies with a
+    /// <c>JobLogsMsg</c>.
+    /// </summary>
+    [JsonDiscriminator("request_job_logs")]
+    public class RequestJobLogsServerMsg : ServerMsg {
+        public FlowSnake JobId { get; set; }
+    }
+
     /// <summary>
     /// Base class of all messages that are sent from a client (judger).
     /// </summary>
@@ -99,6 +147,44 @@ namespace Karenia.Rurikawa.Models.Judger {
         public string? Message { get; set; }
 
         public Dictionary<string, TestResult>? Results { get; set; }
+
+        /// <summary>
+        /// Network access attempts recorded while running, if network access
+        /// was audited.
+        /// </summary>
+        public List<string>? NetworkAttempts { get; set; }
+
+        /// <summary>
+        /// The seed randomized tests were run with.
+        /// </summary>
+        public ulong? Seed { get; set; }
+
+        /// <summary>
+        /// The build log captured by the judger, present if the job failed to
+        /// build. Takes precedence over the output received in <c>JobOutputMsg</c>s.
+        /// </summary>
+        public JobBuildOutput? BuildLog { get; set; }
+
+        /// <summary>
+        /// Whether the results were sent in <c>JobResultChunkMsg</c>s before
+        /// this message. If so, <c>Results</c> only holds the last chunk and
+        /// MUST be merged with the earlier ones.
+        /// </summary>
+        public bool Chunked { get; set; }
+    }
+
+    /// <summary>
+    /// Message that carries a part of the test results of a job, sent before
+    /// a chunked <c>JobResultMsg</c> when the results are large.
+    /// <para>
+    ///     The server MUST merge these results into the corresponding job.
+    /// </para>
+    /// </summary>
+    [JsonDiscriminator("job_result_chunk")]
+    public class JobResultChunkMsg : ClientMsg, IClientResultMsg {
+        public FlowSnake JobId { get; set; }
+
+        public Dictionary<string, TestResult> Results { get; set; }
     }
 
     /// <summary>
@@ -112,6 +198,29 @@ namespace Karenia.Rurikawa.Models.Judger {
         /// Current stage of the job.
         /// </summary>
         public JobStage Stage { get; set; }
+
+        /// <summary>
+        /// Progress through the test list, reported while running tests.
+        /// </summary>
+        public TestProgress? Progress { get; set; }
+
+        /// <summary>
+        /// Results of the tests finished before the job is cancelled or
+        /// aborted. These are also sent in <c>PartialResultMsg</c>s before.
+        /// </summary>
+        public Dictionary<string, TestResult>? Results { get; set; }
+    }
+
+    public class TestProgress {
+        /// <summary>
+        /// Number of tests finished
+        /// </summary>
+        public int Finished { get; set; }
+
+        /// <summary>
+        /// Number of tests in this job
+        /// </summary>
+        public int Total { get; set; }
     }
 
     /// <summary>
@@ -131,6 +240,39 @@ namespace Karenia.Rurikawa.Models.Judger {
         /// </summary>
         /// <value></value>
         public string? Error { get; set; }
+
+        /// <summary>
+        /// Position of this message in the build output of the job, starting
+        /// from 0.
+        /// </summary>
+        public ulong? Seq { get; set; }
+
+        /// <summary>
+        /// Milliseconds since the build started.
+        /// </summary>
+        public ulong? ElapsedMs { get; set; }
+
+        /// <summary>
+        /// The Dockerfile step starting with this message, if any.
+        /// </summary>
+        public BuildStage? Stage { get; set; }
+    }
+
+    /// <summary>
+    /// A step of a Dockerfile build, as in <c>Step 2/5 : RUN make</c>.
+    /// </summary>
+    public class BuildStage {
+        /// <summary>
+        /// Number of the step, starting from 1.
+        /// </summary>
+        public uint Step { get; set; }
+
+        /// <summary>
+        /// Number of steps in the build.
+        /// </summary>
+        public uint Total { get; set; }
+
+        public string Instruction { get; set; }
     }
 
     /// <summary>
@@ -172,6 +314,53 @@ namespace Karenia.Rurikawa.Models.Judger {
         public bool CanAcceptNewTask { get; set; }
     }
 
+    public enum JobDeclineReason {
+        CapacityExceeded,
+        StalePoll,
+        Draining,
+        MissingTags,
+    }
+
+    /// <summary>
+    /// Message that reports the judger declined a job dispatched to it.
+    /// <para>
+    ///     The server SHOULD put the job back into the queue so that it can be
+    ///     dispatched to another judger.
+    /// </para>
+    /// </summary>
+    [JsonDiscriminator("job_declined")]
+    public class JobDeclinedMsg : ClientMsg {
+        public FlowSnake JobId { get; set; }
+
+        public JobDeclineReason Reason { get; set; }
+    }
+
+    /// <summary>
+    /// Message that reports the jobs aborted by an <c>AbortRevisionServerMsg</c>.
+    /// Each of them also reports its own progress as usual.
+    /// </summary>
+    [JsonDiscriminator("revision_aborted")]
+    public class RevisionAbortedMsg : ClientMsg {
+        public string Repo { get; set; }
+
+        public string Revision { get; set; }
+
+        public List<FlowSnake> JobIds { get; set; }
+    }
+
+    /// <summary>
+    /// Message that answers a <c>RequestJobLogsServerMsg</c>, with the id of
+    /// the uploaded log file, or why the log is unavailable.
+    /// </summary>
+    [JsonDiscriminator("job_logs")]
+    public class JobLogsMsg : ClientMsg {
+        public FlowSnake JobId { get; set; }
+
+        public string? ResultFileId { get; set; }
+
+        public string? Error { get; set; }
+    }
+
     [JsonDiscriminator("job_request")]
     public class JobRequestMsg : ClientMsg {
         /// <summary>
@@ -188,6 +377,12 @@ namespace Karenia.Rurikawa.Models.Judger {
         /// An ID for tracking job requests.
         /// </summary>
         public FlowSnake? MessageId { get; set; }
+
+        /// <summary>
+        /// Bytes of command output buffered by the running jobs of this
+        /// judger, if it accounts for them
+        /// </summary>
+        public long? BufferedOutputBytes { get; set; }
     }
 }
 
@@ -43,9 +43,19 @@ namespace Karenia.Rurikawa.Models.Test {
 
         public DateTimeOffset? EndTime { get; set; }
 
-        public int? TimeLimit { get; set; }
+        /// <summary>
+        /// Time limit of every command in seconds. Written with units in the
+        /// config, e.g. <c>"2s"</c> or <c>"500ms"</c>, or as bare seconds.
+        /// </summary>
+        [JsonConverter(typeof(SerDe.TimeLimitConverter))]
+        public double? TimeLimit { get; set; }
 
-        public int? MemoryLimit { get; set; }
+        /// <summary>
+        /// Memory limit of the test container in bytes. Written with units in
+        /// the config, e.g. <c>"256MiB"</c>, or as bare bytes.
+        /// </summary>
+        [JsonConverter(typeof(SerDe.MemoryLimitConverter))]
+        public long? MemoryLimit { get; set; }
 
         public ScoringMode ScoringMode { get; set; }
 
@@ -120,9 +130,11 @@ namespace Karenia.Rurikawa.Models.Test {
 
             public DateTimeOffset? EndTime { get; set; }
 
-            public int? TimeLimit { get; set; }
+            [JsonConverter(typeof(SerDe.TimeLimitConverter))]
+            public double? TimeLimit { get; set; }
 
-            public int? MemoryLimit { get; set; }
+            [JsonConverter(typeof(SerDe.MemoryLimitConverter))]
+            public long? MemoryLimit { get; set; }
         }
     }
 
@@ -145,6 +157,24 @@ namespace Karenia.Rurikawa.Models.Test {
         public double BaseScore { get; set; } = 1.0;
         public bool HasOut { get; set; }
         public bool ShouldFail { get; set; }
+
+        /// <summary>
+        /// Environment variables of this test case, overriding the ones of
+        /// the test suite.
+        /// </summary>
+        public Dictionary<string, string>? Env { get; set; }
+
+        /// <summary>
+        /// The exit code the failing command must return. Implies
+        /// <c>ShouldFail</c>.
+        /// </summary>
+        public int? ExpectedExitCode { get; set; }
+
+        /// <summary>
+        /// A regular expression the stderr of the failing command must
+        /// match. Implies <c>ShouldFail</c>.
+        /// </summary>
+        public string? ExpectedStderr { get; set; }
     }
 
     public enum TestResultKind {
@@ -155,9 +185,12 @@ namespace Karenia.Rurikawa.Models.Test {
         TimeLimitExceeded = 4,
         MemoryLimitExceeded = 5,
         ShouldFail = 6,
+        PresentationError = 7,
+        PartialScore = 8,
         NotRan = -1,
         Waiting = -2,
         Running = -3,
+        Skipped = -4,
         OtherError = -100,
     }
 
@@ -189,9 +222,117 @@ namespace Karenia.Rurikawa.Models.Test {
         public TestResultKind Kind { get; set; }
         public string? ResultFileId { get; set; }
         public double? Score { get; set; }
+        public bool UploadFailed { get; set; }
+
+        /// <summary>
+        /// Resources used by the commands of this test, if they have run.
+        /// </summary>
+        public ResourceUsage? Usage { get; set; }
+    }
+
+    /// <summary>
+    /// Resources used by the commands of a test, as measured by the judger.
+    /// </summary>
+    public class ResourceUsage {
+        /// <summary>
+        /// Total time spent running the commands, in milliseconds.
+        /// </summary>
+        public ulong WallTimeMs { get; set; }
+
+        /// <summary>
+        /// Total CPU time of the commands in milliseconds, if measured.
+        /// </summary>
+        public ulong? CpuTimeMs { get; set; }
+
+        /// <summary>
+        /// The highest peak memory usage among the commands in bytes, if measured.
+        /// </summary>
+        public ulong? PeakMemory { get; set; }
     }
 
     namespace SerDe {
+        /// <summary>
+        /// Reads a number followed by a unit, like the judger does.
+        /// </summary>
+        static class UnitParser {
+            public static readonly (string, double)[] TimeUnits = {
+                ("ms", 0.001), ("s", 1), ("min", 60), ("m", 60), ("h", 3600),
+            };
+
+            public static readonly (string, double)[] MemoryUnits = {
+                ("KiB", 1 << 10), ("MiB", 1 << 20), ("GiB", 1 << 30),
+                ("KB", 1e3), ("MB", 1e6), ("GB", 1e9),
+                ("K", 1 << 10), ("M", 1 << 20), ("G", 1 << 30), ("B", 1),
+            };
+
+            public static double Parse(string s, (string, double)[] units) {
+                s = s.Trim();
+                foreach (var (unit, scale) in units) {
+                    if (!s.EndsWith(unit, StringComparison.Ordinal)) continue;
+                    var number = s.Substring(0, s.Length - unit.Length).TrimEnd();
+                    if (!double.TryParse(
+                            number,
+                            System.Globalization.NumberStyles.Float,
+                            System.Globalization.CultureInfo.InvariantCulture,
+                            out var value)
+                        || !double.IsFinite(value) || value < 0) {
+                        throw new JsonException($"Invalid value '{s}': not a non-negative number");
+                    }
+                    return value * scale;
+                }
+                throw new JsonException($"Invalid value '{s}': unknown unit");
+            }
+        }
+
+        /// <summary>
+        /// Reads time limits like <c>"2s"</c> or bare seconds into seconds.
+        /// </summary>
+        public class TimeLimitConverter : JsonConverter<double?> {
+            public override double? Read(
+                ref Utf8JsonReader reader,
+                Type typeToConvert,
+                JsonSerializerOptions options) {
+                return reader.TokenType switch {
+                    JsonTokenType.Number => reader.GetDouble(),
+                    JsonTokenType.String => UnitParser.Parse(reader.GetString()!, UnitParser.TimeUnits),
+                    _ => throw new JsonException("Expected number or string"),
+                };
+            }
+
+            public override void Write(
+                Utf8JsonWriter writer,
+                double? value,
+                JsonSerializerOptions options) {
+                if (value == null) writer.WriteNullValue();
+                else writer.WriteNumberValue(value.Value);
+            }
+        }
+
+        /// <summary>
+        /// Reads memory limits like <c>"256MiB"</c> or bare bytes into bytes.
+        /// </summary>
+        public class MemoryLimitConverter : JsonConverter<long?> {
+            public override long? Read(
+                ref Utf8JsonReader reader,
+                Type typeToConvert,
+                JsonSerializerOptions options) {
+                return reader.TokenType switch {
+                    JsonTokenType.Number => reader.GetInt64(),
+                    JsonTokenType.String => (long)Math.Round(
+                        UnitParser.Parse(reader.GetString()!, UnitParser.MemoryUnits)),
+                    _ => throw new JsonException("Expected number or string"),
+                };
+            }
+
+            public override void Write(
+                Utf8JsonWriter writer,
+                long? value,
+                JsonSerializerOptions options) {
+                if (value == null) writer.WriteNullValue();
+                else writer.WriteNumberValue(value.Value);
+            }
+        }
+
         public class TestCaseDefinitionConverter : JsonConverter<TestCaseDefinition> {
             public override TestCaseDefinition Read(
                 ref Utf8JsonReader reader,
@@ -218,11 +359,17 @@ namespace Karenia.Rurikawa.Models.Test {
                 string propName_hasOut = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.HasOut)) ?? nameof(TestCaseDefinition.HasOut);
                 string propName_shouldFail = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.ShouldFail)) ?? nameof(TestCaseDefinition.ShouldFail);
                 string propName_baseScore = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.BaseScore)) ?? nameof(TestCaseDefinition.BaseScore);
+                string propName_env = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.Env)) ?? nameof(TestCaseDefinition.Env);
+                string propName_expectedExitCode = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.ExpectedExitCode)) ?? nameof(TestCaseDefinition.ExpectedExitCode);
+                string propName_expectedStderr = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.ExpectedStderr)) ?? nameof(TestCaseDefinition.ExpectedStderr);
 
                 string? name = null;
                 bool? hasOut = null;
                 bool? shouldFail = null;
                 double? baseScore = null;
+                Dictionary<string, string>? env = null;
+                int? expectedExitCode = null;
+                string? expectedStderr = null;
 
                 while (reader.Read()) {
                     if (reader.TokenType == JsonTokenType.EndObject) break;
@@ -247,6 +394,18 @@ namespace Karenia.Rurikawa.Models.Test {
                         if (baseScore != null)
                             throw new JsonException("Duplicate property 'baseScore'");
                         baseScore = reader.GetDouble();
+                    } else if (key == propName_env) {
+                        if (env != null)
+                            throw new JsonException("Duplicate property 'env'");
+                        env = JsonSerializer.Deserialize<Dictionary<string, string>>(ref reader, options);
+                    } else if (key == propName_expectedExitCode) {
+                        if (expectedExitCode != null)
+                            throw new JsonException("Duplicate property 'expectedExitCode'");
+                        expectedExitCode = reader.GetInt32();
+                    } else if (key == propName_expectedStderr) {
+                        if (expectedStderr != null)
+                            throw new JsonException("Duplicate property 'expectedStderr'");
+                        expectedStderr = reader.GetString();
                     } else {
                         throw new JsonException($"Unknown property '{key}'");
                     }
@@ -256,8 +415,11 @@ namespace Karenia.Rurikawa.Models.Test {
                 return new TestCaseDefinition {
                     Name = name,
                     HasOut = hasOut ?? true,
-                    ShouldFail = shouldFail ?? false,
-                    BaseScore = baseScore ?? 1.0
+                    ShouldFail = (shouldFail ?? false) || expectedExitCode != null || expectedStderr != null,
+                    BaseScore = baseScore ?? 1.0,
+                    Env = env,
+                    ExpectedExitCode = expectedExitCode,
+                    ExpectedStderr = expectedStderr
                 };
             }
 
@@ -269,12 +431,25 @@ namespace Karenia.Rurikawa.Models.Test {
                 string propName_hasOut = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.HasOut)) ?? nameof(TestCaseDefinition.HasOut);
                 string propName_shouldFail = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.ShouldFail)) ?? nameof(TestCaseDefinition.ShouldFail);
                 string propName_baseScore = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.BaseScore)) ?? nameof(TestCaseDefinition.BaseScore);
+                string propName_env = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.Env)) ?? nameof(TestCaseDefinition.Env);
+                string propName_expectedExitCode = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.ExpectedExitCode)) ?? nameof(TestCaseDefinition.ExpectedExitCode);
+                string propName_expectedStderr = options.PropertyNamingPolicy?.ConvertName(nameof(TestCaseDefinition.ExpectedStderr)) ?? nameof(TestCaseDefinition.ExpectedStderr);
 
                 writer.WriteStartObject();
                 writer.WriteString(propName_name, value.Name);
                 writer.WriteBoolean(propName_hasOut, value.HasOut);
                 writer.WriteBoolean(propName_shouldFail, value.ShouldFail);
                 writer.WriteNumber(propName_baseScore, value.BaseScore);
+                if (value.Env != null && value.Env.Count > 0) {
+                    writer.WritePropertyName(propName_env);
+                    JsonSerializer.Serialize(writer, value.Env, options);
+                }
+                if (value.ExpectedExitCode != null) {
+                    writer.WriteNumber(propName_expectedExitCode, value.ExpectedExitCode.Value);
+                }
+                if (value.ExpectedStderr != null) {
+                    writer.WriteString(propName_expectedStderr, value.ExpectedStderr);
+                }
                 writer.WriteEndObject();
             }
         }
@@ -17,6 +17,7 @@ namespace Karenia.Rurikawa.Models.WebsocketApi {
         public FlowSnake JobId { get; set; }
         public string? BuildOutputFile { get; set; }
         public JobStage? Stage { get; set; }
+        public TestProgress? Progress { get; set; }
         public JobResultKind? JobResult { get; set; }
         public Dictionary<string, TestResult>? TestResult { get; set; }
     }
@@ -96,8 +96,8 @@ namespace Karenia.Rurikawa.Coordinator.Services {
         /// </returns>
         public async ValueTask<bool> TryUseConnection(HttpContext ctx) {
             using var scope = scopeProvider.CreateScope();
-            if (ctx.Request.Query.TryGetValue("token", out var authStrings)) {
-                var auth = authStrings.First();
+            var auth = GetConnectionToken(ctx.Request, out var subProtocol);
+            if (auth != null) {
                 var tokenEntry = await Authenticate(auth);
                 if (tokenEntry != null) {
                     // A connection id is passed to ensure that the client can safely
@@ -119,7 +119,7 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                     }
                     connLock.Dispose();
 
-                    var ws = await ctx.WebSockets.AcceptWebSocketAsync();
+                    var ws = await ctx.WebSockets.AcceptWebSocketAsync(subProtocol);
 
                     var wrapper = new JudgerWebsocketWrapperTy(
                         ws,
@@ -156,6 +156,40 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             return false;
         }
 
+        /// <summary>
+        /// Read the access token of a judger connecting to the websocket
+        /// endpoint. The token is looked up in the <c>Authorization</c>
+        /// header, then in a <c>rurikawa.token.&lt;token&gt;</c> subprotocol
+        /// and finally in the <c>token</c> query parameter.
+        /// </summary>
+        /// <param name="subProtocol">
+        ///     The subprotocol to accept the connection with, if the judger
+        ///     requested any.
+        /// </param>
+        static string? GetConnectionToken(HttpRequest req, out string? subProtocol) {
+            subProtocol = null;
+            string? token = null;
+
+            var protocols = req.HttpContext.WebSockets.WebSocketRequestedProtocols;
+            foreach (var protocol in protocols) {
+                if (protocol.StartsWith(WsTokenSubProtocolPrefix, StringComparison.Ordinal)) {
+                    token ??= protocol.Substring(WsTokenSubProtocolPrefix.Length);
+                } else if (protocol == WsSubProtocol) {
+                    subProtocol = protocol;
+                }
+            }
+
+            if (req.Headers.TryGetValue("Authorization", out var header)) {
+                token = header.First();
+            } else if (token == null && req.Query.TryGetValue("token", out var query)) {
+                token = query.First();
+            }
+            return string.IsNullOrEmpty(token) ? null : token;
+        }
+
+        const string WsSubProtocol = "rurikawa";
+        const string WsTokenSubProtocolPrefix = "rurikawa.token.";
+
         IDisposable AssignObservables(string clientId, JudgerWebsocketWrapperTy client) {
             return client.Messages.Subscribe((msg) => {
                 logger.LogTrace($"Judger {clientId} sent message of type {msg.GetType().Name}");
@@ -172,6 +206,24 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                         OnJobRequestMessage(clientId, msg1); break;
                     case JobOutputMsg msg1:
                         OnJobOutputMessage(clientId, msg1); break;
+                    case JobDeclinedMsg msg1:
+                        OnJobDeclinedMessage(clientId, msg1); break;
+                    case RevisionAbortedMsg msg1:
+                        logger.LogInformation(
+                            "Judger {0} aborted jobs of {1} at {2}: {3}",
+                            clientId, msg1.Repo, msg1.Revision, string.Join(", ", msg1.JobIds));
+                        break;
+                    case JobLogsMsg msg1:
+                        if (msg1.ResultFileId != null) {
+                            logger.LogInformation(
+                                "Judger {0} uploaded logs of job {1} as {2}",
+                                clientId, msg1.JobId, msg1.ResultFileId);
+                        } else {
+                            logger.LogWarning(
+                                "Judger {0} cannot upload logs of job {1}: {2}",
+                                clientId, msg1.JobId, msg1.Error);
+                        }
+                        break;
                     default:
                         logger.LogCritical("Unable to handle message type {0}", msg.GetType().Name);
                         break;
@@ -206,6 +258,10 @@ namespace Karenia.Rurikawa.Coordinator.Services {
 
                 reqLock.Dispose();
 
+                if (msg.BufferedOutputBytes != null) {
+                    logger.LogDebug("Judger {0} buffers {1} bytes of output", clientId, msg.BufferedOutputBytes);
+                }
+
                 var dispatchedCount = await TryDispatchJobFromDatabase(conn, msg.RequestForNewTask, msg.MessageId);
 
                 if (dispatchedCount > 0) {
@@ -234,11 +290,19 @@ namespace Karenia.Rurikawa.Coordinator.Services {
 
             frontendService.OnJobStautsUpdate(jobId, new Models.WebsocketApi.JobStatusUpdateMsg {
                 JobId = jobId,
-                Stage = msg.Stage
+                Stage = msg.Stage,
+                Progress = msg.Progress
             });
 
             if (job.Stage != msg.Stage) {
                 job.Stage = msg.Stage;
+                // Keep what has been judged in cancelled jobs. Aborted jobs are
+                // run again, so their results are not kept.
+                if (msg.Stage == JobStage.Cancelled && msg.Results != null) {
+                    foreach (var (testId, result) in msg.Results) {
+                        job.Results[testId] = result;
+                    }
+                }
                 await db.SaveChangesAsync();
             }
 
@@ -247,11 +311,28 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                 var redis = scope.ServiceProvider.GetService<RedisService>()!;
                 var redisDb = await redis.GetDatabase();
                 await redisDb.KeyDeleteAsync(
-                    new RedisKey[] { FormatJobStdout(jobId), FormatJobError(jobId) },
+                    new RedisKey[] { FormatJobStdout(jobId), FormatJobError(jobId), FormatJobStages(jobId) },
                     flags: CommandFlags.FireAndForget);
             }
         }
 
+        public async void OnJobDeclinedMessage(string clientId, JobDeclinedMsg msg) {
+            using var scope = scopeProvider.CreateScope();
+            var db = GetDb(scope);
+
+            FlowSnake jobId = msg.JobId;
+            logger.LogInformation("Judger {0} declined job {1}: {2}", clientId, jobId, msg.Reason);
+            var job = await db.Jobs.Where(j => j.Id == jobId).FirstOrDefaultAsync();
+            if (job == null) {
+                logger.LogError("Cannot find job {0}, error?", jobId);
+                return;
+            }
+            if (job.Stage != JobStage.Dispatched) return;
+
+            job.Stage = JobStage.Queued;
+            await db.SaveChangesAsync();
+        }
+
         public async void OnJobResultMessage(string clientId, JobResultMsg msg) {
             using var scope = scopeProvider.CreateScope();
             var db = GetDb(scope);
@@ -264,7 +345,7 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                 return;
             }
 
-            var buildResultFilename = await UploadJobBuildOutput(msg.JobId);
+            var buildResultFilename = await UploadJobBuildOutput(msg.JobId, msg.BuildLog);
 
             if (job == null) {
                 logger.LogError("Unable to find job {0} ({1}) in database! Please recheck", msg.JobId, msg.JobId.Num);
@@ -280,25 +361,47 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             });
 
             job.BuildOutputFile = buildResultFilename;
-            job.Results = msg.Results ?? new Dictionary<string, TestResult>();
+            if (msg.Chunked) {
+                foreach (var (testId, result) in msg.Results ?? new Dictionary<string, TestResult>()) {
+                    job.Results[testId] = result;
+                }
+            } else {
+                job.Results = msg.Results ?? new Dictionary<string, TestResult>();
+            }
             job.Stage = JobStage.Finished;
             job.ResultKind = msg.JobResult;
             job.ResultMessage = msg.Message;
+            job.NetworkAttempts = msg.NetworkAttempts;
+            job.Seed = msg.Seed ?? job.Seed;
             job.FinishTime = DateTimeOffset.Now;
             await db.SaveChangesAsync();
             await tx.CommitAsync();
         }
 
-        async Task<string> UploadJobBuildOutput(FlowSnake jobId) {
+        /// <summary>
+        /// Upload the build output of a job. The build log reported by the
+        /// judger is used if present, otherwise the output received live is used.
+        /// </summary>
+        async Task<string> UploadJobBuildOutput(FlowSnake jobId, JobBuildOutput? buildLog) {
             var db = await redis.GetDatabase();
             // 2MiB
             const int maxLength = 2 * 1024 * 1024;
-            string? buildOutput = await db.StringGetRangeAsync(FormatJobStdout(jobId), -maxLength, -1);
-            string? buildError = await db.StringGetRangeAsync(FormatJobError(jobId), -maxLength, -1);
-            var res = new JobBuildOutput {
-                Output = buildOutput,
-                Error = buildError
-            };
+            var res = buildLog;
+            if (res == null) {
+                string? buildOutput = await db.StringGetRangeAsync(FormatJobStdout(jobId), -maxLength, -1);
+                string? buildError = await db.StringGetRangeAsync(FormatJobError(jobId), -maxLength, -1);
+                res = new JobBuildOutput {
+                    Output = buildOutput,
+                    Error = buildError
+                };
+            }
+            var stages = await db.ListRangeAsync(FormatJobStages(jobId));
+            if (stages.Length > 0) {
+                res.Stages = stages
+                    .Select(x => JsonSerializer.Deserialize<BuildStageMark>((string)x, jsonSerializerOptions)!)
+                    .OrderBy(x => x.Seq)
+                    .ToList();
+            }
             var stringified = JsonSerializer.SerializeToUtf8Bytes(res, jsonSerializerOptions);
             using var scope = scopeProvider.CreateScope();
             var fileBucket = scope.ServiceProvider.GetService<SingleBucketFileStorageService>();
@@ -308,12 +411,34 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             await fileBucket!.UploadFile(filename, new MemoryStream(stringified), stringified.LongLength);
 
             await db.KeyDeleteAsync(
-                new RedisKey[] { FormatJobStdout(jobId), FormatJobError(jobId) },
+                new RedisKey[] { FormatJobStdout(jobId), FormatJobError(jobId), FormatJobStages(jobId) },
                 flags: CommandFlags.FireAndForget);
 
             return filename;
         }
 
+        /// <summary>
+        /// Merge a chunk of the results of a job, which is followed by a
+        /// chunked <c>JobResultMsg</c>.
+        /// </summary>
+        public async Task OnJobResultChunkMessage(string clientId, JobResultChunkMsg msg) {
+            using var scope = scopeProvider.CreateScope();
+            var db = GetDb(scope);
+            using var tx = await db.Database.BeginTransactionAsync();
+
+            var job = await db.Jobs.Where(j => j.Id == msg.JobId).SingleOrDefaultAsync();
+            if (job == null || !ShouldAddResult(job)) {
+                logger.LogError("Judger {0} tried to add result to a stopped job {1}, error?", clientId, msg.JobId);
+                return;
+            }
+
+            foreach (var (testId, result) in msg.Results) {
+                job.Results[testId] = result;
+            }
+            await db.SaveChangesAsync();
+            await tx.CommitAsync();
+        }
+
         async void OnPartialResultMessage(string clientId, PartialResultMsg msg) {
             using var scope = scopeProvider.CreateScope();
             var db = GetDb(scope);
@@ -361,6 +486,21 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                 await db.KeyExpireAsync(key, timeout);
             }
 
+            if (msg.Stage != null) {
+                // Kept apart from the output, which is only kept in part
+                string key = FormatJobStages(msg.JobId);
+                var mark = new BuildStageMark {
+                    Seq = msg.Seq,
+                    ElapsedMs = msg.ElapsedMs,
+                    Stage = msg.Stage,
+                };
+                await db.ListRightPushAsync(
+                    key,
+                    JsonSerializer.Serialize(mark, jsonSerializerOptions),
+                    flags: CommandFlags.FireAndForget);
+                await db.KeyExpireAsync(key, timeout);
+            }
+
             // if (msg.Stream != null)
             //     values.Add(new NameValueEntry("stream", msg.Stream));
             // if (msg.Error != null)
@@ -375,6 +515,7 @@ namespace Karenia.Rurikawa.Coordinator.Services {
 
         public static string FormatJobError(FlowSnake id) => $"job:{id}:error";
         public static string FormatJobStdout(FlowSnake id) => $"job:{id}:stream";
+        public static string FormatJobStages(FlowSnake id) => $"job:{id}:stages";
 
         static bool ShouldChangeStage(Job job) {
             return job.Stage != JobStage.Aborted && job.Stage != JobStage.Cancelled && job.Stage != JobStage.Finished;
@@ -407,6 +548,7 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             var redis = await this.redis.GetDatabase();
             await redis.StringSetAsync(FormatJobStdout(job.Id), "", expiry: TimeSpan.FromHours(2), flags: CommandFlags.FireAndForget);
             await redis.StringSetAsync(FormatJobError(job.Id), "", expiry: TimeSpan.FromHours(2), flags: CommandFlags.FireAndForget);
+            await redis.KeyDeleteAsync(FormatJobStages(job.Id), flags: CommandFlags.FireAndForget);
 
             try {
                 await judger.Socket.SendMessage(new MultipleNewJobServerMsg() {
@@ -442,6 +584,7 @@ namespace Karenia.Rurikawa.Coordinator.Services {
                 foreach (var job in jobs) {
                     await redis.StringSetAsync(FormatJobStdout(job.Id), "", expiry: TimeSpan.FromHours(2), flags: CommandFlags.FireAndForget);
                     await redis.StringSetAsync(FormatJobError(job.Id), "", expiry: TimeSpan.FromHours(2), flags: CommandFlags.FireAndForget);
+                    await redis.KeyDeleteAsync(FormatJobStages(job.Id), flags: CommandFlags.FireAndForget);
 
                     job.Judger = judger.Id;
                     job.Stage = JobStage.Dispatched;
@@ -453,27 +596,54 @@ namespace Karenia.Rurikawa.Coordinator.Services {
 
         static readonly TimeSpan DISPATH_TIMEOUT = TimeSpan.FromMinutes(30);
 
-        protected async Task<Job?> GetLastUndispatchedJobFromDatabase(RurikawaDb db, List<string>? tags, bool allowUntagged) {
-            IQueryable<Job> res = QueuedCriteria(db.Jobs);
-            if (tags != null) {
-                // join the test suites for suite tags
-                var query = res.Join(db.TestSuites, j => j.TestSuite, t => t.Id, (job, suite) => new { job, suite });
-                if (allowUntagged) {
-                    query = query.Where(x => x.suite.Tags == null
-                    || x.suite.Tags.Count == 0
-                    || x.suite.Tags.All(tag => tags.Contains(tag)));
-                } else {
-                    query = query.Where(x => x.suite.Tags != null && x.suite.Tags.All(tag => tags.Contains(tag)));
-                }
-                // but still return a job
-                res = query.Select(x => x.job);
+        /// <summary>
+        /// Fill in the fields of the given jobs only sent to judgers:
+        /// <c>RequiredTags</c> from the tags of their test suites, and
+        /// <c>Credentials</c> from the stored credentials of their repos.
+        /// </summary>
+        protected static async Task FillDispatchInfo(RurikawaDb db, List<Job> jobs) {
+            var suiteIds = jobs.Select(j => j.TestSuite).Distinct().ToList();
+            var suiteTags = await db.TestSuites
+                .Where(s => suiteIds.Contains(s.Id))
+                .ToDictionaryAsync(s => s.Id, s => s.Tags);
+            foreach (var job in jobs) {
+                job.RequiredTags = suiteTags.GetValueOrDefault(job.TestSuite);
+                job.Credentials = job.RepoCredentials;
             }
-            return await res
+        }
+
+        /// <summary>
+        /// Keep only the jobs whose test suites a judger with the given tags
+        /// can run. Jobs are not filtered if the judger has no tags.
+        /// </summary>
+        protected static IQueryable<Job> TagCriteria(RurikawaDb db, IQueryable<Job> jobs, List<string>? tags, bool allowUntagged) {
+            if (tags == null) return jobs;
+            // join the test suites for suite tags
+            var query = jobs.Join(db.TestSuites, j => j.TestSuite, t => t.Id, (job, suite) => new { job, suite });
+            if (allowUntagged) {
+                query = query.Where(x => x.suite.Tags == null
+                || x.suite.Tags.Count == 0
+                || x.suite.Tags.All(tag => tags.Contains(tag)));
+            } else {
+                query = query.Where(x => x.suite.Tags != null && x.suite.Tags.All(tag => tags.Contains(tag)));
+            }
+            // but still return a job
+            return query.Select(x => x.job);
+        }
+
+        protected async Task<Job?> GetLastUndispatchedJobFromDatabase(RurikawaDb db, List<string>? tags, bool allowUntagged) {
+            return await TagCriteria(db, QueuedCriteria(db.Jobs), tags, allowUntagged)
                 .OrderBy(j => j.Id).FirstOrDefaultAsync();
         }
 
-        protected async Task<List<Job>> GetUndispatchedJobsFromDatabase(RurikawaDb db, int count) {
-            var res = await QueuedCriteria(db.Jobs)
+        /// <summary>
+        /// Get at most <c>count</c> undispatched jobs that a judger with the
+        /// given tags can run. Jobs it would decline for missing tags are
+        /// left for other judgers, instead of being offered to it again and
+        /// again.
+        /// </summary>
+        protected async Task<List<Job>> GetUndispatchedJobsFromDatabase(RurikawaDb db, int count, List<string>? tags, bool allowUntagged) {
+            var res = await TagCriteria(db, QueuedCriteria(db.Jobs), tags, allowUntagged)
                 .OrderBy(j => j.Id)
                 .Take(count)
                 .ToListAsync();
@@ -514,6 +684,7 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             if (job == null) return false;
 
             try {
+                await FillDispatchInfo(db, new List<Job> { job });
                 var res = await DispatchJob(judger, job);
                 await db.SaveChangesAsync();
                 await tx.CommitAsync();
@@ -539,9 +710,11 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             using var scope = scopeProvider.CreateScope();
             var db = GetDb(scope);
             using var tx = await db.Database.BeginTransactionAsync(System.Data.IsolationLevel.Serializable);
-            var jobs = await GetUndispatchedJobsFromDatabase(db, count);
+            var jobs = await GetUndispatchedJobsFromDatabase(
+                db, count, judger.DbJudgerEntry.Tags, judger.DbJudgerEntry.AcceptUntaggedJobs);
 
             try {
+                await FillDispatchInfo(db, jobs);
                 var res = await DispatchJobs(judger, jobs, replyTo);
                 await db.SaveChangesAsync();
                 await tx.CommitAsync();
@@ -596,6 +769,28 @@ namespace Karenia.Rurikawa.Coordinator.Services {
             JobQueue.Writer.Complete();
         }
 
+        /// <summary>
+        /// Send a configuration update to all connected judgers.
+        /// </summary>
+        /// <returns>The number of judgers the update was sent to</returns>
+        public async Task<int> BroadcastConfigUpdate(UpdateConfigServerMsg msg) {
+            List<Judger> judgers;
+            using (await connectionLock.LockAsync()) {
+                judgers = connections.Values.ToList();
+            }
+
+            var sent = 0;
+            foreach (var judger in judgers) {
+                try {
+                    await judger.Socket.SendMessage(msg);
+                    sent++;
+                } catch (Exception e) {
+                    logger.LogWarning(e, "Failed to send config update to judger {0}", judger.Id);
+                }
+            }
+            return sent;
+        }
+
         /// <summary>
         /// Get information about connected judgers
         /// </summary>
@@ -120,11 +120,18 @@ namespace Karenia.Rurikawa.Coordinator {
             dis.RegisterType<Models.Judger.JobProgressMsg>();
             dis.RegisterType<Models.Judger.ServerHelloMsg>();
             dis.RegisterType<Models.Judger.JobResultMsg>();
+            dis.RegisterType<Models.Judger.JobResultChunkMsg>();
             dis.RegisterType<Models.Judger.PartialResultMsg>();
             dis.RegisterType<Models.Judger.AbortJobServerMsg>();
+            dis.RegisterType<Models.Judger.AbortRevisionServerMsg>();
+            dis.RegisterType<Models.Judger.UpdateConfigServerMsg>();
+            dis.RegisterType<Models.Judger.RequestJobLogsServerMsg>();
             dis.RegisterType<Models.Judger.NewJobServerMsg>();
             dis.RegisterType<Models.Judger.JobRequestMsg>();
             dis.RegisterType<Models.Judger.JobOutputMsg>();
+            dis.RegisterType<Models.Judger.JobDeclinedMsg>();
+            dis.RegisterType<Models.Judger.RevisionAbortedMsg>();
+            dis.RegisterType<Models.Judger.JobLogsMsg>();
             dis.RegisterType<Models.WebsocketApi.JobStatusUpdateMsg>();
             dis.RegisterType<Models.WebsocketApi.JudgerStatusUpdateMsg>();
             dis.RegisterType<Models.WebsocketApi.NewJobUpdateMsg>();
@@ -0,0 +1,107 @@
+//! Build output forwarded to the coordinator, with timing and the progress of
+//! Dockerfile steps so that a timeline of the build can be shown.
+
+use super::model::{BuildStage, JobOutputMsg};
+use crate::prelude::*;
+use std::time::Instant;
+
+impl BuildStage {
+    /// Parse the first `Step N/M : INSTRUCTION` line in `stream`, as printed
+    /// by the classic Docker builder at the start of every step.
+    pub fn parse(stream: &str) -> Option<BuildStage> {
+        stream.lines().find_map(|line| {
+            let rest = line.trim_start().strip_prefix("Step ")?;
+            let (progress, instruction) = rest.split_once(" : ")?;
+            let (step, total) = progress.split_once('/')?;
+            Some(BuildStage {
+                step: step.parse().ok()?,
+                total: total.parse().ok()?,
+                instruction: instruction.trim().to_owned(),
+            })
+        })
+    }
+}
+
+/// Turns the build output of a job into [`JobOutputMsg`]s numbered in order
+/// and stamped with the time since the build started.
+#[derive(Debug)]
+pub struct BuildLogStamper {
+    job_id: FlowSnake,
+    start: Instant,
+    seq: u64,
+}
+
+impl BuildLogStamper {
+    pub fn new(job_id: FlowSnake) -> BuildLogStamper {
+        BuildLogStamper {
+            job_id,
+            start: Instant::now(),
+            seq: 0,
+        }
+    }
+
+    /// Split a piece of build output into messages like
+    /// [`JobOutputMsg::chunked`], and stamp them. The first message carries
+    /// the Dockerfile step started in `stream`, if any.
+    pub fn stamp(&mut self, stream: Option<String>, error: Option<String>) -> Vec<JobOutputMsg> {
+        let elapsed_ms = self.start.elapsed().as_millis() as u64;
+        let stage = stream.as_deref().and_then(BuildStage::parse);
+        let mut msgs = JobOutputMsg::chunked(self.job_id, stream, error);
+        for msg in &mut msgs {
+            msg.seq = Some(self.seq);
+            msg.elapsed_ms = Some(elapsed_ms);
+            self.seq += 1;
+        }
+        msgs[0].stage = stage;
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_stages() {
+        let output = [
+            "Step 1/3 : FROM alpine:3.14",
+            "\n",
+            " ---> 14119a10abf4\n",
+            "Step 2/3 : RUN apk add --no-cache gcc musl-dev\n",
+            " ---> Running in 6a4f5d0e8d2c\n",
+            "fetch https://dl-cdn.alpinelinux.org/alpine/v3.14/main/x86_64/APKINDEX.tar.gz\n",
+            "Removing intermediate container 6a4f5d0e8d2c\n",
+            "Step 3/3 : COPY . /app\n",
+            "Successfully built 0123456789ab\n",
+        ];
+        let mut stamper = BuildLogStamper::new(FlowSnake::generate());
+        let msgs = output
+            .iter()
+            .flat_map(|x| stamper.stamp(Some(x.to_string()), None))
+            .collect::<Vec<_>>();
+
+        let seqs = msgs.iter().map(|x| x.seq.unwrap()).collect::<Vec<_>>();
+        assert_eq!(seqs, (0..output.len() as u64).collect::<Vec<_>>());
+        assert!(msgs.windows(2).all(|x| x[0].elapsed_ms <= x[1].elapsed_ms));
+        for (msg, line) in msgs.iter().zip(&output) {
+            assert_eq!(msg.stream.as_deref(), Some(*line));
+        }
+
+        let stages = msgs
+            .iter()
+            .filter_map(|x| Some((x.seq?, x.stage.as_ref()?)))
+            .map(|(seq, stage)| (seq, stage.step, stage.total, stage.instruction.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stages,
+            [
+                (0, 1, 3, "FROM alpine:3.14"),
+                (3, 2, 3, "RUN apk add --no-cache gcc musl-dev"),
+                (7, 3, 3, "COPY . /app"),
+            ]
+        );
+
+        assert_eq!(BuildStage::parse("Step one/3 : FROM alpine"), None);
+        assert_eq!(BuildStage::parse("Steps 1/3 were skipped"), None);
+    }
+}
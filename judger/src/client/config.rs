@@ -1,28 +1,265 @@
-use super::model::AbortJob;
-use crate::prelude::{CancellationTokenHandle, FlowSnake};
+use super::{job_log::JobLogConfig, model::JobResultKind, outbox::Outbox};
+use crate::{
+    fs::{extract::ExtractLimits, FsRetry, JudgeRootSearch},
+    prelude::{CancellationTokenHandle, FlowSnake},
+    tester::exec::pull,
+    util::{compress::UploadCompression, mem_budget::MemoryBudget},
+};
 use arc_swap::{ArcSwap, ArcSwapOption};
+use bollard::{auth::DockerCredentials, ClientVersion, Docker};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    io::BufReader,
+    path::{Path, PathBuf},
     sync::atomic::AtomicBool,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::JoinHandle,
 };
-use tokio::{sync::Mutex, task::JoinHandle};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
-    pub host: String,
+    /// Address of the coordinator (including the port if needed), or a list
+    /// of them tried in order, switching to the next one when the connection
+    /// fails or drops.
+    pub host: CoordinatorHosts,
     pub max_concurrent_tasks: usize,
+    /// Maximum number of test results being uploaded at the same time.
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub max_concurrent_uploads: usize,
+    /// Maximum number of undelivered result messages kept in the outbox.
+    #[serde(default = "default_max_outbox_messages")]
+    pub max_outbox_messages: usize,
+    /// Times to try sending a result message, backing off between attempts,
+    /// before queueing it into the outbox to be retried in the background.
+    #[serde(default = "default_result_send_attempts")]
+    pub result_send_attempts: u32,
     pub ssl: bool,
     pub access_token: Option<String>,
+    /// Key name of the access token inside the system keyring. When set, the
+    /// access token is read from (and saved into) the keyring instead of the
+    /// config file. Requires the `keyring` feature.
+    #[serde(default)]
+    pub access_token_keyring: Option<String>,
     pub register_token: Option<String>,
     pub alternate_name: Option<String>,
     pub tags: Option<Vec<String>>,
     pub cache_folder: PathBuf,
     #[serde(default)]
     pub docker_config: Arc<DockerConfig>,
+    /// Seconds to wait for the websocket connection to the coordinator to be
+    /// established, including the TLS and websocket handshakes.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Seconds between two job polls.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Seconds between two keepalive pings to the coordinator.
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    /// Seconds to wait for running jobs to stop when the judger shuts down,
+    /// after which they are left behind.
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+    /// Maximum number of data messages (job progress, output and results)
+    /// waiting to be sent over the websocket at the same time. Pings and job
+    /// requests only ever wait for these messages, so keep it small.
+    #[serde(default = "default_ws_data_send_concurrency")]
+    pub ws_data_send_concurrency: usize,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// How the access token is sent when connecting to the websocket
+    /// endpoint of the coordinator.
+    #[serde(default)]
+    pub ws_auth: WsAuthMethod,
+    /// Limits on the content of downloaded test suite packages.
+    #[serde(default)]
+    pub extract_limits: ExtractLimits,
+    /// Limits overriding `extract_limits` for specific test suites, keyed by
+    /// the name of the test suite.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub suite_extract_limits: HashMap<String, ExtractLimits>,
+    /// Keep the folder of jobs finishing with any of these results for
+    /// debugging, instead of removing it. Jobs aborted by the coordinator
+    /// count as `Aborted`.
+    #[serde(default)]
+    pub retain_job_folder_on: HashSet<JobResultKind>,
+    /// How `judge.toml` is looked for inside submissions.
+    #[serde(default)]
+    pub judge_root_search: JudgeRootSearch,
+    /// Store the content of test suites once for every package, and link the
+    /// folders of test suites sharing the same package to it.
+    #[serde(default)]
+    pub dedup_test_suites: bool,
+    /// Update cached test suites with the patches advertised by the
+    /// coordinator in [`TestSuite::patch`](super::model::TestSuite) instead
+    /// of downloading them in full. Off by default, since the coordinator
+    /// in this repository does not serve patches yet. Has no effect with
+    /// [`dedup_test_suites`](Self::dedup_test_suites).
+    #[serde(default)]
+    pub patch_test_suites: bool,
+    /// Host paths of shared data that test suites may mount read-only by
+    /// name, keyed by name. Paths must be absolute.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub shared_host_paths: HashMap<String, PathBuf>,
+    /// Options of the consolidated log of every job.
+    #[serde(default)]
+    pub job_log: JobLogConfig,
+    /// Retries of operations on the job and test suite folders, for caches
+    /// on networked file systems.
+    #[serde(default)]
+    pub fs_retry: FsRetry,
+    /// Upload job results with more tests than this in chunks of this many
+    /// tests, followed by a final message. Results are uploaded in a single
+    /// request if unset, or if the coordinator does not accept chunks.
+    #[serde(default)]
+    pub result_chunk_size: Option<usize>,
+    /// What to do with a dispatched job whose id is already running.
+    #[serde(default)]
+    pub duplicate_job_policy: DuplicateJobPolicy,
+    /// Maximum number of jobs requested in a single poll. Unlimited if unset.
+    #[serde(default)]
+    pub max_jobs_per_poll: Option<usize>,
+    /// Request fewer jobs per poll the busier this judger is, so that other
+    /// judgers polling the same coordinator get their share of jobs. See
+    /// [`ClientConfig::poll_request_size`].
+    #[serde(default)]
+    pub poll_scale_by_utilization: bool,
+    /// Quarantine test suites whose package keeps failing to extract or
+    /// validate. Failures are not tracked if unset.
+    #[serde(default)]
+    pub suite_quarantine: Option<SuiteQuarantine>,
+    /// Maximum number of bytes of command and build output buffered by all
+    /// running jobs together. Output is given back once it is uploaded or
+    /// dropped. Near this limit, no new jobs are requested, and commands are
+    /// paused until output of others is given back, or truncated if none is
+    /// in time. Unlimited if unset.
+    #[serde(default)]
+    pub output_memory_limit: Option<usize>,
+    /// Compress test results uploaded to the coordinator with this
+    /// compression. Results are uploaded uncompressed if the coordinator
+    /// does not accept it, see [`SharedClientData::upload_compression`].
+    #[serde(default)]
+    pub upload_compression: Option<UploadCompression>,
+    /// Log filter directives (like `info,rurikawa_judger=debug`) replacing
+    /// the ones given on the command line. Applied immediately when changed
+    /// by the coordinator; unsetting it restores the command line filter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_filter: Option<String>,
+}
+
+impl ClientConfig {
+    /// Number of jobs to request in a poll while `active` tasks are running.
+    ///
+    /// If [`poll_scale_by_utilization`](Self::poll_scale_by_utilization) is
+    /// set, the free slots are scaled by the fraction of free slots (rounded
+    /// up), so an idle judger requests all of its free slots while a nearly
+    /// full one requests only one.
+    pub fn poll_request_size(&self, active: usize) -> usize {
+        let max = self.max_concurrent_tasks;
+        let free = max.saturating_sub(active);
+        let mut size = if self.poll_scale_by_utilization && max > 0 {
+            (free * free).div_ceil(max)
+        } else {
+            free
+        };
+        if let Some(cap) = self.max_jobs_per_poll {
+            size = size.min(cap);
+        }
+        size
+    }
+
+    /// The tags of this judger, sorted and without duplicates.
+    pub fn effective_tags(&self) -> Vec<&str> {
+        let mut tags = self
+            .tags
+            .iter()
+            .flatten()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        tags.sort_unstable();
+        tags.dedup();
+        tags
+    }
+
+    /// Tags in `required` this judger does not have.
+    pub fn missing_tags<'a>(&self, required: &'a [String]) -> Vec<&'a str> {
+        let tags = self.effective_tags();
+        required
+            .iter()
+            .map(|x| x.as_str())
+            .filter(|x| !tags.contains(x))
+            .collect()
+    }
+
+    /// Create a new config with the fields in `update` changed, as requested
+    /// by the coordinator. Keys are field names as in the config file. Fields
+    /// not listed in [`RUNTIME_CONFIG_FIELDS`] are ignored with a warning.
+    /// The update is rejected if the new config fails
+    /// [`check_runtime_fields`](Self::check_runtime_fields).
+    pub fn with_update(
+        &self,
+        update: &serde_json::Map<String, serde_json::Value>,
+    ) -> serde_json::Result<ClientConfig> {
+        let mut value = serde_json::to_value(self)?;
+        let fields = value
+            .as_object_mut()
+            .expect("Config should be serialized into an object");
+        for (key, field) in update {
+            if RUNTIME_CONFIG_FIELDS.contains(&key.as_str()) {
+                fields.insert(key.clone(), field.clone());
+            } else if SENSITIVE_CONFIG_FIELDS.contains(&key.as_str()) {
+                tracing::warn!(
+                    "Ignoring update of security-sensitive config field `{}`",
+                    key
+                );
+            } else {
+                tracing::warn!(
+                    "Ignoring update of config field `{}`, which cannot be changed at runtime",
+                    key
+                );
+            }
+        }
+        let cfg: ClientConfig = serde_json::from_value(value)?;
+        cfg.check_runtime_fields()
+            .map_err(<serde_json::Error as serde::de::Error>::custom)?;
+        Ok(cfg)
+    }
+
+    /// Check the values of fields the coordinator may change at runtime:
+    /// the number of tasks and the intervals must be at least 1, and the log
+    /// filter must be valid.
+    pub fn check_runtime_fields(&self) -> Result<(), String> {
+        let positive = [
+            ("max_concurrent_tasks", self.max_concurrent_tasks as u64),
+            ("connect_timeout", self.connect_timeout),
+            ("poll_interval", self.poll_interval),
+            ("keepalive_interval", self.keepalive_interval),
+        ];
+        if let Some((name, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(format!("`{}` must be at least 1", name));
+        }
+        if let Some(directives) = &self.log_filter {
+            tracing_subscriber::EnvFilter::try_new(directives)
+                .map_err(|e| format!("Invalid `log_filter` {:?}: {}", directives, e))?;
+        }
+        Ok(())
+    }
+
+    /// Limits on the package content of the test suite named `suite_name`.
+    pub fn extract_limits_for(&self, suite_name: &str) -> &ExtractLimits {
+        self.suite_extract_limits
+            .get(suite_name)
+            .unwrap_or(&self.extract_limits)
+    }
 }
 
 impl Default for ClientConfig {
@@ -30,23 +267,170 @@ impl Default for ClientConfig {
         ClientConfig {
             host: "".into(),
             max_concurrent_tasks: 1,
+            max_concurrent_uploads: default_max_concurrent_uploads(),
+            max_outbox_messages: default_max_outbox_messages(),
+            result_send_attempts: default_result_send_attempts(),
             ssl: false,
             access_token: None,
+            access_token_keyring: None,
             register_token: None,
             alternate_name: None,
             tags: None,
             cache_folder: PathBuf::new(),
             docker_config: Arc::new(Default::default()),
+            connect_timeout: default_connect_timeout(),
+            poll_interval: default_poll_interval(),
+            keepalive_interval: default_keepalive_interval(),
+            shutdown_grace_period: default_shutdown_grace_period(),
+            ws_data_send_concurrency: default_ws_data_send_concurrency(),
+            tls: Default::default(),
+            ws_auth: Default::default(),
+            extract_limits: Default::default(),
+            suite_extract_limits: HashMap::new(),
+            retain_job_folder_on: HashSet::new(),
+            judge_root_search: Default::default(),
+            dedup_test_suites: false,
+            patch_test_suites: false,
+            shared_host_paths: HashMap::new(),
+            job_log: Default::default(),
+            fs_retry: Default::default(),
+            result_chunk_size: None,
+            duplicate_job_policy: Default::default(),
+            max_jobs_per_poll: None,
+            poll_scale_by_utilization: false,
+            suite_quarantine: None,
+            output_memory_limit: None,
+            upload_compression: None,
+            log_filter: None,
         }
     }
 }
 
+fn default_max_concurrent_uploads() -> usize {
+    4
+}
+
+fn default_max_outbox_messages() -> usize {
+    4096
+}
+
+fn default_result_send_attempts() -> u32 {
+    3
+}
+
+fn default_connect_timeout() -> u64 {
+    30
+}
+
+fn default_poll_interval() -> u64 {
+    10
+}
+
+fn default_keepalive_interval() -> u64 {
+    20
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    60
+}
+
+fn default_ws_data_send_concurrency() -> usize {
+    super::sink::DEFAULT_DATA_SEND_CONCURRENCY
+}
+
+/// Fields of [`ClientConfig`] that the coordinator may change at runtime.
+pub const RUNTIME_CONFIG_FIELDS: &[&str] = &[
+    "max_concurrent_tasks",
+    "connect_timeout",
+    "poll_interval",
+    "keepalive_interval",
+    "shutdown_grace_period",
+    "extract_limits",
+    "suite_extract_limits",
+    "retain_job_folder_on",
+    "judge_root_search",
+    "dedup_test_suites",
+    "patch_test_suites",
+    "job_log",
+    "fs_retry",
+    "result_chunk_size",
+    "result_send_attempts",
+    "duplicate_job_policy",
+    "max_jobs_per_poll",
+    "poll_scale_by_utilization",
+    "suite_quarantine",
+    "upload_compression",
+    "log_filter",
+];
+
+/// Fields of [`ClientConfig`] that must never be changed by the coordinator.
+const SENSITIVE_CONFIG_FIELDS: &[&str] = &[
+    "host",
+    "ssl",
+    "ws_auth",
+    "access_token",
+    "access_token_keyring",
+    "register_token",
+    "cache_folder",
+    "docker_config",
+    "shared_host_paths",
+    "tls",
+];
+
+/// Service name of secrets saved in the system keyring.
+#[cfg(feature = "keyring")]
+pub const KEYRING_SERVICE: &str = "rurikawa";
+
+/// Read the access token saved under `key` in the system keyring. Returns
+/// `Ok(None)` if no such token exists.
+#[cfg(feature = "keyring")]
+pub fn keyring_access_token(key: &str) -> keyring::Result<Option<String>> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, key)?;
+    match entry.get_password() {
+        Ok(token) => Ok(Some(token)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Save the access token under `key` in the system keyring.
+#[cfg(feature = "keyring")]
+pub fn set_keyring_access_token(key: &str, token: &str) -> keyring::Result<()> {
+    keyring::Entry::new(KEYRING_SERVICE, key)?.set_password(token)
+}
+
+/// Address of the local Docker daemon, the same as the one used by
+/// [`Docker::connect_with_local_defaults`].
+#[cfg(unix)]
+const DOCKER_LOCAL_ADDR: &str = "unix:///var/run/docker.sock";
+#[cfg(windows)]
+const DOCKER_LOCAL_ADDR: &str = "npipe:////./pipe/docker_engine";
+
+/// Timeout of requests to the Docker daemon, in seconds.
+const DOCKER_TIMEOUT_SECS: u64 = 120;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct DockerConfig {
     /// The user every docker container should run in.
     pub docker_user: Option<String>,
 
+    /// The user the `build` commands of jobs and the copying of test suite
+    /// files run as, if different from `docker_user`. Build commands run as
+    /// the user of the test container if neither is set.
+    pub build_user: Option<String>,
+
+    /// The user tests run as, if different from `docker_user`. This should be
+    /// an unprivileged user.
+    pub run_user: Option<String>,
+
+    /// User namespace mode of test containers. With user namespace remapping
+    /// (`userns-remap`) enabled in the Docker daemon, root in containers maps
+    /// to an unprivileged host user; `"host"` opts out of it. Leave unset to
+    /// use the daemon default. See
+    /// [`container_root_is_host_root`](crate::tester::runner::container_root_is_host_root).
+    pub userns_mode: Option<String>,
+
     /// CPU share available for image building use. This field will result
     /// in allowing the CPU to run `build_cpu_share * 100ms` in every 100ms
     /// CPU time.
@@ -55,18 +439,535 @@ pub struct DockerConfig {
     /// CPU share available for running use. This field will be the upper limit
     /// of the load factor of all running task in the testing container.
     pub run_cpu_share: Option<f64>,
+
+    /// Maximum number of processes (including threads) in the testing
+    /// container. The peak process count of every command is reported in
+    /// its output regardless of this limit.
+    pub pids_limit: Option<i64>,
+
+    /// Relative block IO weight of the testing container, between 10 and 1000.
+    ///
+    /// Block IO limits require the `blkio` controller of cgroup v1, or the
+    /// `io` controller of cgroup v2. Weights only take effect when the
+    /// device uses the CFQ (cgroup v1) or BFQ (cgroup v2) IO scheduler.
+    pub blkio_weight: Option<u16>,
+
+    /// Read rate limits of the testing container in bytes per second, keyed
+    /// by device path (e.g. `/dev/sda`).
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub blkio_device_read_bps: HashMap<String, i64>,
+
+    /// Write rate limits of the testing container in bytes per second, keyed
+    /// by device path.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub blkio_device_write_bps: HashMap<String, i64>,
+
+    /// Read rate limits of the testing container in IO per second, keyed by
+    /// device path.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub blkio_device_read_iops: HashMap<String, i64>,
+
+    /// Write rate limits of the testing container in IO per second, keyed by
+    /// device path.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub blkio_device_write_iops: HashMap<String, i64>,
+
+    /// Image of the sidecar recording network access attempts of test
+    /// suites with `network.auditRunning` set.
+    ///
+    /// The sidecar is run in the internal network of the testing container,
+    /// and must serve DNS on port 53 and an HTTP proxy on port 3128, refusing
+    /// every request. It should print one line to `stdout` for each attempt,
+    /// e.g. `dns example.com` or `http example.com:443`. Test suites are run
+    /// with network access blocked and without auditing if this is not set.
+    pub network_audit_image: Option<String>,
+
+    /// Image of the sidecars forwarding connections of test suites to the
+    /// hosts in their `network.allowedHosts`.
+    ///
+    /// One sidecar is run for each allowed host, in both the internal network
+    /// of the testing container and the default bridge network. It is run as
+    /// `sh -c <script>`, where the script runs one
+    /// `socat TCP-LISTEN:<port>,fork,reuseaddr TCP:<host>:<port>` per allowed
+    /// port, so the image must provide `sh` and `socat`, e.g.
+    /// `alpine/socat`. Test suites with allowed hosts are run with network
+    /// access blocked if this is not set.
+    pub network_proxy_image: Option<String>,
+
+    /// Milliseconds between sending `SIGTERM` and `SIGKILL` to the processes
+    /// of a timed-out command, giving them a chance to flush their output and
+    /// exit cleanly. `0` kills them with `SIGKILL` right away.
+    pub kill_grace_period_ms: u64,
+
+    /// Maximum disk usage of images built or committed by the judger, in
+    /// bytes. Before building a new image, the oldest of these images are
+    /// removed until their total size fits in this limit. Unlimited if not
+    /// set. See [`prune_images`](crate::tester::runner::prune_images).
+    pub image_disk_limit: Option<u64>,
+
+    /// Docker API version to use, e.g. `"1.40"`. The highest version
+    /// supported by both the judger and the daemon is negotiated if not set.
+    pub api_version: Option<String>,
+
+    /// Fail jobs of test suites with a memory limit if the Docker daemon
+    /// cannot enforce memory limits, instead of running them unconstrained.
+    /// See [`memory_limits_enforceable`](crate::tester::runner::memory_limits_enforceable).
+    pub reject_unenforceable_memory_limits: bool,
+
+    /// When base images are pulled again, for prebuilt images and the `FROM`
+    /// images of Dockerfiles.
+    pub pull_policy: PullPolicy,
+
+    /// Number of times to inspect a finished Docker Exec again if the daemon
+    /// reports no exit code, e.g. when the exec is torn down while being
+    /// inspected. The test fails with an internal error if there is still no
+    /// exit code.
+    pub inspect_exec_retries: u32,
+
+    /// Milliseconds between retries of inspecting a Docker Exec, see
+    /// [`inspect_exec_retries`](Self::inspect_exec_retries).
+    pub inspect_exec_retry_interval_ms: u64,
+
+    /// Credentials of private registries, keyed by registry host (e.g.
+    /// `ghcr.io` or `registry.example.com:5000`; `docker.io` for Docker Hub).
+    /// Used when pulling prebuilt images and the base images of Dockerfiles.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub registry_auth: HashMap<String, RegistryAuth>,
+
+    /// Names of the Docker volumes test suites may mount. Jobs mounting any
+    /// other volume fail, so that test suites cannot read or tamper with
+    /// arbitrary volumes, nor create new ones. Writable volumes are shared by
+    /// every job mounting them, so only list volumes fit for sharing, e.g.
+    /// caches.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_volumes: Vec<String>,
+}
+
+impl DockerConfig {
+    /// The user of the build step, see [`build_user`](Self::build_user).
+    pub fn build_user(&self) -> Option<String> {
+        self.build_user.clone().or_else(|| self.docker_user.clone())
+    }
+
+    /// The user of the tests, see [`run_user`](Self::run_user).
+    pub fn run_user(&self) -> Option<String> {
+        self.run_user.clone().or_else(|| self.docker_user.clone())
+    }
+
+    /// Connect to the local Docker daemon, using the pinned API version or
+    /// negotiating one.
+    pub async fn connect(&self) -> anyhow::Result<Docker> {
+        match &self.api_version {
+            Some(version) => {
+                let parsed = version
+                    .split_once('.')
+                    .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)));
+                let (major_version, minor_version) = parsed.ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid Docker API version {:?}, expected e.g. \"1.40\"",
+                        version
+                    )
+                })?;
+                let version = ClientVersion {
+                    major_version,
+                    minor_version,
+                };
+                Ok(Docker::connect_with_local(
+                    DOCKER_LOCAL_ADDR,
+                    DOCKER_TIMEOUT_SECS,
+                    &version,
+                )?)
+            }
+            None => Ok(Docker::connect_with_local_defaults()?
+                .negotiate_version()
+                .await?),
+        }
+    }
+
+    /// Credentials of the registry `image` is pulled from, if configured.
+    pub fn credentials_for(&self, image: &str) -> Option<DockerCredentials> {
+        let host = pull::registry_host(image);
+        self.registry_auth
+            .iter()
+            .find(|(k, _)| pull::normalize_registry_host(k) == host)
+            .map(|(_, auth)| auth.credentials(host))
+    }
+
+    /// Credentials of every configured registry, keyed by server address, as
+    /// expected by Docker builds.
+    pub fn registry_credentials(&self) -> Option<HashMap<String, DockerCredentials>> {
+        if self.registry_auth.is_empty() {
+            return None;
+        }
+        let credentials = self
+            .registry_auth
+            .iter()
+            .map(|(host, auth)| {
+                let host = pull::normalize_registry_host(host);
+                let credentials = auth.credentials(host);
+                (credentials.serveraddress.clone().unwrap(), credentials)
+            })
+            .collect();
+        Some(credentials)
+    }
+
+    /// Check the values Docker would only reject when creating a container.
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(weight) = self.blkio_weight {
+            if !(10..=1000).contains(&weight) {
+                return Err(format!(
+                    "`blkio_weight` must be between 10 and 1000, got {}",
+                    weight
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Default for DockerConfig {
     fn default() -> Self {
         DockerConfig {
             docker_user: None,
+            build_user: None,
+            run_user: None,
+            userns_mode: None,
             build_cpu_share: Some(0.5),
             run_cpu_share: Some(0.3),
+            pids_limit: None,
+            blkio_weight: None,
+            blkio_device_read_bps: HashMap::new(),
+            blkio_device_write_bps: HashMap::new(),
+            blkio_device_read_iops: HashMap::new(),
+            blkio_device_write_iops: HashMap::new(),
+            network_audit_image: None,
+            network_proxy_image: None,
+            kill_grace_period_ms: 0,
+            image_disk_limit: None,
+            api_version: None,
+            reject_unenforceable_memory_limits: false,
+            pull_policy: PullPolicy::IfNotPresent,
+            inspect_exec_retries: 3,
+            inspect_exec_retry_interval_ms: 200,
+            registry_auth: HashMap::new(),
+            allowed_volumes: vec![],
+        }
+    }
+}
+
+/// TLS settings used when connecting to the coordinator.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// A PEM file of extra CA certificates to trust, in addition to the
+    /// built-in root certificates.
+    pub ca_file: Option<PathBuf>,
+
+    /// A PEM file of the client certificate chain, for coordinators that
+    /// require client authentication. Must be set with `client_key`.
+    pub client_cert: Option<PathBuf>,
+
+    /// A PEM file of the private key (PKCS#8 or RSA) of `client_cert`.
+    pub client_key: Option<PathBuf>,
+
+    /// Skip verifying the certificate of the coordinator.
+    ///
+    /// **NEVER use this in production.** Anyone on the network path can then
+    /// impersonate the coordinator and steal the access token. This is only
+    /// meant for developing against coordinators with self-signed
+    /// certificates; prefer `ca_file` even then.
+    pub danger_skip_verify: bool,
+}
+
+/// Addresses of coordinators, written as a single address or a list of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CoordinatorHosts {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl CoordinatorHosts {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            CoordinatorHosts::One(x) => std::slice::from_ref(x),
+            CoordinatorHosts::Many(x) => x,
         }
     }
 }
 
+/// Read a comma-separated list of addresses.
+impl From<&str> for CoordinatorHosts {
+    fn from(s: &str) -> Self {
+        let mut hosts = s
+            .split(',')
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>();
+        if hosts.len() <= 1 {
+            CoordinatorHosts::One(hosts.pop().unwrap_or_default())
+        } else {
+            CoordinatorHosts::Many(hosts)
+        }
+    }
+}
+
+/// How the access token is passed to the coordinator during the websocket
+/// handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WsAuthMethod {
+    /// In the `Authorization` header.
+    #[default]
+    Header,
+    /// As a websocket subprotocol `rurikawa.token.<token>`, offered along
+    /// with [`WS_SUBPROTOCOL`]. Use this behind reverse proxies that strip
+    /// the `Authorization` header on upgrade requests.
+    Subprotocol,
+    /// In the `token` query parameter of the endpoint URL. Proxies may write
+    /// the URL with the token into their access logs.
+    Query,
+}
+
+/// How a job dispatched again while it is still running is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateJobPolicy {
+    /// Keep running the existing job and drop the new one.
+    #[default]
+    Ignore,
+    /// Cancel the existing job without reporting it, then run the new one
+    /// once it stops.
+    Replace,
+}
+
+/// When a test suite is quarantined. Jobs of a quarantined test suite are
+/// rejected without downloading its package again, until the cooldown ends or
+/// the suite moves to another package.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SuiteQuarantine {
+    /// Number of consecutive failures of the same package quarantining the
+    /// test suite, at least 1.
+    pub failures: u32,
+    /// Seconds a test suite stays quarantined.
+    pub cooldown_secs: u64,
+}
+
+impl Default for SuiteQuarantine {
+    fn default() -> Self {
+        SuiteQuarantine {
+            failures: 3,
+            cooldown_secs: 600,
+        }
+    }
+}
+
+/// Consecutive failures of the package of a test suite, see
+/// [`ClientConfig::suite_quarantine`].
+#[derive(Debug, Clone)]
+pub struct SuiteFailures {
+    /// The package that failed
+    pub package_file_id: String,
+    /// Number of consecutive failures
+    pub count: u32,
+    /// End of the quarantine of the test suite, if quarantined
+    pub quarantined_until: Option<Instant>,
+}
+
+/// When images with mutable tags (e.g. `ubuntu:latest`) are pulled again.
+/// Images pinned by digest are only pulled if not present.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PullPolicy {
+    /// Pull before every build.
+    Always,
+    /// Only pull images not in the local cache.
+    #[default]
+    IfNotPresent,
+    /// Pull images not pulled by this judger in the last 24 hours, including
+    /// those only cached before it started.
+    Daily,
+}
+
+impl std::fmt::Display for PullPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PullPolicy::Always => "always",
+            PullPolicy::IfNotPresent => "if-not-present",
+            PullPolicy::Daily => "daily",
+        })
+    }
+}
+
+/// Credentials of a Docker registry, either a username and password or an
+/// identity token. Never printed, see the [`Debug`] implementation.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RegistryAuth {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// An identity (refresh) token, used instead of the password.
+    pub identity_token: Option<String>,
+}
+
+impl RegistryAuth {
+    /// The credentials sent to the Docker daemon for `host`.
+    pub fn credentials(&self, host: &str) -> DockerCredentials {
+        let serveraddress = if host == pull::DOCKER_HUB_HOST {
+            pull::DOCKER_HUB_SERVER_ADDRESS.to_owned()
+        } else {
+            host.to_owned()
+        };
+        DockerCredentials {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            identitytoken: self.identity_token.clone(),
+            serveraddress: Some(serveraddress),
+            ..Default::default()
+        }
+    }
+}
+
+impl std::fmt::Debug for RegistryAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = |x: &Option<String>| x.as_ref().map(|_| "<redacted>");
+        f.debug_struct("RegistryAuth")
+            .field("username", &self.username)
+            .field("password", &redact(&self.password))
+            .field("identity_token", &redact(&self.identity_token))
+            .finish()
+    }
+}
+
+/// The websocket subprotocol spoken with the coordinator.
+pub const WS_SUBPROTOCOL: &str = "rurikawa";
+
+/// Prefix of the websocket subprotocol carrying the access token.
+pub const WS_TOKEN_SUBPROTOCOL_PREFIX: &str = "rurikawa.token.";
+
+/// A certificate verifier that accepts everything.
+struct NoCertificateVerification;
+
+impl rustls::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+fn open_pem(path: &Path) -> std::io::Result<BufReader<std::fs::File>> {
+    std::fs::File::open(path).map(BufReader::new)
+}
+
+fn invalid_pem(path: &Path, what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{} does not contain valid {}", path.display(), what),
+    )
+}
+
+impl TlsConfig {
+    /// Whether this config differs from the default TLS settings.
+    pub fn is_custom(&self) -> bool {
+        self.ca_file.is_some()
+            || self.client_cert.is_some()
+            || self.client_key.is_some()
+            || self.danger_skip_verify
+    }
+
+    /// Build the rustls client config described by this config. Returns
+    /// `Ok(None)` if the default settings should be used.
+    pub fn client_config(&self) -> std::io::Result<Option<Arc<rustls::ClientConfig>>> {
+        if !self.is_custom() {
+            return Ok(None);
+        }
+
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+        if let Some(ca_file) = &self.ca_file {
+            let (added, _) = config
+                .root_store
+                .add_pem_file(&mut open_pem(ca_file)?)
+                .map_err(|_| invalid_pem(ca_file, "certificates"))?;
+            if added == 0 {
+                return Err(invalid_pem(ca_file, "certificates"));
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert_file), Some(key_file)) => {
+                let certs = rustls::internal::pemfile::certs(&mut open_pem(cert_file)?)
+                    .map_err(|_| invalid_pem(cert_file, "certificates"))?;
+                let mut keys =
+                    rustls::internal::pemfile::pkcs8_private_keys(&mut open_pem(key_file)?)
+                        .map_err(|_| invalid_pem(key_file, "private keys"))?;
+                if keys.is_empty() {
+                    keys = rustls::internal::pemfile::rsa_private_keys(&mut open_pem(key_file)?)
+                        .map_err(|_| invalid_pem(key_file, "private keys"))?;
+                }
+                let key = keys
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| invalid_pem(key_file, "private keys"))?;
+                config.set_single_client_cert(certs, key).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+                })?;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "`client_cert` and `client_key` must be set together",
+                ))
+            }
+        }
+
+        if self.danger_skip_verify {
+            tracing::warn!("!!! TLS certificate verification of the coordinator is DISABLED !!!");
+            tracing::warn!("!!! Anyone on the network can impersonate the coordinator and steal the access token.");
+            tracing::warn!("!!! NEVER use `danger_skip_verify` in production.");
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoCertificateVerification));
+        }
+
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+/// A shared rustls client config. This wrapper exists because
+/// `rustls::ClientConfig` does not implement `Debug`.
+#[derive(Clone)]
+pub struct TlsClientConfig(pub Arc<rustls::ClientConfig>);
+
+impl std::fmt::Debug for TlsClientConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsClientConfig").finish()
+    }
+}
+
+/// Build the HTTP client used to talk to the coordinator.
+fn http_client(tls: Option<&rustls::ClientConfig>) -> reqwest::Client {
+    // WORKAROUND: Client hang issue in hyper crate.
+    // see: https://github.com/hyperium/hyper/issues/2312
+    let mut builder = reqwest::Client::builder()
+        .pool_idle_timeout(std::time::Duration::from_secs(0))
+        .pool_max_idle_per_host(0);
+    if let Some(tls) = tls {
+        builder = builder.use_preconfigured_tls(tls.clone());
+    }
+    builder.build().unwrap()
+}
+
 #[derive(Debug)]
 pub struct SharedClientData {
     /// Configuration of this client
@@ -74,55 +975,274 @@ pub struct SharedClientData {
     /// A unique id for all connection created by this client, similar to
     /// what `state` does in OAuth
     pub conn_id: u128,
+    /// Index of the coordinator in use among [`ClientConfig::host`], wrapping
+    /// around the list.
+    pub host_index: AtomicUsize,
     /// Number of running tests
     pub running_tests: AtomicUsize,
     /// The message id of the ongoing job request
     pub waiting_for_jobs: ArcSwapOption<FlowSnake>,
     /// Whether this client is aborting
     pub aborting: AtomicBool,
+    /// Whether the websocket to the coordinator is connected
+    pub connected: AtomicBool,
+    /// Unix time in seconds of the last successful keepalive ping, 0 if none
+    pub last_keepalive: AtomicU64,
+    /// `Content-Encoding`s of result uploads accepted by the coordinator, as
+    /// advertised in its hello
+    pub accepted_encodings: ArcSwap<Vec<String>>,
+    /// Whether the Docker daemon enforces memory limits, as probed at startup
+    pub memory_limits_enforceable: AtomicBool,
     /// HTTP client
     pub client: reqwest::Client,
+    /// Custom TLS settings for connecting to the coordinator, if any
+    pub tls_config: Option<TlsClientConfig>,
     /// All test suites whose folder is being edited.
     pub locked_test_suite: dashmap::DashMap<FlowSnake, (u64, CancellationTokenHandle)>,
+    /// Locks of the shared test suite contents, keyed by content key. See
+    /// [`suite_store`](super::suite_store).
+    pub suite_content_locks: dashmap::DashMap<String, Arc<Mutex<()>>>,
+    /// Test suites whose files have been validated, mapped to the
+    /// `package_file_id` that was validated.
+    pub validated_test_suite: dashmap::DashMap<FlowSnake, String>,
+    /// Consecutive package failures of test suites, see
+    /// [`ClientConfig::suite_quarantine`].
+    pub suite_failures: dashmap::DashMap<FlowSnake, SuiteFailures>,
     /// Handle for all jobs currently running
     pub running_job_handles: Mutex<HashMap<FlowSnake, (JoinHandle<()>, CancellationTokenHandle)>>,
+    /// Repository and revision of every running job
+    pub running_job_revisions: dashmap::DashMap<FlowSnake, (String, String)>,
     /// Handle for all jobs currently cancelling
     pub cancelling_job_handles: Mutex<HashMap<FlowSnake, JoinHandle<()>>>,
-    /// Information for currently-cancelling jobs.
-    pub cancelling_job_info: dashmap::DashMap<FlowSnake, AbortJob>,
     /// Global cancellation token handle
     pub cancel_handle: CancellationTokenHandle,
+    /// Limits concurrent result uploads of all jobs
+    pub upload_semaphore: Arc<Semaphore>,
+    /// Maximum number of jobs to accept before shutting down. `None` means
+    /// no limit.
+    pub max_jobs: Option<usize>,
+    /// Number of jobs accepted so far
+    pub accepted_jobs: AtomicUsize,
+    /// Number of jobs finished so far
+    pub finished_jobs: AtomicUsize,
+    /// Result messages pending delivery
+    pub outbox: Outbox,
+    /// Budget of buffered command output, see
+    /// [`ClientConfig::output_memory_limit`].
+    pub output_budget: Option<Arc<MemoryBudget>>,
+    /// Replaces the filter of the global logger, see
+    /// [`SharedClientData::apply_log_filter`].
+    pub log_filter_reloader: Option<LogFilterReloader>,
     // /// The docker instance we're connecting
     // pub docker: Docker
 }
 
+/// Replaces the filter of the global logger with the given directives, or
+/// with the initial filter if `None`.
+pub struct LogFilterReloader(pub Box<dyn Fn(Option<&str>) -> Result<(), String> + Send + Sync>);
+
+impl std::fmt::Debug for LogFilterReloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LogFilterReloader")
+    }
+}
+
+/// Name of the file under the cache folder keeping the access token got by
+/// registering, see [`SharedClientData::save_access_token`].
+pub const ACCESS_TOKEN_FILE_NAME: &str = "access_token";
+
+/// Read the access token saved in `cache_folder`, if any. Nothing is read
+/// if `cache_folder` is not set.
+pub fn read_saved_access_token(cache_folder: &Path) -> Option<String> {
+    if cache_folder.as_os_str().is_empty() {
+        return None;
+    }
+    let token = std::fs::read_to_string(cache_folder.join(ACCESS_TOKEN_FILE_NAME)).ok()?;
+    Some(token.trim().to_owned()).filter(|x| !x.is_empty())
+}
+
 impl SharedClientData {
-    pub fn new(cfg: ClientConfig) -> SharedClientData {
+    /// Create the shared data of a client. If `cfg` has no access token, the
+    /// one saved in its cache folder by an earlier registration is used.
+    pub fn new(mut cfg: ClientConfig) -> SharedClientData {
+        if cfg.access_token.is_none() {
+            cfg.access_token = read_saved_access_token(&cfg.cache_folder);
+            if cfg.access_token.is_some() {
+                tracing::info!("Using the access token saved in {}", ACCESS_TOKEN_FILE_NAME);
+            }
+        }
+        let max_concurrent_uploads = cfg.max_concurrent_uploads.max(1);
+        let outbox = Outbox::new(cfg.cache_folder.join("outbox"), cfg.max_outbox_messages);
+        let output_budget = cfg
+            .output_memory_limit
+            .map(|x| Arc::new(MemoryBudget::new(x)));
         SharedClientData {
             cfg: ArcSwap::new(Arc::new(cfg)),
             conn_id: rand::random(),
-            // WORKAROUND: Client hang issue in hyper crate.
-            // see: https://github.com/hyperium/hyper/issues/2312
-            client: reqwest::Client::builder()
-                .pool_idle_timeout(std::time::Duration::from_secs(0))
-                .pool_max_idle_per_host(0)
-                .build()
-                .unwrap(),
+            host_index: AtomicUsize::new(0),
+            client: http_client(None),
+            tls_config: None,
             aborting: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            last_keepalive: AtomicU64::new(0),
+            accepted_encodings: ArcSwap::from_pointee(vec![]),
+            memory_limits_enforceable: AtomicBool::new(true),
             waiting_for_jobs: ArcSwapOption::new(None),
             running_tests: AtomicUsize::new(0),
             locked_test_suite: dashmap::DashMap::new(),
+            suite_content_locks: dashmap::DashMap::new(),
+            validated_test_suite: dashmap::DashMap::new(),
+            suite_failures: dashmap::DashMap::new(),
             running_job_handles: Mutex::new(HashMap::new()),
+            running_job_revisions: DashMap::new(),
             cancelling_job_handles: Mutex::new(HashMap::new()),
-            cancelling_job_info: DashMap::new(),
             cancel_handle: CancellationTokenHandle::new(),
+            upload_semaphore: Arc::new(Semaphore::new(max_concurrent_uploads)),
+            max_jobs: None,
+            accepted_jobs: AtomicUsize::new(0),
+            finished_jobs: AtomicUsize::new(0),
+            outbox,
+            output_budget,
+            log_filter_reloader: None,
+        }
+    }
+
+    /// Replace the filter of the global logger with
+    /// [`ClientConfig::log_filter`], if a reloader is set.
+    pub fn apply_log_filter(&self) {
+        let reloader = match &self.log_filter_reloader {
+            Some(x) => x,
+            None => return,
+        };
+        let cfg = self.cfg();
+        match (reloader.0)(cfg.log_filter.as_deref()) {
+            Ok(()) => tracing::info!(
+                "Log filter set to {}",
+                cfg.log_filter.as_deref().unwrap_or("the command line one")
+            ),
+            Err(e) => tracing::warn!("Failed to set log filter: {}", e),
         }
     }
 
+    /// Compression of result uploads: the configured one, if this judger is
+    /// built with it and the coordinator accepts it.
+    pub fn upload_compression(&self) -> Option<UploadCompression> {
+        let compression = self.cfg().upload_compression?;
+        let accepted = self.accepted_encodings.load();
+        let accepted = accepted
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(compression.content_encoding()));
+        (accepted && compression.is_available()).then_some(compression)
+    }
+
+    /// Ids of running jobs of `revision` of `repo`.
+    pub fn jobs_of_revision(&self, repo: &str, revision: &str) -> Vec<FlowSnake> {
+        self.running_job_revisions
+            .iter()
+            .filter(|x| x.value().0 == repo && x.value().1 == revision)
+            .map(|x| *x.key())
+            .collect()
+    }
+
+    /// Time left in the quarantine of test suite `suite_id`, if it is
+    /// quarantined. Moving to a package other than the failed one lifts the
+    /// quarantine and forgets past failures.
+    pub fn suite_quarantine_left(
+        &self,
+        suite_id: FlowSnake,
+        package_file_id: &str,
+    ) -> Option<Duration> {
+        let mut failures = self.suite_failures.get_mut(&suite_id)?;
+        if failures.package_file_id != package_file_id {
+            drop(failures);
+            self.suite_failures.remove(&suite_id);
+            return None;
+        }
+        let until = failures.quarantined_until?;
+        let now = Instant::now();
+        if now >= until {
+            // Keep the count, so that failing once more quarantines the
+            // suite again
+            failures.quarantined_until = None;
+            return None;
+        }
+        Some(until - now)
+    }
+
+    /// Record a failure of package `package_file_id` of test suite
+    /// `suite_id`. Returns whether the suite is quarantined now.
+    pub fn record_suite_failure(&self, suite_id: FlowSnake, package_file_id: &str) -> bool {
+        let cfg = match self.cfg().suite_quarantine.clone() {
+            Some(cfg) => cfg,
+            None => return false,
+        };
+        let mut failures = self
+            .suite_failures
+            .entry(suite_id)
+            .or_insert_with(|| SuiteFailures {
+                package_file_id: package_file_id.to_owned(),
+                count: 0,
+                quarantined_until: None,
+            });
+        if failures.package_file_id != package_file_id {
+            failures.package_file_id = package_file_id.to_owned();
+            failures.count = 0;
+        }
+        failures.count += 1;
+        if failures.count >= cfg.failures.max(1) {
+            failures.quarantined_until =
+                Some(Instant::now() + Duration::from_secs(cfg.cooldown_secs));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget past failures of test suite `suite_id` after it's prepared
+    /// successfully.
+    pub fn record_suite_success(&self, suite_id: FlowSnake) {
+        self.suite_failures.remove(&suite_id);
+    }
+
+    /// Use the given TLS settings for both the websocket and HTTP connections
+    /// to the coordinator.
+    pub fn set_tls_config(&mut self, tls: Option<Arc<rustls::ClientConfig>>) {
+        self.client = http_client(tls.as_deref());
+        self.tls_config = tls.map(TlsClientConfig);
+    }
+
+    /// Save `token` in the cache folder, readable only by the current user,
+    /// so that it survives restarts. Nothing is saved if the access token is
+    /// kept in the system keyring instead, or the cache folder is not set.
+    pub async fn save_access_token(&self, token: &str) -> std::io::Result<()> {
+        let cfg = self.cfg();
+        if cfg.access_token_keyring.is_some() || cfg.cache_folder.as_os_str().is_empty() {
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(&cfg.cache_folder).await?;
+        crate::fs::write_private(&cfg.cache_folder.join(ACCESS_TOKEN_FILE_NAME), token).await
+    }
+
     pub fn swap_cfg(&self, cfg: Arc<ClientConfig>) -> Arc<ClientConfig> {
         self.cfg.swap(cfg)
     }
 
+    /// Whether the buffered command output of running jobs is near
+    /// [`ClientConfig::output_memory_limit`], so no new jobs should be
+    /// accepted.
+    pub fn output_memory_near_limit(&self) -> bool {
+        self.output_budget
+            .as_ref()
+            .map_or(false, |x| x.is_near_limit())
+    }
+
+    /// Number of jobs that can still be accepted, or `None` if there's no limit.
+    pub fn remaining_jobs(&self) -> Option<usize> {
+        self.max_jobs.map(|max| {
+            max.saturating_sub(self.accepted_jobs.load(std::sync::atomic::Ordering::SeqCst))
+        })
+    }
+
     pub fn cfg(&self) -> arc_swap::Guard<Arc<ClientConfig>> {
         ArcSwap::load(&self.cfg)
     }
@@ -131,6 +1251,28 @@ impl SharedClientData {
         ArcSwap::load_full(&self.cfg)
     }
 
+    /// Address of the coordinator in use.
+    pub fn host(&self) -> String {
+        let cfg = self.cfg();
+        let hosts = cfg.host.as_slice();
+        let idx = self.host_index.load(std::sync::atomic::Ordering::SeqCst);
+        hosts
+            .get(idx % hosts.len().max(1))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Number of coordinators to choose from, at least 1.
+    pub fn host_count(&self) -> usize {
+        self.cfg().host.as_slice().len().max(1)
+    }
+
+    /// Switch to the next coordinator in [`ClientConfig::host`].
+    pub fn next_host(&self) {
+        self.host_index
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
     pub fn register_endpoint(&self) -> String {
         let ssl = if self.cfg().ssl {
             format_args!("https")
@@ -138,7 +1280,7 @@ impl SharedClientData {
             format_args!("http")
         };
 
-        format!("{}://{}/api/v1/judger/register", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/register", ssl, self.host())
     }
 
     pub fn verify_endpoint(&self) -> String {
@@ -148,34 +1290,68 @@ impl SharedClientData {
             format_args!("http")
         };
 
-        format!("{}://{}/api/v1/judger/verify", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/verify", ssl, self.host())
     }
 
+    /// The websocket endpoint of the coordinator. The access token is only
+    /// included if it is sent with [`WsAuthMethod::Query`].
     pub fn websocket_endpoint(&self) -> String {
-        let ssl = if self.cfg().ssl {
+        self.websocket_url(false)
+    }
+
+    /// The websocket endpoint with the access token hidden, for logging.
+    pub fn websocket_endpoint_redacted(&self) -> String {
+        self.websocket_url(true)
+    }
+
+    fn websocket_url(&self, redact: bool) -> String {
+        let cfg = self.cfg();
+        let ssl = if cfg.ssl {
             format_args!("wss")
         } else {
             format_args!("ws")
         };
 
-        if let Some(token) = &self.cfg().access_token {
+        let token = cfg
+            .access_token
+            .as_deref()
+            .filter(|_| cfg.ws_auth == WsAuthMethod::Query);
+        if let Some(token) = token {
             format!(
                 "{}://{}/api/v1/judger/ws?token={}&conn={:x}",
                 ssl,
-                self.cfg().host,
-                token,
+                self.host(),
+                if redact { "<redacted>" } else { token },
                 self.conn_id
             )
         } else {
             format!(
                 "{}://{}/api/v1/judger/ws?conn={:x}",
                 ssl,
-                self.cfg().host,
+                self.host(),
                 self.conn_id
             )
         }
     }
 
+    /// The header carrying the access token in the websocket handshake, if
+    /// it is not sent in the URL.
+    pub fn websocket_auth_header(&self) -> Option<(&'static str, String)> {
+        let cfg = self.cfg();
+        let token = cfg.access_token.as_ref()?;
+        match cfg.ws_auth {
+            WsAuthMethod::Header => Some(("authorization", token.clone())),
+            WsAuthMethod::Subprotocol => Some((
+                "sec-websocket-protocol",
+                format!(
+                    "{}, {}{}",
+                    WS_SUBPROTOCOL, WS_TOKEN_SUBPROTOCOL_PREFIX, token
+                ),
+            )),
+            WsAuthMethod::Query => None,
+        }
+    }
+
     pub fn test_suite_download_endpoint(&self, suite_id: FlowSnake) -> String {
         let ssl = if self.cfg().ssl {
             format_args!("https")
@@ -185,18 +1361,33 @@ impl SharedClientData {
         format!(
             "{}://{}/api/v1/judger/download-suite/{}",
             ssl,
-            self.cfg().host,
+            self.host(),
             suite_id
         )
     }
 
+    pub fn test_suite_patch_endpoint(&self, suite_id: FlowSnake, patch_file_id: &str) -> String {
+        let ssl = if self.cfg().ssl {
+            format_args!("https")
+        } else {
+            format_args!("http")
+        };
+        format!(
+            "{}://{}/api/v1/judger/download-suite/{}/patch/{}",
+            ssl,
+            self.host(),
+            suite_id,
+            patch_file_id
+        )
+    }
+
     pub fn test_suite_info_endpoint(&self, suite_id: FlowSnake) -> String {
         let ssl = if self.cfg().ssl {
             format_args!("https")
         } else {
             format_args!("http")
         };
-        format!("{}://{}/api/v1/tests/{}", ssl, self.cfg().host, suite_id)
+        format!("{}://{}/api/v1/tests/{}", ssl, self.host(), suite_id)
     }
 
     pub fn result_upload_endpoint(&self) -> String {
@@ -205,7 +1396,7 @@ impl SharedClientData {
         } else {
             format_args!("http")
         };
-        format!("{}://{}/api/v1/judger/upload", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/upload", ssl, self.host())
     }
 
     pub fn result_send_endpoint(&self) -> String {
@@ -214,7 +1405,7 @@ impl SharedClientData {
         } else {
             format_args!("http")
         };
-        format!("{}://{}/api/v1/judger/result", ssl, self.cfg().host)
+        format!("{}://{}/api/v1/judger/result", ssl, self.host())
     }
 
     pub fn job_folder_root(&self) -> PathBuf {
@@ -238,13 +1429,84 @@ impl SharedClientData {
             .join(format!("{}.lock", suite_id))
     }
 
+    pub fn test_suite_content_folder_root(&self) -> PathBuf {
+        self.cfg().cache_folder.join("suite-content")
+    }
+
+    pub fn test_suite_content_folder(&self, key: &str) -> PathBuf {
+        self.test_suite_content_folder_root().join(key)
+    }
+
+    pub fn test_suite_content_marker(&self, key: &str) -> PathBuf {
+        self.test_suite_content_folder_root()
+            .join(format!("{}.done", key))
+    }
+
     pub fn temp_file_folder_root(&self) -> PathBuf {
         self.cfg().cache_folder.join("files")
     }
 
+    /// Generate a path for a temporary file. The name is made unique across
+    /// processes (by pid) and inside this process (by a counter), with a random
+    /// part to avoid reusing names of files left by previous runs.
     pub fn random_temp_file_path(&self) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.temp_file_folder_root().join(format!(
+            "{}-{}-{}",
+            std::process::id(),
+            count,
+            FlowSnake::generate()
+        ))
+    }
+
+    /// Path of the file the package `package_id` of test suite `suite_id` is
+    /// downloaded into. Unlike [`random_temp_file_path`](Self::random_temp_file_path),
+    /// the path stays the same across runs, so that interrupted downloads can
+    /// be resumed.
+    ///
+    /// Bytes of `package_id` other than ASCII letters, digits and `-` are
+    /// escaped as `_` followed by their hex value, so that distinct packages
+    /// never share a file.
+    pub fn download_temp_file_path(&self, suite_id: FlowSnake, package_id: &str) -> PathBuf {
+        let mut escaped = String::with_capacity(package_id.len());
+        for x in package_id.bytes() {
+            match x {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' => escaped.push(x as char),
+                _ => escaped.push_str(&format!("_{:02x}", x)),
+            }
+        }
         self.temp_file_folder_root()
-            .join(FlowSnake::generate().to_string())
+            .join(format!("download-{}-{}", suite_id, escaped))
+    }
+
+    /// Remove the partial downloads (and their progress) of test suite
+    /// `suite_id` left behind for packages other than `package_id`, which
+    /// can never be resumed since the package has changed.
+    pub async fn remove_stale_downloads(
+        &self,
+        suite_id: FlowSnake,
+        package_id: &str,
+    ) -> std::io::Result<()> {
+        let current = self.download_temp_file_path(suite_id, package_id);
+        let current_progress = crate::fs::net::download_progress_path(&current);
+        let prefix = format!("download-{}-", suite_id);
+        let mut entries = match tokio::fs::read_dir(self.temp_file_folder_root()).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let stale = entry.file_name().to_string_lossy().starts_with(&prefix)
+                && path != current
+                && path != current_progress;
+            if stale {
+                tracing::info!("Removing stale partial download {}", path.display());
+                tokio::fs::remove_file(&path).await?;
+            }
+        }
+        Ok(())
     }
 
     pub async fn obtain_suite_lock(&self, suite_id: FlowSnake) -> Option<CancellationTokenHandle> {
@@ -284,3 +1546,221 @@ impl SharedClientData {
         res - 1
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_update() {
+        let cfg = ClientConfig {
+            access_token: Some("secret".into()),
+            ..Default::default()
+        };
+        let update = serde_json::json!({
+            "max_concurrent_tasks": 4,
+            "poll_interval": 3,
+            "access_token": "evil",
+            "cache_folder": "/",
+            "alternate_name": "judger",
+        });
+        let updated = cfg.with_update(update.as_object().unwrap()).unwrap();
+        assert_eq!(updated.max_concurrent_tasks, 4);
+        assert_eq!(updated.poll_interval, 3);
+        assert_eq!(updated.access_token.as_deref(), Some("secret"));
+        assert_eq!(updated.cache_folder, PathBuf::new());
+        assert_eq!(updated.alternate_name, None);
+
+        let invalid = serde_json::json!({ "max_concurrent_tasks": "many" });
+        assert!(cfg.with_update(invalid.as_object().unwrap()).is_err());
+
+        for field in &[
+            "max_concurrent_tasks",
+            "poll_interval",
+            "keepalive_interval",
+        ] {
+            let mut zero = serde_json::Map::new();
+            zero.insert(field.to_string(), 0.into());
+            assert!(cfg.with_update(&zero).is_err());
+        }
+
+        let filter = serde_json::json!({ "log_filter": "info,rurikawa_judger=debug" });
+        let updated = cfg.with_update(filter.as_object().unwrap()).unwrap();
+        assert_eq!(
+            updated.log_filter.as_deref(),
+            Some("info,rurikawa_judger=debug")
+        );
+        let invalid = serde_json::json!({ "log_filter": "rurikawa_judger=loud" });
+        assert!(cfg.with_update(invalid.as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_poll_request_size() {
+        let mut cfg = ClientConfig {
+            max_concurrent_tasks: 8,
+            ..Default::default()
+        };
+        assert_eq!(cfg.poll_request_size(2), 6);
+        assert_eq!(cfg.poll_request_size(10), 0);
+
+        cfg.poll_scale_by_utilization = true;
+        assert_eq!(cfg.poll_request_size(0), 8);
+        assert_eq!(cfg.poll_request_size(4), 2);
+        assert_eq!(cfg.poll_request_size(7), 1);
+        assert_eq!(cfg.poll_request_size(8), 0);
+
+        cfg.max_jobs_per_poll = Some(3);
+        assert_eq!(cfg.poll_request_size(0), 3);
+    }
+
+    #[test]
+    fn test_missing_tags() {
+        let cfg = ClientConfig {
+            tags: Some(vec!["gpu".into(), "arm".into(), "gpu".into()]),
+            ..Default::default()
+        };
+        assert_eq!(cfg.effective_tags(), ["arm", "gpu"]);
+        let required = vec!["gpu".to_owned(), "x86".to_owned()];
+        assert_eq!(cfg.missing_tags(&required), ["x86"]);
+        assert!(ClientConfig::default().missing_tags(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_suite_quarantine() {
+        let data = SharedClientData::new(ClientConfig {
+            suite_quarantine: Some(SuiteQuarantine {
+                failures: 2,
+                cooldown_secs: 60,
+            }),
+            ..Default::default()
+        });
+        let suite = FlowSnake::generate();
+        assert!(!data.record_suite_failure(suite, "pkg"));
+        assert_eq!(data.suite_quarantine_left(suite, "pkg"), None);
+        assert!(data.record_suite_failure(suite, "pkg"));
+        assert!(data.suite_quarantine_left(suite, "pkg").is_some());
+
+        // A new package lifts the quarantine
+        assert_eq!(data.suite_quarantine_left(suite, "pkg-2"), None);
+        assert!(!data.record_suite_failure(suite, "pkg-2"));
+        data.record_suite_success(suite);
+        assert!(!data.record_suite_failure(suite, "pkg-2"));
+
+        let untracked = SharedClientData::new(ClientConfig::default());
+        assert!(!untracked.record_suite_failure(suite, "pkg"));
+        assert!(!untracked.record_suite_failure(suite, "pkg"));
+        assert_eq!(untracked.suite_quarantine_left(suite, "pkg"), None);
+    }
+
+    #[test]
+    fn test_coordinator_hosts() {
+        let hosts = |x| serde_json::from_value::<CoordinatorHosts>(x).unwrap();
+        assert_eq!(
+            hosts(serde_json::json!("a.com")),
+            CoordinatorHosts::One("a.com".into())
+        );
+        let many = hosts(serde_json::json!(["a.com", "b.com:8080"]));
+        assert_eq!(many, CoordinatorHosts::from("a.com, b.com:8080"));
+        assert_eq!(CoordinatorHosts::from(""), CoordinatorHosts::One("".into()));
+
+        let data = SharedClientData::new(ClientConfig {
+            host: many,
+            ..Default::default()
+        });
+        assert_eq!(data.host(), "a.com");
+        data.next_host();
+        assert_eq!(
+            data.result_send_endpoint(),
+            "http://b.com:8080/api/v1/judger/result"
+        );
+        data.next_host();
+        assert_eq!(data.host(), "a.com");
+    }
+
+    #[test]
+    fn test_websocket_auth() {
+        let cfg = |ws_auth| {
+            SharedClientData::new(ClientConfig {
+                host: "example.com".into(),
+                access_token: Some("secret".into()),
+                ws_auth,
+                ..Default::default()
+            })
+        };
+
+        let header = cfg(WsAuthMethod::Header);
+        assert!(!header.websocket_endpoint().contains("secret"));
+        assert_eq!(
+            header.websocket_auth_header(),
+            Some(("authorization", "secret".to_owned()))
+        );
+
+        let subprotocol = cfg(WsAuthMethod::Subprotocol);
+        assert!(!subprotocol.websocket_endpoint().contains("secret"));
+        let (name, value) = subprotocol.websocket_auth_header().unwrap();
+        assert_eq!(name, "sec-websocket-protocol");
+        assert_eq!(value, "rurikawa, rurikawa.token.secret");
+
+        let query = cfg(WsAuthMethod::Query);
+        assert!(query.websocket_endpoint().contains("token=secret"));
+        assert!(!query.websocket_endpoint_redacted().contains("secret"));
+        assert_eq!(query.websocket_auth_header(), None);
+    }
+
+    #[test]
+    fn test_docker_config_check() {
+        let cfg = |blkio_weight| DockerConfig {
+            blkio_weight,
+            ..Default::default()
+        };
+        assert!(cfg(None).check().is_ok());
+        assert!(cfg(Some(10)).check().is_ok());
+        assert!(cfg(Some(1000)).check().is_ok());
+        assert!(cfg(Some(0)).check().is_err());
+        assert!(cfg(Some(1001)).check().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_stale_downloads() {
+        let root = std::env::temp_dir().join(format!("rurikawa-config-{}", rand::random::<u32>()));
+        let data = SharedClientData::new(ClientConfig {
+            cache_folder: root.clone(),
+            ..Default::default()
+        });
+        let suite = FlowSnake::generate();
+        let other_suite = FlowSnake::generate();
+        let old = data.download_temp_file_path(suite, "pkg-1");
+        let current = data.download_temp_file_path(suite, "pkg-2");
+        let other = data.download_temp_file_path(other_suite, "pkg-1");
+        let progress = crate::fs::net::download_progress_path;
+        std::fs::create_dir_all(data.temp_file_folder_root()).unwrap();
+        for path in &[&old, &current, &other] {
+            std::fs::write(path, "partial").unwrap();
+            std::fs::write(progress(path), "{}").unwrap();
+        }
+
+        data.remove_stale_downloads(suite, "pkg-2").await.unwrap();
+        assert!(!old.exists() && !progress(&old).exists());
+        assert!(current.exists() && progress(&current).exists());
+        assert!(other.exists() && progress(&other).exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_download_temp_file_path_is_unique() {
+        let data = SharedClientData::new(ClientConfig::default());
+        let suite = FlowSnake::generate();
+        let name = |x| {
+            data.download_temp_file_path(suite, x)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        assert_eq!(name("pkg-1"), format!("download-{}-pkg-1", suite));
+        assert_eq!(name("a.b"), format!("download-{}-a_2eb", suite));
+        assert_eq!(name("a_b"), format!("download-{}-a_5fb", suite));
+        assert_ne!(name("a/b"), name("a_2fb"));
+    }
+}
@@ -10,6 +10,37 @@ pub enum JobExecErr {
     #[error(display = "No such config: {}", _0)]
     NoSuchConfig(String),
 
+    /// The submission is missing files required by the test suite, listed
+    /// as in [`RequiredFile::describe`](crate::tester::model::RequiredFile::describe)
+    #[error(display = "Missing required files: {}", _0)]
+    MissingRequiredFiles(String),
+
+    /// The test suite package is missing files referenced by its config
+    #[error(display = "Test suite is missing file: {}", _0)]
+    SuiteMissingFile(String),
+
+    /// The test suite is quarantined after its package failed repeatedly,
+    /// for this many more seconds
+    #[error(display = "Test suite is quarantined for {}s", _0)]
+    SuiteQuarantined(u64),
+
+    /// The test suite package exceeds the extraction limits
+    #[error(display = "Test suite package rejected: {}", _0)]
+    ExtractLimitExceeded(String),
+
+    /// The image config contains an invalid build arg
+    #[error(display = "Invalid build arg: {}", _0)]
+    InvalidBuildArg(String),
+
+    /// The test suite references shared data not provided by this judger
+    #[error(display = "Unknown shared data: {}", _0)]
+    UnknownSharedData(String),
+
+    /// The test suite has a memory limit that the Docker daemon of this
+    /// judger cannot enforce
+    #[error(display = "Memory limit cannot be enforced")]
+    UnenforceableMemoryLimit,
+
     #[error(display = "Git clone error: {}", _0)]
     Git(std::io::Error),
 
@@ -94,4 +125,6 @@ pub enum ClientConnectionErr {
     BadAccessToken,
     #[error(display = "Bad register token")]
     BadRegisterToken,
+    #[error(display = "Connection timed out after {:?}", _0)]
+    Timeout(std::time::Duration),
 }
@@ -0,0 +1,209 @@
+//! A consolidated log of every job, for archival.
+//!
+//! The log combines the build output, the output of every test, verdicts and
+//! timings of a job. It is written into the job folder as `job-log.json` and
+//! `job-log.txt` if the folder is retained, and uploaded as the result file
+//! of the pseudo test [`JOB_LOG_TEST_ID`] if configured, or when requested by
+//! the coordinator later.
+
+use super::model::{ClientMsg, JobBuildOutput, JobResultKind, TestResultKind};
+use crate::{
+    prelude::*,
+    tester::{exec::TestSuite, ProcessInfo},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write, path::Path, time::Duration};
+
+/// Test id the job log is uploaded as.
+pub const JOB_LOG_TEST_ID: &str = "__job_log";
+
+/// File name of the job log inside a retained job folder.
+const JOB_LOG_FILE_NAME: &str = "job-log.json";
+
+/// Options of job logs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobLogConfig {
+    /// Assemble a log of every job. Logs are written into the job folder if
+    /// it is retained.
+    pub enabled: bool,
+    /// Upload the log of every job to the coordinator.
+    pub upload: bool,
+    /// Maximum length of each output stream of each command, in bytes.
+    pub output_len: usize,
+    /// Maximum total length of test outputs in a log, in bytes. Outputs of
+    /// tests after the limit is reached are left out.
+    pub max_size: usize,
+}
+
+impl Default for JobLogConfig {
+    fn default() -> Self {
+        JobLogConfig {
+            enabled: false,
+            upload: false,
+            output_len: 4096,
+            max_size: 4 << 20,
+        }
+    }
+}
+
+/// Log entry of a single test.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestLog {
+    pub name: String,
+    pub kind: Option<TestResultKind>,
+    pub score: Option<f64>,
+    pub duration_ms: u64,
+    pub output: Vec<ProcessInfo>,
+}
+
+/// The consolidated log of a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobLog {
+    pub job_id: FlowSnake,
+    /// Result of the job, `None` if it is cancelled or aborted.
+    pub result: Option<JobResultKind>,
+    pub message: Option<String>,
+    pub duration_ms: u64,
+    /// Time spent preparing the image and containers.
+    pub build_duration_ms: Option<u64>,
+    pub build_log: Option<JobBuildOutput>,
+    /// Every test that has run, in the order they run.
+    pub tests: Vec<TestLog>,
+    /// Whether any output is truncated or left out.
+    pub truncated: bool,
+}
+
+impl JobLog {
+    pub fn new(job_id: FlowSnake) -> Self {
+        JobLog {
+            job_id,
+            result: None,
+            message: None,
+            duration_ms: 0,
+            build_duration_ms: None,
+            build_log: None,
+            tests: vec![],
+            truncated: false,
+        }
+    }
+
+    /// Record the timings and outputs of the tests in `suite`, keeping at most
+    /// `max_size` bytes of outputs in total.
+    pub fn record_suite(&mut self, suite: &TestSuite, max_size: usize) {
+        self.build_duration_ms = suite.build_duration.map(|x| x.as_millis() as u64);
+        let mut size = 0;
+        for record in &suite.records {
+            size += record
+                .output
+                .iter()
+                .map(|x| x.stdout.len() + x.stderr.len())
+                .sum::<usize>();
+            let output = if size <= max_size {
+                record.output.clone()
+            } else {
+                self.truncated = true;
+                vec![]
+            };
+            self.truncated |= record.truncated;
+            self.tests.push(TestLog {
+                name: record.name.clone(),
+                kind: None,
+                score: None,
+                duration_ms: record.duration.as_millis() as u64,
+                output,
+            });
+        }
+    }
+
+    /// Fill in the verdicts from the final message `msg` of the job, which
+    /// took `duration` in total.
+    pub fn finish(&mut self, msg: &ClientMsg, duration: Duration) {
+        self.duration_ms = duration.as_millis() as u64;
+        if let ClientMsg::JobResult(res) = msg {
+            self.result = Some(res.job_result);
+            self.message = res.message.clone();
+            if self.build_log.is_none() {
+                self.build_log = res.build_log.clone();
+            }
+            for test in &mut self.tests {
+                if let Some(result) = res.results.get(&test.name) {
+                    test.kind = Some(result.kind);
+                    test.score = result.score;
+                }
+            }
+        }
+    }
+
+    /// Human-readable form of this log.
+    pub fn to_text(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "Job {}", self.job_id);
+        match self.result {
+            Some(result) => {
+                let _ = writeln!(s, "Result: {:?}", result);
+            }
+            None => s.push_str("Result: cancelled or aborted\n"),
+        }
+        if let Some(message) = &self.message {
+            let _ = writeln!(s, "Message: {}", message);
+        }
+        let _ = writeln!(s, "Duration: {} ms", self.duration_ms);
+        if let Some(build) = self.build_duration_ms {
+            let _ = writeln!(s, "Build duration: {} ms", build);
+        }
+        if self.truncated {
+            s.push_str("Note: some outputs are truncated or left out\n");
+        }
+
+        if let Some(build_log) = &self.build_log {
+            s.push_str("\n=== Build output ===\n");
+            s.push_str(&build_log.output);
+            if !build_log.error.is_empty() {
+                s.push_str("\n=== Build errors ===\n");
+                s.push_str(&build_log.error);
+            }
+            s.push('\n');
+        }
+
+        for test in &self.tests {
+            let _ = write!(s, "\n=== Test {}: ", test.name);
+            match test.kind {
+                Some(kind) => {
+                    let _ = write!(s, "{:?}", kind);
+                }
+                None => s.push_str("no result"),
+            }
+            if let Some(score) = test.score {
+                let _ = write!(s, ", score {}", score);
+            }
+            let _ = writeln!(s, ", {} ms ===", test.duration_ms);
+            for info in &test.output {
+                let _ = writeln!(s, "$ {}", info.command);
+                let _ = writeln!(s, "(returned {})", info.ret_code);
+                if !info.stdout.is_empty() {
+                    let _ = writeln!(s, "--- stdout ---\n{}", info.stdout);
+                }
+                if !info.stderr.is_empty() {
+                    let _ = writeln!(s, "--- stderr ---\n{}", info.stderr);
+                }
+            }
+        }
+        s
+    }
+
+    /// Write this log into `folder` as `job-log.json` and `job-log.txt`.
+    pub async fn write_to(&self, folder: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(folder.join(JOB_LOG_FILE_NAME), json).await?;
+        tokio::fs::write(folder.join("job-log.txt"), self.to_text()).await
+    }
+
+    /// Read the log written into `folder` by [`write_to`](Self::write_to).
+    pub async fn read_from(folder: &Path) -> std::io::Result<Self> {
+        let json = tokio::fs::read(folder.join(JOB_LOG_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
@@ -0,0 +1,153 @@
+//! A small HTTP server exposing the health of the judger to monitoring
+//! systems, in the Prometheus text format.
+//!
+//! Metrics are served at `/metrics`, and are read from the live
+//! [`SharedClientData`] on every request.
+
+use super::config::SharedClientData;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    net::TcpListener,
+    sync::{atomic::Ordering, Arc},
+};
+
+/// Serve the metrics of `data` on `listener` until the server fails. The
+/// listener must be in non-blocking mode.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    data: Arc<SharedClientData>,
+) -> hyper::Result<()> {
+    let make_svc = make_service_fn(move |_| {
+        let data = data.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| respond(req, data.clone()))) }
+    });
+    Server::from_tcp(listener)?.serve(make_svc).await
+}
+
+async fn respond(
+    req: Request<Body>,
+    data: Arc<SharedClientData>,
+) -> Result<Response<Body>, Infallible> {
+    let res = if req.uri().path() == "/metrics" {
+        Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(render_metrics(&data).await))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    };
+    Ok(res.unwrap())
+}
+
+/// The metrics of `data` in the Prometheus text format.
+pub async fn render_metrics(data: &SharedClientData) -> String {
+    let mut metrics = vec![
+        (
+            "rurikawa_running_jobs",
+            "Number of jobs running on this judger.",
+            data.running_job_handles.lock().await.len() as u64,
+        ),
+        (
+            "rurikawa_running_tests",
+            "Number of task slots in use, by jobs from being started until their result is reported.",
+            data.running_tests.load(Ordering::SeqCst) as u64,
+        ),
+        (
+            "rurikawa_connected",
+            "Whether the websocket to the coordinator is connected.",
+            data.connected.load(Ordering::SeqCst) as u64,
+        ),
+        (
+            "rurikawa_cached_test_suites",
+            "Number of test suites whose files are ready on this judger.",
+            data.validated_test_suite.len() as u64,
+        ),
+        (
+            "rurikawa_last_keepalive_timestamp_seconds",
+            "Unix time of the last successful keepalive ping, 0 if none.",
+            data.last_keepalive.load(Ordering::SeqCst),
+        ),
+    ];
+    if let Some(budget) = &data.output_budget {
+        metrics.push((
+            "rurikawa_output_memory_used_bytes",
+            "Bytes of command and build output buffered by running jobs.",
+            budget.used() as u64,
+        ));
+        metrics.push((
+            "rurikawa_output_memory_limit_bytes",
+            "Limit of bytes of output buffered by running jobs.",
+            budget.limit() as u64,
+        ));
+    }
+    let mut out = String::new();
+    for (name, help, value) in metrics.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{client::config::ClientConfig, prelude::*};
+
+    #[tokio::test]
+    async fn test_metrics_server() {
+        let data = Arc::new(SharedClientData::new(ClientConfig::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, data.clone()));
+
+        let get = |path: &'static str| async move {
+            reqwest::get(format!("http://{}{}", addr, path))
+                .await
+                .unwrap()
+        };
+        let metrics = get("/metrics").await.text().await.unwrap();
+        assert!(
+            metrics.contains("\nrurikawa_running_jobs 0\n"),
+            "{}",
+            metrics
+        );
+        assert!(metrics.contains("\nrurikawa_connected 0\n"), "{}", metrics);
+
+        data.running_job_handles.lock().await.insert(
+            FlowSnake::generate(),
+            (tokio::spawn(async {}), CancellationTokenHandle::new()),
+        );
+        let metrics = get("/metrics").await.text().await.unwrap();
+        assert!(
+            metrics.contains("\nrurikawa_running_jobs 1\n"),
+            "{}",
+            metrics
+        );
+
+        assert_eq!(get("/").await.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let data = SharedClientData::new(ClientConfig {
+            output_memory_limit: Some(1024),
+            ..Default::default()
+        });
+        let metrics = render_metrics(&data).await;
+        assert!(
+            metrics.contains("\nrurikawa_output_memory_used_bytes 0\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("\nrurikawa_output_memory_limit_bytes 1024\n"),
+            "{}",
+            metrics
+        );
+    }
+}
@@ -1,39 +1,60 @@
+pub mod build_log;
 pub mod config;
 mod err;
+pub mod job_log;
+pub mod metrics;
 pub mod model;
+pub mod outbox;
 pub mod sink;
+pub mod suite_store;
 
 pub use self::err::*;
 use self::{
-    config::{ClientConfig, SharedClientData},
+    build_log::BuildLogStamper,
+    config::{ClientConfig, DuplicateJobPolicy, SharedClientData},
+    job_log::{JobLog, JOB_LOG_TEST_ID},
     model::*,
+    outbox::send_or_enqueue,
     sink::*,
 };
 use crate::{
     client::model::JobResultKind,
     config::{JudgeToml, JudgerPublicConfig},
-    fs::{self, JUDGE_FILE_NAME},
+    fs::{self, extract::ExtractLimitExceeded, JUDGE_FILE_NAME},
     prelude::*,
-    tester::model::{JudgerPrivateConfig, TestSuiteOptions},
+    tester::{
+        model::{JudgerPrivateConfig, TestSuiteOptions},
+        validate, BuildError,
+    },
 };
 use anyhow::{Context, Result};
 use futures::prelude::*;
 use http::Method;
+use itertools::Itertools;
 use respector::prelude::*;
 use serde_json::from_slice;
-use std::{collections::HashMap, path::PathBuf, sync::atomic::Ordering, sync::Arc};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    sync::Arc,
+};
+use tokio_tungstenite::{
+    client_async_tls_with_config,
+    tungstenite::{self, Message},
+    Connector,
+};
 use tracing::info_span;
 use tracing_futures::Instrument;
 
+/// Minimum interval between two progress reports of a running job.
+const PROGRESS_REPORT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 /// Try to register at the coordinator if no access token was specified.
 ///
 /// Returns `Ok(true)` if register was success, `Ok(false)` if register is not
 /// needed or not applicable.
-pub async fn try_register(
-    client_data: &mut SharedClientData,
-    refresh: bool,
-) -> anyhow::Result<bool> {
+pub async fn try_register(client_data: &SharedClientData, refresh: bool) -> anyhow::Result<bool> {
     tracing::info!(
         "Registering judger. Access token: {:?}; Register token: {:?}",
         client_data.cfg().access_token,
@@ -50,14 +71,30 @@ pub async fn try_register(
         alternate_name: client_data.cfg().alternate_name.clone(),
         tags: client_data.cfg().tags.clone(),
     };
-    let endpoint = client_data.register_endpoint();
     let client = &client_data.client;
 
-    let req = client
-        .request(Method::POST, &endpoint)
-        .json(&req_body)
-        .build()?;
-    let res = client.execute(req).await?;
+    // Like `connect_to_coordinator`, try the following coordinators if the
+    // one in use cannot be reached
+    let count = client_data.host_count();
+    let mut tried = 0;
+    let res = loop {
+        let req = client
+            .request(Method::POST, &client_data.register_endpoint())
+            .json(&req_body)
+            .build()?;
+        let err = match client.execute(req).await {
+            Ok(res) => break res,
+            Err(e) => e,
+        };
+        tried += 1;
+        if count > 1 {
+            tracing::warn!("Failed to register at {}: {}", client_data.host(), err);
+            client_data.next_host();
+        }
+        if tried >= count {
+            return Err(err.into());
+        }
+    };
 
     let status = res.status().as_u16();
     if status >= 300 {
@@ -76,45 +113,155 @@ pub async fn try_register(
     tracing::info!("Got new access token: {}", res);
 
     let new_cfg = ClientConfig {
-        access_token: Some(res),
+        access_token: Some(res.clone()),
         ..(**client_data.cfg()).clone()
     };
     client_data.swap_cfg(Arc::new(new_cfg));
+    if let Err(e) = client_data.save_access_token(&res).await {
+        tracing::warn!("Failed to save access token: {}", e);
+    }
 
     Ok(true)
 }
 
+/// Outcome of verifying the access token of this judger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenVerification {
+    /// The coordinator accepts the token.
+    Valid,
+    /// There's no token, or the coordinator rejects it.
+    Invalid,
+    /// The token cannot be verified now, because the coordinator cannot be
+    /// reached or fails to answer.
+    Unreachable(String),
+}
+
 /// Verify if the current registration is active.
-pub async fn verify_self(cfg: &SharedClientData) -> anyhow::Result<bool> {
+///
+/// Like [`connect_to_coordinator`], the following coordinators are tried if
+/// the one in use cannot be reached, and the one answering stays in use.
+pub async fn verify_self(cfg: &SharedClientData) -> TokenVerification {
     tracing::info!("Verifying access token {:?}", cfg.cfg().access_token);
     if cfg.cfg().access_token.is_none() {
-        return Ok(false);
+        return TokenVerification::Invalid;
+    }
+
+    let count = cfg.host_count();
+    let mut tried = 0;
+    loop {
+        let res = verify_at_host(cfg).await;
+        let err = match &res {
+            TokenVerification::Unreachable(e) => e,
+            _ => return res,
+        };
+        tried += 1;
+        if count > 1 {
+            tracing::warn!("Failed to verify at {}: {}", cfg.host(), err);
+            cfg.next_host();
+        }
+        if tried >= count {
+            return res;
+        }
     }
+}
 
+/// Verify the access token at the coordinator in use.
+async fn verify_at_host(cfg: &SharedClientData) -> TokenVerification {
     let endpoint = cfg.verify_endpoint();
     let res = cfg
         .client
         .request(Method::GET, &endpoint)
         .header("authorization", cfg.cfg().access_token.as_ref().unwrap())
         .send()
-        .await?
-        .status()
-        .is_success();
-    Ok(res)
+        .await;
+    match res {
+        Ok(res) if res.status().is_success() => TokenVerification::Valid,
+        Ok(res)
+            if res.status() == http::StatusCode::UNAUTHORIZED
+                || res.status() == http::StatusCode::FORBIDDEN =>
+        {
+            TokenVerification::Invalid
+        }
+        Ok(res) => TokenVerification::Unreachable(format!("status code {}", res.status())),
+        Err(e) => TokenVerification::Unreachable(e.to_string()),
+    }
 }
 
+/// Connect to the coordinator in use, or the following ones in
+/// [`ClientConfig::host`] if it cannot be
+/// connected to. The one connected to stays in use, so that registration and
+/// HTTP requests go to it too. Fails with the last error after trying every
+/// coordinator once, leaving the first one tried in use again.
 pub async fn connect_to_coordinator(
     cfg: &SharedClientData,
+) -> Result<(RawWsSink, WsStream), ClientConnectionErr> {
+    let count = cfg.host_count();
+    let mut tried = 0;
+    loop {
+        let err = match connect_to_host(cfg).await {
+            Ok(x) => return Ok(x),
+            Err(e) => e,
+        };
+        tried += 1;
+        if count > 1 {
+            tracing::warn!("Failed to connect to {}: {}", cfg.host(), err);
+            cfg.next_host();
+        }
+        if tried >= count {
+            return Err(err);
+        }
+    }
+}
+
+/// Connect to the coordinator in use.
+async fn connect_to_host(
+    cfg: &SharedClientData,
 ) -> Result<(RawWsSink, WsStream), ClientConnectionErr> {
     let endpoint = cfg.websocket_endpoint();
-    let req = http::Request::builder().uri(&endpoint);
-    tracing::info!("Connecting to {}", endpoint);
-    let (client, _) = connect_async(req.body(()).unwrap()).await?;
+    let mut req = http::Request::builder().uri(&endpoint);
+    if let Some((name, value)) = cfg.websocket_auth_header() {
+        req = req.header(name, value);
+    }
+    tracing::info!("Connecting to {}", cfg.websocket_endpoint_redacted());
+    let timeout = std::time::Duration::from_secs(cfg.cfg().connect_timeout);
+    let (client, _) = tokio::time::timeout(
+        timeout,
+        connect_with_tls(
+            req.body(()).unwrap(),
+            cfg.tls_config.as_ref().map(|x| x.0.clone()),
+        ),
+    )
+    .await
+    .map_err(|_| ClientConnectionErr::Timeout(timeout))??;
     let (cli_sink, cli_stream) = client.split();
     tracing::info!("Connection success");
     Ok((cli_sink, cli_stream))
 }
 
+/// Connect to a websocket endpoint, using the given TLS settings if the
+/// endpoint is secure.
+async fn connect_with_tls(
+    req: http::Request<()>,
+    tls: Option<Arc<rustls::ClientConfig>>,
+) -> Result<(WsDuplex, http::Response<()>), tungstenite::Error> {
+    let uri = req.uri();
+    let host = uri.host().ok_or(tungstenite::Error::Url(
+        tungstenite::error::UrlError::NoHostName,
+    ))?;
+    let port = uri
+        .port_u16()
+        .or_else(|| match uri.scheme_str() {
+            Some("wss") => Some(443),
+            Some("ws") => Some(80),
+            _ => None,
+        })
+        .ok_or(tungstenite::Error::Url(
+            tungstenite::error::UrlError::UnsupportedUrlScheme,
+        ))?;
+    let socket = tokio::net::TcpStream::connect(format!("{}:{}", host, port)).await?;
+    client_async_tls_with_config(req, socket, None, tls.map(Connector::Rustls)).await
+}
+
 async fn fetch_test_suite_data(
     suite_id: FlowSnake,
     cfg: &SharedClientData,
@@ -131,57 +278,128 @@ async fn fetch_test_suite_data(
     Ok(res)
 }
 
+/// Make sure test suite `suite_id` is downloaded and up to date, and read its
+/// public config.
+///
+/// Cancelling `cancel` stops waiting for the suite lock or downloading the
+/// suite, releasing the suite lock and removing the temp file. Jobs of a
+/// quarantined suite fail with [`JobExecErr::SuiteQuarantined`] right away.
 pub async fn check_download_read_test_suite(
     suite_id: FlowSnake,
     cfg: &SharedClientData,
+    cancel: CancellationTokenHandle,
 ) -> Result<JudgerPublicConfig, JobExecErr> {
     tracing::info!("Checking test suite {}", suite_id);
+    let retry = cfg.cfg().fs_retry.clone();
     let suite_folder_root = cfg.test_suite_folder_root();
-    tokio::fs::create_dir_all(suite_folder_root).await?;
-    let suite_folder = cfg.test_suite_folder(suite_id);
+    retry.create_dir_all(&suite_folder_root).await?;
+
+    // Lock this specific test suite and let all other concurrent tasks to wait
+    // until downloading completes. Tasks that waited for another one retry
+    // obtaining the lock, so that the lockfile and suite folder are never read
+    // while being written.
+    let handle = loop {
+        let lock = cfg
+            .obtain_suite_lock(suite_id)
+            .instrument(info_span!("suite_lock", %suite_id))
+            .with_cancel(cancel.clone())
+            .await
+            .ok_or(JobExecErr::Cancelled)?;
+        if let Some(x) = lock {
+            break AutoReleaseToken(x, cfg, suite_id);
+        }
+    };
+
+    let suite_data = fetch_test_suite_data(suite_id, cfg).await?;
 
-    // My fault - The cancellation token should automagically cancel itself when
-    // dropped in this case - If download fails then it won't cancel.
+    if let Some(left) = cfg.suite_quarantine_left(suite_id, &suite_data.package_file_id) {
+        tracing::warn!("Test suite {} is quarantined for {:?}", suite_id, left);
+        return Err(JobExecErr::SuiteQuarantined(left.as_secs().max(1)));
+    }
 
-    /// This struct automatically releases the test suite inside it if dropped.
-    ///
-    /// TODO: Move this struct inside `SharedClientData`.
-    struct AutoReleaseToken<'a>(CancellationTokenHandle, &'a SharedClientData, FlowSnake);
-    impl<'a> Drop for AutoReleaseToken<'a> {
-        fn drop(&mut self) {
-            let Self(canceller, client_data, suite_id) = self;
-            canceller.cancel();
-            client_data.suite_unlock(*suite_id);
+    let res = prepare_test_suite(suite_id, &suite_data, cfg, &cancel, handle).await;
+    match &res {
+        Ok(_) => cfg.record_suite_success(suite_id),
+        Err(e) if is_package_failure(e) => {
+            if cfg.record_suite_failure(suite_id, &suite_data.package_file_id) {
+                tracing::warn!(
+                    "Test suite {} quarantined after repeated failures, last error: {}",
+                    suite_id,
+                    e
+                );
+            }
         }
+        Err(_) => {}
     }
+    res
+}
 
-    tracing::debug!("Folder created: {:?}", suite_folder);
-    let handle = cfg
-        .obtain_suite_lock(suite_id)
-        .instrument(info_span!("suite_lock", %suite_id))
-        .await
-        .map(|x| AutoReleaseToken(x, cfg, suite_id));
+/// Whether `err` preparing a test suite is caused by its package being broken,
+/// i.e. it cannot be extracted or fails validation. Network, coordinator and
+/// local disk errors don't count, since they are not the package's fault.
+fn is_package_failure(err: &JobExecErr) -> bool {
+    match err {
+        JobExecErr::ExtractLimitExceeded(_)
+        | JobExecErr::SuiteMissingFile(_)
+        | JobExecErr::NoSuchFile(_)
+        | JobExecErr::Json(_) => true,
+        // 7-Zip failing to list or extract the archive
+        JobExecErr::Any(e) => e
+            .downcast_ref::<std::io::Error>()
+            .map_or(false, |e| e.kind() == std::io::ErrorKind::InvalidData),
+        _ => false,
+    }
+}
 
-    // Lock this specific test suite and let all other concurrent tasks to wait
-    // until downloading completes
+// My fault - The cancellation token should automagically cancel itself when
+// dropped in this case - If download fails then it won't cancel.
 
-    let suite_data = fetch_test_suite_data(suite_id, cfg).await?;
+/// This struct automatically releases the test suite inside it if dropped.
+///
+/// TODO: Move this struct inside `SharedClientData`.
+struct AutoReleaseToken<'a>(CancellationTokenHandle, &'a SharedClientData, FlowSnake);
+impl<'a> Drop for AutoReleaseToken<'a> {
+    fn drop(&mut self) {
+        let Self(canceller, client_data, suite_id) = self;
+        canceller.cancel(None);
+        client_data.suite_unlock(*suite_id);
+    }
+}
+
+/// Download test suite `suite_id` into its folder if it is not up to date
+/// with `suite_data`, then read and validate its public config. The suite
+/// lock `handle` is released once the config is read.
+///
+/// Failures of the package here are counted towards the quarantine of the
+/// suite, see [`is_package_failure`] and
+/// [`SharedClientData::record_suite_failure`].
+async fn prepare_test_suite(
+    suite_id: FlowSnake,
+    suite_data: &TestSuite,
+    cfg: &SharedClientData,
+    cancel: &CancellationTokenHandle,
+    handle: AutoReleaseToken<'_>,
+) -> Result<JudgerPublicConfig, JobExecErr> {
+    let retry = cfg.cfg().fs_retry.clone();
+    let suite_folder = cfg.test_suite_folder(suite_id);
 
     let dir_exists = {
-        let create_dir = tokio::fs::create_dir(&suite_folder).await;
-        match create_dir {
+        let create_dir = retry.run(|| tokio::fs::create_dir(&suite_folder)).await;
+        let exists = match create_dir {
             Ok(()) => false,
             Err(e) => match e.kind() {
                 std::io::ErrorKind::AlreadyExists => true,
                 _ => return Err(e.into()),
             },
-        }
+        };
+        fs::verify_dir(&suite_folder).await?;
+        exists
     };
 
     let lockfile = cfg.test_suite_folder_lockfile(suite_id);
 
-    let lockfile_up_to_date = {
-        let lockfile_data = tokio::fs::read_to_string(&lockfile).await;
+    let suite_data_locked = {
+        let lockfile_data = retry.run(|| tokio::fs::read_to_string(&lockfile)).await;
         let lockfile_data = match lockfile_data {
             Ok(f) => Some(f),
             Err(e) => match e.kind() {
@@ -190,44 +408,69 @@ pub async fn check_download_read_test_suite(
             },
         };
 
-        let suite_data_locked = lockfile_data
+        lockfile_data
             .as_deref()
-            .and_then(|x| serde_json::from_str::<TestSuite>(x).ok());
-
-        suite_data_locked
-            .map(|locked| locked.package_file_id == suite_data.package_file_id)
-            .unwrap_or(false)
+            .and_then(|x| serde_json::from_str::<TestSuite>(x).ok())
     };
+    let lockfile_up_to_date = suite_data_locked
+        .as_ref()
+        .map(|locked| is_lock_up_to_date(locked, suite_data))
+        .unwrap_or(false);
 
+    let mut applied_patch_ids = vec![];
     if !dir_exists || !lockfile_up_to_date {
-        let endpoint = cfg.test_suite_download_endpoint(suite_id);
-        let filename = cfg.random_temp_file_path();
-        let file_folder_root = cfg.temp_file_folder_root();
-
-        fs::ensure_removed_dir(&suite_folder).await?;
-        tokio::fs::create_dir_all(file_folder_root).await?;
-        tracing::info!(
-            "Test suite does not exist. Initiating download of suite {} from {} to {:?}",
-            suite_id,
-            &endpoint,
-            &filename
-        );
-        fs::net::download_unzip(
-            cfg.client.clone(),
-            cfg.client
-                .get(&endpoint)
-                .header("authorization", cfg.cfg().access_token.as_ref().unwrap())
-                .build()?,
-            &suite_folder,
-            &filename,
-        )
-        .await?;
+        let download = |dest: PathBuf| download_test_suite(suite_id, suite_data, dest, cfg, cancel);
+        // Stored contents are shared by suites, so they are never patched
+        let may_patch = dir_exists && cfg.cfg().patch_test_suites && !cfg.cfg().dedup_test_suites;
+        let patch = suite_data
+            .patch
+            .as_ref()
+            .filter(|_| may_patch)
+            .zip(suite_data_locked.as_ref())
+            .filter(|(patch, locked)| locked.package_file_id == patch.base_package_file_id);
+        let patched = match patch {
+            Some((patch, locked)) => {
+                let res =
+                    patch_test_suite(suite_id, patch, &suite_folder, suite_data, cfg, cancel).await;
+                match res {
+                    Ok(()) => {
+                        applied_patch_ids = locked.applied_patch_ids.clone();
+                        applied_patch_ids.push(patch.patch_file_id.clone());
+                        true
+                    }
+                    Err(JobExecErr::Cancelled) => return Err(JobExecErr::Cancelled),
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to patch suite {}, downloading it in full: {}",
+                            suite_id,
+                            e
+                        );
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+        if patched {
+            // Nothing more to download
+        } else if cfg.cfg().dedup_test_suites {
+            suite_store::link_content(cfg, &suite_folder, &suite_data.package_file_id, download)
+                .await?;
+        } else {
+            retry.run(|| fs::ensure_removed_dir(&suite_folder)).await?;
+            download(suite_folder.clone()).await?;
+        }
     }
 
     // Rewrite lockfile AFTER all data are saved
     if !lockfile_up_to_date {
-        let serialized = serde_json::to_string(&suite_data)?;
-        tokio::fs::write(&lockfile, &serialized).await?;
+        let serialized = serde_json::to_string(&TestSuite {
+            applied_patch_ids,
+            ..suite_data.clone()
+        })?;
+        retry
+            .run(|| fs::write_atomic(&lockfile, &serialized))
+            .await?;
     }
 
     tracing::info!("Suite downloaded");
@@ -246,9 +489,6 @@ pub async fn check_download_read_test_suite(
     //   V
     // let _ = fs::ensure_removed_dir(&cfg.test_suite_folder(suite_id)).await;
 
-    // The handle should be dropped right here
-    drop(handle);
-
     let mut judger_conf_dir = suite_folder.clone();
     judger_conf_dir.push("testconf.json");
     let judger_conf = match tokio::fs::read(&judger_conf_dir).await {
@@ -262,11 +502,154 @@ pub async fn check_download_read_test_suite(
             _ => return Err(JobExecErr::Io(e)),
         },
     };
+
+    // The handle should be dropped right here, after the suite config is read
+    drop(handle);
     let judger_conf = serde_json::from_slice::<JudgerPublicConfig>(&judger_conf)?;
 
+    // Validate the suite only once for every package it's downloaded from
+    let validated = cfg
+        .validated_test_suite
+        .get(&suite_id)
+        .map(|x| *x == suite_data.package_file_id)
+        .unwrap_or(false);
+    if !validated {
+        let missing = validate::find_missing_suite_files(&judger_conf, &suite_folder).await?;
+        if !missing.is_empty() {
+            cfg.validated_test_suite.remove(&suite_id);
+            return Err(JobExecErr::SuiteMissingFile(
+                missing
+                    .iter()
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .join(", "),
+            ));
+        }
+        cfg.validated_test_suite
+            .insert(suite_id, suite_data.package_file_id.clone());
+        tracing::info!("Suite {} validated", suite_id);
+    }
+
     Ok(judger_conf)
 }
 
+/// Whether the suite folder `locked` in its lockfile is the package of
+/// `suite_data`. A folder patched into the package is not if the coordinator
+/// now advertises a different patch into it, since the patches diverged.
+fn is_lock_up_to_date(locked: &TestSuite, suite_data: &TestSuite) -> bool {
+    if locked.package_file_id != suite_data.package_file_id {
+        return false;
+    }
+    match (locked.applied_patch_ids.last(), &suite_data.patch) {
+        (Some(applied), Some(patch)) => *applied == patch.patch_file_id,
+        _ => true,
+    }
+}
+
+/// Download `patch` of test suite `suite_id` and apply it onto its folder
+/// `suite_folder`, see [`fs::apply_patch`].
+async fn patch_test_suite(
+    suite_id: FlowSnake,
+    patch: &SuitePatch,
+    suite_folder: &Path,
+    suite_data: &TestSuite,
+    cfg: &SharedClientData,
+    cancel: &CancellationTokenHandle,
+) -> Result<(), JobExecErr> {
+    tracing::info!(
+        "Patching suite {} from package {} with {}",
+        suite_id,
+        patch.base_package_file_id,
+        patch.patch_file_id
+    );
+    let endpoint = cfg.test_suite_patch_endpoint(suite_id, &patch.patch_file_id);
+    let patch_dir = cfg.random_temp_file_path();
+    let res: Result<(), JobExecErr> = async {
+        let dest = patch_dir.clone();
+        let package_id = &patch.patch_file_id;
+        download_package(
+            suite_id, suite_data, &endpoint, package_id, dest, cfg, cancel,
+        )
+        .await?;
+        fs::apply_patch(suite_folder, &patch_dir).await?;
+        Ok(())
+    }
+    .await;
+    let _ = fs::ensure_removed_dir(&patch_dir).await;
+    res
+}
+
+/// Download the package of test suite `suite_id` and extract it into `dest`.
+async fn download_test_suite(
+    suite_id: FlowSnake,
+    suite_data: &TestSuite,
+    dest: PathBuf,
+    cfg: &SharedClientData,
+    cancel: &CancellationTokenHandle,
+) -> Result<(), JobExecErr> {
+    let endpoint = cfg.test_suite_download_endpoint(suite_id);
+    let package_id = &suite_data.package_file_id;
+    download_package(
+        suite_id, suite_data, &endpoint, package_id, dest, cfg, cancel,
+    )
+    .await
+}
+
+/// Download the archive `package_id` at `endpoint` for test suite
+/// `suite_id`, and extract it into `dest` within the extraction limits of
+/// the suite. Interrupted downloads are resumed if possible.
+async fn download_package(
+    suite_id: FlowSnake,
+    suite_data: &TestSuite,
+    endpoint: &str,
+    package_id: &str,
+    dest: PathBuf,
+    cfg: &SharedClientData,
+    cancel: &CancellationTokenHandle,
+) -> Result<(), JobExecErr> {
+    let filename = cfg.download_temp_file_path(suite_id, package_id);
+    let file_folder_root = cfg.temp_file_folder_root();
+
+    cfg.cfg()
+        .fs_retry
+        .clone()
+        .create_dir_all(&file_folder_root)
+        .await?;
+    if let Err(e) = cfg.remove_stale_downloads(suite_id, package_id).await {
+        tracing::warn!(
+            "Failed to remove stale downloads of suite {}: {}",
+            suite_id,
+            e
+        );
+    }
+    tracing::info!(
+        "Initiating download of suite {} from {} to {:?}",
+        suite_id,
+        endpoint,
+        &filename
+    );
+    let extract_limits = cfg.cfg().extract_limits_for(&suite_data.name).clone();
+    let res = fs::net::download_unzip(
+        cfg.client.clone(),
+        cfg.client
+            .get(endpoint)
+            .header("authorization", cfg.cfg().access_token.as_ref().unwrap())
+            .build()?,
+        &dest,
+        &filename,
+        &extract_limits,
+        cancel.child_token(),
+    )
+    .await;
+    if cancel.is_cancelled() {
+        tracing::info!("Download of suite {} cancelled", suite_id);
+        return Err(JobExecErr::Cancelled);
+    }
+    res.map_err(|e| match e.downcast::<ExtractLimitExceeded>() {
+        Ok(e) => JobExecErr::ExtractLimitExceeded(e.0),
+        Err(e) => JobExecErr::Any(e),
+    })
+}
+
 fn extract_job_err(job_id: FlowSnake, err: &JobExecErr) -> ClientMsg {
     tracing::warn!("job {} aborted because of error: {:?}", job_id, &err);
 
@@ -279,6 +662,32 @@ fn extract_job_err(job_id: FlowSnake, err: &JobExecErr) -> ClientMsg {
             JobResultKind::CompileError,
             format!("Cannot find config for {} in `judger.toml`", f),
         ),
+        JobExecErr::MissingRequiredFiles(f) => (
+            JobResultKind::CompileError,
+            format!("Submission is missing required files: {}", f),
+        ),
+        JobExecErr::InvalidBuildArg(e) => (JobResultKind::CompileError, e.clone()),
+        JobExecErr::ExtractLimitExceeded(e) => (
+            JobResultKind::JudgerError,
+            format!("Test suite package rejected: {}", e),
+        ),
+        JobExecErr::UnknownSharedData(e) => (JobResultKind::JudgerError, e.clone()),
+        JobExecErr::UnenforceableMemoryLimit => (
+            JobResultKind::JudgerError,
+            "The memory limit of this test suite cannot be enforced by this judger".into(),
+        ),
+        JobExecErr::SuiteMissingFile(f) => (
+            JobResultKind::JudgerError,
+            format!("Test suite is missing file: {}", f),
+        ),
+        JobExecErr::SuiteQuarantined(secs) => (
+            JobResultKind::JudgerError,
+            format!(
+                "Test suite package failed repeatedly on this judger, \
+                retrying it in {}s",
+                secs
+            ),
+        ),
         JobExecErr::Io(e) => (JobResultKind::JudgerError, format!("IO error: {}", e)),
         JobExecErr::Ws(e) => (
             JobResultKind::JudgerError,
@@ -321,9 +730,20 @@ fn extract_job_err(job_id: FlowSnake, err: &JobExecErr) -> ClientMsg {
         results: HashMap::new(),
         job_result: err,
         message: Some(msg),
+        build_log: None,
+        network_attempts: None,
+        seed: None,
+        chunked: false,
     })
 }
 
+/// Results of finished tests to be reported with a cancelled job, if any.
+fn partial_results(
+    finished: &mut HashMap<String, TestResult>,
+) -> Option<HashMap<String, TestResult>> {
+    Some(std::mem::take(finished)).filter(|x| !x.is_empty())
+}
+
 pub async fn handle_job_wrapper(
     job: Job,
     send: Arc<WsSink>,
@@ -331,11 +751,34 @@ pub async fn handle_job_wrapper(
     cfg: Arc<SharedClientData>,
 ) {
     let job_id = job.id;
+    let started = std::time::Instant::now();
     flag_new_job(send.clone(), cfg.clone()).await;
 
-    let res_handle = handle_job(job, send.clone(), cancel, cfg.clone())
-        .instrument(tracing::info_span!("handle_job", %job_id))
-        .await;
+    let mut log = JobLog::new(job_id);
+    let mut finished = HashMap::new();
+    let res_handle = handle_job(
+        job,
+        send.clone(),
+        cancel.clone(),
+        cfg.clone(),
+        &mut log,
+        &mut finished,
+    )
+    .instrument(tracing::info_span!("handle_job", %job_id))
+    .await;
+
+    // The new dispatch of a replaced job owns its handle and reports its
+    // results, and the coordinator would drop them after a terminal stage.
+    if cancel.cancellation_reason() == Some(CancelReason::Replaced) {
+        tracing::info!("{}: replaced by a new dispatch", job_id);
+        flag_finished_job(cfg.clone()).await;
+        let _ = fs::ensure_removed_dir(&cfg.job_folder(job_id))
+            .await
+            .inspect_err(|e| {
+                tracing::error!("Failed to remove directory for job {}: {}", job_id, e)
+            });
+        return;
+    }
 
     let msg = match res_handle {
         Ok(_res) => ClientMsg::JobResult(_res),
@@ -343,46 +786,38 @@ pub async fn handle_job_wrapper(
         Err(JobExecErr::Aborted) => ClientMsg::JobProgress(JobProgressMsg {
             job_id,
             stage: JobStage::Aborted,
+            progress: None,
+            results: partial_results(&mut finished),
         }),
         Err(JobExecErr::Cancelled) => ClientMsg::JobProgress(JobProgressMsg {
             job_id,
-            stage: {
-                if cfg
-                    .cancelling_job_info
-                    .get(&job_id)
-                    .map_or(true, |x| x.as_cancel)
-                {
-                    JobStage::Cancelled
-                } else {
-                    JobStage::Aborted
-                }
+            stage: match cancel.cancellation_reason() {
+                // Aborted jobs are run again by the coordinator
+                Some(CancelReason::ServerAbort) | Some(CancelReason::Shutdown) => JobStage::Aborted,
+                _ => JobStage::Cancelled,
             },
+            progress: None,
+            results: partial_results(&mut finished),
         }),
         Err(e) => extract_job_err(job_id, &e),
     };
 
-    loop {
-        // Ah yes, do-while pattern
-        let mut req = cfg.client.post(&cfg.result_send_endpoint()).json(&msg);
-        if let Some(token) = &cfg.cfg().access_token {
-            req = req.header("authorization", token.as_str());
-        }
-        let res = req
-            .send()
-            .and_then(|r| async {
-                let status = r.status();
-                if !status.is_success() {
-                    r.text().await.inspect(|t| {
-                        tracing::error!("Error when sending job result mesage: {}\n{}", status, t)
-                    })?;
-                }
-                Ok(())
-            })
-            .await;
-        if res.is_ok() {
-            break;
-        }
-    }
+    let result_kind = match &msg {
+        ClientMsg::JobResult(res) => Some(res.job_result),
+        ClientMsg::JobProgress(JobProgressMsg {
+            stage: JobStage::Aborted,
+            ..
+        }) => Some(JobResultKind::Aborted),
+        _ => None,
+    };
+    let retain_folder = result_kind
+        .map(|kind| cfg.cfg().retain_job_folder_on.contains(&kind))
+        .unwrap_or(false);
+    log.finish(&msg, started.elapsed());
+
+    send_or_enqueue(msg, &cfg, &send).await;
+
+    save_job_log(&log, retain_folder, &cfg).await;
 
     flag_finished_job(cfg.clone()).await;
 
@@ -391,42 +826,157 @@ pub async fn handle_job_wrapper(
     {
         cfg.running_job_handles.lock().await.remove(&job_id);
     }
+    cfg.running_job_revisions.remove(&job_id);
 
-    let _ = fs::ensure_removed_dir(&cfg.job_folder(job_id))
-        .await
-        .inspect_err(|e| tracing::error!("Failed to remove directory for job {}: {}", job_id, e));
-    tracing::info!("{}: cleanup complete", job_id);
+    if retain_folder {
+        tracing::info!(
+            "{}: job folder retained at {}",
+            job_id,
+            cfg.job_folder(job_id).display()
+        );
+    } else {
+        let _ = fs::ensure_removed_dir(&cfg.job_folder(job_id))
+            .await
+            .inspect_err(|e| {
+                tracing::error!("Failed to remove directory for job {}: {}", job_id, e)
+            });
+        tracing::info!("{}: cleanup complete", job_id);
+    }
+
+    let finished_jobs = cfg.finished_jobs.fetch_add(1, Ordering::SeqCst) + 1;
+    if cfg
+        .max_jobs
+        .map(|max| finished_jobs >= max)
+        .unwrap_or(false)
+    {
+        tracing::warn!("All {} job(s) finished, shutting down", finished_jobs);
+        cfg.cancel_handle.cancel(CancelReason::Shutdown);
+    }
+}
+
+/// Write the log of a job into its folder if it is retained, and upload it if
+/// configured.
+async fn save_job_log(log: &JobLog, retain_folder: bool, cfg: &SharedClientData) {
+    let log_cfg = cfg.cfg().job_log.clone();
+    if !log_cfg.enabled {
+        return;
+    }
+    if retain_folder {
+        let folder = cfg.job_folder(log.job_id);
+        match log.write_to(&folder).await {
+            Ok(()) => tracing::info!("{}: job log written", log.job_id),
+            Err(e) => tracing::warn!("{}: failed to write job log: {}", log.job_id, e),
+        }
+    }
+    if log_cfg.upload {
+        let upload_info = Arc::new(ResultUploadConfig {
+            client: cfg.client.clone(),
+            endpoint: cfg.result_upload_endpoint(),
+            access_token: cfg.cfg().access_token.clone(),
+            job_id: log.job_id,
+            semaphore: cfg.upload_semaphore.clone(),
+            compression: cfg.upload_compression(),
+        });
+        if let Ok(file) = upload_test_result(log, upload_info, JOB_LOG_TEST_ID).await {
+            tracing::info!("{}: job log uploaded as {}", log.job_id, file);
+        }
+    }
+}
+
+/// Upload the log of the finished job `job_id` as requested by the
+/// coordinator, and tell it the id of the uploaded file or why the log is
+/// unavailable.
+async fn upload_job_logs(job_id: FlowSnake, cfg: &SharedClientData, send: &WsSink) {
+    let log = if cfg.running_job_handles.lock().await.contains_key(&job_id) {
+        Err("Job is still running".to_owned())
+    } else {
+        JobLog::read_from(&cfg.job_folder(job_id))
+            .await
+            .map_err(|e| format!("Logs unavailable: {}", e))
+    };
+    let res = match log {
+        Ok(log) => {
+            let upload_info = Arc::new(ResultUploadConfig {
+                client: cfg.client.clone(),
+                endpoint: cfg.result_upload_endpoint(),
+                access_token: cfg.cfg().access_token.clone(),
+                job_id,
+                semaphore: cfg.upload_semaphore.clone(),
+                compression: cfg.upload_compression(),
+            });
+            upload_test_result(log, upload_info, JOB_LOG_TEST_ID)
+                .await
+                .map_err(|e| format!("Failed to upload logs: {}", e))
+        }
+        Err(e) => Err(e),
+    };
+    match &res {
+        Ok(file) => tracing::info!("{}: job log uploaded as {} on request", job_id, file),
+        Err(e) => tracing::warn!("{}: cannot upload requested job log: {}", job_id, e),
+    }
+    let (result_file_id, error) = match res {
+        Ok(file) => (Some(file), None),
+        Err(e) => (None, Some(e)),
+    };
+    // The result endpoint only accepts job results, so the reply goes
+    // through the websocket instead of the outbox.
+    let reply = ClientMsg::JobLogs(JobLogsMsg {
+        job_id,
+        result_file_id,
+        error,
+    });
+    if let Err(e) = send.send_msg(&reply).await {
+        tracing::warn!("{}: cannot reply to job log request: {}", job_id, e);
+    }
 }
 
+/// Run `job`, recording the timings and outputs of its tests into `log`.
+/// Results of finished tests are also collected into `finished`, so that
+/// they are kept if the job is cancelled.
 pub async fn handle_job(
     job: Job,
     send: Arc<WsSink>,
     cancel: CancellationTokenHandle,
     cfg: Arc<SharedClientData>,
+    log: &mut JobLog,
+    finished: &mut HashMap<String, TestResult>,
 ) -> Result<JobResultMsg, JobExecErr> {
     let client = reqwest::Client::new();
 
     tracing::info!("created");
 
-    let mut public_cfg = check_download_read_test_suite(job.test_suite, &*cfg)
+    let public_cfg = check_download_read_test_suite(job.test_suite, &*cfg, cancel.clone())
         .with_cancel(cancel.clone())
         .instrument(info_span!("download_test_suites", %job.test_suite))
-        .await
-        .ok_or(JobExecErr::Cancelled)?
-        .context("fetching public config")?;
+        .await;
+    // Cancellation must not be wrapped in a context, or it would be reported
+    // as an error.
+    let mut public_cfg = match public_cfg {
+        None | Some(Err(JobExecErr::Cancelled)) => return Err(JobExecErr::Cancelled),
+        Some(res) => res.context("fetching public config")?,
+    };
 
     public_cfg.binds.get_or_insert_with(Vec::new);
+    public_cfg
+        .resolve_shared_binds(&cfg.cfg().shared_host_paths)
+        .map_err(JobExecErr::UnknownSharedData)?;
+    public_cfg
+        .check_volumes(&cfg.cfg().docker_config.allowed_volumes)
+        .map_err(JobExecErr::UnknownSharedData)?;
     tracing::info!("got test suite");
 
     send.send_msg(&ClientMsg::JobProgress(JobProgressMsg {
         job_id: job.id,
         stage: JobStage::Fetching,
+        progress: None,
+        results: None,
     }))
     .await?;
 
     // Clone the repo specified in job
     let job_path = cfg.job_folder(job.id);
-    let _ = fs::ensure_removed_dir(&job_path).await;
+    let retry = cfg.cfg().fs_retry.clone();
+    let _ = retry.run(|| fs::ensure_removed_dir(&job_path)).await;
 
     fs::net::git_clone(
         &job_path,
@@ -434,6 +984,7 @@ pub async fn handle_job(
             repo: job.repo,
             revision: job.revision,
             depth: 3,
+            credentials: job.credentials,
         },
     )
     .with_cancel(cancel.clone())
@@ -444,7 +995,7 @@ pub async fn handle_job(
 
     tracing::info!("fetched");
 
-    let job_path: PathBuf = fs::find_judge_root(&job_path)
+    let job_path: PathBuf = fs::find_judge_root(&job_path, &cfg.cfg().judge_root_search)
         .await
         .context("finding judger root")?;
     let mut judge_cfg = job_path.clone();
@@ -452,6 +1003,13 @@ pub async fn handle_job(
 
     tracing::info!("found job description file at {:?}", &judge_cfg);
 
+    let missing = fs::find_missing_required_files(&job_path, &public_cfg.required_files)
+        .await
+        .context("checking required files")?;
+    if !missing.is_empty() {
+        return Err(JobExecErr::MissingRequiredFiles(missing.join(", ")));
+    }
+
     let judge_cfg = tokio::fs::read(judge_cfg)
         .await
         .context("reading config file")?;
@@ -468,7 +1026,19 @@ pub async fn handle_job(
     let image = judge_job_cfg.image.clone();
 
     // Check job paths to be relative & does not navigate into parent
-    if let crate::tester::model::Image::Dockerfile { path, .. } = &image {
+    if let crate::tester::model::Image::Dockerfile {
+        path, build_args, ..
+    } = &image
+    {
+        let suspicious = crate::tester::model::check_build_args(build_args)
+            .map_err(JobExecErr::InvalidBuildArg)?;
+        for name in suspicious {
+            tracing::warn!(
+                "Build arg `{}` looks like a secret. Build args are visible in image history!",
+                name
+            );
+        }
+
         crate::util::path_security::assert_child_path(path)
             .context("testing if config references external path")?;
         // Note: There's no hard links in a git repository, and also we can't
@@ -485,9 +1055,21 @@ pub async fn handle_job(
     send.send_msg(&ClientMsg::JobProgress(JobProgressMsg {
         job_id: job.id,
         stage: JobStage::Running,
+        progress: Some(TestProgress {
+            finished: 0,
+            total: job.tests.len(),
+        }),
+        results: None,
     }))
     .await?;
 
+    if public_cfg.memory_limit.is_some() && !cfg.memory_limits_enforceable.load(Ordering::SeqCst) {
+        if cfg.cfg().docker_config.reject_unenforceable_memory_limits {
+            return Err(JobExecErr::UnenforceableMemoryLimit);
+        }
+        tracing::warn!("job {} has a memory limit that cannot be enforced", job.id);
+    }
+
     let suite_root_path = cfg.test_suite_folder(job.test_suite);
     let mut tests_path = suite_root_path.clone();
     tests_path.push(&public_cfg.mapped_dir.from);
@@ -496,12 +1078,27 @@ pub async fn handle_job(
         mapped_test_root_dir: public_cfg.mapped_dir.to.clone(),
     };
 
+    // Stable across runs of the same job, so that re-running it reproduces
+    // randomized tests.
+    let seed = job.seed.unwrap_or(job.id.0);
+    tracing::info!("job {} runs with seed {}", job.id, seed);
+
     let options = TestSuiteOptions {
         tests: job.tests.clone(),
-        time_limit: public_cfg.time_limit.map(|x| x as usize),
+        time_limit: public_cfg.time_limit,
         mem_limit: public_cfg.memory_limit.map(|x| x as usize),
         build_image: true,
         remove_image: true,
+        report_all: false,
+        shuffle_seed: None,
+        fail_fast: false,
+        seed: Some(seed),
+        record_output_len: {
+            let log_cfg = &cfg.cfg().job_log;
+            Some(log_cfg.output_len).filter(|_| log_cfg.enabled)
+        },
+        docker_config: cfg.cfg().docker_config.clone(),
+        output_budget: cfg.output_budget.clone(),
     };
 
     let mut suite = crate::tester::exec::TestSuite::from_config(
@@ -517,24 +1114,52 @@ pub async fn handle_job(
     .context("during TestSuite::from_config")?;
 
     tracing::info!("options created");
-    let (ch_send, ch_recv) = tokio::sync::mpsc::unbounded_channel();
+    let (ch_send, ch_recv) = tokio::sync::mpsc::unbounded_channel::<(String, TestResult)>();
 
     let recv_handle = tokio::spawn({
         let mut recv = ch_recv;
         let ws_send = send.clone();
+        let cfg = cfg.clone();
         let job_id = job.id;
+        let total = job.tests.len();
         async move {
+            let mut results = HashMap::new();
+            let mut finished = 0;
+            let mut last_progress = std::time::Instant::now();
             while let Some((key, res)) = recv.recv().await {
                 tracing::info!("Job {}: recv message for key={}", job_id, key);
-                // Omit error; it doesn't matter
+                let is_finished = !matches!(res.kind, TestResultKind::Running);
+                if is_finished {
+                    results.insert(key.clone(), res.clone());
+                }
+                let msg = ClientMsg::PartialResult(PartialResultMsg {
+                    job_id,
+                    test_id: key,
+                    test_result: res,
+                });
+                send_or_enqueue(msg, &cfg, &ws_send).await;
+
+                if !is_finished {
+                    continue;
+                }
+                finished += 1;
+                // Throttle progress messages, but always report the last one
+                if finished < total && last_progress.elapsed() < PROGRESS_REPORT_INTERVAL {
+                    continue;
+                }
+                last_progress = std::time::Instant::now();
+                let msg = ClientMsg::JobProgress(JobProgressMsg {
+                    job_id,
+                    stage: JobStage::Running,
+                    progress: Some(TestProgress { finished, total }),
+                    results: None,
+                });
+                // Progress is informational; don't wait for reconnection
                 let _ = ws_send
-                    .send_msg(&ClientMsg::PartialResult(PartialResultMsg {
-                        job_id,
-                        test_id: key,
-                        test_result: res,
-                    }))
+                    .send_conf(Message::text(serde_json::to_string(&msg).unwrap()), true)
                     .await;
             }
+            results
         }
     });
 
@@ -545,20 +1170,35 @@ pub async fn handle_job(
         let mut recv = build_ch_recv;
         let ws_send = send.clone();
         let job_id = job.id;
+        // The build log is kept until the job finishes
+        let mut reservation = cfg.output_budget.as_ref().map(|x| x.reserve());
         async move {
+            let mut build_log = JobBuildOutput::default();
+            let mut stamper = BuildLogStamper::new(job_id);
             while let Some(res) = recv.recv().await {
-                let _ = ws_send
-                    .send_msg(&ClientMsg::JobOutput(JobOutputMsg {
-                        job_id,
-                        stream: res.stream,
-                        error: res.error,
-                    }))
-                    .await;
+                build_log.push(res.stream.as_deref(), res.error.as_deref());
+                if let Some(reservation) = &mut reservation {
+                    // The log is bounded, so it is kept even if the budget
+                    // is exhausted.
+                    let size = build_log.output.len() + build_log.error.len();
+                    let more = size.saturating_sub(reservation.size());
+                    if more > 0 {
+                        let _ = reservation.grow(more, std::time::Duration::ZERO).await;
+                    }
+                }
+                for msg in stamper.stamp(res.stream, res.error) {
+                    let _ = ws_send.send_msg(&ClientMsg::JobOutput(msg)).await;
+                }
             }
+            (build_log, reservation)
         }
     });
 
-    let docker = bollard::Docker::connect_with_local_defaults().unwrap();
+    let docker_config = cfg.cfg().docker_config.clone();
+    let docker = docker_config
+        .connect()
+        .await
+        .context("connecting to Docker")?;
 
     tracing::info!("started.");
 
@@ -567,6 +1207,8 @@ pub async fn handle_job(
         endpoint: cfg.result_upload_endpoint(),
         access_token: cfg.cfg().access_token.clone(),
         job_id: job.id,
+        semaphore: cfg.upload_semaphore.clone(),
+        compression: cfg.upload_compression(),
     });
 
     let result = suite
@@ -580,12 +1222,36 @@ pub async fn handle_job(
         )
         .instrument(info_span!("run_job"))
         .await
-        .context("during TestSuite::run")?;
+        .context("during TestSuite::run");
 
     tracing::info!("finished running");
 
-    let _ = build_recv_handle.await;
-    let _ = recv_handle.await;
+    let (build_log, _build_log_reservation) = build_recv_handle.await.unwrap_or_default();
+    // All results are received here, since the sender is dropped with `suite.run`
+    *finished = recv_handle.await.unwrap_or_default();
+    if cfg.cfg().job_log.enabled {
+        log.record_suite(&suite, cfg.cfg().job_log.max_size);
+        log.build_log = Some(build_log.clone());
+    }
+
+    let result = match result.map_err(JobExecErr::from) {
+        Ok(result) => result,
+        // Attach the build log, so that the stored result is self-contained
+        Err(JobExecErr::Build(e)) if !matches!(e, BuildError::Cancelled) => {
+            tracing::warn!("job {} failed to build: {}", job.id, e);
+            return Ok(JobResultMsg {
+                job_id: job.id,
+                results: HashMap::new(),
+                job_result: JobResultKind::CompileError,
+                message: Some(e.to_string()),
+                build_log: Some(build_log),
+                network_attempts: None,
+                seed: Some(seed),
+                chunked: false,
+            });
+        }
+        Err(e) => return Err(e),
+    };
 
     tracing::info!("finished");
 
@@ -594,6 +1260,10 @@ pub async fn handle_job(
         results: result,
         job_result: JobResultKind::Accepted,
         message: None,
+        build_log: None,
+        network_attempts: suite.network_attempts.take(),
+        seed: Some(seed),
+        chunked: false,
     };
     Ok(job_result)
 }
@@ -606,9 +1276,37 @@ pub async fn flag_finished_job(client_config: Arc<SharedClientData>) {
     client_config.finish_job();
 }
 
-pub async fn accept_job(job: Job, send: Arc<WsSink>, client_config: Arc<SharedClientData>) {
+/// Start running a job. Returns whether the job is started, which is not the
+/// case if a job of the same id is running and ignored by
+/// [`DuplicateJobPolicy`].
+pub async fn accept_job(job: Job, send: Arc<WsSink>, client_config: Arc<SharedClientData>) -> bool {
     tracing::info!("Received job {}", job.id);
     let job_id = job.id;
+
+    let mut handles = client_config.running_job_handles.lock().await;
+    let replaced = if handles.contains_key(&job_id) {
+        let policy = client_config.cfg().duplicate_job_policy;
+        match policy {
+            DuplicateJobPolicy::Ignore => {
+                tracing::warn!("Job {} is already running, ignoring the duplicate", job_id);
+                return false;
+            }
+            DuplicateJobPolicy::Replace => {
+                tracing::warn!("Job {} is already running, replacing it", job_id);
+                let (handle, cancel) = handles.remove(&job_id).unwrap();
+                cancel.cancel(CancelReason::Replaced);
+                Some(handle)
+            }
+        }
+    } else {
+        None
+    };
+    drop(handles);
+
+    client_config.accepted_jobs.fetch_add(1, Ordering::SeqCst);
+    client_config
+        .running_job_revisions
+        .insert(job_id, (job.repo.clone(), job.revision.clone()));
     let cancel_handle = client_config.cancel_handle.child_token();
     let cancel_token = cancel_handle.child_token();
 
@@ -619,21 +1317,76 @@ pub async fn accept_job(job: Job, send: Arc<WsSink>, client_config: Arc<SharedCl
             // Hardcoded 30mins.
             // TODO: change this
             tokio::time::sleep(std::time::Duration::from_secs(30 * 60)).await;
-            cancel_token.cancel();
+            cancel_token.cancel(CancelReason::Timeout);
         }
     });
 
-    let handle = tokio::spawn(handle_job_wrapper(
-        job,
-        send,
-        cancel_token,
-        client_config.clone(),
-    ));
+    let handle = tokio::spawn({
+        let client_config = client_config.clone();
+        async move {
+            // The replaced job removes its folder when it stops, so it must
+            // stop before the new one starts.
+            if let Some(replaced) = replaced {
+                let _ = replaced.await;
+            }
+            handle_job_wrapper(job, send, cancel_token, client_config).await
+        }
+    });
     client_config
         .running_job_handles
         .lock()
         .await
         .insert(job_id, (handle, cancel_handle));
+    true
+}
+
+/// Tell the coordinator that this judger will not run the given job.
+async fn decline_job(job_id: FlowSnake, reason: JobDeclineReason, send: &WsSink) {
+    tracing::warn!("Declining job {}: {:?}", job_id, reason);
+    let _ = send
+        .send_msg(&ClientMsg::JobDeclined(JobDeclinedMsg { job_id, reason }))
+        .await
+        .inspect_err(|e| tracing::error!("Failed to decline job {}: {}", job_id, e));
+}
+
+/// Start cancelling a job in the background.
+async fn spawn_cancel_job(job: AbortJob, client_config: &Arc<SharedClientData>) {
+    let job_id = job.job_id;
+    let (inserted_send, inserted_recv) = futures::channel::oneshot::channel();
+    let abort = tokio::spawn(cancel_job(job, client_config.clone(), inserted_recv));
+    client_config
+        .cancelling_job_handles
+        .lock()
+        .await
+        .insert(job_id, abort);
+    let _ = inserted_send.send(());
+}
+
+/// Cancel every running job of a revision, and report them to the
+/// coordinator.
+async fn abort_revision(msg: AbortRevision, client_config: &Arc<SharedClientData>, send: &WsSink) {
+    let job_ids = client_config.jobs_of_revision(&msg.repo, &msg.revision);
+    tracing::info!(
+        "Aborting {} job(s) of {} at {}",
+        job_ids.len(),
+        msg.repo,
+        msg.revision
+    );
+    for &job_id in &job_ids {
+        let job = AbortJob {
+            job_id,
+            as_cancel: msg.as_cancel,
+        };
+        spawn_cancel_job(job, client_config).await;
+    }
+    let _ = send
+        .send_msg(&ClientMsg::RevisionAborted(RevisionAbortedMsg {
+            repo: msg.repo,
+            revision: msg.revision,
+            job_ids,
+        }))
+        .await
+        .inspect_err(|e| tracing::error!("Failed to report aborted jobs: {}", e));
 }
 
 async fn cancel_job(
@@ -642,7 +1395,11 @@ async fn cancel_job(
     inserted: futures::channel::oneshot::Receiver<()>,
 ) {
     let job_id = job.job_id;
-    client_config.cancelling_job_info.insert(job_id, job);
+    let reason = if job.as_cancel {
+        CancelReason::UserCancelled
+    } else {
+        CancelReason::ServerAbort
+    };
     let job = client_config
         .running_job_handles
         .lock()
@@ -650,7 +1407,7 @@ async fn cancel_job(
         .remove(&job_id);
 
     if let Some((handle, cancel)) = job {
-        cancel.cancel();
+        cancel.cancel(reason);
         match handle.await {
             Ok(_) => tracing::info!("Cancelled job {}", job_id),
             Err(e) => tracing::warn!("Unable to cancel job {}: {}", job_id, e),
@@ -667,36 +1424,46 @@ async fn cancel_job(
             .await
             .remove(&job_id);
     }
-    client_config.cancelling_job_info.remove(&job_id);
 }
 
+/// Ping the coordinator every [`ClientConfig::keepalive_interval`] seconds.
+/// The interval is read before every ping, so that config updates take effect.
 async fn keepalive(
     client_config: Arc<SharedClientData>,
     keepalive_token: CancellationTokenHandle,
     ws: Arc<WsSink>,
-    interval: std::time::Duration,
 ) {
-    while tokio::time::sleep(interval)
-        .with_cancel(client_config.cancel_handle.child_token())
-        .await
-        .is_some()
-    {
+    loop {
+        let interval = std::time::Duration::from_secs(client_config.cfg().keepalive_interval);
+        if tokio::time::sleep(interval)
+            .with_cancel(client_config.cancel_handle.child_token())
+            .await
+            .is_none()
+        {
+            break;
+        }
         if let Err(e) = ws
-            .send_conf(tokio_tungstenite::tungstenite::Message::Ping(vec![]), true)
+            .send_control(tokio_tungstenite::tungstenite::Message::Ping(vec![]), true)
             .await
         {
-            keepalive_token.cancel();
+            keepalive_token.cancel(None);
             tracing::error!("Server disconnected: {}", e);
             break;
         };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs());
+        client_config.last_keepalive.store(now, Ordering::SeqCst);
     }
 }
 
+/// Poll jobs from the coordinator every [`ClientConfig::poll_interval`]
+/// seconds. The interval is read before every poll, so that config updates
+/// take effect.
 async fn poll_jobs(
     client_config: Arc<SharedClientData>,
     keepalive_token: CancellationTokenHandle,
     ws: Arc<WsSink>,
-    poll_interval: std::time::Duration,
     retry_interval: std::time::Duration,
     poll_timeout: std::time::Duration,
 ) {
@@ -712,28 +1479,48 @@ async fn poll_jobs(
             }
         }
 
+        let remaining_jobs = client_config.remaining_jobs();
+        if remaining_jobs == Some(0) {
+            tracing::info!("Job limit reached, no more jobs will be requested");
+            break 'outer;
+        }
+
         let message_id = FlowSnake::generate();
 
         client_config
             .waiting_for_jobs
             .store(Some(Arc::new(message_id)));
 
-        let active_task_count = client_config.running_tests.load(Ordering::SeqCst) as u32;
-        let request_for_new_task =
-            client_config.cfg().max_concurrent_tasks as u32 - active_task_count;
+        let active_task_count = client_config.running_tests.load(Ordering::SeqCst);
+        let mut request_for_new_task = client_config.cfg().poll_request_size(active_task_count);
+        if let Some(remaining) = remaining_jobs {
+            request_for_new_task = request_for_new_task.min(remaining);
+        }
+        if client_config.output_memory_near_limit() {
+            tracing::warn!("Buffered output near its memory limit, not asking for new jobs");
+            request_for_new_task = 0;
+        }
+        let active_task_count = active_task_count as u32;
+        let request_for_new_task = request_for_new_task as u32;
+        let buffered_output_bytes = client_config
+            .output_budget
+            .as_ref()
+            .map(|x| x.used() as u64);
 
         tracing::debug!(
-            "Polling jobs from server. Asking for {} new jobs.",
-            request_for_new_task
+            "Polling jobs from server. Asking for {} new jobs with {} running.",
+            request_for_new_task,
+            active_task_count
         );
 
         let msg = ClientMsg::JobRequest(JobRequestMsg {
             active_task_count,
             request_for_new_task,
             message_id: Some(message_id),
+            buffered_output_bytes,
         });
         if let Some(Ok(_)) = ws
-            .send_msg(&msg)
+            .send_control_msg(&msg)
             .with_cancel(keepalive_token.child_token())
             .await
         {
@@ -758,6 +1545,7 @@ async fn poll_jobs(
             }
         });
 
+        let poll_interval = std::time::Duration::from_secs(client_config.cfg().poll_interval);
         if tokio::time::sleep(poll_interval)
             .with_cancel(keepalive_token.child_token())
             .await
@@ -784,14 +1572,12 @@ pub async fn client_loop(
         client_config.clone(),
         keepalive_token,
         ws_send.clone(),
-        std::time::Duration::from_secs(20),
     ));
 
     let poll_jobs_handle = tokio::spawn(poll_jobs(
         client_config.clone(),
         keepalive_cancel.child_token(),
         ws_send.clone(),
-        std::time::Duration::from_secs(10),
         std::time::Duration::from_secs(1),
         std::time::Duration::from_secs(60),
     ));
@@ -822,27 +1608,86 @@ pub async fn client_loop(
                                 }
                             };
 
-                            if proceed {
-                                for job in msg.jobs {
-                                    accept_job(job, ws_send.clone(), client_config.clone()).await
+                            let mut capacity =
+                                client_config.cfg().max_concurrent_tasks.saturating_sub(
+                                    client_config.running_job_handles.lock().await.len(),
+                                );
+                            for job in msg.jobs {
+                                let decline_reason = if !proceed {
+                                    Some(JobDeclineReason::StalePoll)
+                                } else if client_config.cancel_handle.is_cancelled()
+                                    || client_config.remaining_jobs() == Some(0)
+                                {
+                                    Some(JobDeclineReason::Draining)
+                                } else if capacity == 0 {
+                                    Some(JobDeclineReason::CapacityExceeded)
+                                } else if !client_config
+                                    .cfg()
+                                    .missing_tags(job.required_tags.as_deref().unwrap_or_default())
+                                    .is_empty()
+                                {
+                                    Some(JobDeclineReason::MissingTags)
+                                } else {
+                                    None
+                                };
+
+                                match decline_reason {
+                                    Some(reason) => decline_job(job.id, reason, &ws_send).await,
+                                    None => {
+                                        if accept_job(job, ws_send.clone(), client_config.clone())
+                                            .await
+                                        {
+                                            capacity -= 1;
+                                        }
+                                    }
                                 }
                             }
                         }
                         ServerMsg::AbortJob(job) => {
-                            let job_id = job.job_id;
-                            let (inserted_send, inserted_recv) =
-                                futures::channel::oneshot::channel();
-                            let abort =
-                                tokio::spawn(cancel_job(job, client_config.clone(), inserted_recv));
-                            client_config
-                                .cancelling_job_handles
-                                .lock()
-                                .await
-                                .insert(job_id, abort);
-                            let _ = inserted_send.send(());
+                            spawn_cancel_job(job, &client_config).await;
                         }
-                        ServerMsg::ServerHello => {
+                        ServerMsg::AbortRevision(msg) => {
+                            abort_revision(msg, &client_config, &ws_send).await;
+                        }
+                        ServerMsg::ServerHello(msg) => {
                             tracing::info!("Hi, server o/");
+                            client_config
+                                .accepted_encodings
+                                .store(Arc::new(msg.accept_encodings));
+                            if let Some(compression) = client_config.cfg().upload_compression {
+                                match client_config.upload_compression() {
+                                    Some(_) => tracing::info!(
+                                        "Uploading results compressed with {}",
+                                        compression.content_encoding()
+                                    ),
+                                    None => tracing::warn!(
+                                        "{} compression of results is not accepted by the coordinator or not built in, uploading them uncompressed",
+                                        compression.content_encoding()
+                                    ),
+                                }
+                            }
+                        }
+                        ServerMsg::RequestJobLogs(msg) => {
+                            let client_config = client_config.clone();
+                            let ws_send = ws_send.clone();
+                            tokio::spawn(async move {
+                                upload_job_logs(msg.job_id, &client_config, &ws_send).await
+                            });
+                        }
+                        ServerMsg::UpdateConfig(msg) => {
+                            match client_config.cfg().with_update(&msg.config) {
+                                Ok(cfg) => {
+                                    client_config.swap_cfg(Arc::new(cfg));
+                                    tracing::info!(
+                                        "Config updated by coordinator: {:?}",
+                                        msg.config.keys().collect::<Vec<_>>()
+                                    );
+                                    if msg.config.contains_key("log_filter") {
+                                        client_config.apply_log_filter();
+                                    }
+                                }
+                                Err(e) => tracing::warn!("Invalid config update: {}", e),
+                            }
                         }
                     }
                 }
@@ -860,3 +1705,323 @@ pub async fn client_loop(
     tracing::warn!("Disconnected!");
     ws_send
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::config::CoordinatorHosts;
+
+    #[tokio::test]
+    async fn test_coordinator_failover() {
+        // Nothing listens on the port of a dropped listener
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap().to_string();
+        drop(refused);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+
+        let cfg = SharedClientData::new(ClientConfig {
+            host: CoordinatorHosts::Many(vec![refused_addr.clone(), addr.clone()]),
+            connect_timeout: 5,
+            ..Default::default()
+        });
+        let res = connect_to_coordinator(&cfg).await;
+        assert!(res.is_ok(), "{}", res.err().unwrap());
+        let _ws = server.await.unwrap();
+
+        // The coordinator connected to stays in use
+        assert_eq!(cfg.host(), addr);
+        assert!(cfg.register_endpoint().contains(&addr));
+        assert!(cfg.verify_endpoint().contains(&addr));
+
+        // Every coordinator is tried once, then the first one is used again
+        let cfg = SharedClientData::new(ClientConfig {
+            host: CoordinatorHosts::Many(vec![refused_addr.clone(), refused_addr.clone()]),
+            ..Default::default()
+        });
+        assert!(connect_to_coordinator(&cfg).await.is_err());
+        assert_eq!(cfg.host_index.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_registration_failover() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap().to_string();
+        drop(refused);
+
+        // Answers the verification and the registration
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                socket
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\ntoken",
+                    )
+                    .await
+                    .unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+            }
+            requests
+        });
+
+        let cfg = || ClientConfig {
+            host: CoordinatorHosts::Many(vec![refused_addr.clone(), addr.clone()]),
+            access_token: Some("token".into()),
+            register_token: Some("register".into()),
+            ..Default::default()
+        };
+        let data = SharedClientData::new(cfg());
+        assert_eq!(verify_self(&data).await, TokenVerification::Valid);
+        assert_eq!(data.host(), addr);
+        let data = SharedClientData::new(cfg());
+        assert!(try_register(&data, true).await.unwrap());
+        assert_eq!(data.host(), addr);
+
+        let requests = server.await.unwrap();
+        assert!(
+            requests[0].starts_with("GET /api/v1/judger/verify"),
+            "{:?}",
+            requests
+        );
+        assert!(
+            requests[1].starts_with("POST /api/v1/judger/register"),
+            "{:?}",
+            requests
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replaced_job_reports_replacement_only() {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Request, Response,
+        };
+        use std::convert::Infallible;
+
+        // The first fetch of the test suite hangs until its job is replaced,
+        // later ones fail at once. Results are posted to the same server.
+        let fetches = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let posted = Arc::new(std::sync::Mutex::new(vec![]));
+        let make_svc = make_service_fn({
+            let (fetches, posted) = (fetches.clone(), posted.clone());
+            move |_| {
+                let (fetches, posted) = (fetches.clone(), posted.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let (fetches, posted) = (fetches.clone(), posted.clone());
+                        async move {
+                            if req.method() == Method::POST {
+                                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                                let msg = serde_json::from_slice::<ClientMsg>(&body).unwrap();
+                                posted.lock().unwrap().push(msg);
+                            } else if fetches.fetch_add(1, Ordering::SeqCst) == 0 {
+                                futures::future::pending::<()>().await;
+                            } else {
+                                return Ok::<_, Infallible>(
+                                    Response::builder().status(404).body(Body::empty()).unwrap(),
+                                );
+                            }
+                            Ok(Response::new(Body::empty()))
+                        }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr().to_string();
+        tokio::spawn(server);
+
+        let root = std::env::temp_dir().join(format!("rurikawa-replace-{}", rand::random::<u32>()));
+        let cfg = Arc::new(SharedClientData::new(ClientConfig {
+            host: addr.as_str().into(),
+            cache_folder: root.clone(),
+            duplicate_job_policy: DuplicateJobPolicy::Replace,
+            ..Default::default()
+        }));
+        let send = Arc::new(WsSink::discarding());
+        let job = Job {
+            id: FlowSnake::generate(),
+            repo: "https://example.com/repo.git".into(),
+            revision: "main".into(),
+            test_suite: FlowSnake::generate(),
+            tests: vec![],
+            stage: JobStage::Dispatched,
+            results: HashMap::new(),
+            seed: None,
+            required_tags: None,
+            credentials: None,
+        };
+
+        assert!(accept_job(job.clone(), send.clone(), cfg.clone()).await);
+        while fetches.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Replacing doesn't wait for the old job to stop
+        let accepted = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            accept_job(job.clone(), send, cfg.clone()),
+        )
+        .await;
+
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+        while posted.lock().unwrap().is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Give the replaced job the chance to report anything it would
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let posted = posted.lock().unwrap().clone();
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(accepted, Ok(true));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        // Only the replacement reports, with a terminal result of its own
+        assert_eq!(posted.len(), 1, "{:?}", posted);
+        assert!(
+            matches!(&posted[0], ClientMsg::JobResult(x) if x.job_id == job.id),
+            "{:?}",
+            posted
+        );
+    }
+
+    #[tokio::test]
+    async fn test_job_logs_reply_not_queued() {
+        let root = std::env::temp_dir().join(format!("rurikawa-logs-{}", rand::random::<u32>()));
+        let cfg = SharedClientData::new(ClientConfig {
+            cache_folder: root.clone(),
+            ..Default::default()
+        });
+        let job_id = FlowSnake::generate();
+        // The job has no log on disk, so the reply carries an error
+        upload_job_logs(job_id, &cfg, &WsSink::discarding()).await;
+        let queued = cfg.outbox.len().await;
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert_eq!(queued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_download_error_does_not_quarantine_suite() {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Request, Response,
+        };
+        use std::convert::Infallible;
+
+        let suite = TestSuite {
+            id: FlowSnake::generate(),
+            name: "suite".into(),
+            title: "".into(),
+            description: "".into(),
+            tags: None,
+            package_file_id: "pkg".into(),
+            patch: None,
+            applied_patch_ids: vec![],
+        };
+        // The suite info is served, but downloading its package fails
+        let info = serde_json::to_vec(&suite).unwrap();
+        let make_svc = make_service_fn(move |_| {
+            let info = info.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let res = if req.uri().path().starts_with("/api/v1/tests/") {
+                        Response::new(Body::from(info.clone()))
+                    } else {
+                        Response::builder().status(503).body(Body::empty()).unwrap()
+                    };
+                    async move { Ok::<_, Infallible>(res) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr().to_string();
+        tokio::spawn(server);
+
+        let root =
+            std::env::temp_dir().join(format!("rurikawa-quarantine-{}", rand::random::<u32>()));
+        let cfg = SharedClientData::new(ClientConfig {
+            host: addr.as_str().into(),
+            access_token: Some("token".into()),
+            cache_folder: root.clone(),
+            suite_quarantine: Some(config::SuiteQuarantine {
+                failures: 1,
+                cooldown_secs: 600,
+            }),
+            ..Default::default()
+        });
+        let res =
+            check_download_read_test_suite(suite.id, &cfg, CancellationTokenHandle::new()).await;
+        let quarantined = cfg.suite_quarantine_left(suite.id, "pkg");
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(res.is_err());
+        assert_eq!(quarantined, None);
+        // A broken package does count
+        assert!(is_package_failure(&JobExecErr::Any(
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "7zip failed").into()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_access_token_persisted() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 9\r\nconnection: close\r\n\r\nnew-token",
+                )
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+
+        let root = std::env::temp_dir().join(format!("rurikawa-token-{}", rand::random::<u32>()));
+        let cfg = || ClientConfig {
+            host: addr.as_str().into(),
+            register_token: Some("register".into()),
+            cache_folder: root.clone(),
+            ..Default::default()
+        };
+        let data = SharedClientData::new(cfg());
+        let registered = try_register(&data, false).await;
+        let request = server.await.unwrap();
+        // A restarted judger picks the token up
+        let restarted = SharedClientData::new(cfg()).cfg().access_token.clone();
+        let metadata = std::fs::metadata(root.join(config::ACCESS_TOKEN_FILE_NAME));
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(registered.unwrap());
+        assert!(
+            request.starts_with("POST /api/v1/judger/register"),
+            "{}",
+            request
+        );
+        assert_eq!(restarted.as_deref(), Some("new-token"));
+        // Without a cache folder, nothing is read from the working directory
+        assert_eq!(config::read_saved_access_token(Path::new("")), None);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata.unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        #[cfg(not(unix))]
+        assert!(metadata.is_ok());
+    }
+}
@@ -1,10 +1,12 @@
 use crate::{
+    fs::net::GitCredentials,
     prelude::FlowSnake,
     tester::{ExecErrorKind, JobFailure, ProcessInfo},
+    util::compress::UploadCompression,
 };
-use respector::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Semaphore;
 
 /// Message sent from server. See documentation on the server side.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,8 +18,32 @@ pub enum ServerMsg {
     MultiNewJob(MultiNewJob),
     #[serde(rename = "abort_job")]
     AbortJob(AbortJob),
+    #[serde(rename = "abort_revision")]
+    AbortRevision(AbortRevision),
     #[serde(rename = "server_hello")]
-    ServerHello,
+    ServerHello(ServerHelloMsg),
+    #[serde(rename = "update_config")]
+    UpdateConfig(UpdateConfigMsg),
+    #[serde(rename = "request_job_logs")]
+    RequestJobLogs(RequestJobLogs),
+}
+
+/// Greeting of the coordinator, sent after connecting.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerHelloMsg {
+    /// `Content-Encoding`s accepted for result uploads. Coordinators not
+    /// sending it only accept uncompressed uploads.
+    #[serde(default)]
+    pub accept_encodings: Vec<String>,
+}
+
+/// Fields of the client config to be changed at runtime, keyed by their names
+/// in the config file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateConfigMsg {
+    pub config: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +60,24 @@ pub struct AbortJob {
     pub as_cancel: bool,
 }
 
+/// Abort every running job of a revision of a repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AbortRevision {
+    pub repo: String,
+    pub revision: String,
+    pub as_cancel: bool,
+}
+
+/// Upload the log of a finished job, for debugging it after the fact. Logs
+/// are only available for jobs whose folder is retained with job logs
+/// enabled. Answered with a [`JobLogsMsg`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestJobLogs {
+    pub job_id: FlowSnake,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Job {
@@ -44,6 +88,17 @@ pub struct Job {
     pub tests: Vec<String>,
     pub stage: JobStage,
     pub results: HashMap<String, TestResult>,
+    /// Seed for randomized tests. Derived from the job id if not set.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Tags the judger must have to run this job, i.e. the tags of its test
+    /// suite.
+    #[serde(default)]
+    pub required_tags: Option<Vec<String>>,
+    /// Credentials for cloning the repository if it's private. Never
+    /// serialized, so that they don't end up in logs or on disk.
+    #[serde(default, skip_serializing)]
+    pub credentials: Option<GitCredentials>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,6 +110,25 @@ pub struct TestSuite {
     pub description: String,
     pub tags: Option<Vec<String>>,
     pub package_file_id: String,
+    /// A patch turning an earlier package of this test suite into this one,
+    /// if the coordinator has one. Only used with
+    /// [`ClientConfig::patch_test_suites`](super::config::ClientConfig).
+    #[serde(default)]
+    pub patch: Option<SuitePatch>,
+    /// Ids of the patches applied on this judger since the last full download
+    /// of the suite. Only kept in the lockfile of the suite.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub applied_patch_ids: Vec<String>,
+}
+
+/// A patch of a test suite package, holding the files changed since the
+/// package `base_package_file_id`. Paths deleted since then are listed in
+/// [`PATCH_DELETED_LIST`](crate::fs::PATCH_DELETED_LIST).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SuitePatch {
+    pub base_package_file_id: String,
+    pub patch_file_id: String,
 }
 
 /// Message sent from client
@@ -76,6 +150,23 @@ pub enum ClientMsg {
     #[serde(rename = "job_result")]
     JobResult(JobResultMsg),
 
+    /// Part of the test results of a job, sent before a chunked
+    /// [`JobResultMsg`]
+    #[serde(rename = "job_result_chunk")]
+    JobResultChunk(JobResultChunkMsg),
+
+    /// Tells the coordinator that a dispatched job will not be run
+    #[serde(rename = "job_declined")]
+    JobDeclined(JobDeclinedMsg),
+
+    /// Reports the jobs aborted by an [`AbortRevision`]
+    #[serde(rename = "revision_aborted")]
+    RevisionAborted(RevisionAbortedMsg),
+
+    /// Answers a [`RequestJobLogs`]
+    #[serde(rename = "job_logs")]
+    JobLogs(JobLogsMsg),
+
     // Obsolete
     // #[serde(rename = "client_status")]
     // ClientStatus(ClientStatusMsg),
@@ -85,7 +176,25 @@ pub enum ClientMsg {
     JobRequest(JobRequestMsg),
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+impl ClientMsg {
+    /// The job this message is about, if any.
+    pub fn job_id(&self) -> Option<FlowSnake> {
+        match self {
+            ClientMsg::JobProgress(x) => Some(x.job_id),
+            ClientMsg::PartialResult(x) => Some(x.job_id),
+            ClientMsg::JobOutput(x) => Some(x.job_id),
+            ClientMsg::JobResult(x) => Some(x.job_id),
+            ClientMsg::JobResultChunk(x) => Some(x.job_id),
+            ClientMsg::JobDeclined(x) => Some(x.job_id),
+            ClientMsg::JobLogs(x) => Some(x.job_id),
+            ClientMsg::ReceiveJob(_) | ClientMsg::JobRequest(_) | ClientMsg::RevisionAborted(_) => {
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TestResultKind {
     Accepted = 0,
     WrongAnswer = 1,
@@ -94,9 +203,14 @@ pub enum TestResultKind {
     TimeLimitExceeded = 4,
     MemoryLimitExceeded = 5,
     ShouldFail = 6,
+    PresentationError = 7,
+    /// The scorer of the suite gave a score between 0 and 1.
+    PartialScore = 8,
     NotRan = -1,
     Waiting = -2,
     Running = -3,
+    /// Not run because an earlier test failed in fail-fast mode.
+    Skipped = -4,
     OtherError = -100,
 }
 
@@ -113,7 +227,7 @@ pub enum JobStage {
     Aborted,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum JobResultKind {
     Accepted,
     CompileError,
@@ -129,8 +243,18 @@ pub struct ResultUploadConfig {
     pub endpoint: String,
     pub access_token: Option<String>,
     pub job_id: FlowSnake,
+    /// Limits the number of concurrent uploads across all jobs.
+    pub semaphore: Arc<Semaphore>,
+    /// Compression of uploaded results, if accepted by the coordinator.
+    pub compression: Option<UploadCompression>,
 }
 
+/// Times to retry a failed result upload before giving up.
+const UPLOAD_RETRY_COUNT: u32 = 3;
+
+/// Initial delay between result upload retries. Doubles after each retry.
+const UPLOAD_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub type Score = Option<f64>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -139,6 +263,45 @@ pub struct TestResult {
     pub kind: TestResultKind,
     pub score: Score,
     pub result_file_id: Option<String>,
+    /// The output of this test failed to be uploaded, so `result_file_id` is
+    /// missing even though there should be one.
+    #[serde(default)]
+    pub upload_failed: bool,
+    /// Resources used by the commands of this test, if they have run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResourceUsage>,
+}
+
+/// Resources used by the commands of a test.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceUsage {
+    /// Total time spent running the commands, in milliseconds.
+    pub wall_time_ms: u64,
+    /// Total CPU time of the commands in milliseconds, if measured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_time_ms: Option<u64>,
+    /// The highest peak memory usage among the commands in bytes, if measured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_memory: Option<u64>,
+}
+
+impl ResourceUsage {
+    /// Resources used by the commands with `output`, `None` if none of them
+    /// is timed.
+    pub fn of(output: &[ProcessInfo]) -> Option<ResourceUsage> {
+        if output.iter().all(|x| x.wall_time_ms.is_none()) {
+            return None;
+        }
+        Some(ResourceUsage {
+            wall_time_ms: output.iter().filter_map(|x| x.wall_time_ms).sum(),
+            cpu_time_ms: output
+                .iter()
+                .filter_map(|x| x.cpu_time_ms)
+                .reduce(|a, b| a + b),
+            peak_memory: output.iter().filter_map(|x| x.peak_memory).max(),
+        })
+    }
 }
 
 /// Represents the resulting score of a single test
@@ -170,10 +333,13 @@ impl TestResult {
                     kind: TestResultKind::Accepted,
                     score: s.to_score().map(|x| x * base_score),
                     result_file_id: None,
+                    upload_failed: false,
+                    usage: None,
                 },
                 None,
             ),
             Err(e) => {
+                let mut score = None;
                 let (kind, cache) = match e {
                     JobFailure::OutputMismatch(m) => (
                         TestResultKind::WrongAnswer,
@@ -184,6 +350,15 @@ impl TestResult {
                         }),
                     ),
 
+                    JobFailure::PresentationError(m) => (
+                        TestResultKind::PresentationError,
+                        Some(FailedJobOutputCacheFile {
+                            output: m.output,
+                            stdout_diff: Some(m.diff),
+                            message: None,
+                        }),
+                    ),
+
                     JobFailure::ExecError(e) => {
                         let (res, msg) = match e.kind {
                             ExecErrorKind::RuntimeError(e) => {
@@ -194,6 +369,10 @@ impl TestResult {
                                 Some("Some command's return code is not 0".into()),
                             ),
                             ExecErrorKind::TimedOut => (TestResultKind::TimeLimitExceeded, None),
+                            ExecErrorKind::MappedExitCode { code, verdict } => (
+                                verdict,
+                                Some(format!("Some command's return code is {}", code)),
+                            ),
                         };
                         (
                             res,
@@ -219,9 +398,9 @@ impl TestResult {
                         Some(FailedJobOutputCacheFile {
                             output: out.output,
                             stdout_diff: None,
-                            message: Some(
-                                "One of the commands should return a non-zero value".into(),
-                            ),
+                            message: Some(out.reason.unwrap_or_else(|| {
+                                "One of the commands should return a non-zero value".into()
+                            })),
                         }),
                     ),
 
@@ -234,13 +413,28 @@ impl TestResult {
                             message: out.reason,
                         }),
                     ),
+                    JobFailure::ScorerRejected(out) => {
+                        if out.score > 0.0 {
+                            score = Some(out.score * base_score);
+                        }
+                        (
+                            out.kind(),
+                            Some(FailedJobOutputCacheFile {
+                                output: out.output,
+                                stdout_diff: None,
+                                message: out.reason,
+                            }),
+                        )
+                    }
                 };
 
                 (
                     TestResult {
                         kind,
-                        score: None,
+                        score,
                         result_file_id: None,
+                        upload_failed: false,
+                        usage: None,
                     },
                     cache,
                 )
@@ -249,31 +443,113 @@ impl TestResult {
     }
 }
 
+/// Upload the output of a failed test, returning the id of the uploaded file.
+///
+/// Failed uploads are retried with exponential backoff.
 pub async fn upload_test_result(
-    f: FailedJobOutputCacheFile,
+    f: impl Serialize + Sync,
     upload_info: Arc<ResultUploadConfig>,
     test_id: &str,
-) -> Option<String> {
+) -> reqwest::Result<String> {
+    // Permits are held while compressing and uploading, but not while
+    // backing off, so that failing uploads don't hold back the others
+    let semaphore = &upload_info.semaphore;
+    let acquire = move || async move {
+        semaphore
+            .acquire()
+            .await
+            .expect("Upload semaphore should never be closed")
+    };
+
+    // Compressed once for every retry
+    let compressed = match upload_info.compression {
+        Some(compression) => {
+            let _permit = acquire().await;
+            compress_upload(&f, compression, test_id).await
+        }
+        None => None,
+    };
+
+    let mut delay = UPLOAD_RETRY_DELAY;
+    let mut retries = 0;
+    loop {
+        let res = {
+            let _permit = acquire().await;
+            try_upload_test_result(&f, compressed.as_ref(), &upload_info, test_id).await
+        };
+        match res {
+            Ok(id) => return Ok(id),
+            Err(e) if retries < UPLOAD_RETRY_COUNT => {
+                log::warn!(
+                    "Failed to upload result of {} (retrying in {:?}):\n{:?}",
+                    test_id,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                retries += 1;
+            }
+            Err(e) => {
+                log::error!("Failed to upload result of {}:\n{:?}", test_id, e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Serialize `f` as JSON compressed with `compression`, or `None` if that
+/// fails, in which case it is uploaded uncompressed.
+async fn compress_upload(
+    f: &(impl Serialize + Sync),
+    compression: UploadCompression,
+    test_id: &str,
+) -> Option<(UploadCompression, bytes::Bytes)> {
+    // Compressing large outputs takes a while, so it's kept off the runtime
+    let res = match serde_json::to_vec(f) {
+        Ok(json) => tokio::task::spawn_blocking(move || compression.compress(&json))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e))),
+        Err(e) => Err(e.into()),
+    };
+    match res {
+        Ok(data) => Some((compression, data.into())),
+        Err(e) => {
+            log::warn!(
+                "Failed to compress result of {}, uploading it uncompressed: {}",
+                test_id,
+                e
+            );
+            None
+        }
+    }
+}
+
+async fn try_upload_test_result(
+    f: &(impl Serialize + Sync),
+    compressed: Option<&(UploadCompression, bytes::Bytes)>,
+    upload_info: &ResultUploadConfig,
+    test_id: &str,
+) -> reqwest::Result<String> {
     let mut post = upload_info.client.post(&upload_info.endpoint);
     if let Some(hdr) = upload_info.access_token.as_ref() {
         post = post.header("authorization", hdr);
     }
-    let post = post
-        .query(&[
-            ("jobId", upload_info.job_id.to_string().as_str()),
-            ("testId", test_id),
-        ])
-        .json(&f)
-        .send()
-        .await;
-    let resp = post
-        .and_then(|x| x.error_for_status())
-        .inspect_err(|e| log::warn!("Failed to upload:\n{:?}", e))
-        .ok()?;
-    resp.text()
-        .await
-        .inspect_err(|e| log::warn!("Failed to upload:\n{:?}", e))
-        .ok()
+    post = post.query(&[
+        ("jobId", upload_info.job_id.to_string().as_str()),
+        ("testId", test_id),
+    ]);
+    post = match compressed {
+        Some((compression, data)) => post
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(
+                http::header::CONTENT_ENCODING,
+                compression.content_encoding(),
+            )
+            .body(data.clone()),
+        None => post.json(f),
+    };
+    post.send().await?.error_for_status()?.text().await
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -282,11 +558,66 @@ pub struct ReceiveJobMsg {
     pub job_id: FlowSnake,
 }
 
+/// The reason why a judger declines a job.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub enum JobDeclineReason {
+    /// The judger is already running as many jobs as it can.
+    CapacityExceeded,
+    /// The job replies to a job request that is no longer active.
+    StalePoll,
+    /// The judger is shutting down.
+    Draining,
+    /// The judger lacks some tags required by the job.
+    MissingTags,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobDeclinedMsg {
+    pub job_id: FlowSnake,
+    pub reason: JobDeclineReason,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct JobProgressMsg {
     pub job_id: FlowSnake,
     pub stage: JobStage,
+    /// Progress through the test list, reported while running tests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<TestProgress>,
+    /// Results of the tests finished before the job is cancelled or aborted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub results: Option<HashMap<String, TestResult>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TestProgress {
+    /// Number of tests finished
+    pub finished: usize,
+    /// Number of tests in this job
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionAbortedMsg {
+    pub repo: String,
+    pub revision: String,
+    /// Running jobs of the revision that are aborted.
+    pub job_ids: Vec<FlowSnake>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobLogsMsg {
+    pub job_id: FlowSnake,
+    /// Id of the uploaded log, uploaded as the result file of the pseudo test
+    /// [`JOB_LOG_TEST_ID`](super::job_log::JOB_LOG_TEST_ID).
+    pub result_file_id: Option<String>,
+    /// Why the log is not uploaded, if it isn't.
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -303,6 +634,76 @@ pub struct JobOutputMsg {
     pub job_id: FlowSnake,
     pub stream: Option<String>,
     pub error: Option<String>,
+    /// Position of this message in the build output of the job, starting
+    /// from 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Milliseconds since the build started, measured with a monotonic
+    /// clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u64>,
+    /// The Dockerfile step starting with this message, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<BuildStage>,
+}
+
+/// A step of a Dockerfile build, as in `Step 2/5 : RUN make`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildStage {
+    /// Number of the step, starting from 1.
+    pub step: u32,
+    /// Number of steps in the build.
+    pub total: u32,
+    /// The instruction run in this step.
+    pub instruction: String,
+}
+
+/// Maximum length of the `stream` of a single [`JobOutputMsg`], in bytes.
+/// Longer output is split into multiple messages, so that no single message
+/// occupies the websocket for long.
+pub const MAX_JOB_OUTPUT_CHUNK_LEN: usize = 16 * 1024;
+
+impl JobOutputMsg {
+    /// Split the output into messages with at most
+    /// [`MAX_JOB_OUTPUT_CHUNK_LEN`] bytes of `stream` each. The error is
+    /// attached to the last message.
+    pub fn chunked(
+        job_id: FlowSnake,
+        stream: Option<String>,
+        error: Option<String>,
+    ) -> Vec<JobOutputMsg> {
+        let mut res = vec![];
+        let mut rest = stream.as_deref().unwrap_or("");
+        while rest.len() > MAX_JOB_OUTPUT_CHUNK_LEN {
+            let mut end = MAX_JOB_OUTPUT_CHUNK_LEN;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            res.push(JobOutputMsg {
+                job_id,
+                stream: Some(rest[..end].to_owned()),
+                error: None,
+                seq: None,
+                elapsed_ms: None,
+                stage: None,
+            });
+            rest = &rest[end..];
+        }
+        res.push(JobOutputMsg {
+            job_id,
+            stream: if res.is_empty() {
+                stream
+            } else {
+                Some(rest.to_owned())
+            },
+            error,
+            seq: None,
+            elapsed_ms: None,
+            stage: None,
+        });
+        res
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -312,6 +713,95 @@ pub struct JobResultMsg {
     pub job_result: JobResultKind,
     pub results: HashMap<String, TestResult>,
     pub message: Option<String>,
+    /// The build log, attached when the job fails to build.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_log: Option<JobBuildOutput>,
+    /// Network access attempts recorded while running, if the network was
+    /// audited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_attempts: Option<Vec<String>>,
+    /// The seed tests were run with, for reproducing randomized tests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Whether the results were split into [`JobResultChunkMsg`]s sent
+    /// before this message, in which case `results` only holds the last
+    /// chunk and should be merged with the earlier ones.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub chunked: bool,
+}
+
+impl JobResultMsg {
+    /// Split this message into chunks of at most `size` test results,
+    /// followed by the final message, if it holds more than `size` results.
+    pub fn into_chunks(mut self, size: usize) -> Vec<ClientMsg> {
+        let size = size.max(1);
+        if self.results.len() <= size {
+            return vec![ClientMsg::JobResult(self)];
+        }
+
+        let job_id = self.job_id;
+        let mut results = std::mem::take(&mut self.results)
+            .into_iter()
+            .collect::<Vec<_>>();
+        results.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let last = results.split_off((results.len() - 1) / size * size);
+        let mut msgs = results
+            .chunks(size)
+            .map(|chunk| {
+                ClientMsg::JobResultChunk(JobResultChunkMsg {
+                    job_id,
+                    results: chunk.iter().cloned().collect(),
+                })
+            })
+            .collect::<Vec<_>>();
+        msgs.push(ClientMsg::JobResult(JobResultMsg {
+            results: last.into_iter().collect(),
+            chunked: true,
+            ..self
+        }));
+        msgs
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobResultChunkMsg {
+    pub job_id: FlowSnake,
+    pub results: HashMap<String, TestResult>,
+}
+
+/// Maximum length of each stream of [`JobBuildOutput`], in bytes.
+pub const MAX_BUILD_LOG_LEN: usize = 2 * 1024 * 1024;
+
+/// The captured output of building the image of a job. Only the last
+/// [`MAX_BUILD_LOG_LEN`] bytes of each stream are kept.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobBuildOutput {
+    pub output: String,
+    pub error: String,
+}
+
+impl JobBuildOutput {
+    fn append_bounded(buf: &mut String, s: &str) {
+        buf.push_str(s);
+        if buf.len() > MAX_BUILD_LOG_LEN {
+            let mut start = buf.len() - MAX_BUILD_LOG_LEN;
+            while !buf.is_char_boundary(start) {
+                start += 1;
+            }
+            buf.drain(..start);
+        }
+    }
+
+    pub fn push(&mut self, stream: Option<&str>, error: Option<&str>) {
+        if let Some(stream) = stream {
+            Self::append_bounded(&mut self.output, stream);
+        }
+        if let Some(error) = error {
+            Self::append_bounded(&mut self.error, error);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -328,6 +818,10 @@ pub struct JobRequestMsg {
     pub active_task_count: u32,
     pub request_for_new_task: u32,
     pub message_id: Option<FlowSnake>,
+    /// Bytes of command output buffered by running jobs, if accounted. See
+    /// [`ClientConfig::output_memory_limit`](super::config::ClientConfig::output_memory_limit).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffered_output_bytes: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -345,3 +839,129 @@ pub struct JudgerRegisterMessage {
     pub alternate_name: Option<String>,
     pub tags: Option<Vec<String>>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server,
+    };
+    use std::{convert::Infallible, io::Read, sync::Mutex};
+
+    #[test]
+    fn test_server_hello() {
+        let hello = serde_json::from_str::<ServerMsg>(r#"{"_t":"server_hello"}"#).unwrap();
+        assert!(matches!(hello, ServerMsg::ServerHello(x) if x.accept_encodings.is_empty()));
+        let hello = r#"{"_t":"server_hello","acceptEncodings":["gzip"]}"#;
+        let hello = serde_json::from_str::<ServerMsg>(hello).unwrap();
+        assert!(matches!(hello, ServerMsg::ServerHello(x) if x.accept_encodings == ["gzip"]));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_upload() {
+        type Received = Arc<Mutex<Vec<(Option<String>, Vec<u8>)>>>;
+        let received = Received::default();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let make_svc = make_service_fn({
+            let received = received.clone();
+            move |_| {
+                let received = received.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let received = received.clone();
+                        async move {
+                            let encoding = req
+                                .headers()
+                                .get("Content-Encoding")
+                                .map(|x| x.to_str().unwrap().to_owned());
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            received.lock().unwrap().push((encoding, body.to_vec()));
+                            Ok::<_, Infallible>(Response::new(Body::from("file-id")))
+                        }
+                    }))
+                }
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_svc));
+
+        let result = serde_json::json!({ "stdout": "0123456789".repeat(10000) });
+        let upload = |compression| {
+            let info = ResultUploadConfig {
+                client: reqwest::Client::new(),
+                endpoint: format!("http://{}/upload", addr),
+                access_token: None,
+                job_id: FlowSnake::generate(),
+                semaphore: Arc::new(Semaphore::new(1)),
+                compression,
+            };
+            upload_test_result(result.clone(), Arc::new(info), "a")
+        };
+        assert_eq!(upload(None).await.unwrap(), "file-id");
+        assert_eq!(
+            upload(Some(UploadCompression::Gzip)).await.unwrap(),
+            "file-id"
+        );
+
+        let expected = serde_json::to_vec(&result).unwrap();
+        let received = received.lock().unwrap();
+        assert_eq!(received[0], (None, expected.clone()));
+
+        let (encoding, body) = &received[1];
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        assert!(body.len() < expected.len() / 10);
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_upload_retry_releases_permit() {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let make_svc = make_service_fn({
+            let requests = requests.clone();
+            move |_| {
+                let requests = requests.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                        // Only the first request fails
+                        let count = requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        let status = if count == 0 { 500 } else { 200 };
+                        let res = Response::builder()
+                            .status(status)
+                            .body(Body::from("file-id"))
+                            .unwrap();
+                        async move { Ok::<_, Infallible>(res) }
+                    }))
+                }
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_svc));
+
+        let info = Arc::new(ResultUploadConfig {
+            client: reqwest::Client::new(),
+            endpoint: format!("http://{}/upload", addr),
+            access_token: None,
+            job_id: FlowSnake::generate(),
+            semaphore: Arc::new(Semaphore::new(1)),
+            compression: None,
+        });
+        let failing = tokio::spawn(upload_test_result("a", info.clone(), "a"));
+        while requests.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // Uploaded while the first upload backs off
+        let other = upload_test_result("b", info.clone(), "b");
+        let other = tokio::time::timeout(UPLOAD_RETRY_DELAY / 2, other).await;
+        assert_eq!(other.unwrap().unwrap(), "file-id");
+        assert_eq!(failing.await.unwrap().unwrap(), "file-id");
+    }
+}
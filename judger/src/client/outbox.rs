@@ -0,0 +1,662 @@
+//! A durable queue for result messages that could not be delivered to the
+//! coordinator.
+//!
+//! Messages are kept both in memory and on disk (one JSON file per message,
+//! named by its sequence number), so that they survive restarts of both the
+//! coordinator and the judger. Messages are delivered strictly in the order
+//! they are queued, which preserves the order of messages of every job.
+//!
+//! Messages rejected by the coordinator would fail the same way every time
+//! they are resent, so they are moved out of the queue instead of blocking
+//! it, and kept on disk as `rejected-<seq>.json` for inspection.
+
+use super::{config::SharedClientData, model::ClientMsg, sink::WsSink};
+use crate::prelude::*;
+use err_derive::Error;
+use futures::Future;
+use respector::prelude::*;
+use std::{collections::VecDeque, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, Notify};
+
+/// Delay before retrying a failed delivery for the first time.
+const RETRY_START_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay between delivery retries.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Delay before retrying a delivery that failed `failures` times in a row.
+///
+/// The delay doubles after every failure up to [`RETRY_MAX_DELAY`], and is
+/// shortened by up to a quarter at random, so that judgers that lost the
+/// coordinator at the same time do not retry all at once.
+fn retry_delay(failures: u32) -> Duration {
+    let delay = RETRY_START_DELAY
+        .checked_mul(1 << failures.saturating_sub(1).min(16))
+        .map_or(RETRY_MAX_DELAY, |x| x.min(RETRY_MAX_DELAY));
+    delay.mul_f64(1.0 - rand::random::<f64>() * 0.25)
+}
+
+/// Error of delivering a message to the coordinator.
+#[derive(Debug, Error)]
+pub enum DeliverErr {
+    /// The coordinator rejected the message, so sending it again would fail
+    /// the same way
+    #[error(display = "coordinator rejected the message with {}: {}", _0, _1)]
+    Rejected(reqwest::StatusCode, String),
+
+    /// The message may be delivered by trying again later
+    #[error(display = "{}", _0)]
+    Transient(anyhow::Error),
+}
+
+impl From<reqwest::Error> for DeliverErr {
+    fn from(e: reqwest::Error) -> Self {
+        DeliverErr::Transient(e.into())
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for DeliverErr {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        DeliverErr::Transient(e.into())
+    }
+}
+
+/// Run `f` until it succeeds, at most `attempts` times, sleeping for
+/// [`retry_delay`] between attempts. Returns the last error on failure, or the
+/// first one if the message is rejected.
+async fn retry_with_backoff<F, Fut>(attempts: u32, mut f: F) -> Result<(), DeliverErr>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), DeliverErr>>,
+{
+    let mut failures = 0;
+    loop {
+        match f().await {
+            Ok(()) => return Ok(()),
+            Err(e @ DeliverErr::Rejected(..)) => return Err(e),
+            Err(e) => {
+                failures += 1;
+                if failures >= attempts {
+                    return Err(e);
+                }
+                let delay = retry_delay(failures);
+                tracing::warn!("Failed to deliver message, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OutboxEntry {
+    seq: u64,
+    msg: ClientMsg,
+}
+
+#[derive(Debug, Default)]
+struct OutboxState {
+    entries: VecDeque<OutboxEntry>,
+    next_seq: u64,
+}
+
+/// A bounded, persistent queue of messages pending delivery.
+///
+/// When the queue is full, the oldest partial result is evicted first, since
+/// the final job result contains every test result anyway. If there are only
+/// job results in the queue, the oldest one is evicted. Every eviction is
+/// logged as an error.
+#[derive(Debug)]
+pub struct Outbox {
+    folder: PathBuf,
+    capacity: usize,
+    state: Mutex<OutboxState>,
+    notify: Notify,
+}
+
+impl Outbox {
+    pub fn new(folder: PathBuf, capacity: usize) -> Outbox {
+        Outbox {
+            folder,
+            capacity: capacity.max(1),
+            state: Mutex::new(OutboxState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    fn entry_path(&self, seq: u64) -> PathBuf {
+        self.folder.join(format!("{:020}.json", seq))
+    }
+
+    /// Path of a rejected message. It is not loaded back into the queue.
+    fn rejected_path(&self, seq: u64) -> PathBuf {
+        self.folder.join(format!("rejected-{:020}.json", seq))
+    }
+
+    /// Load messages left on disk by a previous run. Returns the number of
+    /// messages loaded.
+    pub async fn load(&self) -> std::io::Result<usize> {
+        tokio::fs::create_dir_all(&self.folder).await?;
+        let mut found = vec![];
+        let mut dir = tokio::fs::read_dir(&self.folder).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            let seq = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.parse::<u64>().ok());
+            if let Some(seq) = seq {
+                found.push((seq, path));
+            }
+        }
+        found.sort_unstable_by_key(|(seq, _)| *seq);
+
+        let mut state = self.state.lock().await;
+        for (seq, path) in found {
+            let data = tokio::fs::read(&path).await?;
+            match serde_json::from_slice::<ClientMsg>(&data) {
+                Ok(msg) => {
+                    state.next_seq = state.next_seq.max(seq + 1);
+                    state.entries.push_back(OutboxEntry { seq, msg });
+                }
+                Err(e) => {
+                    tracing::error!("Dropping corrupted outbox entry {}: {}", path.display(), e);
+                    let _ = tokio::fs::remove_file(&path).await;
+                }
+            }
+        }
+        if !state.entries.is_empty() {
+            self.notify.notify_one();
+        }
+        Ok(state.entries.len())
+    }
+
+    /// Number of messages pending delivery.
+    pub async fn len(&self) -> usize {
+        self.state.lock().await.entries.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Whether there are messages of the given job pending delivery.
+    pub async fn has_pending(&self, job_id: FlowSnake) -> bool {
+        self.state
+            .lock()
+            .await
+            .entries
+            .iter()
+            .any(|x| x.msg.job_id() == Some(job_id))
+    }
+
+    /// Queue a message for delivery, persisting it on disk.
+    pub async fn push(&self, msg: ClientMsg) -> std::io::Result<()> {
+        let mut state = self.state.lock().await;
+        while state.entries.len() >= self.capacity {
+            let idx = state
+                .entries
+                .iter()
+                .position(|x| matches!(x.msg, ClientMsg::PartialResult(_)))
+                .unwrap_or(0);
+            let evicted = state.entries.remove(idx).unwrap();
+            tracing::error!(
+                "Outbox is full ({} messages), evicting message #{} of job {:?}",
+                self.capacity,
+                evicted.seq,
+                evicted.msg.job_id()
+            );
+            let _ = tokio::fs::remove_file(self.entry_path(evicted.seq)).await;
+        }
+
+        let seq = state.next_seq;
+        tokio::fs::create_dir_all(&self.folder).await?;
+        let data = serde_json::to_vec(&msg).unwrap();
+        tokio::fs::write(self.entry_path(seq), data).await?;
+        state.next_seq += 1;
+        state.entries.push_back(OutboxEntry { seq, msg });
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Keep a message rejected by the coordinator on disk, out of the queue.
+    pub async fn push_rejected(&self, msg: &ClientMsg) -> std::io::Result<()> {
+        let mut state = self.state.lock().await;
+        let seq = state.next_seq;
+        tokio::fs::create_dir_all(&self.folder).await?;
+        let data = serde_json::to_vec(msg).unwrap();
+        tokio::fs::write(self.rejected_path(seq), data).await?;
+        state.next_seq += 1;
+        Ok(())
+    }
+
+    /// The oldest message pending delivery.
+    async fn front(&self) -> Option<(u64, ClientMsg)> {
+        self.state
+            .lock()
+            .await
+            .entries
+            .front()
+            .map(|x| (x.seq, x.msg.clone()))
+    }
+
+    /// Remove a delivered message.
+    async fn remove(&self, seq: u64) {
+        let mut state = self.state.lock().await;
+        if let Some(idx) = state.entries.iter().position(|x| x.seq == seq) {
+            state.entries.remove(idx);
+        }
+        drop(state);
+        let _ = tokio::fs::remove_file(self.entry_path(seq))
+            .await
+            .inspect_err(|e| tracing::warn!("Failed to remove outbox entry #{}: {}", seq, e));
+    }
+
+    /// Move a message rejected by the coordinator out of the queue, keeping
+    /// it on disk.
+    async fn reject(&self, seq: u64) {
+        let mut state = self.state.lock().await;
+        if let Some(idx) = state.entries.iter().position(|x| x.seq == seq) {
+            state.entries.remove(idx);
+        }
+        drop(state);
+        let _ = tokio::fs::rename(self.entry_path(seq), self.rejected_path(seq))
+            .await
+            .inspect_err(|e| tracing::warn!("Failed to move outbox entry #{}: {}", seq, e));
+    }
+}
+
+/// Deliver a message to the coordinator. Partial results are sent through the
+/// websocket connection, while other messages are posted to the result
+/// endpoint. Large job results are posted in chunks if configured.
+pub async fn deliver(
+    msg: &ClientMsg,
+    cfg: &SharedClientData,
+    ws: &WsSink,
+) -> Result<(), DeliverErr> {
+    match msg {
+        ClientMsg::PartialResult(_) => {
+            let serialized = serde_json::to_string(msg).unwrap();
+            ws.send_conf(
+                tokio_tungstenite::tungstenite::Message::text(serialized),
+                true,
+            )
+            .await?;
+            return Ok(());
+        }
+        ClientMsg::JobResult(res) => {
+            let chunk_size = cfg.cfg().result_chunk_size;
+            match chunk_size {
+                Some(size) if res.results.len() > size => {
+                    return deliver_chunked(msg, res.clone().into_chunks(size), cfg).await;
+                }
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+
+    check_response(post(msg, cfg).await?).await
+}
+
+/// Deliver `chunks` of the job result `msg` in order. Chunks are merged by the
+/// coordinator, so resending them after a failure is harmless.
+///
+/// Coordinators not supporting chunks reject the first one, in which case
+/// the whole message is posted at once instead.
+async fn deliver_chunked(
+    msg: &ClientMsg,
+    chunks: Vec<ClientMsg>,
+    cfg: &SharedClientData,
+) -> Result<(), DeliverErr> {
+    let mut chunks = chunks.into_iter();
+    if let Some(first) = chunks.next() {
+        let res = post(&first, cfg).await?;
+        if res.status().is_client_error() {
+            tracing::warn!(
+                "Coordinator rejected chunked results ({}), uploading them at once",
+                res.status()
+            );
+            return check_response(post(msg, cfg).await?).await;
+        }
+        check_response(res).await?;
+    }
+    for chunk in chunks {
+        check_response(post(&chunk, cfg).await?).await?;
+    }
+    Ok(())
+}
+
+/// Post a message to the result endpoint.
+async fn post(msg: &ClientMsg, cfg: &SharedClientData) -> reqwest::Result<reqwest::Response> {
+    let mut req = cfg.client.post(cfg.result_send_endpoint()).json(msg);
+    if let Some(token) = &cfg.cfg().access_token {
+        req = req.header("authorization", token.as_str());
+    }
+    req.send().await
+}
+
+/// Check the response to a posted message. Client errors other than timeouts
+/// and rate limiting mean the message itself is rejected.
+async fn check_response(res: reqwest::Response) -> Result<(), DeliverErr> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let text = res.text().await.unwrap_or_default();
+    if status.is_client_error()
+        && status != reqwest::StatusCode::REQUEST_TIMEOUT
+        && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+    {
+        return Err(DeliverErr::Rejected(status, text));
+    }
+    Err(DeliverErr::Transient(anyhow::anyhow!(
+        "coordinator responded {}: {}",
+        status,
+        text
+    )))
+}
+
+/// Deliver a message directly, or queue it into the outbox if delivery fails
+/// [`result_send_attempts`](super::config::ClientConfig::result_send_attempts)
+/// times or earlier messages of the same job are still pending.
+pub async fn send_or_enqueue(msg: ClientMsg, cfg: &SharedClientData, ws: &WsSink) {
+    let pending = match msg.job_id() {
+        Some(job_id) => cfg.outbox.has_pending(job_id).await,
+        None => false,
+    };
+    if !pending {
+        let attempts = cfg.cfg().result_send_attempts;
+        match retry_with_backoff(attempts, || deliver(&msg, cfg, ws)).await {
+            Ok(()) => return,
+            Err(e @ DeliverErr::Rejected(..)) => {
+                tracing::error!("Dropping message of job {:?}: {}", msg.job_id(), e);
+                if let Err(e) = cfg.outbox.push_rejected(&msg).await {
+                    tracing::error!("Failed to keep rejected message: {}", e);
+                }
+                return;
+            }
+            Err(e) => tracing::warn!("Failed to deliver message, queueing it: {}", e),
+        }
+    }
+    if let Err(e) = cfg.outbox.push(msg).await {
+        tracing::error!("Failed to persist message into outbox: {}", e);
+    }
+}
+
+/// Keep delivering queued messages in order, backing off when the coordinator
+/// is unavailable. Stops when the client is cancelled.
+pub async fn drain_outbox(cfg: Arc<SharedClientData>, ws: Arc<WsSink>) {
+    let mut failures = 0;
+    loop {
+        let notified = cfg.outbox.notify.notified();
+        let (seq, msg) = match cfg.outbox.front().await {
+            Some(x) => x,
+            None => {
+                if notified
+                    .with_cancel(cfg.cancel_handle.child_token())
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        match deliver(&msg, &cfg, &ws).await {
+            Ok(()) => {
+                cfg.outbox.remove(seq).await;
+                failures = 0;
+            }
+            Err(e @ DeliverErr::Rejected(..)) => {
+                tracing::error!(
+                    "Dropping queued message #{} of job {:?}: {}",
+                    seq,
+                    msg.job_id(),
+                    e
+                );
+                cfg.outbox.reject(seq).await;
+                failures = 0;
+            }
+            Err(e) => {
+                failures += 1;
+                let delay = retry_delay(failures);
+                tracing::warn!(
+                    "Failed to deliver queued message #{}, retrying in {:?}: {}",
+                    seq,
+                    delay,
+                    e
+                );
+                if tokio::time::sleep(delay)
+                    .with_cancel(cfg.cancel_handle.child_token())
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{
+        config::ClientConfig,
+        model::{JobResultKind, JobResultMsg, PartialResultMsg, TestResult, TestResultKind},
+    };
+
+    fn partial(job_id: FlowSnake, test_id: &str) -> ClientMsg {
+        ClientMsg::PartialResult(PartialResultMsg {
+            job_id,
+            test_id: test_id.into(),
+            test_result: TestResult {
+                kind: TestResultKind::Accepted,
+                score: None,
+                result_file_id: None,
+                upload_failed: false,
+                usage: None,
+            },
+        })
+    }
+
+    fn result(job_id: FlowSnake) -> ClientMsg {
+        ClientMsg::JobResult(JobResultMsg {
+            job_id,
+            job_result: JobResultKind::Accepted,
+            results: Default::default(),
+            message: None,
+            build_log: None,
+            network_attempts: None,
+            seed: None,
+            chunked: false,
+        })
+    }
+
+    fn test_id(msg: &ClientMsg) -> Option<&str> {
+        match msg {
+            ClientMsg::PartialResult(x) => Some(x.test_id.as_str()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_persists_and_evicts_partial_results_first() {
+        let root = std::env::temp_dir().join(format!("rurikawa-outbox-{}", rand::random::<u32>()));
+        let job = FlowSnake::generate();
+
+        let outbox = Outbox::new(root.clone(), 3);
+        outbox.push(partial(job, "a")).await.unwrap();
+        outbox.push(partial(job, "b")).await.unwrap();
+        outbox.push(result(job)).await.unwrap();
+        // Full: "a" is evicted, then "b", before any job result
+        outbox.push(partial(job, "c")).await.unwrap();
+        outbox.push(partial(job, "d")).await.unwrap();
+
+        // A new outbox in the same folder sees the same messages in order
+        let reloaded = Outbox::new(root.clone(), 3);
+        let loaded = reloaded.load().await.unwrap();
+        let has_pending = reloaded.has_pending(job).await;
+        let state = reloaded.state.into_inner();
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        let order = state
+            .entries
+            .iter()
+            .map(|x| test_id(&x.msg))
+            .collect::<Vec<_>>();
+        assert_eq!(loaded, 3);
+        assert_eq!(state.next_seq, 5);
+        assert!(has_pending);
+        assert_eq!(order, vec![None, Some("c"), Some("d")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_delivery_backs_off_and_queues() {
+        let mut attempts = vec![];
+        let res = retry_with_backoff(5, || {
+            attempts.push(tokio::time::Instant::now());
+            async {
+                Err(DeliverErr::Transient(anyhow::anyhow!(
+                    "coordinator responded 502 Bad Gateway"
+                )))
+            }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.len(), 5);
+        let delays = attempts.windows(2).map(|x| x[1] - x[0]).collect::<Vec<_>>();
+        assert!(delays[0] >= RETRY_START_DELAY.mul_f64(0.75));
+        assert!(delays.windows(2).all(|x| x[0] < x[1]), "{:?}", delays);
+        assert!((1..20).all(|x| retry_delay(x) <= RETRY_MAX_DELAY));
+
+        // The undelivered message is then kept on disk
+        let root = std::env::temp_dir().join(format!("rurikawa-outbox-{}", rand::random::<u32>()));
+        let job = FlowSnake::generate();
+        // Nothing listens at the address once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let cfg = SharedClientData::new(ClientConfig {
+            host: addr.to_string().as_str().into(),
+            cache_folder: root.clone(),
+            result_send_attempts: 2,
+            ..Default::default()
+        });
+        send_or_enqueue(result(job), &cfg, &WsSink::new()).await;
+        let reloaded = Outbox::new(root.join("outbox"), 3);
+        let loaded = reloaded.load().await.unwrap();
+        let has_pending = reloaded.has_pending(job).await;
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        assert_eq!(loaded, 1);
+        assert!(has_pending);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_message_does_not_block_queue() {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Request, Response,
+        };
+        use std::{
+            convert::Infallible,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        // The first message posted is rejected, later ones are accepted
+        let posts = Arc::new(AtomicUsize::new(0));
+        let make_svc = make_service_fn({
+            let posts = posts.clone();
+            move |_| {
+                let posts = posts.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                        let status = match posts.fetch_add(1, Ordering::SeqCst) {
+                            0 => 400,
+                            _ => 200,
+                        };
+                        async move {
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr().to_string();
+        tokio::spawn(server);
+
+        let root = std::env::temp_dir().join(format!("rurikawa-outbox-{}", rand::random::<u32>()));
+        let cfg = Arc::new(SharedClientData::new(ClientConfig {
+            host: addr.as_str().into(),
+            cache_folder: root.clone(),
+            ..Default::default()
+        }));
+        let (rejected, accepted) = (FlowSnake::generate(), FlowSnake::generate());
+        cfg.outbox.push(result(rejected)).await.unwrap();
+        cfg.outbox.push(result(accepted)).await.unwrap();
+
+        let drain = tokio::spawn(drain_outbox(cfg.clone(), Arc::new(WsSink::discarding())));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while !cfg.outbox.is_empty().await && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        cfg.cancel_handle.cancel(None);
+        drain.await.unwrap();
+
+        let remaining = cfg.outbox.len().await;
+        let kept = std::fs::read(cfg.outbox.rejected_path(0));
+        let reloaded = Outbox::new(root.join("outbox"), 3);
+        let loaded = reloaded.load().await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(remaining, 0);
+        assert_eq!(posts.load(Ordering::SeqCst), 2);
+        // The rejected message is kept aside, and not queued again on restart
+        let kept = serde_json::from_slice::<ClientMsg>(&kept.unwrap()).unwrap();
+        assert_eq!(kept.job_id(), Some(rejected));
+        assert_eq!(loaded, 0);
+    }
+
+    #[test]
+    fn test_job_result_chunks() {
+        let job = FlowSnake::generate();
+        let mut msg = match result(job) {
+            ClientMsg::JobResult(x) => x,
+            _ => unreachable!(),
+        };
+        for test in ["a", "b", "c", "d", "e"].iter().cloned() {
+            if let ClientMsg::PartialResult(x) = partial(job, test) {
+                msg.results.insert(x.test_id, x.test_result);
+            }
+        }
+
+        assert_eq!(msg.clone().into_chunks(5).len(), 1);
+
+        let chunks = msg.into_chunks(2);
+        let names = chunks
+            .iter()
+            .map(|x| {
+                let results = match x {
+                    ClientMsg::JobResultChunk(x) => &x.results,
+                    ClientMsg::JobResult(x) => {
+                        assert!(x.chunked);
+                        &x.results
+                    }
+                    _ => unreachable!(),
+                };
+                let mut names = results.keys().map(|x| x.as_str()).collect::<Vec<_>>();
+                names.sort_unstable();
+                names.join("")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["ab", "cd", "e"]);
+        assert!(matches!(chunks.last(), Some(ClientMsg::JobResult(_))));
+    }
+}
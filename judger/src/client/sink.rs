@@ -10,7 +10,10 @@ use futures::{
 };
 use serde::Serialize;
 use std::{fmt::Debug, sync::Arc};
-use tokio::{net::TcpStream, sync::Mutex};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, Semaphore},
+};
 use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
 use tungstenite::Message;
 
@@ -19,16 +22,50 @@ pub type WsSink = WebsocketSink;
 pub type RawWsSink = SplitSink<WsDuplex, Message>;
 pub type WsStream = SplitStream<WsDuplex>;
 
+/// Default number of data messages allowed to wait for the socket at the same
+/// time. See [`WebsocketSink::with_data_concurrency`].
+pub const DEFAULT_DATA_SEND_CONCURRENCY: usize = 1;
+
+/// A websocket sink shared by all tasks of a client.
+///
+/// Messages are either data messages (job progress, output and results),
+/// sent with [`send`](Self::send) and friends, or control messages (pings
+/// and job requests), sent with [`send_control`](Self::send_control). Only a
+/// limited number of data messages may wait for the socket at the same time,
+/// and control messages skip the queue of the others, so that bulk output
+/// never delays keepalives by more than a few messages.
 pub struct WebsocketSink {
     sink: ArcSwapOption<Mutex<RawWsSink>>,
     handle: ArcSwapAny<Arc<CancellationTokenHandle>>,
+    /// Drop every message instead of waiting for a connection.
+    discard: bool,
+    /// Permits of sending data messages.
+    data_permits: Semaphore,
 }
 
 impl WebsocketSink {
     pub fn new() -> WebsocketSink {
+        Self::with_data_concurrency(DEFAULT_DATA_SEND_CONCURRENCY)
+    }
+
+    /// Create a sink allowing at most `n` data messages to wait for the
+    /// socket at the same time. Control messages wait for at most these `n`
+    /// data messages to be sent.
+    pub fn with_data_concurrency(n: usize) -> WebsocketSink {
         WebsocketSink {
             sink: arc_swap::ArcSwapOption::new(None),
             handle: ArcSwapAny::new(Arc::new(CancellationTokenHandle::new())),
+            discard: false,
+            data_permits: Semaphore::new(n.max(1)),
+        }
+    }
+
+    /// Create a sink that silently drops every message sent into it, for
+    /// running jobs without a connection to the coordinator.
+    pub fn discarding() -> WebsocketSink {
+        WebsocketSink {
+            discard: true,
+            ..Self::new()
         }
     }
 
@@ -36,10 +73,50 @@ impl WebsocketSink {
         self.send_conf(msg, false).await
     }
 
+    /// Send a data message. If `err_if_connection_fail` is not set, waits
+    /// until connected.
     pub async fn send_conf(
         &self,
         msg: Message,
         err_if_connection_fail: bool,
+    ) -> Result<(), tungstenite::Error> {
+        if self.discard {
+            return Ok(());
+        }
+        let _permit = self
+            .data_permits
+            .acquire()
+            .await
+            .expect("Data permits are never closed");
+        self.send_raw(msg, err_if_connection_fail).await
+    }
+
+    /// Send a control message ahead of the data messages waiting to be sent.
+    /// If `err_if_connection_fail` is not set, waits until connected.
+    pub async fn send_control(
+        &self,
+        msg: Message,
+        err_if_connection_fail: bool,
+    ) -> Result<(), tungstenite::Error> {
+        if self.discard {
+            return Ok(());
+        }
+        self.send_raw(msg, err_if_connection_fail).await
+    }
+
+    /// Serialize `msg` and send it as a control message.
+    pub async fn send_control_msg<M: Serialize + Sync>(
+        &self,
+        msg: &M,
+    ) -> Result<(), tungstenite::Error> {
+        let serialized = serde_json::to_string(msg).unwrap();
+        self.send_control(Message::text(serialized), false).await
+    }
+
+    async fn send_raw(
+        &self,
+        msg: Message,
+        err_if_connection_fail: bool,
     ) -> Result<(), tungstenite::Error> {
         let mut sink = self.sink.load();
         if sink.is_none() && err_if_connection_fail {
@@ -75,6 +152,14 @@ impl WebsocketSink {
             + Stream<Item = Result<Message, tungstenite::Error>>
             + Unpin,
     {
+        if self.discard {
+            return Ok(());
+        }
+        let _permit = self
+            .data_permits
+            .acquire()
+            .await
+            .expect("Data permits are never closed");
         let mut sink = self.sink.load();
         if sink.is_none() && err_if_connection_fail {
             return Err(tungstenite::Error::AlreadyClosed);
@@ -98,7 +183,7 @@ impl WebsocketSink {
         self.sink.swap(Some(Arc::new(Mutex::new(sink))));
         self.handle
             .swap(Arc::new(CancellationTokenHandle::new()))
-            .cancel();
+            .cancel(None);
     }
 
     pub fn clear_socket(&self) {
@@ -150,3 +235,79 @@ where
 //     type Error = tungstenite::Error;
 
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpSocket};
+
+    /// Socket buffer size of the test connection, small enough that the
+    /// sender is blocked by the slow receiver.
+    const BUFFER_SIZE: u32 = 64 * 1024;
+
+    #[tokio::test]
+    async fn test_control_messages_skip_data_queue() {
+        const DATA_COUNT: usize = 32;
+
+        let listener = TcpSocket::new_v4().unwrap();
+        listener.set_recv_buffer_size(BUFFER_SIZE).unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener: TcpListener = listener.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A slow receiver recording the order of text messages.
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut received = vec![];
+            while received.len() < DATA_COUNT + 1 {
+                match ws.next().await {
+                    Some(Ok(Message::Text(text))) => received.push(text.len()),
+                    Some(Ok(_)) => {}
+                    _ => break,
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            received
+        });
+
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_send_buffer_size(BUFFER_SIZE).unwrap();
+        let stream = socket.connect(addr).await.unwrap();
+        let (client, _) = tokio_tungstenite::client_async(
+            format!("ws://{}/", addr),
+            MaybeTlsStream::Plain(stream),
+        )
+        .await
+        .unwrap();
+        let (client_sink, _client_stream) = client.split();
+
+        let sink = Arc::new(WebsocketSink::new());
+        sink.load_socket(client_sink);
+
+        let data = "x".repeat(256 * 1024);
+        let senders = (0..DATA_COUNT)
+            .map(|_| {
+                let sink = sink.clone();
+                let data = data.clone();
+                tokio::spawn(async move { sink.send(Message::text(data)).await.unwrap() })
+            })
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        sink.send_control(Message::text("control"), true)
+            .await
+            .unwrap();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+
+        let received = server.await.unwrap();
+        let control_idx = received.iter().position(|x| *x == "control".len()).unwrap();
+        assert!(
+            control_idx < DATA_COUNT / 2,
+            "control message was received after {} data messages",
+            control_idx
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Content-addressed storage of test suites.
+//!
+//! When [`ClientConfig::dedup_test_suites`](super::config::ClientConfig) is
+//! set, the content of every test suite package is extracted only once into
+//! `suite-content/<key>`, where `<key>` is derived from the `package_file_id`
+//! of the package. The folder of every test suite is then a symbolic link to
+//! the content of its package, so test suites sharing the same package share
+//! the same files on disk.
+//!
+//! A content folder is complete only if its `<key>.done` marker exists. The
+//! content is removed when the last test suite linking to it is removed or
+//! moves to another package, see [`release_content`].
+
+use super::{config::SharedClientData, JobExecErr};
+use crate::fs;
+use futures::Future;
+use std::path::{Path, PathBuf};
+
+/// Key of the stored content of the package `package_file_id`, usable as a
+/// file name.
+///
+/// This is the 64-bit FNV-1a hash of the id, which stays stable across
+/// versions of the judger and Rust.
+pub fn content_key(package_file_id: &str) -> String {
+    let hash = package_file_id
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Content key of the package `suite_folder` links to, if it is a link into
+/// the content store.
+async fn linked_key(cfg: &SharedClientData, suite_folder: &Path) -> Option<String> {
+    let target = tokio::fs::read_link(suite_folder).await.ok()?;
+    if target.parent()? != cfg.test_suite_content_folder_root() {
+        return None;
+    }
+    Some(target.file_name()?.to_string_lossy().into_owned())
+}
+
+#[cfg(unix)]
+async fn symlink_dir(target: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink(target, link).await
+}
+
+#[cfg(windows)]
+async fn symlink_dir(target: &Path, link: &Path) -> std::io::Result<()> {
+    tokio::fs::symlink_dir(target, link).await
+}
+
+/// Make `suite_folder` a link to the stored content of `package_file_id`.
+///
+/// If the content is not stored yet, `download` is called with the folder
+/// to extract the package into. The content previously linked by
+/// `suite_folder` is released afterwards.
+pub async fn link_content<F, Fut>(
+    cfg: &SharedClientData,
+    suite_folder: &Path,
+    package_file_id: &str,
+    download: F,
+) -> Result<(), JobExecErr>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<(), JobExecErr>>,
+{
+    let key = content_key(package_file_id);
+    let old_key = linked_key(cfg, suite_folder).await;
+    tokio::fs::create_dir_all(cfg.test_suite_content_folder_root()).await?;
+
+    {
+        let lock = cfg
+            .suite_content_locks
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+
+        let content = cfg.test_suite_content_folder(&key);
+        let marker = cfg.test_suite_content_marker(&key);
+        if tokio::fs::metadata(&marker).await.is_err() {
+            tracing::info!("Storing content of package {} as {}", package_file_id, key);
+            fs::ensure_removed_dir(&content).await?;
+            download(content.clone()).await?;
+            fs::write_atomic(&marker, package_file_id).await?;
+        } else {
+            tracing::info!(
+                "Reusing stored content {} of package {}",
+                key,
+                package_file_id
+            );
+        }
+
+        fs::ensure_removed_dir(suite_folder).await?;
+        symlink_dir(&content, suite_folder).await?;
+    }
+
+    if let Some(old_key) = old_key.filter(|x| *x != key) {
+        release_content(cfg, &old_key).await?;
+    }
+    Ok(())
+}
+
+/// Number of test suite folders linking to the stored content `key`.
+pub async fn content_references(cfg: &SharedClientData, key: &str) -> std::io::Result<usize> {
+    let mut entries = tokio::fs::read_dir(cfg.test_suite_folder_root()).await?;
+    let mut count = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        if linked_key(cfg, &entry.path()).await.as_deref() == Some(key) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Remove the stored content `key` if no test suite folder links to it
+/// anymore. Returns whether the content is removed.
+///
+/// Anything evicting test suites must remove the link of the test suite
+/// first and call this function, instead of removing the content directly.
+pub async fn release_content(cfg: &SharedClientData, key: &str) -> std::io::Result<bool> {
+    let lock = cfg
+        .suite_content_locks
+        .entry(key.to_owned())
+        .or_default()
+        .clone();
+    let _guard = lock.lock().await;
+
+    if content_references(cfg, key).await? > 0 {
+        return Ok(false);
+    }
+    tracing::info!("Removing unreferenced suite content {}", key);
+    // Remove the marker first, so that a partially removed content is never
+    // considered complete.
+    match tokio::fs::remove_file(cfg.test_suite_content_marker(key)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::ensure_removed_dir(&cfg.test_suite_content_folder(key)).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::config::ClientConfig;
+
+    #[test]
+    fn test_content_key() {
+        assert_eq!(content_key(""), "cbf29ce484222325");
+        assert_eq!(content_key("a"), "af63dc4c8601ec8c");
+        assert_ne!(content_key("package-1"), content_key("package-2"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shared_content() {
+        let root =
+            std::env::temp_dir().join(format!("rurikawa-suite-store-{}", rand::random::<u32>()));
+        let cfg = SharedClientData::new(ClientConfig {
+            cache_folder: root.clone(),
+            ..Default::default()
+        });
+        tokio::fs::create_dir_all(cfg.test_suite_folder_root())
+            .await
+            .unwrap();
+
+        let downloads = std::sync::atomic::AtomicUsize::new(0);
+        let download = |dir: PathBuf| {
+            downloads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                tokio::fs::create_dir_all(&dir).await?;
+                tokio::fs::write(dir.join("testconf.json"), "{}").await?;
+                Ok(())
+            }
+        };
+
+        let a = cfg.test_suite_folder_root().join("a");
+        let b = cfg.test_suite_folder_root().join("b");
+        link_content(&cfg, &a, "pkg", download).await.unwrap();
+        link_content(&cfg, &b, "pkg", download).await.unwrap();
+        assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(b.join("testconf.json").exists());
+
+        let key = content_key("pkg");
+        assert_eq!(content_references(&cfg, &key).await.unwrap(), 2);
+
+        // Still used by `b`
+        link_content(&cfg, &a, "pkg-2", download).await.unwrap();
+        assert!(cfg.test_suite_content_folder(&key).exists());
+        assert!(b.join("testconf.json").exists());
+
+        // Not used anymore
+        link_content(&cfg, &b, "pkg-2", download).await.unwrap();
+        assert!(!cfg.test_suite_content_folder(&key).exists());
+        assert!(!cfg.test_suite_content_marker(&key).exists());
+        assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+    }
+}
@@ -11,6 +11,176 @@ pub struct JudgeToml {
 pub struct JudgeTomlTestConfig {
     /// Base image to build from, if needed.
     pub image: Image,
+    /// Commands to be run once in the container before running any test.
+    /// A failing command results in a compile error.
     pub build: Option<Vec<String>>,
+    /// Commands to be run for every test.
+    #[serde(default)]
     pub run: Vec<String>,
+    /// Name of a built-in [`LanguageProfile`] supplying `build` and `run`
+    /// commands, which are only used if not specified in this config.
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Main source file for the language profile. Defaults to `main` with the
+    /// extension of the language (`Main.java` for Java).
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Arguments appended to the run command of the language profile, e.g.
+    /// `"$input"`.
+    #[serde(default)]
+    pub args: Option<String>,
+}
+
+/// Default commands of a language, for jobs that do not need a handwritten
+/// build process.
+///
+/// `{source}` in commands is replaced with the main source file, and `{stem}`
+/// with its name without the extension.
+#[derive(Debug, Clone, Copy)]
+pub struct LanguageProfile {
+    pub name: &'static str,
+    /// Extension of source files, without the leading dot.
+    pub extension: &'static str,
+    /// Name of the main source file without the extension.
+    pub default_stem: &'static str,
+    pub build: &'static [&'static str],
+    pub run: &'static str,
+}
+
+/// Built-in language profiles.
+pub const LANGUAGE_PROFILES: &[LanguageProfile] = &[
+    LanguageProfile {
+        name: "c",
+        extension: "c",
+        default_stem: "main",
+        build: &["gcc -O2 -o program {source}"],
+        run: "./program",
+    },
+    LanguageProfile {
+        name: "cpp",
+        extension: "cpp",
+        default_stem: "main",
+        build: &["g++ -O2 -std=c++17 -o program {source}"],
+        run: "./program",
+    },
+    LanguageProfile {
+        name: "rust",
+        extension: "rs",
+        default_stem: "main",
+        build: &["rustc -O -o program {source}"],
+        run: "./program",
+    },
+    LanguageProfile {
+        name: "java",
+        extension: "java",
+        default_stem: "Main",
+        build: &["javac -d . {source}"],
+        run: "java {stem}",
+    },
+    LanguageProfile {
+        name: "python",
+        extension: "py",
+        default_stem: "main",
+        build: &[],
+        run: "python3 {source}",
+    },
+];
+
+impl LanguageProfile {
+    /// Find the built-in profile named `name`.
+    pub fn find(name: &str) -> Option<&'static LanguageProfile> {
+        LANGUAGE_PROFILES.iter().find(|x| x.name == name)
+    }
+}
+
+impl JudgeTomlTestConfig {
+    /// The `build` and `run` commands of this job, filling in the ones not
+    /// specified from its language profile.
+    pub fn commands(&self) -> Result<(Vec<String>, Vec<String>), String> {
+        let profile = match &self.language {
+            Some(name) => LanguageProfile::find(name).ok_or_else(|| {
+                format!(
+                    "Unknown language {:?}, available languages are: {}",
+                    name,
+                    LANGUAGE_PROFILES
+                        .iter()
+                        .map(|x| x.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?,
+            None => {
+                if self.run.is_empty() {
+                    return Err("Either `run` or `language` must be specified".into());
+                }
+                return Ok((self.build.clone().unwrap_or_default(), self.run.clone()));
+            }
+        };
+
+        let source = self
+            .source
+            .clone()
+            .unwrap_or_else(|| format!("{}.{}", profile.default_stem, profile.extension));
+        let stem = std::path::Path::new(&source)
+            .file_stem()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let fill = |cmd: &str| cmd.replace("{source}", &source).replace("{stem}", &stem);
+
+        let build = match &self.build {
+            Some(build) => build.clone(),
+            None => profile.build.iter().map(|x| fill(x)).collect(),
+        };
+        let run = if self.run.is_empty() {
+            let mut run = fill(profile.run);
+            if let Some(args) = &self.args {
+                run.push(' ');
+                run.push_str(args);
+            }
+            vec![run]
+        } else {
+            self.run.clone()
+        };
+        Ok((build, run))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn job(cfg: &str) -> JudgeTomlTestConfig {
+        let cfg = format!("image = {{ source = \"image\", tag = \"x\" }}\n{}", cfg);
+        toml::from_str(&cfg).unwrap()
+    }
+
+    #[test]
+    fn test_language_profiles() {
+        let (build, run) = job("language = \"cpp\"\nargs = \"$input\"")
+            .commands()
+            .unwrap();
+        assert_eq!(build, ["g++ -O2 -std=c++17 -o program main.cpp"]);
+        assert_eq!(run, ["./program $input"]);
+
+        let (build, run) = job("language = \"java\"\nsource = \"src/Lexer.java\"")
+            .commands()
+            .unwrap();
+        assert_eq!(build, ["javac -d . src/Lexer.java"]);
+        assert_eq!(run, ["java Lexer"]);
+
+        // Explicit commands override the profile
+        let (build, run) =
+            job("language = \"python\"\nbuild = [\"pip install .\"]\nrun = [\"lexer $input\"]")
+                .commands()
+                .unwrap();
+        assert_eq!(build, ["pip install ."]);
+        assert_eq!(run, ["lexer $input"]);
+
+        let (build, run) = job("run = [\"./program\"]").commands().unwrap();
+        assert!(build.is_empty());
+        assert_eq!(run, ["./program"]);
+
+        assert!(job("language = \"cobol\"").commands().is_err());
+        assert!(job("").commands().is_err());
+    }
 }
@@ -0,0 +1,263 @@
+//! The judger as a library.
+//!
+//! [`Judger`] drives the same engine as the `rurikawa` binary without going
+//! through its command line, so that it can be embedded into other services.
+
+use crate::{
+    client::{
+        client_loop,
+        config::{ClientConfig, SharedClientData},
+        connect_to_coordinator, handle_job,
+        job_log::JobLog,
+        model::{Job, JobResultMsg},
+        outbox::drain_outbox,
+        sink::WsSink,
+        try_register, verify_self, JobExecErr, TokenVerification,
+    },
+    fs,
+    prelude::{CancelReason, CancellationTokenHandle},
+};
+use anyhow::{bail, Result};
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+/// Initial delay before reconnecting to the coordinator. Grows after every
+/// failed attempt.
+const START_RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// Maximum delay before reconnecting to the coordinator.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(256);
+
+/// A judger instance.
+pub struct Judger {
+    data: Arc<SharedClientData>,
+    sink: Arc<WsSink>,
+}
+
+impl Judger {
+    /// Create a judger with the given config. Fails if the TLS settings in
+    /// the config are invalid.
+    pub fn new(cfg: ClientConfig) -> Result<Judger> {
+        let tls_config = cfg.tls.client_config()?;
+        let mut data = SharedClientData::new(cfg);
+        data.set_tls_config(tls_config);
+        Ok(Judger::from_shared(data))
+    }
+
+    /// Create a judger out of already set-up client data.
+    pub fn from_shared(data: SharedClientData) -> Judger {
+        let sink = WsSink::with_data_concurrency(data.cfg().ws_data_send_concurrency);
+        Judger {
+            data: Arc::new(data),
+            sink: Arc::new(sink),
+        }
+    }
+
+    /// Data shared by all jobs of this judger, including its current config.
+    pub fn shared(&self) -> &Arc<SharedClientData> {
+        &self.data
+    }
+
+    /// The handle stopping this judger. Cancelling it makes
+    /// [`connect_and_serve`](Self::connect_and_serve) return after all
+    /// running jobs are stopped.
+    pub fn cancel_handle(&self) -> CancellationTokenHandle {
+        self.data.cancel_handle.clone()
+    }
+
+    /// Make sure this judger holds a valid access token, registering at the
+    /// coordinator with the register token if needed (or if `refresh` is
+    /// set).
+    ///
+    /// Fails if the coordinator rejects the access token and there's no
+    /// register token to get a new one, since such a judger would never be
+    /// able to connect. A token that cannot be verified because the
+    /// coordinator is unreachable is kept.
+    pub async fn ensure_registered(&self, refresh: bool) -> Result<()> {
+        match verify_self(&self.data).await {
+            TokenVerification::Valid if !refresh => return Ok(()),
+            TokenVerification::Unreachable(e) if !refresh => {
+                tracing::warn!("Cannot verify access token, keeping it: {}", e);
+                return Ok(());
+            }
+            TokenVerification::Invalid if self.data.cfg().register_token.is_none() => {
+                if self.data.cfg().access_token.is_none() {
+                    bail!("No access token or register token is provided.");
+                }
+                bail!(
+                    "Access token is rejected by the coordinator, and no register token is \
+                     provided to get a new one. Please check your access token."
+                );
+            }
+            TokenVerification::Invalid => tracing::warn!("Verification failed. Registering."),
+            _ => tracing::info!("Refreshing access token."),
+        }
+
+        if !try_register(&self.data, true).await? {
+            bail!("Judger cannot be registered. Please check your register token.");
+        }
+        match verify_self(&self.data).await {
+            TokenVerification::Valid => {}
+            TokenVerification::Invalid => bail!(
+                "Judger cannot be verified with the latest access token! This might be a server issue."
+            ),
+            TokenVerification::Unreachable(e) => {
+                tracing::warn!("Cannot verify the new access token: {}", e)
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a single job, and return its result.
+    ///
+    /// The test suite is still fetched from the coordinator and test results
+    /// are uploaded to it, but no progress is reported over the websocket.
+    /// Cancelling [`cancel_handle`](Self::cancel_handle) cancels the job.
+    pub async fn run_job(&self, job: Job) -> Result<JobResultMsg, JobExecErr> {
+        let job_id = job.id;
+        let res = handle_job(
+            job,
+            Arc::new(WsSink::discarding()),
+            self.data.cancel_handle.child_token(),
+            self.data.clone(),
+            &mut JobLog::new(job_id),
+            &mut HashMap::new(),
+        )
+        .await;
+        let _ = fs::ensure_removed_dir(&self.data.job_folder(job_id)).await;
+        res
+    }
+
+    /// Connect to the coordinator and judge the jobs it sends, reconnecting
+    /// whenever the connection drops, until [`cancel_handle`](Self::cancel_handle)
+    /// is cancelled or the maximum number of jobs is reached.
+    ///
+    /// Returns after all running jobs are finished or stopped.
+    pub async fn connect_and_serve(&self) {
+        let data = &self.data;
+        match data.outbox.load().await {
+            Ok(0) => {}
+            Ok(n) => tracing::warn!("{} undelivered result message(s) found in outbox", n),
+            Err(e) => tracing::error!("Failed to load outbox: {}", e),
+        }
+        tokio::spawn(drain_outbox(data.clone(), self.sink.clone()));
+
+        let mut wait_time = START_RECONNECT_DELAY;
+        loop {
+            self.sink.clear_socket();
+            let (sink, stream) = match connect_to_coordinator(data).await {
+                Ok(e) => e,
+                Err(e) => {
+                    // Exponential wait time
+                    tracing::warn!("Failed to connect: {}", e);
+                    let cancelled = tokio::select! {
+                        _ = tokio::time::sleep(wait_time) => false,
+                        _ = data.cancel_handle.cancelled() => true,
+                    };
+                    if cancelled {
+                        break;
+                    }
+                    wait_time = std::cmp::min(wait_time.mul_f64(1.6), MAX_RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            wait_time = START_RECONNECT_DELAY;
+            self.sink.load_socket(sink);
+            data.connected.store(true, Ordering::SeqCst);
+
+            client_loop(stream, self.sink.clone(), data.clone()).await;
+            data.connected.store(false, Ordering::SeqCst);
+            if data.cancel_handle.is_cancelled() {
+                break;
+            }
+            if data.host_count() > 1 {
+                tracing::warn!("Disconnected from {}, failing over", data.host());
+                data.next_host();
+            }
+        }
+
+        let grace_period = Duration::from_secs(data.cfg().shutdown_grace_period);
+        if stop_jobs(data, grace_period).await {
+            tracing::warn!("All things cancelled");
+        } else {
+            tracing::error!(
+                "Jobs did not stop within {:?}, leaving them behind",
+                grace_period
+            );
+        }
+    }
+}
+
+/// Cancel all jobs of the judger, and wait at most `grace_period` for them to
+/// stop. Returns whether all of them stopped in time.
+///
+/// Jobs are stopped through their cancellation tokens, which are children of
+/// the one of the judger, and report themselves as aborted.
+async fn stop_jobs(data: &SharedClientData, grace_period: Duration) -> bool {
+    tracing::warn!("Preparing to stop jobs.");
+    data.cancel_handle.cancel(CancelReason::Shutdown);
+
+    tracing::warn!("Collecting cancelling jobs.");
+    let cancelling = data
+        .cancelling_job_handles
+        .lock()
+        .await
+        .drain()
+        .collect::<Vec<_>>();
+
+    tracing::warn!("Collecting running jobs.");
+    let running = data
+        .running_job_handles
+        .lock()
+        .await
+        .drain()
+        .collect::<Vec<_>>();
+
+    let cancelling = cancelling.into_iter().map(|(id, fut)| {
+        tracing::info!("Waiting for job {} to cancel...", id);
+        fut
+    });
+    let running = running.into_iter().map(|(id, fut)| {
+        tracing::info!("Waiting for job {} to abort...", id);
+        fut.0
+    });
+    let all = futures::future::join_all(cancelling.chain(running));
+    tokio::time::timeout(grace_period, all).await.is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prelude::FlowSnake;
+
+    #[tokio::test]
+    async fn test_stop_jobs() {
+        let data = SharedClientData::new(ClientConfig::default());
+        let token = data.cancel_handle.child_token();
+        let (cancelled_send, cancelled_recv) = tokio::sync::oneshot::channel();
+        let job = tokio::spawn({
+            let token = token.clone();
+            async move {
+                token.cancelled().await;
+                let _ = cancelled_send.send(());
+            }
+        });
+        data.running_job_handles
+            .lock()
+            .await
+            .insert(FlowSnake::generate(), (job, token));
+        assert!(stop_jobs(&data, Duration::from_secs(5)).await);
+        assert!(cancelled_recv.await.is_ok());
+
+        // Jobs not stopping are left behind after the grace period
+        let stuck = tokio::spawn(futures::future::pending::<()>());
+        data.running_job_handles.lock().await.insert(
+            FlowSnake::generate(),
+            (stuck, data.cancel_handle.child_token()),
+        );
+        assert!(!stop_jobs(&data, Duration::from_millis(10)).await);
+    }
+}
@@ -0,0 +1,331 @@
+//! Extracting archives with limits on their content, so that malicious or
+//! corrupted packages (e.g. zip bombs) cannot fill up the disk.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Component, Path},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::process::Command;
+
+/// Interval between checks of the files extracted so far.
+const CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Limits on the content of an archive being extracted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtractLimits {
+    /// Maximum total size of all extracted files, in bytes.
+    pub max_total_size: u64,
+    /// Maximum number of extracted files and folders.
+    pub max_file_count: u64,
+    /// Maximum size of a single extracted file, in bytes.
+    pub max_file_size: u64,
+    /// Maximum number of components in the path of an extracted file.
+    pub max_path_depth: usize,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            max_total_size: 4 << 30,
+            max_file_count: 100_000,
+            max_file_size: 1 << 30,
+            max_path_depth: 32,
+        }
+    }
+}
+
+/// An archive exceeds the [`ExtractLimits`].
+#[derive(Debug)]
+pub struct ExtractLimitExceeded(pub String);
+
+impl std::fmt::Display for ExtractLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Extraction limit exceeded: {}", self.0)
+    }
+}
+
+impl std::error::Error for ExtractLimitExceeded {}
+
+/// Running totals of the entries checked so far.
+#[derive(Debug, Default)]
+struct Usage {
+    total_size: u64,
+    file_count: u64,
+}
+
+impl ExtractLimits {
+    /// Check an entry of `size` bytes at the relative `path`, and add it into
+    /// `usage`.
+    fn check_entry(
+        &self,
+        usage: &mut Usage,
+        path: &Path,
+        size: u64,
+    ) -> Result<(), ExtractLimitExceeded> {
+        let mut depth = 0;
+        for component in path.components() {
+            match component {
+                Component::Normal(_) => depth += 1,
+                Component::CurDir => {}
+                _ => {
+                    return Err(ExtractLimitExceeded(format!(
+                        "{} escapes the extraction folder",
+                        path.display()
+                    )))
+                }
+            }
+        }
+        if depth > self.max_path_depth {
+            return Err(ExtractLimitExceeded(format!(
+                "{} is nested deeper than {} levels",
+                path.display(),
+                self.max_path_depth
+            )));
+        }
+        if size > self.max_file_size {
+            return Err(ExtractLimitExceeded(format!(
+                "{} has {} bytes, more than {} bytes allowed",
+                path.display(),
+                size,
+                self.max_file_size
+            )));
+        }
+
+        usage.file_count += 1;
+        usage.total_size += size;
+        if usage.file_count > self.max_file_count {
+            return Err(ExtractLimitExceeded(format!(
+                "more than {} files",
+                self.max_file_count
+            )));
+        }
+        if usage.total_size > self.max_total_size {
+            return Err(ExtractLimitExceeded(format!(
+                "more than {} bytes in total",
+                self.max_total_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check the technical listing of an archive (`7z l -slt`).
+    ///
+    /// Sizes in the listing come from the archive headers, which may lie, so
+    /// the extracted files must be checked again with
+    /// [`check_dir`](Self::check_dir).
+    pub fn check_listing(&self, listing: &str) -> Result<(), ExtractLimitExceeded> {
+        let mut usage = Usage::default();
+        // Entries come after the separator line, one block of `key = value`
+        // lines per entry.
+        let entries = listing
+            .lines()
+            .skip_while(|line| line.trim() != "----------")
+            .skip(1);
+
+        let mut path = None;
+        let mut size = 0;
+        for line in entries.chain(std::iter::once("")) {
+            let line = line.trim();
+            if line.is_empty() {
+                if let Some(path) = path.take() {
+                    self.check_entry(&mut usage, Path::new(path), size)?;
+                }
+                size = 0;
+            } else if let Some((key, value)) = line.split_once(" = ") {
+                match key {
+                    "Path" => path = Some(value),
+                    "Size" => size = value.parse().unwrap_or(0),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the files inside `dir`. Symbolic links are not followed.
+    pub fn check_dir(&self, dir: &Path) -> anyhow::Result<()> {
+        let mut usage = Usage::default();
+        let mut stack = vec![dir.to_owned()];
+        while let Some(current) = stack.pop() {
+            let entries = match std::fs::read_dir(&current) {
+                Ok(x) => x,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                let path = entry.path();
+                let rel_path = path.strip_prefix(dir).unwrap_or(&path);
+                if meta.is_dir() {
+                    self.check_entry(&mut usage, rel_path, 0)?;
+                    stack.push(path);
+                } else {
+                    self.check_entry(&mut usage, rel_path, meta.len())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extract `archive` into `dir` with 7-Zip, enforcing `limits` on both the
+/// listing of the archive and the files being extracted.
+///
+/// If any limit is exceeded, the extraction is stopped, `dir` is removed and
+/// an [`ExtractLimitExceeded`] error is returned.
+pub async fn extract_archive(
+    archive: &Path,
+    dir: &Path,
+    limits: &ExtractLimits,
+) -> anyhow::Result<()> {
+    let res = extract_archive_inner(archive, dir, limits).await;
+    if let Err(e) = &res {
+        if e.is::<ExtractLimitExceeded>() {
+            log::warn!("Rejected archive {}: {}", archive.display(), e);
+            let _ = super::ensure_removed_dir(dir).await;
+        }
+    }
+    res
+}
+
+async fn extract_archive_inner(
+    archive: &Path,
+    dir: &Path,
+    limits: &ExtractLimits,
+) -> anyhow::Result<()> {
+    let listing = Command::new("7z")
+        .args(["l", "-slt", &archive.to_string_lossy()])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !listing.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "7zip failed to list archive, exited with output:\n{}",
+                String::from_utf8_lossy(&listing.stdout)
+            ),
+        )
+        .into());
+    }
+    limits.check_listing(&String::from_utf8_lossy(&listing.stdout))?;
+
+    let child = Command::new("7z")
+        .args([
+            "x",
+            &archive.to_string_lossy(),
+            &format!("-o{}", dir.to_string_lossy()),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    // Dropping this future kills 7-Zip.
+    let output = child.wait_with_output();
+    tokio::pin!(output);
+
+    let check = || {
+        let dir = dir.to_owned();
+        let limits = limits.clone();
+        async move { tokio::task::spawn_blocking(move || limits.check_dir(&dir)).await? }
+    };
+
+    let unzip_res = loop {
+        tokio::select! {
+            res = &mut output => break res?,
+            _ = tokio::time::sleep(CHECK_INTERVAL) => check().await?,
+        }
+    };
+    if !unzip_res.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "7zip failed to extract, exited with output:\n{}",
+                String::from_utf8_lossy(&unzip_res.stdout)
+            ),
+        )
+        .into());
+    }
+    check().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listing_limits() {
+        // A tiny archive claiming to expand into a huge file.
+        let listing = "\
+7-Zip [64] 16.02 : Copyright (c) 1999-2016 Igor Pavlov : 2016-05-21
+
+Listing archive: bomb.zip
+
+--
+Path = bomb.zip
+Type = zip
+Physical Size = 1100
+
+----------
+Path = data
+Folder = +
+Size = 0
+
+Path = data/zeros
+Folder = -
+Size = 10737418240
+Packed Size = 1000
+";
+        let limits = ExtractLimits::default();
+        let err = limits.check_listing(listing).unwrap_err();
+        assert!(err.0.contains("data/zeros"), "{}", err);
+
+        let ok = listing.replace("10737418240", "1024");
+        limits.check_listing(&ok).unwrap();
+
+        let many = ExtractLimits {
+            max_file_count: 1,
+            ..Default::default()
+        };
+        assert!(many.check_listing(&ok).is_err());
+
+        let escape = ok.replace("Path = data/zeros", "Path = ../zeros");
+        assert!(limits.check_listing(&escape).is_err());
+    }
+
+    #[test]
+    fn test_dir_limits() {
+        let root = std::env::temp_dir().join(format!("rurikawa-extract-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+        std::fs::write(root.join("a/b/c/zeros"), vec![0u8; 4096]).unwrap();
+
+        let res = |limits: ExtractLimits| {
+            limits
+                .check_dir(&root)
+                .map_err(|e| e.downcast::<ExtractLimitExceeded>().unwrap())
+        };
+        let ok = res(ExtractLimits::default());
+        let too_large = res(ExtractLimits {
+            max_file_size: 1024,
+            ..Default::default()
+        });
+        let too_large_total = res(ExtractLimits {
+            max_total_size: 1024,
+            ..Default::default()
+        });
+        let too_deep = res(ExtractLimits {
+            max_path_depth: 3,
+            ..Default::default()
+        });
+        let _ = std::fs::remove_dir_all(&root);
+
+        assert!(ok.is_ok());
+        assert!(too_large.is_err());
+        assert!(too_large_total.is_err());
+        assert!(too_deep.is_err());
+    }
+}
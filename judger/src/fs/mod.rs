@@ -1,16 +1,26 @@
 //! File-system-related stuff. Including manipulating test folders, performing git operations and so on.
 
+use crate::{tester::model::RequiredFile, util::path_security::assert_child_path};
 use futures::{future::BoxFuture, prelude::*};
+use ignore::gitignore::GitignoreBuilder;
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
-use tokio::fs::read_dir;
+use tokio::{fs::read_dir, io::AsyncWriteExt};
 
+pub mod extract;
 pub mod net;
 
 pub const JUDGE_FILE_NAME: &str = "judge.toml";
 
-/// Remove a directory recursively.
+/// Remove a directory recursively. If `path` is a symbolic link, only the
+/// link itself is removed.
 pub fn ensure_removed_dir(path: &Path) -> BoxFuture<Result<(), std::io::Error>> {
     async move {
+        if let Ok(meta) = tokio::fs::symlink_metadata(path).await {
+            if meta.file_type().is_symlink() {
+                return remove_dir_link(path).await;
+            }
+        }
         let entries = match read_dir(path).await {
             Ok(dir) => tokio_stream::wrappers::ReadDirStream::new(dir),
             Err(e) => match e.kind() {
@@ -45,36 +55,662 @@ pub fn ensure_removed_dir(path: &Path) -> BoxFuture<Result<(), std::io::Error>>
     .boxed()
 }
 
-pub fn find_judge_root(path: &Path) -> BoxFuture<Result<PathBuf, std::io::Error>> {
-    async move {
-        let mut dir = tokio_stream::wrappers::ReadDirStream::new(read_dir(path).await?);
-        let mut dirs = vec![];
-        let mut files = vec![];
-        while let Some(content) = dir.next().await {
-            let content = content?;
-            if content.file_type().await?.is_dir() {
-                dirs.push(content);
+#[cfg(windows)]
+async fn remove_dir_link(path: &Path) -> std::io::Result<()> {
+    tokio::fs::remove_dir(path).await
+}
+
+#[cfg(not(windows))]
+async fn remove_dir_link(path: &Path) -> std::io::Result<()> {
+    tokio::fs::remove_file(path).await
+}
+
+/// Name of the file in a test suite patch listing the paths deleted by the
+/// patch, one per line, relative to the root of the suite.
+pub const PATCH_DELETED_LIST: &str = ".rurikawa-deleted";
+
+/// Apply the patch extracted into `patch` onto `dir`. Files in the patch
+/// replace the ones in `dir`, and paths listed in [`PATCH_DELETED_LIST`] are
+/// removed. Everything else in `dir` is left untouched.
+///
+/// The patch is applied onto a copy of `dir` beside it, which then takes the
+/// place of `dir`, so that `dir` is never seen half-patched and is left as it
+/// was if the patch fails. Unchanged files are hard-linked into the copy
+/// where possible.
+pub async fn apply_patch(dir: &Path, patch: &Path) -> std::io::Result<()> {
+    let deleted = match tokio::fs::read_to_string(patch.join(PATCH_DELETED_LIST)).await {
+        Ok(list) => list,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let deleted = deleted
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    // Checked before touching anything, so that a bad patch changes nothing
+    for path in &deleted {
+        assert_child_path(Path::new(path))?;
+    }
+
+    let patched = sibling_path(dir, "patched");
+    let unpatched = sibling_path(dir, "unpatched");
+    // Left behind by an earlier patch that was interrupted
+    remove_dir_if_exists(&patched).await?;
+    remove_dir_if_exists(&unpatched).await?;
+
+    let res = async {
+        link_or_copy_dir(dir, &patched).await?;
+        apply_patch_files(&patched, patch, &deleted).await
+    }
+    .await;
+    if let Err(e) = res {
+        let _ = remove_dir_if_exists(&patched).await;
+        return Err(e);
+    }
+
+    if let Err(e) = tokio::fs::rename(dir, &unpatched).await {
+        let _ = remove_dir_if_exists(&patched).await;
+        return Err(e);
+    }
+    if let Err(e) = tokio::fs::rename(&patched, dir).await {
+        let _ = tokio::fs::rename(&unpatched, dir).await;
+        let _ = remove_dir_if_exists(&patched).await;
+        return Err(e);
+    }
+    if let Err(e) = remove_dir_if_exists(&unpatched).await {
+        log::warn!("Failed to remove {:?}: {}", unpatched, e);
+    }
+    Ok(())
+}
+
+/// Copy the files in `patch` into `dir` and remove the `deleted` paths from
+/// it. Files in `dir` may be hard links shared with other folders, so they
+/// are replaced rather than written through.
+async fn apply_patch_files(dir: &Path, patch: &Path, deleted: &[&str]) -> std::io::Result<()> {
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(rel_dir) = dirs.pop() {
+        let mut entries = read_dir(patch.join(&rel_dir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let rel = rel_dir.join(entry.file_name());
+            if rel == Path::new(PATCH_DELETED_LIST) {
+                continue;
+            }
+            let file_type = entry.file_type().await?;
+            if file_type.is_symlink() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Patch contains a symbolic link {}", rel.display()),
+                ));
+            } else if file_type.is_dir() {
+                tokio::fs::create_dir_all(dir.join(&rel)).await?;
+                dirs.push(rel);
             } else {
-                files.push(content)
+                match tokio::fs::remove_file(dir.join(&rel)).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+                tokio::fs::copy(entry.path(), dir.join(&rel)).await?;
             }
         }
-        for f in files {
-            if f.file_name() == JUDGE_FILE_NAME {
-                return Ok(path.into());
+    }
+
+    for path in deleted {
+        let path = dir.join(path);
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(&path).await?,
+            Ok(_) => tokio::fs::remove_file(&path).await?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// A path beside `dir` named after it with `suffix`.
+fn sibling_path(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+/// Remove the directory `path` if it exists. Unlike [`ensure_removed_dir`],
+/// permissions of the files inside are not changed, since they may be hard
+/// links to files still in use.
+async fn remove_dir_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Recreate the directory `from` at `to`, hard-linking its files where
+/// possible and copying them otherwise.
+async fn link_or_copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(to).await?;
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(rel_dir) = dirs.pop() {
+        let mut entries = read_dir(from.join(&rel_dir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let rel = rel_dir.join(entry.file_name());
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                tokio::fs::create_dir(to.join(&rel)).await?;
+                dirs.push(rel);
+            } else if file_type.is_symlink() {
+                copy_symlink(&entry.path(), &to.join(&rel)).await?;
+            } else if tokio::fs::hard_link(entry.path(), to.join(&rel))
+                .await
+                .is_err()
+            {
+                tokio::fs::copy(entry.path(), to.join(&rel)).await?;
             }
         }
-        for d in dirs {
-            match find_judge_root(&d.path()).await {
-                Ok(res) => return Ok(res),
-                Err(_e) => {
-                    continue;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn copy_symlink(from: &Path, to: &Path) -> std::io::Result<()> {
+    let target = tokio::fs::read_link(from).await?;
+    tokio::fs::symlink(target, to).await
+}
+
+#[cfg(not(unix))]
+async fn copy_symlink(from: &Path, to: &Path) -> std::io::Result<()> {
+    tokio::fs::copy(from, to).await.map(|_| ())
+}
+
+/// Write `data` into `path` atomically, so that readers see either the old
+/// content or the new content, but never a partially-written file.
+///
+/// The data is written into a temporary file beside `path`, flushed to disk,
+/// and then renamed onto `path`.
+pub async fn write_atomic(path: &Path, data: impl AsRef<[u8]>) -> std::io::Result<()> {
+    write_atomic_inner(path, data.as_ref(), false).await
+}
+
+/// Write `data` into `path` atomically like [`write_atomic`], making the file
+/// only readable and writable by its owner on Unix.
+pub async fn write_private(path: &Path, data: impl AsRef<[u8]>) -> std::io::Result<()> {
+    write_atomic_inner(path, data.as_ref(), true).await
+}
+
+async fn write_atomic_inner(path: &Path, data: &[u8], private: bool) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let temp_path = path.with_file_name(temp_name);
+
+    let res = async {
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if private {
+            options.mode(0o600);
+        }
+        #[cfg(not(unix))]
+        let _ = private;
+        let mut file = options.open(&temp_path).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&temp_path, path).await
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    res
+}
+
+/// Retries of file system operations failing with transient errors.
+///
+/// This is aimed at caches on networked file systems (e.g. NFS), where
+/// operations occasionally fail with errors like `ESTALE` and succeed when
+/// retried. Local disks don't need it, so only a single attempt is made by
+/// default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FsRetry {
+    /// Maximum number of attempts of every operation, at least 1.
+    pub attempts: u32,
+    /// Milliseconds to wait before retrying, doubled after every attempt.
+    pub delay_ms: u64,
+}
+
+impl Default for FsRetry {
+    fn default() -> Self {
+        FsRetry {
+            attempts: 1,
+            delay_ms: 100,
+        }
+    }
+}
+
+/// Whether `e` may go away when the operation is retried.
+pub fn is_transient_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    if matches!(
+        e.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use nix::errno::Errno;
+        if let Some(code) = e.raw_os_error() {
+            return [Errno::ESTALE, Errno::EAGAIN, Errno::EINTR, Errno::EIO]
+                .iter()
+                .any(|x| *x as i32 == code);
+        }
+    }
+    false
+}
+
+impl FsRetry {
+    /// Run `op`, retrying it if it fails with a transient error.
+    pub async fn run<T, F, Fut>(&self, mut op: F) -> std::io::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = std::io::Result<T>>,
+    {
+        let mut delay = std::time::Duration::from_millis(self.delay_ms);
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Err(e) if attempt < self.attempts && is_transient_error(&e) => {
+                    log::warn!(
+                        "File system operation failed (attempt {}/{}), retrying: {}",
+                        attempt,
+                        self.attempts,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Create `path` and all of its parents, and verify that it is a
+    /// directory afterwards.
+    pub async fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        self.run(|| tokio::fs::create_dir_all(path)).await?;
+        verify_dir(path).await
+    }
+}
+
+/// Check that `path` exists and is a directory.
+pub async fn verify_dir(path: &Path) -> std::io::Result<()> {
+    let meta = tokio::fs::metadata(path).await?;
+    if !meta.is_dir() {
+        return Err(std::io::Error::other(format!(
+            "{} is not a directory after creating it",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// How [`find_judge_root`] looks for `judge.toml` inside a submission.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JudgeRootSearch {
+    /// Maximum depth of folders searched, where 0 means only the root folder
+    /// of the submission. `None` means no limit.
+    pub max_depth: Option<usize>,
+    /// Fail if more than one `judge.toml` is found at any depth, instead of
+    /// picking the shallowest one. Use this to reject submissions carrying
+    /// extra configs, e.g. inside vendored dependencies.
+    pub require_unique: bool,
+}
+
+/// Find the folder containing `judge.toml` inside `path`.
+///
+/// Folders are searched level by level, and the shallowest `judge.toml` is
+/// picked. Finding multiple ones at the same depth is an error listing all of
+/// them, since there is no way to tell which one is intended. Symbolic links
+/// are not followed.
+pub async fn find_judge_root(
+    path: &Path,
+    search: &JudgeRootSearch,
+) -> Result<PathBuf, std::io::Error> {
+    let mut found = vec![];
+    let mut level = vec![path.to_owned()];
+    let mut depth = 0;
+    while !level.is_empty() {
+        let mut next_level = vec![];
+        let mut found_here = vec![];
+        for dir in level {
+            let entries = match read_dir(&dir).await {
+                Ok(x) => x,
+                // Unreadable subfolders are skipped
+                Err(_) if depth > 0 => continue,
+                Err(e) => return Err(e),
+            };
+            let mut entries = tokio_stream::wrappers::ReadDirStream::new(entries);
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                if entry.file_type().await?.is_dir() {
+                    next_level.push(entry.path());
+                } else if entry.file_name() == JUDGE_FILE_NAME {
+                    found_here.push(dir.clone());
                 }
             }
         }
-        Err(std::io::Error::new(
+
+        if found_here.len() > 1 {
+            return Err(ambiguous_judge_root(found_here));
+        }
+        found.append(&mut found_here);
+        if !search.require_unique && !found.is_empty() {
+            break;
+        }
+        if search.max_depth == Some(depth) {
+            break;
+        }
+        level = next_level;
+        depth += 1;
+    }
+
+    match found.len() {
+        0 => Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
-            "Cannot find any folder that contains `judge.toml`.",
-        ))
+            format!(
+                "Cannot find any folder that contains `{}`.",
+                JUDGE_FILE_NAME
+            ),
+        )),
+        1 => Ok(found.pop().unwrap()),
+        _ => Err(ambiguous_judge_root(found)),
+    }
+}
+
+/// Descriptions of the requirements in `required` not met by the content of
+/// `root`, see [`RequiredFile::describe`]. Patterns are matched like
+/// gitignore patterns against the paths of all files and folders relative to
+/// `root`, except the ones inside `.git`. Symbolic links are not followed.
+pub async fn find_missing_required_files(
+    root: &Path,
+    required: &[RequiredFile],
+) -> std::io::Result<Vec<String>> {
+    if required.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let mut read = tokio_stream::wrappers::ReadDirStream::new(read_dir(root.join(&dir)).await?);
+        while let Some(entry) = read.next().await {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let is_dir = entry.file_type().await?.is_dir();
+            if is_dir {
+                if path == Path::new(".git") {
+                    continue;
+                }
+                dirs.push(path.clone());
+            }
+            entries.push((path, is_dir));
+        }
+    }
+
+    let mut missing = vec![];
+    for requirement in required {
+        let mut found = false;
+        for pattern in requirement.patterns() {
+            let mut builder = GitignoreBuilder::new(root);
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            let matcher = builder
+                .build()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            if entries
+                .iter()
+                .any(|(path, is_dir)| matcher.matched(path, *is_dir).is_ignore())
+            {
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            missing.push(requirement.describe());
+        }
+    }
+    Ok(missing)
+}
+
+fn ambiguous_judge_root(mut found: Vec<PathBuf>) -> std::io::Error {
+    found.sort();
+    let list = found
+        .iter()
+        .map(|x| format!("\n- {}", x.join(JUDGE_FILE_NAME).display()))
+        .collect::<String>();
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Found multiple `{}` files:{}", JUDGE_FILE_NAME, list),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_atomic_replaces_file() {
+        let root = std::env::temp_dir().join(format!("rurikawa-fs-{}", rand::random::<u32>()));
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        let path = root.join("suite.lock");
+
+        write_atomic(&path, "old").await.unwrap();
+        write_atomic(&path, "new").await.unwrap();
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let files = std::fs::read_dir(&root).unwrap().count();
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        assert_eq!(content, "new");
+        assert_eq!(files, 1, "temporary files should not be left behind");
+    }
+
+    #[tokio::test]
+    async fn test_apply_patch() {
+        let root = std::env::temp_dir().join(format!("rurikawa-fs-{}", rand::random::<u32>()));
+        let (suite, patch) = (root.join("suite"), root.join("patch"));
+        for dir in [suite.join("tests/old"), patch.join("tests")] {
+            tokio::fs::create_dir_all(dir).await.unwrap();
+        }
+        let files = [
+            (suite.join("testconf.json"), "{}"),
+            (suite.join("tests/1.in"), "1"),
+            (suite.join("tests/2.in"), "2"),
+            (suite.join("tests/old/3.in"), "3"),
+            (patch.join("tests/2.in"), "two"),
+            (patch.join(PATCH_DELETED_LIST), "tests/old\n"),
+        ];
+        for (path, content) in files.iter() {
+            tokio::fs::write(path, content).await.unwrap();
+        }
+        let modified = |path: &str| {
+            std::fs::metadata(suite.join(path))
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        let untouched = modified("tests/1.in");
+        // Files of the suite may be shared with other folders
+        let shared = root.join("shared.in");
+        std::fs::hard_link(suite.join("tests/2.in"), &shared).unwrap();
+
+        apply_patch(&suite, &patch).await.unwrap();
+        let read = |path: &str| std::fs::read_to_string(suite.join(path)).ok();
+        let res = (
+            read("tests/1.in"),
+            read("tests/2.in"),
+            read("testconf.json"),
+            suite.join("tests/old").exists(),
+            suite.join(PATCH_DELETED_LIST).exists(),
+            modified("tests/1.in"),
+            std::fs::read_to_string(&shared).unwrap(),
+        );
+
+        // A patch failing halfway leaves the suite as it was
+        #[cfg(unix)]
+        let broken = {
+            std::os::unix::fs::symlink("/", patch.join("tests/link")).unwrap();
+            let res = apply_patch(&suite, &patch).await;
+            std::fs::remove_file(patch.join("tests/link")).unwrap();
+            let siblings = std::fs::read_dir(&root).unwrap().count();
+            (res.is_err(), read("tests/1.in"), siblings)
+        };
+
+        // Deleted paths outside the suite are rejected
+        tokio::fs::write(patch.join(PATCH_DELETED_LIST), "../patch\n")
+            .await
+            .unwrap();
+        let escaping = apply_patch(&suite, &patch).await;
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(res.0.as_deref(), Some("1"));
+        assert_eq!(res.1.as_deref(), Some("two"));
+        assert_eq!(res.2.as_deref(), Some("{}"));
+        assert!(!res.3, "deleted directory should be removed");
+        assert!(!res.4, "list of deleted paths should not be copied");
+        assert_eq!(res.5, untouched);
+        assert_eq!(res.6, "2", "shared files should not be written through");
+        assert!(escaping.is_err());
+        #[cfg(unix)]
+        assert_eq!(broken, (true, Some("1".into()), 3));
+    }
+
+    #[tokio::test]
+    async fn test_find_missing_required_files() {
+        let root = std::env::temp_dir().join(format!("rurikawa-fs-{}", rand::random::<u32>()));
+        for dir in ["src/util", ".git"] {
+            tokio::fs::create_dir_all(root.join(dir)).await.unwrap();
+        }
+        for file in ["README.md", "src/util/solution.py", ".git/HEAD"] {
+            tokio::fs::write(root.join(file), "").await.unwrap();
+        }
+        let required = serde_json::from_value::<Vec<RequiredFile>>(serde_json::json!([
+            "README.md",
+            "src/**/*.py",
+            "*.py",
+            "src/",
+            "HEAD",
+            { "anyOf": ["Makefile", "CMakeLists.txt"] },
+            { "anyOf": ["LICENSE", "src/util"] },
+        ]))
+        .unwrap();
+
+        let missing = find_missing_required_files(&root, &required).await;
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(
+            missing.unwrap(),
+            vec!["`HEAD`", "one of `Makefile`, `CMakeLists.txt`"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_judge_root() {
+        let root = std::env::temp_dir().join(format!("rurikawa-fs-{}", rand::random::<u32>()));
+        for dir in ["a/b", "c/d/e", "vendor/x"] {
+            tokio::fs::create_dir_all(root.join(dir)).await.unwrap();
+        }
+        for file in ["a/judge.toml", "c/d/e/judge.toml"] {
+            tokio::fs::write(root.join(file), "").await.unwrap();
+        }
+        let find = |search: JudgeRootSearch| {
+            let root = root.clone();
+            async move { find_judge_root(&root, &search).await }
+        };
+
+        let shallowest = find(Default::default()).await;
+        let too_shallow = find(JudgeRootSearch {
+            max_depth: Some(0),
+            ..Default::default()
+        })
+        .await;
+        let not_unique = find(JudgeRootSearch {
+            require_unique: true,
+            ..Default::default()
+        })
+        .await;
+        let unique_within_depth = find(JudgeRootSearch {
+            max_depth: Some(2),
+            require_unique: true,
+        })
+        .await;
+
+        tokio::fs::write(root.join("vendor/judge.toml"), "")
+            .await
+            .unwrap();
+        let ambiguous = find(Default::default()).await;
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(shallowest.unwrap(), root.join("a"));
+        assert!(too_shallow.is_err());
+        assert!(not_unique.is_err());
+        assert_eq!(unique_within_depth.unwrap(), root.join("a"));
+        let err = ambiguous.unwrap_err().to_string();
+        assert!(err.contains("vendor"), "{}", err);
+        assert!(
+            err.contains(&format!("a{}judge.toml", std::path::MAIN_SEPARATOR)),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fs_retry() {
+        let retry = FsRetry {
+            attempts: 3,
+            delay_ms: 1,
+        };
+        let calls = std::cell::Cell::new(0);
+        let flaky = |fail_times| {
+            let calls = &calls;
+            move || {
+                calls.set(calls.get() + 1);
+                let res = if calls.get() <= fail_times {
+                    Err(std::io::Error::from(std::io::ErrorKind::Interrupted))
+                } else {
+                    Ok(calls.get())
+                };
+                async move { res }
+            }
+        };
+
+        assert_eq!(retry.run(flaky(2)).await.unwrap(), 3);
+        calls.set(0);
+        assert!(retry.run(flaky(3)).await.is_err());
+        assert_eq!(calls.get(), 3);
+
+        // Only one attempt by default
+        calls.set(0);
+        assert!(FsRetry::default().run(flaky(1)).await.is_err());
+        assert_eq!(calls.get(), 1);
+
+        // Non-transient errors are not retried
+        calls.set(0);
+        let res = retry
+            .run(|| {
+                calls.set(calls.get() + 1);
+                async { Err::<(), _>(std::io::Error::from(std::io::ErrorKind::NotFound)) }
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls.get(), 1);
     }
-    .boxed()
 }
@@ -1,6 +1,11 @@
 pub mod client;
 pub mod config;
+pub mod engine;
 pub mod fs;
+pub mod local;
 pub mod prelude;
+pub mod selftest;
 pub mod tester;
 pub mod util;
+
+pub use engine::Judger;
@@ -0,0 +1,540 @@
+//! Running test jobs in the local environment, without a coordinator.
+
+use crate::{
+    client::{
+        config::DockerConfig,
+        model::{JobResultKind, JobResultMsg, TestResult, TestResultKind},
+    },
+    config::{JudgeToml, JudgerPublicConfig},
+    fs::{self, JUDGE_FILE_NAME},
+    prelude::*,
+    tester::{
+        compare::CompareReport,
+        exec::{TestRecord, TestSuite},
+        model::{canonical_join, check_build_args, Image, JudgerPrivateConfig, TestSuiteOptions},
+        validate, ProcessInfo,
+    },
+};
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// The default name of the test suite config file.
+pub const SUITE_CONFIG_FILE_NAME: &str = "testconf.json";
+
+/// Options for running a job locally.
+#[derive(Debug, Clone)]
+pub struct LocalRunOptions {
+    /// Either a folder where `judge.toml` can be found in it or its
+    /// subfolders, or the `judge.toml` file itself.
+    pub job_path: PathBuf,
+    /// Path of the test suite config file. The folder containing it is used
+    /// as the root of the test suite.
+    pub suite_config_path: PathBuf,
+    /// Report the output comparison of every test, even accepted ones.
+    pub report_all: bool,
+    /// Shuffle tests with this seed instead of running them in order.
+    pub shuffle_seed: Option<u64>,
+    /// Skip the remaining tests after the first one not accepted.
+    pub fail_fast: bool,
+    /// Seed for randomized tests, see [`TestSuiteOptions::seed`].
+    pub seed: Option<u64>,
+    /// Host paths of shared data the test suite may mount by name.
+    pub shared_host_paths: HashMap<String, PathBuf>,
+    /// Run commands on this machine instead of in Docker, see
+    /// [`TestSuite::run_on_host`].
+    pub no_docker: bool,
+    /// Keep the output of every test in [`LocalRunResult::records`].
+    pub record_output: bool,
+}
+
+/// The result of a locally-run job.
+#[derive(Debug)]
+pub struct LocalRunResult {
+    /// A random id given to the job.
+    pub job_id: FlowSnake,
+    /// Results of every test, keyed by test name.
+    pub results: HashMap<String, TestResult>,
+    /// Comparison reports, if [`LocalRunOptions::report_all`] is set.
+    pub reports: HashMap<String, CompareReport>,
+    /// Names of tests in the order they ran.
+    pub order: Vec<String>,
+    /// The seed used to shuffle tests, if any.
+    pub shuffle_seed: Option<u64>,
+    /// The seed for randomized tests, if any.
+    pub seed: Option<u64>,
+    /// Network access attempts recorded while running, if the network was
+    /// audited.
+    pub network_attempts: Option<Vec<String>>,
+    /// Timing of every test, and its output if
+    /// [`LocalRunOptions::record_output`] is set.
+    pub records: Vec<TestRecord>,
+}
+
+/// Timing and output of a test in a [`LocalJobReport`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalTestReport {
+    pub duration_ms: u64,
+    pub output: Vec<ProcessInfo>,
+}
+
+/// A machine-readable report of a locally-run job. The job result is the
+/// one the coordinator would receive, with the timing and output of every
+/// test added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalJobReport {
+    #[serde(flatten)]
+    pub result: JobResultMsg,
+    /// Timing and output of every test that has run, keyed by test name.
+    pub tests: HashMap<String, LocalTestReport>,
+}
+
+impl LocalRunResult {
+    /// The report of this job.
+    pub fn report(&self) -> LocalJobReport {
+        let tests = self
+            .records
+            .iter()
+            .map(|x| {
+                let test = LocalTestReport {
+                    duration_ms: x.duration.as_millis() as u64,
+                    output: x.output.clone(),
+                };
+                (x.name.clone(), test)
+            })
+            .collect();
+        LocalJobReport {
+            result: JobResultMsg {
+                job_id: self.job_id,
+                job_result: JobResultKind::Accepted,
+                results: self.results.clone(),
+                message: None,
+                build_log: None,
+                network_attempts: self.network_attempts.clone(),
+                seed: self.seed,
+                chunked: false,
+            },
+            tests,
+        }
+    }
+
+    /// Whether every test in this job is accepted.
+    pub fn all_accepted(&self) -> bool {
+        self.results
+            .values()
+            .all(|x| matches!(x.kind, TestResultKind::Accepted))
+    }
+}
+
+/// Check the test suite package at `source` without Docker or a coordinator,
+/// returning a description of every problem found. `source` is either a
+/// local folder, a local zip package or an HTTP(S) URL of a zip package.
+/// Packages are extracted into a temporary folder first.
+///
+/// If the suite ships a `judge.toml`, e.g. for a sample job, it is checked
+/// as well.
+pub async fn validate_suite(
+    source: &str,
+    cancel: CancellationTokenHandle,
+) -> Result<validate::SuiteProblems> {
+    let folder = std::env::temp_dir().join(format!("rurikawa-validate-{}", FlowSnake::generate()));
+    if !(source.starts_with("http://") || source.starts_with("https://")) {
+        let path = Path::new(source);
+        let meta = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("reading {}", path.display()))?;
+        if meta.is_dir() {
+            return validate_suite_folder(path).await;
+        }
+        let res = async {
+            fs::extract::extract_archive(path, &folder, &Default::default())
+                .await
+                .with_context(|| format!("extracting {}", path.display()))?;
+            validate_suite_folder(&folder).await
+        }
+        .await;
+        let _ = tokio::fs::remove_dir_all(&folder).await;
+        return res;
+    }
+
+    let client = reqwest::Client::new();
+    let req = client
+        .get(source)
+        .build()
+        .context("building download request")?;
+    let res = async {
+        tokio::fs::create_dir_all(&folder).await?;
+        fs::net::download_unzip(
+            client,
+            req,
+            &folder,
+            &folder.with_extension("zip"),
+            &Default::default(),
+            cancel,
+        )
+        .await
+        .with_context(|| format!("downloading {}", source))?;
+        validate_suite_folder(&folder).await
+    }
+    .await;
+    let _ = tokio::fs::remove_dir_all(&folder).await;
+    res
+}
+
+async fn validate_suite_folder(folder: &Path) -> Result<validate::SuiteProblems> {
+    let config_path = folder.join(SUITE_CONFIG_FILE_NAME);
+    let cfg = tokio::fs::read(&config_path)
+        .await
+        .with_context(|| format!("reading {}", config_path.display()))?;
+    let cfg =
+        serde_json::from_slice::<JudgerPublicConfig>(&cfg).context("parsing test suite config")?;
+    let mut problems = validate::lint_suite(&cfg, folder).await?;
+
+    let judge_file = folder.join(JUDGE_FILE_NAME);
+    match tokio::fs::read(&judge_file).await {
+        Ok(judge) => match toml::from_slice::<JudgeToml>(&judge) {
+            Ok(judge) => {
+                problems.extend(validate::lint_judge_toml(&judge, &cfg.name, folder).await?)
+            }
+            Err(e) => problems
+                .errors
+                .push(format!("Invalid `{}`: {}", JUDGE_FILE_NAME, e)),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("reading {}", judge_file.display()));
+        }
+    }
+    Ok(problems)
+}
+
+/// Find the folder containing `judge.toml` from the given job path.
+async fn resolve_judge_file(job_path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let meta = tokio::fs::metadata(job_path)
+        .await
+        .with_context(|| format!("reading job path {}", job_path.display()))?;
+    if meta.is_file() {
+        let root = job_path.parent().map(|x| x.to_owned()).unwrap_or_default();
+        Ok((root, job_path.to_owned()))
+    } else {
+        let root = fs::find_judge_root(job_path, &Default::default())
+            .await
+            .context("finding judger root")?;
+        let file = root.join(JUDGE_FILE_NAME);
+        Ok((root, file))
+    }
+}
+
+/// Run a job in the local environment with Docker, or directly on this
+/// machine if [`LocalRunOptions::no_docker`] is set.
+pub async fn run_local(
+    opt: LocalRunOptions,
+    cancel: CancellationTokenHandle,
+) -> Result<LocalRunResult> {
+    let job_id = FlowSnake::generate();
+    let (job_root, judge_file) = resolve_judge_file(&opt.job_path).await?;
+
+    let judge_cfg = tokio::fs::read(&judge_file)
+        .await
+        .with_context(|| format!("reading {}", judge_file.display()))?;
+    let judge_cfg = toml::from_slice::<JudgeToml>(&judge_cfg).context("parsing judger config")?;
+
+    let suite_root = opt
+        .suite_config_path
+        .parent()
+        .map(|x| x.to_owned())
+        .unwrap_or_default();
+    let public_cfg = tokio::fs::read(&opt.suite_config_path)
+        .await
+        .with_context(|| format!("reading {}", opt.suite_config_path.display()))?;
+    let mut public_cfg = serde_json::from_slice::<JudgerPublicConfig>(&public_cfg)
+        .context("parsing test suite config")?;
+    public_cfg.binds.get_or_insert_with(Vec::new);
+    public_cfg
+        .resolve_shared_binds(&opt.shared_host_paths)
+        .map_err(anyhow::Error::msg)?;
+
+    let job_cfg = judge_cfg
+        .jobs
+        .get(&public_cfg.name)
+        .with_context(|| format!("Cannot find config for {} in `judge.toml`", public_cfg.name))?;
+
+    if let Image::Dockerfile { build_args, .. } = &job_cfg.image {
+        let suspicious = check_build_args(build_args).map_err(anyhow::Error::msg)?;
+        for name in suspicious {
+            log::warn!(
+                "Build arg `{}` looks like a secret. Build args are visible in image history!",
+                name
+            );
+        }
+    }
+
+    let missing = validate::find_missing_suite_files(&public_cfg, &suite_root).await?;
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "Test suite is missing files: {}",
+            missing.iter().map(|x| x.display()).join(", ")
+        );
+    }
+    let test_root_dir = canonical_join(&suite_root, &public_cfg.mapped_dir.from);
+    // Without Docker, test files are read where they are instead of from the
+    // mapped directory inside the container.
+    let mapped_test_root_dir = if opt.no_docker {
+        test_root_dir.clone()
+    } else {
+        public_cfg.mapped_dir.to.clone()
+    };
+    let private_cfg = JudgerPrivateConfig {
+        test_root_dir,
+        mapped_test_root_dir,
+    };
+
+    let mut tests = public_cfg
+        .test_groups
+        .values()
+        .flatten()
+        .map(|x| x.name.clone())
+        .collect::<Vec<_>>();
+    tests.sort();
+
+    let options = TestSuiteOptions {
+        tests,
+        time_limit: public_cfg.time_limit,
+        mem_limit: public_cfg.memory_limit.map(|x| x as usize),
+        build_image: !opt.no_docker,
+        remove_image: !opt.no_docker,
+        report_all: opt.report_all,
+        shuffle_seed: opt.shuffle_seed,
+        fail_fast: opt.fail_fast,
+        record_output_len: opt.record_output.then(|| usize::MAX),
+        seed: opt.seed,
+        docker_config: Default::default(),
+        output_budget: None,
+    };
+
+    let mut suite = TestSuite::from_config(
+        format!("local-{}", job_id),
+        job_cfg.image.clone(),
+        &suite_root,
+        private_cfg,
+        public_cfg,
+        job_cfg,
+        options,
+    )
+    .await
+    .context("during TestSuite::from_config")?;
+
+    let order = suite.test_order();
+    let results = if opt.no_docker {
+        log::warn!("Running commands without Docker, they are not isolated from this machine!");
+        suite
+            .run_on_host(job_root, cancel)
+            .await
+            .context("during TestSuite::run_on_host")?
+    } else {
+        let docker = DockerConfig::default().connect().await?;
+        suite
+            .run(docker, job_root, None, None, None, cancel)
+            .await
+            .context("during TestSuite::run")?
+    };
+
+    Ok(LocalRunResult {
+        job_id,
+        results,
+        reports: std::mem::take(&mut suite.reports),
+        order,
+        shuffle_seed: opt.shuffle_seed,
+        seed: opt.seed,
+        network_attempts: suite.network_attempts.take(),
+        records: std::mem::take(&mut suite.records),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SUITE_CONFIG: &str = r#"{
+        "name": "local",
+        "testGroups": { "default": ["a", "b"] },
+        "vars": { "$stdin": "in", "$stdout": "out" },
+        "run": [],
+        "mappedDir": { "from": "tests", "to": "/tests" }
+    }"#;
+
+    /// Run the job `files` on the host, without Docker.
+    async fn run_on_host(files: &[(&str, &str)]) -> anyhow::Result<LocalRunResult> {
+        let root = std::env::temp_dir().join(format!("rurikawa-local-{}", rand::random::<u32>()));
+        tokio::fs::create_dir_all(root.join("tests")).await.unwrap();
+        for (path, content) in files.iter() {
+            tokio::fs::write(root.join(path), content).await.unwrap();
+        }
+
+        let res = run_local(
+            LocalRunOptions {
+                job_path: root.clone(),
+                suite_config_path: root.join(SUITE_CONFIG_FILE_NAME),
+                report_all: false,
+                shuffle_seed: None,
+                fail_fast: false,
+                seed: None,
+                shared_host_paths: HashMap::new(),
+                no_docker: true,
+                record_output: true,
+            },
+            CancellationTokenHandle::new(),
+        )
+        .await;
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        res
+    }
+
+    /// Validate a well-formed suite folder with each file in `overrides`
+    /// replaced by its content, or removed if it is `None`.
+    async fn validate_with(overrides: &[(&str, Option<&str>)]) -> validate::SuiteProblems {
+        let mut files = vec![
+            (
+                JUDGE_FILE_NAME,
+                "[jobs.local]\nimage = { source = \"dockerfile\", path = \".\" }\nrun = [\"cat $stdin\"]\n",
+            ),
+            ("Dockerfile", "FROM alpine\n"),
+            (SUITE_CONFIG_FILE_NAME, SUITE_CONFIG),
+            ("tests/a.in", "1\n"),
+            ("tests/a.out", "1\n"),
+            ("tests/b.in", "2\n"),
+            ("tests/b.out", "2\n"),
+        ];
+        files.retain(|x| overrides.iter().all(|(file, _)| x.0 != *file));
+        files.extend(
+            overrides
+                .iter()
+                .filter_map(|(file, content)| Some((*file, (*content)?))),
+        );
+
+        let root =
+            std::env::temp_dir().join(format!("rurikawa-validate-{}", rand::random::<u32>()));
+        tokio::fs::create_dir_all(root.join("tests")).await.unwrap();
+        for (path, content) in files {
+            tokio::fs::write(root.join(path), content).await.unwrap();
+        }
+        let res = validate_suite(root.to_str().unwrap(), CancellationTokenHandle::new()).await;
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        res.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_validate_suite() {
+        let problems = validate_with(&[]).await;
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        let problems = validate_with(&[("tests/b.out", None)]).await;
+        assert!(problems.is_fatal());
+        assert!(problems.errors[0].starts_with("Missing file"));
+
+        let suite_config =
+            SUITE_CONFIG.replace("\"run\"", "\"testTimeLimits\": { \"c\": \"1s\" }, \"run\"");
+        let problems = validate_with(&[(SUITE_CONFIG_FILE_NAME, Some(&suite_config))]).await;
+        assert_eq!(
+            problems.errors,
+            ["Time limit is set for test \"c\", which is not defined"]
+        );
+
+        let judge = "[jobs.other]\nimage = { source = \"image\", tag = \"alpine\" }\nrun = [\"cat $stdin\"]\n";
+        let problems = validate_with(&[(JUDGE_FILE_NAME, Some(judge))]).await;
+        assert_eq!(
+            problems.errors,
+            ["No job named \"local\" in `judge.toml`, found \"other\""]
+        );
+
+        let judge = "[jobs.local]\nimage = { source = \"dockerfile\", path = \"../..\" }\nrun = [\"cat $stdin\"]\n";
+        let problems = validate_with(&[(JUDGE_FILE_NAME, Some(judge))]).await;
+        assert!(problems.is_fatal());
+        assert!(problems.errors[0].starts_with("Job \"local\": unsafe context directory"));
+
+        let problems = validate_with(&[("Dockerfile", None)]).await;
+        assert_eq!(problems.errors.len(), 1);
+        assert!(problems.errors[0].starts_with("Job \"local\": missing Dockerfile"));
+
+        let judge = "[jobs.local]\nimage = { source = \"image\", tag = \"alpine\" }\nlanguage = \"cobol\"\n";
+        let problems = validate_with(&[(JUDGE_FILE_NAME, Some(judge))]).await;
+        assert!(problems.errors[0].starts_with("Job \"local\": Unknown language \"cobol\""));
+
+        // Warnings alone are not fatal
+        let suite_config = SUITE_CONFIG.replace(
+            "\"run\"",
+            "\"parallelTests\": 2, \"specialJudgeScript\": \"spj.js\", \"run\"",
+        );
+        let problems = validate_with(&[
+            (SUITE_CONFIG_FILE_NAME, Some(&suite_config)),
+            ("spj.js", Some("")),
+        ])
+        .await;
+        assert!(!problems.is_fatal(), "{:?}", problems);
+        assert_eq!(problems.warnings.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_json_report() {
+        let res = run_on_host(&[
+            (
+                "judge.toml",
+                "[jobs.local]\nimage = { source = \"image\", tag = \"alpine\" }\nrun = [\"cat $stdin\"]\n",
+            ),
+            (SUITE_CONFIG_FILE_NAME, SUITE_CONFIG),
+            ("tests/a.in", "1\n"),
+            ("tests/a.out", "1\n"),
+            ("tests/b.in", "2\n"),
+            ("tests/b.out", "3\n"),
+        ])
+        .await;
+
+        let json = serde_json::to_string(&res.unwrap().report()).unwrap();
+        let report = serde_json::from_str::<LocalJobReport>(&json).unwrap();
+        assert_eq!(report.result.results["a"].kind, TestResultKind::Accepted);
+        assert_eq!(report.result.results["b"].kind, TestResultKind::WrongAnswer);
+        assert_eq!(report.tests["b"].output[0].stdout, "2\n");
+
+        // The coordinator can read the same JSON as a job result
+        let msg = serde_json::from_str::<JobResultMsg>(&json).unwrap();
+        assert_eq!(msg.job_id, report.result.job_id);
+        assert_eq!(msg.results.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_per_test_time_limit() {
+        let suite_config = r#"{
+            "name": "local",
+            "timeLimit": "10s",
+            "testTimeLimits": { "b": "100ms" },
+            "testGroups": { "default": ["a", "b"] },
+            "vars": { "$stdin": "in", "$stdout": "out" },
+            "run": [],
+            "mappedDir": { "from": "tests", "to": "/tests" }
+        }"#;
+        let res = run_on_host(&[
+            (
+                "judge.toml",
+                "[jobs.local]\nimage = { source = \"image\", tag = \"alpine\" }\nrun = [\"sleep 0.5 && cat $stdin\"]\n",
+            ),
+            (SUITE_CONFIG_FILE_NAME, suite_config),
+            ("tests/a.in", "1\n"),
+            ("tests/a.out", "1\n"),
+            ("tests/b.in", "2\n"),
+            ("tests/b.out", "2\n"),
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(res.results["a"].kind, TestResultKind::Accepted);
+        assert_eq!(res.results["b"].kind, TestResultKind::TimeLimitExceeded);
+    }
+}
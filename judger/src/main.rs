@@ -2,21 +2,22 @@ use clap::Clap;
 use dirs::home_dir;
 use once_cell::sync::OnceCell;
 use rurikawa_judger::{
-    client::{
-        client_loop, config::*, connect_to_coordinator, sink::WsSink, try_register, verify_self,
+    client::{config::*, metrics::serve_metrics},
+    local::{self, run_local, LocalRunOptions, SUITE_CONFIG_FILE_NAME},
+    prelude::{CancelReason, CancellationTokenHandle},
+    selftest::run_self_test,
+    tester::{
+        model::SEED_ENV_VAR,
+        runner::{memory_limits_enforceable, prune_networks, runs_as_host_root},
     },
-    prelude::CancellationTokenHandle,
+    Judger,
 };
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     process::exit,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::{reload, EnvFilter, FmtSubscriber};
 
 mod opt;
 
@@ -28,9 +29,35 @@ fn main() {
     let opt = opt::Opts::parse();
     tracing_log::LogTracer::builder().init().unwrap();
 
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(opt.opt.log_level)
-        .finish();
+    let filter = opt
+        .opt
+        .log_filter_file
+        .as_deref()
+        .and_then(read_log_filter_file)
+        .or_else(|| {
+            opt.opt.log_filter.as_deref().and_then(|directives| {
+                EnvFilter::try_new(directives)
+                    .map_err(|e| eprintln!("Invalid log filter {:?}: {}", directives, e))
+                    .ok()
+            })
+        })
+        .unwrap_or_else(|| EnvFilter::new(opt.opt.log_level.to_string()));
+    let initial_filter = filter.to_string();
+
+    let builder = FmtSubscriber::builder()
+        .with_env_filter(filter)
+        .with_filter_reloading();
+    let reload_handle = builder.reload_handle();
+    let subscriber = builder.finish();
+
+    let config_reload_handle = reload_handle.clone();
+    let log_filter_reloader = LogFilterReloader(Box::new(move |directives: Option<&str>| {
+        let filter =
+            EnvFilter::try_new(directives.unwrap_or(&initial_filter)).map_err(|e| e.to_string())?;
+        config_reload_handle
+            .reload(filter)
+            .map_err(|e| e.to_string())
+    }));
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
@@ -40,13 +67,245 @@ fn main() {
         .enable_all()
         .build()
         .expect("Failed to initialize runtime");
-    rt.block_on(async_main(opt));
+    if let Some(path) = opt.opt.log_filter_file.clone() {
+        rt.spawn(reload_log_filter_on_sighup(reload_handle, path));
+    }
+    rt.spawn(stop_on_sigterm());
+    rt.block_on(async_main(opt, log_filter_reloader));
+}
+
+/// Read log filter directives from the given file.
+fn read_log_filter_file(path: &Path) -> Option<EnvFilter> {
+    let directives = std::fs::read_to_string(path)
+        .map_err(|e| eprintln!("Failed to read log filter file {}: {}", path.display(), e))
+        .ok()?;
+    let directives = directives.trim();
+    EnvFilter::try_new(directives)
+        .map_err(|e| eprintln!("Invalid log filter {:?}: {}", directives, e))
+        .ok()
+}
+
+/// Reload the log filter from `path` every time SIGHUP is received, without
+/// interrupting running jobs.
+#[cfg(unix)]
+async fn reload_log_filter_on_sighup<S>(handle: reload::Handle<EnvFilter, S>, path: PathBuf)
+where
+    S: tracing::Subscriber,
+{
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Some(filter) = read_log_filter_file(&path) {
+            let display = filter.to_string();
+            match handle.reload(filter) {
+                Ok(()) => log::warn!("Log filter reloaded: {}", display),
+                Err(e) => log::error!("Failed to reload log filter: {}", e),
+            }
+        }
+    }
+}
+
+/// Stop like on Ctrl-C every time SIGTERM is received, so that containers and
+/// jobs are cleaned up before exiting.
+#[cfg(unix)]
+async fn stop_on_sigterm() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Failed to listen for SIGTERM: {}", e);
+            return;
+        }
+    };
+    while terminate.recv().await.is_some() {
+        handle_ctrl_c();
+    }
 }
 
-async fn async_main(opt: opt::Opts) {
+#[cfg(not(unix))]
+async fn stop_on_sigterm() {}
+
+#[cfg(not(unix))]
+async fn reload_log_filter_on_sighup<S>(_handle: reload::Handle<EnvFilter, S>, _path: PathBuf)
+where
+    S: tracing::Subscriber,
+{
+    log::warn!("Reloading log filter is only supported on Unix systems");
+}
+
+async fn async_main(opt: opt::Opts, log_filter_reloader: LogFilterReloader) {
     match opt.cmd {
-        opt::SubCmd::Connect(cmd) => client(cmd).await,
-        opt::SubCmd::Run(_) => {}
+        opt::SubCmd::Connect(cmd) => client(cmd, log_filter_reloader).await,
+        opt::SubCmd::Run(cmd) => run(cmd).await,
+        opt::SubCmd::SelfTest(cmd) => self_test(cmd).await,
+        opt::SubCmd::ValidateSuite(cmd) => validate_suite(cmd).await,
+    }
+}
+
+async fn run(cmd: opt::RunSubCmd) {
+    let json_output = cmd
+        .output
+        .clone()
+        .or_else(|| (cmd.format == opt::OutputFormat::Json).then(|| PathBuf::from("-")));
+    let options = LocalRunOptions {
+        job_path: cmd.job.unwrap_or_else(|| PathBuf::from(".")),
+        suite_config_path: cmd
+            .config
+            .unwrap_or_else(|| PathBuf::from(SUITE_CONFIG_FILE_NAME)),
+        report_all: cmd.report_all,
+        shuffle_seed: cmd.shuffle.map(|seed| seed.unwrap_or_else(rand::random)),
+        fail_fast: cmd.fail_fast,
+        seed: Some(cmd.seed.unwrap_or_else(rand::random)),
+        shared_host_paths: cmd.shared_path.into_iter().collect(),
+        no_docker: cmd.no_docker,
+        record_output: json_output.is_some(),
+    };
+
+    let handle = CancellationTokenHandle::new();
+    ABORT_HANDLE.set(handle.clone()).unwrap();
+
+    let res = match run_local(options, handle).await {
+        Ok(res) => res,
+        Err(e) => {
+            log::error!("Failed to run job: {:?}", e);
+            exit(1);
+        }
+    };
+
+    if let Some(path) = &json_output {
+        let report = serde_json::to_string_pretty(&res.report()).unwrap();
+        if path.as_os_str() == "-" {
+            println!("{}", report);
+            if !res.all_accepted() {
+                exit(1);
+            }
+            return;
+        }
+        if let Err(e) = std::fs::write(path, report) {
+            log::error!("Failed to write results into {}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+
+    if let Some(seed) = res.shuffle_seed {
+        println!("Tests shuffled with seed {}", seed);
+    }
+    if let Some(seed) = res.seed {
+        println!("Tests run with {}={}", SEED_ENV_VAR, seed);
+    }
+    for name in &res.order {
+        let result = &res.results[name];
+        match result.score {
+            Some(score) => println!("{}: {:?} (score {})", name, result.kind, score),
+            None => println!("{}: {:?}", name, result.kind),
+        }
+        if let Some(report) = res.reports.get(name) {
+            println!(
+                "  output {}, {} line(s) differ",
+                if report.matched {
+                    "matched"
+                } else {
+                    "mismatched"
+                },
+                report.mismatched_lines
+            );
+            for line in report.diff.lines() {
+                println!("  | {}", line);
+            }
+            if let Some(tokens) = &report.tokens {
+                println!("  {} token(s) differ", tokens.mismatched_tokens);
+                for x in &tokens.mismatches {
+                    println!(
+                        "  token {}: got {:?}, expected {:?}",
+                        x.index, x.got, x.expected
+                    );
+                }
+                if let Some(deviation) = tokens.max_float_deviation {
+                    println!("  largest numeric deviation: {:e}", deviation);
+                }
+            }
+        }
+    }
+    if let Some(attempts) = &res.network_attempts {
+        println!("{} network access attempt(s) recorded", attempts.len());
+        for attempt in attempts {
+            println!("  {}", attempt);
+        }
+    }
+
+    if !res.all_accepted() {
+        exit(1);
+    }
+}
+
+async fn self_test(cmd: opt::SelfTestSubCmd) {
+    let handle = CancellationTokenHandle::new();
+    ABORT_HANDLE.set(handle.clone()).unwrap();
+
+    let report = match run_self_test(cmd.keep_files, handle).await {
+        Ok(report) => report,
+        Err(e) => {
+            log::error!("Self-test failed to run: {:?}", e);
+            exit(1);
+        }
+    };
+
+    for check in &report.checks {
+        println!(
+            "{}: expected {:?}, got {:?} ... {}",
+            check.test,
+            check.expected,
+            check.got,
+            if check.passed() { "ok" } else { "FAILED" }
+        );
+    }
+    if cmd.keep_files {
+        println!("Sample suite kept at {}", report.folder.display());
+    }
+
+    if report.passed() {
+        println!("Self-test passed");
+    } else {
+        println!("Self-test failed");
+        exit(1);
+    }
+}
+
+async fn validate_suite(cmd: opt::ValidateSuiteSubCmd) {
+    let handle = CancellationTokenHandle::new();
+    ABORT_HANDLE.set(handle.clone()).unwrap();
+
+    let problems = match local::validate_suite(&cmd.source, handle).await {
+        Ok(problems) => problems,
+        Err(e) => {
+            log::error!("Failed to validate test suite: {:?}", e);
+            exit(1);
+        }
+    };
+
+    for error in &problems.errors {
+        println!("error: {}", error);
+    }
+    for warning in &problems.warnings {
+        println!("warning: {}", warning);
+    }
+    if problems.is_empty() {
+        println!("No problems found");
+    } else {
+        println!(
+            "{} error(s), {} warning(s) found",
+            problems.errors.len(),
+            problems.warnings.len()
+        );
+    }
+    if problems.is_fatal() {
+        exit(1);
     }
 }
 
@@ -79,8 +338,13 @@ async fn update_client_config(source_path: &Path, cfg: &ClientConfig) -> std::io
 }
 
 fn override_config_using_cmd(cmd: &opt::ConnectSubCmd, cfg: &mut ClientConfig) {
+    if let Some(key) = cmd.keyring_key.clone() {
+        cfg.access_token_keyring = Some(key);
+    }
     if let Some(token) = cmd.access_token.clone() {
         cfg.access_token = Some(token);
+    } else if let Some(key) = cfg.access_token_keyring.clone() {
+        read_keyring_token(&key, cfg);
     }
     if let Some(token) = cmd.register_token.clone() {
         cfg.register_token = Some(token);
@@ -92,14 +356,52 @@ fn override_config_using_cmd(cmd: &opt::ConnectSubCmd, cfg: &mut ClientConfig) {
         cfg.ssl = ssl;
     }
     if let Some(host) = cmd.host.clone() {
-        cfg.host = host;
+        cfg.host = host.as_str().into();
     }
     if let Some(tags) = cmd.tag.clone() {
         cfg.tags = Some(tags);
     }
 }
 
-async fn client(cmd: opt::ConnectSubCmd) {
+#[cfg(feature = "keyring")]
+fn read_keyring_token(key: &str, cfg: &mut ClientConfig) {
+    match keyring_access_token(key) {
+        Ok(Some(token)) => cfg.access_token = Some(token),
+        Ok(None) => log::warn!("No access token found in keyring under key {:?}", key),
+        Err(e) => log::error!("Failed to read access token from keyring: {}", e),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn read_keyring_token(_key: &str, _cfg: &mut ClientConfig) {
+    log::warn!("A keyring key is supplied, but this judger is built without `keyring` feature");
+}
+
+/// Save the access token into keyring if requested, and strip it from the
+/// config to be written into file. Fails if the token cannot be saved, so that
+/// it is neither written into file nor lost.
+fn save_keyring_token(cfg: &ClientConfig) -> Result<ClientConfig, String> {
+    let mut cfg = cfg.clone();
+    if let (Some(key), Some(token)) = (&cfg.access_token_keyring, &cfg.access_token) {
+        #[cfg(feature = "keyring")]
+        set_keyring_access_token(key, token)
+            .map_err(|e| format!("Failed to save access token into keyring: {}", e))?;
+        #[cfg(not(feature = "keyring"))]
+        {
+            let _ = (key, token);
+            return Err(
+                "Cannot save access token into keyring: this judger is built without `keyring` feature"
+                    .into(),
+            );
+        }
+    }
+    if cfg.access_token_keyring.is_some() {
+        cfg.access_token = None;
+    }
+    Ok(cfg)
+}
+
+async fn client(cmd: opt::ConnectSubCmd, log_filter_reloader: LogFilterReloader) {
     let cache_folder = cmd.temp_folder_path.clone().unwrap_or_else(|| {
             let mut dir =
                 home_dir().expect("Failed to get home directory. Please provide a storage folder manually via `--temp-folder-path <path>`");
@@ -114,121 +416,130 @@ async fn client(cmd: opt::ConnectSubCmd) {
 
     override_config_using_cmd(&cmd, &mut cfg);
     cfg.cache_folder = cache_folder.clone();
+    if cfg.max_concurrent_tasks == 0 {
+        log::error!(
+            "`--concurrent-tasks` (or `max_concurrent_tasks` in config) must be at least 1"
+        );
+        exit(1);
+    }
+    if let Err(e) = cfg.check_runtime_fields() {
+        log::error!("Invalid config: {}", e);
+        exit(1);
+    }
+    if let Err(e) = cfg.docker_config.check() {
+        log::error!("Invalid docker config: {}", e);
+        exit(1);
+    }
 
+    let tls_config = match cfg.tls.client_config() {
+        Ok(tls) => tls,
+        Err(e) => {
+            log::error!("Invalid TLS config: {}", e);
+            exit(1);
+        }
+    };
     let mut cfg = SharedClientData::new(cfg);
+    cfg.set_tls_config(tls_config);
+    cfg.log_filter_reloader = Some(log_filter_reloader);
+    if cfg.cfg().log_filter.is_some() {
+        cfg.apply_log_filter();
+    }
+    cfg.max_jobs = if cmd.once { Some(1) } else { cmd.max_jobs };
+    if cfg.max_jobs == Some(0) {
+        log::error!("`--max-jobs` must be at least 1");
+        exit(1);
+    }
 
-    let verify_res = verify_self(&cfg)
-        .await
-        .expect("Error when verifying judger status");
-
-    // retry register if not verified or force refresh
-    let refresh = !verify_res || cmd.refresh;
-    if refresh {
-        log::warn!("Verification failed. Registering.");
-        let register_res = try_register(&mut cfg, refresh)
-            .await
-            .expect("Error when registering judger");
-        if !register_res {
-            panic!("Judger cannot be registered. Please check your register token.");
-        }
-        if !verify_self(&cfg)
-            .await
-            .expect("Error when verifying judger status")
-        {
-            panic!("Judger cannot be verified with the latest access token! This might be a server issue.");
+    let judger = Judger::from_shared(cfg);
+    log::info!(
+        "Judger tags: [{}]",
+        judger.shared().cfg().effective_tags().join(", ")
+    );
+    let docker_config = judger.shared().cfg().docker_config.clone();
+    match docker_config.connect().await {
+        Ok(docker) => {
+            log::info!("Using Docker API version {}", docker.client_version());
+            match memory_limits_enforceable(&docker).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::error!(
+                        "Docker on this host does not enforce memory limits! Test suites with \
+                         memory limits {}. Enable memory and swap accounting to fix this.",
+                        if docker_config.reject_unenforceable_memory_limits {
+                            "will be rejected"
+                        } else {
+                            "will run unconstrained"
+                        }
+                    );
+                    judger
+                        .shared()
+                        .memory_limits_enforceable
+                        .store(false, Ordering::SeqCst);
+                }
+                Err(e) => log::warn!("Failed to check if memory limits are enforceable: {}", e),
+            }
+            match runs_as_host_root(&docker, &docker_config).await {
+                Ok(true) => log::warn!(
+                    "Tests will run as root on the host! Configure an unprivileged `run_user`, \
+                     or enable user namespace remapping in Docker."
+                ),
+                Ok(false) => {}
+                Err(e) => log::warn!("Failed to check the user namespace of Docker: {}", e),
+            }
+            match prune_networks(&docker).await {
+                Ok(0) => {}
+                Ok(n) => log::info!("Removed {} networks left behind by earlier runs", n),
+                Err(e) => log::warn!("Failed to prune stale networks: {}", e),
+            }
         }
+        Err(e) => log::warn!("Failed to connect to Docker: {:#}", e),
+    }
+    if let Err(e) = judger.ensure_registered(cmd.refresh).await {
+        log::error!("Cannot authenticate with the coordinator: {:#}", e);
+        exit(1);
     }
 
     tokio::fs::create_dir_all(&cache_folder).await.unwrap();
     if !cmd.no_save {
-        update_client_config(&cache_folder, &cfg.cfg())
-            .await
-            .unwrap();
+        match save_keyring_token(&judger.shared().cfg()) {
+            Ok(mut saved_cfg) => {
+                // The access token got by registering is kept in its own
+                // private file
+                if saved_cfg.access_token.is_some()
+                    && saved_cfg.access_token == read_saved_access_token(&cache_folder)
+                {
+                    saved_cfg.access_token = None;
+                }
+                update_client_config(&cache_folder, &saved_cfg)
+                    .await
+                    .unwrap();
+            }
+            Err(e) => log::error!("{}; config not saved", e),
+        }
     }
 
-    let client_config = Arc::new(cfg);
-    let handle = client_config.cancel_handle.clone();
-    ABORT_HANDLE.set(handle).unwrap();
-
-    const START_WAIT_TIME: Duration = Duration::from_millis(250);
-    const MAX_WAIT_TIME: Duration = Duration::from_secs(256);
-    let mut wait_time = START_WAIT_TIME;
-
-    let client_sink = Arc::new(WsSink::new());
-
-    loop {
-        client_sink.clear_socket();
-        let (sink, stream) = match connect_to_coordinator(&client_config).await {
-            Ok(e) => e,
+    if let Some(addr) = cmd.metrics_addr {
+        let listener = match std::net::TcpListener::bind(addr)
+            .and_then(|x| x.set_nonblocking(true).map(|_| x))
+        {
+            Ok(listener) => listener,
             Err(e) => {
-                // Exponential wait time
-                tracing::warn!("Failed to connect: {}", e);
-                tokio::time::sleep(wait_time).await;
-                wait_time = std::cmp::min(wait_time.mul_f64(1.6), MAX_WAIT_TIME);
-                continue;
+                log::error!("Cannot serve metrics at {}: {}", addr, e);
+                exit(1);
             }
         };
-        wait_time = START_WAIT_TIME;
-        client_sink.load_socket(sink);
-
-        client_loop(stream, client_sink.clone(), client_config.clone()).await;
-        if client_config.cancel_handle.is_cancelled() {
-            break;
-        }
-    }
-
-    tracing::warn!("Preparing to stop jobs.");
-
-    tracing::warn!("Collecting cancelling jobs.");
-    let mut cancelling_guard = client_config.cancelling_job_handles.lock().await;
-    let mut cancelling = cancelling_guard.drain().collect::<Vec<_>>();
-    drop(cancelling_guard);
-
-    tracing::warn!("Collecting running jobs.");
-    let mut running_guard = client_config.running_job_handles.lock().await;
-    let mut running = running_guard.drain().collect::<Vec<_>>();
-    drop(running_guard);
-
-    {
-        // tracing::warn!("Awaiting pending jobs.");
-        // let res = client_sink
-        //     .send_all(&mut futures::stream::iter(
-        //         cancelling
-        //             .iter()
-        //             .map(|x| x.0)
-        //             .chain(running.iter().map(|x| x.0))
-        //             .map(|id| JobResultMsg {
-        //                 job_id: id,
-        //                 job_result: rurikawa_judger::client::model::JobResultKind::Aborted,
-        //                 results: HashMap::new(),
-        //                 message: Some("This job was aborted by judger".into()),
-        //             })
-        //             .map(|result| {
-        //                 Ok(tungstenite::Message::Text(
-        //                     serde_json::to_string(&result).unwrap(),
-        //                 ))
-        //             }),
-        //     ))
-        //     .await;
-
-        // if res.is_err() {
-        //     log::error!("Failed to send abort messages: {}", res.unwrap_err())
-        // }
-    }
-
-    // tracing::warn!("Abort messages sent.");
-
-    let cancelling = cancelling.drain(..).map(|(id, fut)| {
-        log::info!("Waiting for job {} to cancel...", id);
-        fut
-    });
-    let running = running.drain(..).map(|(id, fut)| {
-        log::info!("Waiting for job {} to abort...", id);
-        fut.0
-    });
-    futures::future::join_all(cancelling.chain(running)).await;
-
-    tracing::warn!("All things cancelled");
+        log::info!("Serving metrics at http://{}/metrics", addr);
+        let data = judger.shared().clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listener, data).await {
+                log::error!("Metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    ABORT_HANDLE.set(judger.cancel_handle()).unwrap();
+
+    judger.connect_and_serve().await;
 }
 
 fn handle_ctrl_c() {
@@ -236,7 +547,7 @@ fn handle_ctrl_c() {
         log::warn!("Waiting for existing jobs to complete... Press Ctrl-C again to force quit.");
         CTRL_C.store(true, Ordering::SeqCst);
         if let Some(x) = ABORT_HANDLE.get() {
-            x.cancel();
+            x.cancel(CancelReason::Shutdown);
         }
     } else if !CTRL_C_TWICE.load(Ordering::SeqCst) {
         log::error!("Force quit!");
@@ -1,6 +1,6 @@
 use clap::Clap;
 use clap::{crate_authors, crate_license, crate_version};
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
 /// The judger client of the online judging platform Rurikawa OJ.
 #[derive(Clap, Debug, Clone)]
@@ -23,6 +23,17 @@ pub struct Opts {
 pub struct GlobalOpts {
     #[clap(long, short = 'l', default_value = "info", env = "LOG_LEVEL")]
     pub log_level: tracing::level_filters::LevelFilter,
+
+    /// Per-module log filter directives in `RUST_LOG` style, e.g.
+    /// `info,rurikawa_judger::client=debug`. Overrides `--log-level`.
+    #[clap(long, env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+
+    /// A file containing log filter directives. Its content is applied when
+    /// the judger starts and every time it receives SIGHUP. Overrides
+    /// `--log-filter` and `--log-level`.
+    #[clap(long, env = "RURIKAWA_LOG_FILTER_FILE")]
+    pub log_filter_file: Option<PathBuf>,
     // #[clap(long = "docker")]
     // pub docker_path: String,
 }
@@ -36,11 +47,21 @@ pub enum SubCmd {
     /// Run a single test job in local environment
     #[clap(name = "run")]
     Run(RunSubCmd),
+
+    /// Run a bundled sample test suite to check if this judger works
+    #[clap(name = "self-test")]
+    SelfTest(SelfTestSubCmd),
+
+    /// Check a test suite package for problems without running it. Exits
+    /// with 1 if jobs of the suite would fail
+    #[clap(name = "validate", alias = "validate-suite")]
+    ValidateSuite(ValidateSuiteSubCmd),
 }
 
 #[derive(Clap, Debug, Clone)]
 pub struct ConnectSubCmd {
-    /// The coordinator's address (include port if needed).
+    /// The coordinator's address (include port if needed), or a
+    /// comma-separated list of them to fail over between.
     /// The previous host will be used if not supplied.
     #[clap(env = "RURIKAWA_HOST")]
     pub host: Option<String>,
@@ -49,8 +70,14 @@ pub struct ConnectSubCmd {
     #[clap(long, short, env = "RURIKAWA_SSL")]
     pub ssl: Option<bool>,
 
-    /// Max task count that can be runned concurrently.
-    #[clap(long, short, env = "RURIKAWA_CONCURRENT_TASKS")]
+    /// Max task count that can be runned concurrently, overriding
+    /// `max_concurrent_tasks` in the config. Must be at least 1.
+    #[clap(
+        long,
+        short,
+        alias = "max-concurrent-tasks",
+        env = "RURIKAWA_CONCURRENT_TASKS"
+    )]
     pub concurrent_tasks: Option<usize>,
 
     /// Path of temp folder, defaults to ~/.rurikawa/
@@ -61,6 +88,12 @@ pub struct ConnectSubCmd {
     #[clap(long, env = "RURIKAWA_ACCESS_TOKEN")]
     pub access_token: Option<String>,
 
+    /// Read the access token from the system keyring under this key name.
+    /// Command line and environment tokens take precedence over the keyring,
+    /// which takes precedence over the config file.
+    #[clap(long, env = "RURIKAWA_KEYRING_KEY")]
+    pub keyring_key: Option<String>,
+
     /// Supply or override existing register token
     #[clap(long, short, env = "RURIKAWA_REGISTER_TOKEN")]
     pub register_token: Option<String>,
@@ -81,6 +114,19 @@ pub struct ConnectSubCmd {
     /// Do not save updated data into config file.
     #[clap(long, env = "RURIKAWA_NO_SAVE")]
     pub no_save: bool,
+
+    /// Accept exactly one job, and exit after it finishes. Same as `--max-jobs 1`.
+    #[clap(long, conflicts_with = "max-jobs")]
+    pub once: bool,
+
+    /// Accept at most this many jobs in total, and exit after all of them finish.
+    #[clap(long, name = "max-jobs")]
+    pub max_jobs: Option<usize>,
+
+    /// Serve metrics of this judger in the Prometheus text format at
+    /// `/metrics` on this address, e.g. `127.0.0.1:9100`.
+    #[clap(long, env = "RURIKAWA_METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
 }
 
 #[derive(Clap, Debug, Clone)]
@@ -91,7 +137,93 @@ pub struct RunSubCmd {
     #[clap(name = "job-path")]
     pub job: Option<PathBuf>,
 
-    /// Configuration file of tests.
+    /// Configuration file of tests. Defaults to `testconf.json` in current folder.
     #[clap(long, short, name = "config-file-path")]
     pub config: Option<PathBuf>,
+
+    /// Report the detailed output comparison of every test, including
+    /// accepted ones.
+    #[clap(long)]
+    pub report_all: bool,
+
+    /// Run tests in a random order instead of by their names. A random seed
+    /// is used if not specified. The seed is printed for reproduction.
+    #[clap(long, name = "seed")]
+    pub shuffle: Option<Option<u64>>,
+
+    /// Stop at the first test not accepted, skipping the remaining ones.
+    #[clap(long)]
+    pub fail_fast: bool,
+
+    /// Seed for randomized tests, passed to tests as `RURIKAWA_SEED`. A
+    /// random seed is used if not specified. The seed is printed for
+    /// reproduction.
+    #[clap(long, name = "test-seed")]
+    pub seed: Option<u64>,
+
+    /// Provide shared data to be mounted by the test suite, in the form of
+    /// `name=/absolute/host/path`. Can be specified multiple times.
+    #[clap(long, name = "name=path", parse(try_from_str = parse_shared_path))]
+    pub shared_path: Vec<(String, PathBuf)>,
+
+    /// Run commands directly on this machine in the job folder instead of in
+    /// a Docker container, without building the image. Meant for debugging
+    /// the commands of a suite without a Docker daemon. Suites with binds or
+    /// service containers are rejected, and only the time limit is enforced.
+    ///
+    /// INSECURE: commands can do anything you can. Only use it locally with
+    /// suites and jobs you trust.
+    #[clap(long)]
+    pub no_docker: bool,
+
+    /// Format of the results printed: `text` for humans, or `json` for the
+    /// same report as `--output -`.
+    #[clap(long, default_value = "text", possible_values = &["text", "json"])]
+    pub format: OutputFormat,
+
+    /// Write the results as JSON into this file, or to stdout if it's `-`.
+    /// Test results have the same schema as the ones sent to the
+    /// coordinator, along with the timing and output of every test.
+    #[clap(long, short, name = "output-path")]
+    pub output: Option<PathBuf>,
+}
+
+/// Format of the results of the `run` subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+fn parse_shared_path(s: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `name=path`, got {:?}", s))?;
+    Ok((name.to_owned(), PathBuf::from(path)))
+}
+
+#[derive(Clap, Debug, Clone)]
+pub struct ValidateSuiteSubCmd {
+    /// A folder containing `testconf.json`, a zip package or the URL of one.
+    #[clap(name = "path-or-url")]
+    pub source: String,
+}
+
+#[derive(Clap, Debug, Clone)]
+pub struct SelfTestSubCmd {
+    /// Keep the files of the sample test suite after running.
+    #[clap(long)]
+    pub keep_files: bool,
 }
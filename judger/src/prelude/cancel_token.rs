@@ -1,11 +1,83 @@
 use async_trait::async_trait;
 use futures::{pin_mut, Future, FutureExt};
+use std::sync::{Arc, Mutex};
 
-// as tokio_util releases v0.4.0, these types are no longer required.
+pub type CancellationToken<'a> = tokio_util::sync::WaitForCancellationFuture<'a>;
 
-pub type CancellationTokenHandle = tokio_util::sync::CancellationToken;
+/// Why a [`CancellationTokenHandle`] was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// The user cancelled the job.
+    UserCancelled,
+    /// The job ran out of time.
+    Timeout,
+    /// The coordinator aborted the job, so that it can be run again.
+    ServerAbort,
+    /// The judger is shutting down.
+    Shutdown,
+    /// The job was dispatched again and replaced by the new dispatch, which
+    /// reports the results instead.
+    Replaced,
+}
 
-pub type CancellationToken<'a> = tokio_util::sync::WaitForCancellationFuture<'a>;
+/// A cancellation token remembering why it was cancelled.
+///
+/// Child tokens are cancelled along with their parent, and report the reason
+/// of the parent unless they were cancelled by themselves first.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationTokenHandle {
+    token: tokio_util::sync::CancellationToken,
+    /// Set once this token itself is cancelled, to the reason given if any.
+    reason: Arc<Mutex<Option<Option<CancelReason>>>>,
+    parent: Option<Box<CancellationTokenHandle>>,
+}
+
+impl CancellationTokenHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token cancelled along with this one, which can also be cancelled
+    /// on its own.
+    pub fn child_token(&self) -> Self {
+        CancellationTokenHandle {
+            token: self.token.child_token(),
+            reason: Default::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    /// Cancel this token and its children, for `reason` if given. The reason
+    /// of a token already cancelled is kept.
+    pub fn cancel(&self, reason: impl Into<Option<CancelReason>>) {
+        let mut own = self.reason.lock().unwrap();
+        if !self.token.is_cancelled() {
+            *own = Some(reason.into());
+        }
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Wait until this token is cancelled.
+    pub fn cancelled(&self) -> CancellationToken<'_> {
+        self.token.cancelled()
+    }
+
+    /// Why this token was cancelled, if it was and a reason was given.
+    pub fn cancellation_reason(&self) -> Option<CancelReason> {
+        if !self.is_cancelled() {
+            return None;
+        }
+        let own = *self.reason.lock().unwrap();
+        match own {
+            Some(reason) => reason,
+            None => self.parent.as_ref()?.cancellation_reason(),
+        }
+    }
+}
 
 #[async_trait]
 pub trait CancelFutureExt {
@@ -33,3 +105,52 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cancellation_reason() {
+        let root = CancellationTokenHandle::new();
+        let child = root.child_token();
+        let grandchild = child.child_token();
+        assert_eq!(grandchild.cancellation_reason(), None);
+
+        root.cancel(CancelReason::Shutdown);
+        assert!(grandchild.is_cancelled());
+        assert_eq!(child.cancellation_reason(), Some(CancelReason::Shutdown));
+        assert_eq!(
+            grandchild.cancellation_reason(),
+            Some(CancelReason::Shutdown)
+        );
+
+        // The first reason is kept
+        child.cancel(CancelReason::UserCancelled);
+        assert_eq!(child.cancellation_reason(), Some(CancelReason::Shutdown));
+    }
+
+    #[test]
+    fn test_child_cancellation_reason() {
+        let root = CancellationTokenHandle::new();
+        let child = root.child_token();
+        let sibling = root.child_token();
+
+        child.cancel(CancelReason::Timeout);
+        assert!(!root.is_cancelled());
+        assert!(!sibling.is_cancelled());
+        assert_eq!(child.cancellation_reason(), Some(CancelReason::Timeout));
+
+        root.cancel(CancelReason::ServerAbort);
+        assert_eq!(child.cancellation_reason(), Some(CancelReason::Timeout));
+        assert_eq!(
+            sibling.cancellation_reason(),
+            Some(CancelReason::ServerAbort)
+        );
+
+        let unexplained = CancellationTokenHandle::new();
+        unexplained.cancel(None);
+        assert!(unexplained.is_cancelled());
+        assert_eq!(unexplained.child_token().cancellation_reason(), None);
+    }
+}
@@ -0,0 +1,178 @@
+//! Self-test of the judger against a tiny sample test suite bundled in the
+//! binary.
+//!
+//! The sample suite is written into a temporary folder and run through the
+//! same path as local jobs, so a successful self-test means that Docker is
+//! usable and images can be built, containers run and outputs compared.
+
+use crate::{
+    client::model::TestResultKind,
+    local::{run_local, LocalRunOptions, SUITE_CONFIG_FILE_NAME},
+    prelude::*,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Files of the sample suite, as `(relative path, content)`.
+const SAMPLE_FILES: &[(&str, &str)] = &[
+    ("judge.toml", include_str!("sample/judge.toml")),
+    ("Dockerfile", include_str!("sample/Dockerfile")),
+    ("echo.sh", include_str!("sample/echo.sh")),
+    (SUITE_CONFIG_FILE_NAME, include_str!("sample/testconf.json")),
+    (
+        "tests/accepted.in",
+        include_str!("sample/tests/accepted.in"),
+    ),
+    (
+        "tests/accepted.out",
+        include_str!("sample/tests/accepted.out"),
+    ),
+    (
+        "tests/wrong_answer.in",
+        include_str!("sample/tests/wrong_answer.in"),
+    ),
+    (
+        "tests/wrong_answer.out",
+        include_str!("sample/tests/wrong_answer.out"),
+    ),
+    (
+        "tests/pipeline_failed.in",
+        include_str!("sample/tests/pipeline_failed.in"),
+    ),
+    (
+        "tests/pipeline_failed.out",
+        include_str!("sample/tests/pipeline_failed.out"),
+    ),
+    (
+        "tests/should_fail.in",
+        include_str!("sample/tests/should_fail.in"),
+    ),
+];
+
+/// Expected verdicts of every test in the sample suite.
+const EXPECTED_RESULTS: &[(&str, TestResultKind)] = &[
+    ("accepted", TestResultKind::Accepted),
+    ("wrong_answer", TestResultKind::WrongAnswer),
+    ("pipeline_failed", TestResultKind::PipelineFailed),
+    ("should_fail", TestResultKind::Accepted),
+];
+
+/// The verdict of a single test in the self-test.
+#[derive(Debug, Clone)]
+pub struct SelfTestCheck {
+    pub test: String,
+    pub expected: TestResultKind,
+    /// The actual verdict, `None` if the test did not produce any result.
+    pub got: Option<TestResultKind>,
+}
+
+impl SelfTestCheck {
+    pub fn passed(&self) -> bool {
+        self.got == Some(self.expected)
+    }
+}
+
+/// The result of a self-test.
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    /// Folder the sample suite was written into.
+    pub folder: PathBuf,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// Whether every test got its expected verdict.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|x| x.passed())
+    }
+}
+
+/// Write the sample suite into `folder`.
+async fn write_sample(folder: &Path) -> std::io::Result<()> {
+    for (path, content) in SAMPLE_FILES {
+        let path = folder.join(path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&path, content).await?;
+    }
+    Ok(())
+}
+
+/// Run the sample suite with Docker and check its verdicts. The sample suite
+/// is removed afterwards unless `keep_files` is set.
+pub async fn run_self_test(
+    keep_files: bool,
+    cancel: CancellationTokenHandle,
+) -> Result<SelfTestReport> {
+    let folder = std::env::temp_dir().join(format!("rurikawa-selftest-{}", FlowSnake::generate()));
+    write_sample(&folder)
+        .await
+        .with_context(|| format!("writing sample suite into {}", folder.display()))?;
+
+    let res = run_local(
+        LocalRunOptions {
+            job_path: folder.clone(),
+            suite_config_path: folder.join(SUITE_CONFIG_FILE_NAME),
+            report_all: false,
+            shuffle_seed: None,
+            fail_fast: false,
+            seed: None,
+            shared_host_paths: HashMap::new(),
+            no_docker: false,
+            record_output: false,
+        },
+        cancel,
+    )
+    .await;
+
+    if !keep_files {
+        let _ = tokio::fs::remove_dir_all(&folder).await;
+    }
+    let res = res.context("running sample suite")?;
+
+    let checks = EXPECTED_RESULTS
+        .iter()
+        .map(|(test, expected)| SelfTestCheck {
+            test: test.to_string(),
+            expected: *expected,
+            got: res.results.get(*test).map(|x| x.kind),
+        })
+        .collect();
+    Ok(SelfTestReport { folder, checks })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{JudgeToml, JudgerPublicConfig};
+
+    #[test]
+    fn test_sample_suite_is_consistent() {
+        let file = |name: &str| {
+            SAMPLE_FILES
+                .iter()
+                .find(|(path, _)| *path == name)
+                .map(|(_, content)| *content)
+                .unwrap()
+        };
+        let judge_cfg = toml::from_str::<JudgeToml>(file("judge.toml")).unwrap();
+        let public_cfg =
+            serde_json::from_str::<JudgerPublicConfig>(file(SUITE_CONFIG_FILE_NAME)).unwrap();
+        assert!(judge_cfg.jobs.contains_key(&public_cfg.name));
+
+        let mut tests = public_cfg
+            .test_groups
+            .values()
+            .flatten()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<_>>();
+        tests.sort_unstable();
+        let mut expected = EXPECTED_RESULTS.iter().map(|x| x.0).collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(tests, expected);
+    }
+}
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Echo every input line, and exit with an error on `crash`.
+while read -r line; do
+    if [ "$line" = crash ]; then
+        exit 3
+    fi
+    echo "$line"
+done
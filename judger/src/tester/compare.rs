@@ -0,0 +1,333 @@
+//! Comparison between the output of a test and its expected output.
+
+use super::utils::diff;
+use itertools::{EitherOrBoth, Itertools};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+static EOF_PATTERN: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new(r"\r?\n").unwrap());
+
+/// The detailed outcome of comparing a test's output against the expected one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CompareReport {
+    /// Whether the output is accepted by this comparison.
+    pub matched: bool,
+    /// Number of lines that differ between the two outputs.
+    pub mismatched_lines: usize,
+    /// A line diff of the two outputs, including unchanged lines.
+    pub diff: String,
+    /// The first line of the output longer than the line length limit,
+    /// counting from 1. The outputs are not diffed if there's one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_too_long: Option<usize>,
+    /// The token by token comparison of the outputs, if their lines differ.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<TokenReport>,
+}
+
+/// Maximum number of mismatched tokens listed in a [`TokenReport`].
+const MAX_LISTED_TOKEN_MISMATCHES: usize = 20;
+
+/// The outcome of comparing two outputs token by token, see
+/// [`compare_tokens`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenReport {
+    /// Number of tokens that differ, including missing and extra ones.
+    pub mismatched_tokens: usize,
+    /// The first [`MAX_LISTED_TOKEN_MISMATCHES`] tokens that differ.
+    pub mismatches: Vec<TokenMismatch>,
+    /// The largest absolute difference between differing tokens that are
+    /// both numbers, if there are such tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_float_deviation: Option<f64>,
+}
+
+/// A token of the output differing from the expected one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenMismatch {
+    /// Position of the token, counting from 0.
+    pub index: usize,
+    /// The token in the output, `None` if the output ends before it.
+    pub got: Option<String>,
+    /// The expected token, `None` if the output has extra tokens.
+    pub expected: Option<String>,
+}
+
+/// The region of a test's output to be compared against the expected output,
+/// for tests printing other stuff (e.g. debugging messages) before the answer.
+///
+/// The region is extracted before any comparison, from the output already
+/// truncated by the judger. A truncated output ends with a truncation notice,
+/// which is then part of `lastLines` regions and fails the comparison.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum OutputRegion {
+    /// The last `lines` lines, ignoring trailing empty lines.
+    LastLines { lines: usize },
+    /// Everything after the last line equal to `marker`, ignoring leading
+    /// and trailing whitespaces. Empty if there's no such line.
+    AfterMarker { marker: String },
+    /// Capture group `group` of the first match of the regular expression
+    /// `pattern`. Group 0 is the whole match. Empty if nothing matches.
+    Regex {
+        pattern: String,
+        #[serde(default = "default_regex_group")]
+        group: usize,
+    },
+}
+
+fn default_regex_group() -> usize {
+    1
+}
+
+impl OutputRegion {
+    /// Check that this region is valid.
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            OutputRegion::Regex { pattern, group } => {
+                let re = regex::Regex::new(pattern)
+                    .map_err(|e| format!("Invalid output region pattern: {}", e))?;
+                if *group >= re.captures_len() {
+                    return Err(format!(
+                        "Output region pattern {:?} has no capture group {}",
+                        pattern, group
+                    ));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Extract this region out of `output`.
+    pub fn extract<'a>(&self, output: &'a str) -> Cow<'a, str> {
+        match self {
+            OutputRegion::LastLines { lines } => {
+                let trimmed = output.trim_end();
+                let start = trimmed
+                    .rmatch_indices('\n')
+                    .nth(lines.saturating_sub(1))
+                    .map(|(i, _)| i + 1)
+                    .unwrap_or(0);
+                if *lines == 0 {
+                    Cow::Borrowed("")
+                } else {
+                    Cow::Borrowed(&trimmed[start..])
+                }
+            }
+            OutputRegion::AfterMarker { marker } => {
+                let mut start = None;
+                let mut offset = 0;
+                for line in output.split_inclusive('\n') {
+                    offset += line.len();
+                    if line.trim() == marker.trim() {
+                        start = Some(offset);
+                    }
+                }
+                Cow::Borrowed(start.map_or("", |start| &output[start..]))
+            }
+            OutputRegion::Regex { pattern, group } => regex::Regex::new(pattern)
+                .ok()
+                .and_then(|re| Some(re.captures(output)?.get(*group)?.as_str().to_owned()))
+                .map_or(Cow::Borrowed(""), Cow::Owned),
+        }
+    }
+}
+
+/// Default of [`JudgerPublicConfig::max_line_length`](super::model::JudgerPublicConfig::max_line_length).
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Compare `got` against `expected` line by line.
+///
+/// Leading and trailing whitespaces are trimmed and line endings are
+/// normalized before comparing. A line of `got` longer than `max_line_len`
+/// bytes and than every line of `expected` is a mismatch without a diff, so
+/// that pathological outputs are never diffed.
+pub fn compare_output(got: &str, expected: &str, max_line_len: usize) -> CompareReport {
+    // * Actually there is a test that should not have passed,
+    // * because the `.out` file is missing a `\n`.
+    // * We trim the result here anyway...
+    let got = EOF_PATTERN.replace_all(got.trim(), "\n");
+    let expected = EOF_PATTERN.replace_all(expected.trim(), "\n");
+
+    let max_line_len = expected
+        .lines()
+        .map(|x| x.len())
+        .fold(max_line_len, usize::max);
+    if let Some((i, line)) = got
+        .lines()
+        .enumerate()
+        .find(|(_, x)| x.len() > max_line_len)
+    {
+        return CompareReport {
+            tokens: None,
+            matched: false,
+            mismatched_lines: 1,
+            diff: format!(
+                "Output line too long: line {} has {} bytes, over the limit of {} bytes\n",
+                i + 1,
+                line.len(),
+                max_line_len
+            ),
+            line_too_long: Some(i + 1),
+        };
+    }
+
+    let (different, diff) = diff(&got, &expected);
+    let mismatched_lines = diff
+        .lines()
+        .filter(|l| l.starts_with('+') || l.starts_with('-'))
+        .count();
+    CompareReport {
+        matched: !different,
+        mismatched_lines,
+        diff,
+        line_too_long: None,
+        tokens: different.then(|| compare_tokens(&got, &expected)),
+    }
+}
+
+/// Compare `got` against `expected` token by token like [`tokens_match`],
+/// reporting the tokens that differ.
+pub fn compare_tokens(got: &str, expected: &str) -> TokenReport {
+    let mut report = TokenReport::default();
+    let pairs = got
+        .split_whitespace()
+        .zip_longest(expected.split_whitespace());
+    for (index, pair) in pairs.enumerate() {
+        let (got, expected) = match pair {
+            EitherOrBoth::Both(got, expected) if got == expected => continue,
+            EitherOrBoth::Both(got, expected) => (Some(got), Some(expected)),
+            EitherOrBoth::Left(got) => (Some(got), None),
+            EitherOrBoth::Right(expected) => (None, Some(expected)),
+        };
+        report.mismatched_tokens += 1;
+        if let (Some(Ok(got)), Some(Ok(expected))) =
+            (got.map(str::parse::<f64>), expected.map(str::parse::<f64>))
+        {
+            let deviation = (got - expected).abs();
+            if deviation.is_finite() {
+                report.max_float_deviation = Some(
+                    report
+                        .max_float_deviation
+                        .map_or(deviation, |x| x.max(deviation)),
+                );
+            }
+        }
+        if report.mismatches.len() < MAX_LISTED_TOKEN_MISMATCHES {
+            report.mismatches.push(TokenMismatch {
+                index,
+                got: got.map(Into::into),
+                expected: expected.map(Into::into),
+            });
+        }
+    }
+    report
+}
+
+/// Compare `got` against `expected` token by token, ignoring any difference
+/// in whitespaces and line breaks.
+pub fn tokens_match(got: &str, expected: &str) -> bool {
+    got.split_whitespace().eq(expected.split_whitespace())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_whitespace_only_difference() {
+        let expected = "1 2 3\n4 5 6\n";
+        let got = "1  2 3\r\n4\t5 6   \n\n";
+        assert!(!compare_output(got, expected, DEFAULT_MAX_LINE_LENGTH).matched);
+        assert!(tokens_match(got, expected));
+    }
+
+    #[test]
+    fn test_token_difference() {
+        let expected = "1 2 3\n4 5 6\n";
+        let got = "1 2 3\n4 5 7\n";
+        assert!(!compare_output(got, expected, DEFAULT_MAX_LINE_LENGTH).matched);
+        assert!(!tokens_match(got, expected));
+        assert!(!tokens_match("1 2 3 4 5 6 7", expected));
+    }
+
+    #[test]
+    fn test_token_report() {
+        let report = compare_output("1 2.0000001\n3\nx\n", "1 2\n4\n", DEFAULT_MAX_LINE_LENGTH);
+        let tokens = report.tokens.unwrap();
+        assert_eq!(tokens.mismatched_tokens, 3);
+        assert_eq!(
+            tokens.mismatches[2],
+            TokenMismatch {
+                index: 3,
+                got: Some("x".into()),
+                expected: None,
+            }
+        );
+        let deviation = tokens.max_float_deviation.unwrap();
+        assert!((deviation - 1.0).abs() < 1e-9, "{}", deviation);
+
+        let close = compare_tokens("1.0000001", "1");
+        assert!(close.max_float_deviation.unwrap() < 1e-6);
+        assert_eq!(compare_tokens("a b", "a  b"), TokenReport::default());
+        assert_eq!(
+            compare_output("1\n", "1\n", DEFAULT_MAX_LINE_LENGTH).tokens,
+            None
+        );
+    }
+
+    #[test]
+    fn test_line_too_long() {
+        let long = "x".repeat(100);
+        let got = format!("1\n{}\n", long);
+        let report = compare_output(&got, "1\n2\n", 10);
+        assert!(!report.matched);
+        assert_eq!(report.line_too_long, Some(2));
+        assert!(report.diff.starts_with("Output line too long"));
+
+        // Expected outputs with long lines raise the limit
+        assert!(compare_output(&got, &got, 10).matched);
+        assert_eq!(compare_output(&got, "1\n2\n", 100).line_too_long, None);
+    }
+
+    #[test]
+    fn test_output_region() {
+        let output = "debug: 1\ndebug: 2\n===\n42\n43\n\n";
+        let last = |lines| OutputRegion::LastLines { lines }.extract(output);
+        assert_eq!(last(1), "43");
+        assert_eq!(last(2), "42\n43");
+        assert_eq!(last(10), output.trim_end());
+        assert_eq!(last(0), "");
+
+        let after = |marker: &str| {
+            OutputRegion::AfterMarker {
+                marker: marker.into(),
+            }
+            .extract(output)
+        };
+        assert_eq!(after("==="), "42\n43\n\n");
+        assert_eq!(after("nope"), "");
+
+        let re = OutputRegion::Regex {
+            pattern: r"answer = (\d+)".into(),
+            group: 1,
+        };
+        assert_eq!(re.check(), Ok(()));
+        assert_eq!(re.extract("x\nanswer = 42\n"), "42");
+        assert_eq!(re.extract("no answer"), "");
+        assert!(
+            compare_output(&re.extract("answer = 42"), "42\n", DEFAULT_MAX_LINE_LENGTH).matched
+        );
+
+        let bad_group = OutputRegion::Regex {
+            pattern: r"\d+".into(),
+            group: 1,
+        };
+        assert!(bad_group.check().is_err());
+    }
+}
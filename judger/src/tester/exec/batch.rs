@@ -0,0 +1,151 @@
+//! Running all steps of a [`Test`](super::Test) in a single script.
+//!
+//! Every step is run in a subshell, after which a marker line carrying the
+//! step index (and the return code on `stdout`) is printed to both `stdout`
+//! and `stderr`. The output is then split back by these markers. Markers
+//! always start with a newline, which is removed when splitting, so the
+//! output of every step is kept unchanged.
+
+use super::Step;
+use crate::{
+    prelude::*,
+    tester::{
+        runner::CommandRunner, utils::convert_code, ExecError, ExecErrorKind, JobFailure,
+        ProcessInfo,
+    },
+};
+use indexmap::IndexMap;
+use std::io;
+
+/// Build a script running every command in `cmds` in order. The script exits
+/// after the `i`-th command if it returns non-zero and `stop_on_error[i]` is
+/// set.
+fn batch_script(marker: &str, cmds: &[&str], stop_on_error: &[bool]) -> String {
+    let mut script = String::new();
+    for (i, cmd) in cmds.iter().enumerate() {
+        script.push_str(&format!(
+            "(\n{cmd}\n)\n__rurikawa_ret=$?\nprintf '\\n{marker}:{i}:%d\\n' \"$__rurikawa_ret\"\nprintf '\\n{marker}:{i}\\n' >&2\n",
+            cmd = cmd,
+            marker = marker,
+            i = i
+        ));
+        if stop_on_error[i] {
+            script.push_str("[ \"$__rurikawa_ret\" -eq 0 ] || exit 0\n");
+        }
+    }
+    script
+}
+
+/// Take the output before the marker of step `i` out of `s`, and return it
+/// along with the rest of the marker line.
+fn split_marker<'a>(s: &mut &'a str, marker: &str, i: usize) -> Option<(&'a str, &'a str)> {
+    let pat = format!("\n{}:{}", marker, i);
+    let idx = s.find(&pat)?;
+    let output = &s[..idx];
+    let rest = &s[idx + pat.len()..];
+    let line_end = rest.find('\n').unwrap_or(rest.len());
+    let line = &rest[..line_end];
+    *s = rest.get(line_end + 1..).unwrap_or("");
+    Some((output, line))
+}
+
+/// Split the output of a batch script back into the output of every step.
+///
+/// If the script ended without reaching the marker of a step (e.g. it was
+/// killed, or its output was truncated), the remaining output is reported as
+/// that step's, with the return code of the whole script. The time spent by
+/// the whole script is reported as the first step's.
+fn split_output(
+    info: ProcessInfo,
+    marker: &str,
+    cmds: &[&str],
+    stop_on_error: &[bool],
+) -> Vec<ProcessInfo> {
+    let mut stdout = info.stdout.as_str();
+    let mut stderr = info.stderr.as_str();
+    let mut res = vec![];
+    for (i, cmd) in cmds.iter().enumerate() {
+        let out = split_marker(&mut stdout, marker, i);
+        let err = split_marker(&mut stderr, marker, i);
+        let ret_code = out.and_then(|(_, line)| line.strip_prefix(':')?.parse::<i32>().ok());
+        match (out, err, ret_code) {
+            (Some((out, _)), Some((err, _)), Some(ret_code)) => res.push(ProcessInfo {
+                command: cmd.to_string(),
+                stdout: out.to_owned(),
+                stderr: err.to_owned(),
+                ret_code: convert_code(ret_code),
+                combined: None,
+                ..info.clone()
+            }),
+            _ => {
+                // Steps after a failed one are not run if `stop_on_error` is set
+                let stopped = i > 0 && stop_on_error[i - 1] && res[i - 1].ret_code != 0;
+                if !stopped {
+                    res.push(ProcessInfo {
+                        command: cmd.to_string(),
+                        stdout: out.map(|x| x.0).unwrap_or(stdout).to_owned(),
+                        stderr: err.map(|x| x.0).unwrap_or(stderr).to_owned(),
+                        ret_code: if info.ret_code != 0 {
+                            info.ret_code
+                        } else {
+                            -1
+                        },
+                        combined: None,
+                        ..info.clone()
+                    });
+                }
+                break;
+            }
+        }
+    }
+    for info in res.iter_mut().skip(1) {
+        info.wall_time_ms = None;
+        info.cpu_time_ms = None;
+    }
+    res
+}
+
+/// Run all `steps` in a single command with `runner`, and return the output of
+/// every step that has run.
+///
+/// The timeouts of all steps are summed up and applied to the whole script.
+/// If `stop_on_error` is set, the script stops after the first failing step
+/// that does not allow failure.
+pub(super) async fn run_batch(
+    steps: &[Step],
+    runner: &(impl CommandRunner + Send),
+    variables: &IndexMap<String, String>,
+    stop_on_error: bool,
+) -> Result<Vec<ProcessInfo>, JobFailure> {
+    let marker = format!("__RURIKAWA_STEP_{}", FlowSnake::generate());
+    let cmds = steps.iter().map(|x| x.cmd.0.as_str()).collect::<Vec<_>>();
+    let stop_on_error = steps
+        .iter()
+        .map(|x| stop_on_error && !x.allow_failure)
+        .collect::<Vec<_>>();
+    let script = batch_script(&marker, &cmds, &stop_on_error);
+
+    let timeout = steps
+        .iter()
+        .map(|x| x.timeout)
+        .sum::<Option<std::time::Duration>>();
+    let info = if let Some(timeout) = timeout {
+        runner.run_timeout(&script, variables, timeout).await
+    } else {
+        runner.run(&script, variables).await
+    };
+    let info = info.map_err(|e: io::Error| match e.kind() {
+        io::ErrorKind::TimedOut => JobFailure::ExecError(ExecError {
+            stage: 0,
+            kind: ExecErrorKind::TimedOut,
+            output: vec![],
+        }),
+        _ => JobFailure::InternalError(e.to_string()),
+    })?;
+
+    let mut res = split_output(info, &marker, &cmds, &stop_on_error);
+    for (info, step) in res.iter_mut().zip(steps) {
+        info.is_user_command = step.is_user_command;
+    }
+    Ok(res)
+}
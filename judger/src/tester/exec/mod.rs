@@ -1,26 +1,47 @@
+mod batch;
+pub mod pull;
 mod test_suite;
 mod tests;
 
 use super::{
+    compare::{compare_output, tokens_match, CompareReport, OutputRegion, DEFAULT_MAX_LINE_LENGTH},
     model::*,
-    runner::{CommandRunner, DockerCommandRunner, DockerCommandRunnerOptions},
+    runner::{
+        container_name, image_labels, sanitize_hostname, timed_out_output, CommandRunner,
+        DockerCommandRunner, DockerCommandRunnerOptions, TokioCommandRunner,
+        DEFAULT_MAX_OUTPUT_SIZE,
+    },
     spj::{self, SpjEnvironment},
-    utils::diff,
-    BuildError, ExecError, ExecErrorKind, JobFailure, OutputMismatch, ProcessInfo,
+    BuildError, ExecError, ExecErrorKind, JobFailure, OutputMismatch, ProcessInfo, ScorerFailure,
     ShouldFailFailure,
 };
 use crate::{
-    client::model::{upload_test_result, ResultUploadConfig, TestResult, TestResultKind},
+    client::{
+        config::DockerConfig,
+        model::{
+            upload_test_result, ResourceUsage, ResultUploadConfig, TestResult, TestResultKind,
+        },
+    },
     config::JudgeTomlTestConfig,
     prelude::*,
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bollard::models::{BuildInfo, Mount};
 use futures::prelude::*;
-use itertools::Itertools;
-use once_cell::sync::Lazy;
+use indexmap::IndexMap;
 use path_slash::PathBufExt;
-use std::{collections::HashMap, io, path::Path, path::PathBuf, sync::Arc, time};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time,
+};
 use tokio::{
     io::{AsyncBufReadExt, AsyncReadExt, BufReader},
     sync::mpsc::UnboundedSender,
@@ -86,7 +107,7 @@ impl Capturable {
     async fn capture(
         self,
         runner: &(impl CommandRunner + Send),
-        variables: &HashMap<String, String>,
+        variables: &IndexMap<String, String>,
     ) -> PopenResult<ProcessInfo> {
         runner.run(&self.0, variables).await
     }
@@ -102,6 +123,10 @@ pub struct Step {
 
     /// The timeout of the command's execution.
     pub timeout: Option<time::Duration>,
+
+    /// If the test goes on when the command returns non-zero. See
+    /// [`RawStep::allow_failure`].
+    pub allow_failure: bool,
 }
 
 impl Step {
@@ -111,6 +136,7 @@ impl Step {
             cmd,
             is_user_command,
             timeout: None,
+            allow_failure: false,
         }
     }
 
@@ -120,6 +146,12 @@ impl Step {
         self
     }
 
+    /// Set whether the test goes on when this [`Step`] fails.
+    pub fn set_allow_failure(mut self, allow_failure: bool) -> Self {
+        self.allow_failure = allow_failure;
+        self
+    }
+
     /// Make a new [`Step`] with a `timeout`.
     pub fn with_timeout(
         cmd: Capturable,
@@ -130,6 +162,7 @@ impl Step {
             cmd,
             is_user_command,
             timeout,
+            allow_failure: false,
         }
     }
 
@@ -142,18 +175,11 @@ impl Step {
     pub async fn capture(
         self,
         runner: &(impl CommandRunner + Send),
-        variables: &HashMap<String, String>,
+        variables: &IndexMap<String, String>,
     ) -> PopenResult<ProcessInfo> {
         let is_user_command = self.is_user_command;
         if let Some(timeout) = self.timeout {
-            tokio::time::timeout(timeout, self.cmd.capture(runner, variables))
-                .await
-                .map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::TimedOut,
-                        format!("Popen capture timed out at {}s", timeout.as_secs_f64()),
-                    )
-                })?
+            runner.run_timeout(&self.cmd.0, variables, timeout).await
         } else {
             self.cmd.capture(runner, variables).await
         }
@@ -164,7 +190,72 @@ impl Step {
     }
 }
 
-static EOF_PATTERN: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new(r"\r?\n").unwrap());
+/// Details of a [`Test`] run besides its verdict.
+#[derive(Debug, Clone, Default)]
+pub struct TestRunDetails {
+    /// The detailed report of the output comparison, `None` if no output
+    /// comparison happened, e.g. when the test failed before its last step or
+    /// it's judged by a special judger.
+    pub report: Option<CompareReport>,
+    /// Output of every command that has run.
+    pub output: Vec<ProcessInfo>,
+}
+
+/// Timing and output of a test run in a [`TestSuite`].
+#[derive(Debug, Clone)]
+pub struct TestRecord {
+    pub name: String,
+    /// Time spent running the test.
+    pub duration: time::Duration,
+    /// Output of every command that has run, truncated to
+    /// [`TestSuiteOptions::record_output_len`]. Empty if outputs are not kept.
+    pub output: Vec<ProcessInfo>,
+    /// Whether any output is truncated.
+    pub truncated: bool,
+}
+
+impl TestRecord {
+    fn new(
+        name: String,
+        duration: time::Duration,
+        output: &[ProcessInfo],
+        limit: Option<usize>,
+    ) -> Self {
+        let mut truncated = false;
+        let mut truncate = |s: &str| {
+            let limit = limit.unwrap_or(0);
+            if s.len() <= limit {
+                return s.to_owned();
+            }
+            truncated = true;
+            let mut end = limit;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{}\n--- truncated, {} bytes in total", &s[..end], s.len())
+        };
+        let output = match limit {
+            Some(_) => output
+                .iter()
+                .map(|info| ProcessInfo {
+                    stdout: truncate(&info.stdout),
+                    stderr: truncate(&info.stderr),
+                    combined: None,
+                    // Only the truncated copy is kept
+                    reservation: Default::default(),
+                    ..info.clone()
+                })
+                .collect(),
+            None => vec![],
+        };
+        TestRecord {
+            name,
+            duration,
+            output,
+            truncated,
+        }
+    }
+}
 
 /// A particular [`Test`] consisting of multiple [`Step`]s.
 ///
@@ -179,6 +270,45 @@ pub struct Test {
 
     /// If this [`Test`] is _intended_ to fail.
     should_fail: bool,
+
+    /// The exit code the failing command must return, if any.
+    expected_exit_code: Option<i32>,
+
+    /// The pattern the `stderr` of the failing command must match, if any.
+    expected_stderr: Option<regex::Regex>,
+
+    /// If a mismatched output that only differs in whitespaces should be
+    /// reported as a presentation error.
+    presentation_error: bool,
+
+    /// The region of `stdout` to be compared against the expected output.
+    output_region: Option<OutputRegion>,
+
+    /// If all steps should be run in a single script. See
+    /// [`JudgerPublicConfig::batch_commands`].
+    batch: bool,
+
+    /// Commands run before the steps. See [`JudgerPublicConfig::before_each`].
+    before_each: Vec<String>,
+
+    /// Commands run after the steps. See [`JudgerPublicConfig::after_each`].
+    after_each: Vec<String>,
+
+    /// Command scoring the output instead of comparing it. See
+    /// [`JudgerPublicConfig::scorer`].
+    scorer: Option<String>,
+
+    /// How `stderr` of the last step affects the verdict.
+    stderr_policy: StderrPolicy,
+
+    /// Expected `stderr` of the last step under [`StderrPolicy::Compare`].
+    expected_stderr_out: Option<String>,
+
+    /// See [`JudgerPublicConfig::max_line_length`].
+    max_line_length: usize,
+
+    /// See [`JudgerPublicConfig::exit_code_map`].
+    exit_code_map: Arc<HashMap<i32, TestResultKind>>,
 }
 
 impl Test {
@@ -187,6 +317,18 @@ impl Test {
             steps: vec![],
             expected: None,
             should_fail: false,
+            expected_exit_code: None,
+            expected_stderr: None,
+            presentation_error: false,
+            output_region: None,
+            batch: false,
+            before_each: vec![],
+            after_each: vec![],
+            scorer: None,
+            stderr_policy: StderrPolicy::Ignore,
+            expected_stderr_out: None,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            exit_code_map: Default::default(),
         }
     }
 
@@ -205,6 +347,105 @@ impl Test {
         self
     }
 
+    /// Make this [`Test`] intended to fail, with the failing command returning
+    /// `exit_code` and printing `stderr` matching the given pattern if set.
+    pub fn expected_failure(
+        &mut self,
+        exit_code: Option<i32>,
+        stderr: Option<regex::Regex>,
+    ) -> &mut Self {
+        self.should_fail = true;
+        self.expected_exit_code = exit_code;
+        self.expected_stderr = stderr;
+        self
+    }
+
+    /// Why the failing command `info` doesn't fail the expected way, if so.
+    fn unexpected_failure(&self, info: &ProcessInfo) -> Option<String> {
+        if let Some(code) = self.expected_exit_code {
+            if info.ret_code != code {
+                return Some(format!(
+                    "Command `{}` should return {}, got {}",
+                    info.command, code, info.ret_code
+                ));
+            }
+        }
+        if let Some(pattern) = &self.expected_stderr {
+            if !pattern.is_match(&info.stderr) {
+                return Some(format!(
+                    "The stderr of command `{}` should match `{}`",
+                    info.command, pattern
+                ));
+            }
+        }
+        None
+    }
+
+    pub fn output_region(&mut self, region: Option<OutputRegion>) -> &mut Self {
+        self.output_region = region;
+        self
+    }
+
+    pub fn presentation_error(&mut self, enabled: bool) -> &mut Self {
+        self.presentation_error = enabled;
+        self
+    }
+
+    pub fn batch(&mut self, enabled: bool) -> &mut Self {
+        self.batch = enabled;
+        self
+    }
+
+    pub fn scorer(&mut self, scorer: Option<String>) -> &mut Self {
+        self.scorer = scorer;
+        self
+    }
+
+    /// Set how `stderr` of the last step affects the verdict, comparing it
+    /// against `expected` under [`StderrPolicy::Compare`].
+    pub fn stderr_policy(&mut self, policy: StderrPolicy, expected: Option<String>) -> &mut Self {
+        self.stderr_policy = policy;
+        self.expected_stderr_out = expected;
+        self
+    }
+
+    /// Treat output lines longer than `len` bytes as mismatches without
+    /// diffing them, see [`compare_output`].
+    pub fn max_line_length(&mut self, len: usize) -> &mut Self {
+        self.max_line_length = len;
+        self
+    }
+
+    /// Report commands exiting with a code in `map` with its verdict.
+    pub fn exit_code_map(&mut self, map: Arc<HashMap<i32, TestResultKind>>) -> &mut Self {
+        self.exit_code_map = map;
+        self
+    }
+
+    /// The diff of `stderr` of the last step `info` if it is not accepted by
+    /// the [`StderrPolicy`].
+    fn stderr_mismatch(&self, info: &ProcessInfo) -> Option<String> {
+        let expected = match self.stderr_policy {
+            StderrPolicy::Ignore => return None,
+            StderrPolicy::FailIfNonempty if info.stderr.is_empty() => return None,
+            StderrPolicy::FailIfNonempty => "",
+            StderrPolicy::Compare => self.expected_stderr_out.as_deref()?,
+        };
+        let cmp = compare_output(&info.stderr, expected, self.max_line_length);
+        match self.stderr_policy {
+            StderrPolicy::Compare if cmp.matched => None,
+            _ => Some(format!("stderr:\n{}", cmp.diff)),
+        }
+    }
+
+    /// Run `before` before the steps of this [`Test`], and `after` after them
+    /// regardless of the verdict.
+    pub fn hooks(&mut self, before: Vec<String>, after: Vec<String>) -> &mut Self {
+        self.before_each = before;
+        self.after_each = after;
+        self
+    }
+
     /// Run this specific [`Test`], and return a score (`1.0` when scoring mode is off).
     ///
     /// # Arguments
@@ -216,22 +457,120 @@ impl Test {
     pub async fn run(
         self,
         runner: &(impl CommandRunner + Send),
-        variables: &HashMap<String, String>,
+        variables: &IndexMap<String, String>,
+        spj: Option<&mut SpjEnvironment>,
+    ) -> Result<f64, JobFailure> {
+        self.run_inner(runner, variables, spj, &mut TestRunDetails::default())
+            .await
+    }
+
+    /// Run this specific [`Test`] like [`Test::run`], and also return the
+    /// detailed report of the output comparison regardless of the verdict.
+    ///
+    /// The report is `None` if no output comparison happened, e.g. when the
+    /// test failed before its last step or it's judged by a special judger.
+    pub async fn run_with_report(
+        self,
+        runner: &(impl CommandRunner + Send),
+        variables: &IndexMap<String, String>,
+        spj: Option<&mut SpjEnvironment>,
+    ) -> (Result<f64, JobFailure>, Option<CompareReport>) {
+        let (res, details) = self.run_with_details(runner, variables, spj).await;
+        (res, details.report)
+    }
+
+    /// Run this specific [`Test`] like [`Test::run`], and also return the
+    /// [`TestRunDetails`] regardless of the verdict.
+    pub async fn run_with_details(
+        self,
+        runner: &(impl CommandRunner + Send),
+        variables: &IndexMap<String, String>,
         spj: Option<&mut SpjEnvironment>,
+    ) -> (Result<f64, JobFailure>, TestRunDetails) {
+        let mut details = TestRunDetails::default();
+        let res = self.run_inner(runner, variables, spj, &mut details).await;
+        if let Err(e) = &res {
+            details.output = e.output().to_vec();
+        }
+        (res, details)
+    }
+
+    async fn run_inner(
+        mut self,
+        runner: &(impl CommandRunner + Send),
+        variables: &IndexMap<String, String>,
+        spj: Option<&mut SpjEnvironment>,
+        details: &mut TestRunDetails,
+    ) -> Result<f64, JobFailure> {
+        let before_each = std::mem::take(&mut self.before_each);
+        let after_each = std::mem::take(&mut self.after_each);
+        let timeout = self.steps.first().and_then(|x| x.timeout);
+
+        for cmd in &before_each {
+            run_hook(runner, cmd, variables, timeout)
+                .await
+                .map_err(|e| JobFailure::InternalError(format!("`before_each` command {}", e)))?;
+        }
+        let res = self.run_steps(runner, variables, spj, details).await;
+        for cmd in &after_each {
+            if let Err(e) = run_hook(runner, cmd, variables, timeout).await {
+                log::warn!("`after_each` command {}", e);
+            }
+        }
+        res
+    }
+
+    async fn run_steps(
+        mut self,
+        runner: &(impl CommandRunner + Send),
+        variables: &IndexMap<String, String>,
+        spj: Option<&mut SpjEnvironment>,
+        details: &mut TestRunDetails,
     ) -> Result<f64, JobFailure> {
         let spj_enabled = spj.as_ref().map_or(false, |x| x.features().case());
         let mut output: Vec<ProcessInfo> = vec![];
         let steps_len = self.steps.len();
         let mut test_failed = false;
-        for (i, step) in self.steps.into_iter().enumerate() {
-            let info = match step.capture(runner, variables).await {
+
+        // Failures are only allowed if some steps still count.
+        if self.steps.iter().all(|x| x.allow_failure) {
+            self.steps.iter_mut().for_each(|x| x.allow_failure = false);
+        }
+
+        // In batch mode, all steps are run beforehand and then checked one by one.
+        let mut batched = if self.batch && steps_len > 1 {
+            let stop_on_error = !spj_enabled;
+            Some(batch::run_batch(&self.steps, runner, variables, stop_on_error).await?)
+        } else {
+            None
+        }
+        .map(|x| x.into_iter());
+
+        for (i, step) in std::mem::take(&mut self.steps).into_iter().enumerate() {
+            let is_user_command = step.is_user_command;
+            let timeout = step.timeout;
+            let allow_failure = step.allow_failure;
+            let info = match &mut batched {
+                Some(infos) => match infos.next() {
+                    Some(info) => Ok(info),
+                    None => break,
+                },
+                None => step.capture(runner, variables).await,
+            };
+            let info = match info {
                 Ok(res) => res,
                 Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    if let Some(info) = timed_out_output(&e) {
+                        output.push(ProcessInfo {
+                            is_user_command,
+                            ..info.clone()
+                        });
+                    }
                     return Err(JobFailure::ExecError(ExecError {
                         stage: i,
                         kind: ExecErrorKind::TimedOut,
                         output,
-                    }))
+                    }));
                 }
                 Err(e) => return Err(JobFailure::InternalError(e.to_string())),
             };
@@ -242,13 +581,28 @@ impl Test {
             #[allow(clippy::comparison_chain)]
             {
                 let code = info.ret_code;
-                if code > 0 {
+                if code != 0 && allow_failure {
+                    // Kept in the output, but the test goes on.
+                    log::debug!("Command `{}` failed with {}, allowed", info.command, code);
+                } else if code > 0 {
                     if self.should_fail {
+                        if let Some(reason) = self.unexpected_failure(&info) {
+                            return Err(JobFailure::ShouldFail(ShouldFailFailure {
+                                output,
+                                reason: Some(reason),
+                            }));
+                        }
                         // Bail out of test, but it's totally fine.
                         test_failed = true;
                         break;
                     } else if spj_enabled {
                         // Ignore and continue with the rest.
+                    } else if let Some(&verdict) = self.exit_code_map.get(&code) {
+                        return Err(JobFailure::ExecError(ExecError {
+                            stage: i,
+                            kind: ExecErrorKind::MappedExitCode { code, verdict },
+                            output,
+                        }));
                     } else {
                         return Err(JobFailure::ExecError(ExecError {
                             stage: i,
@@ -257,12 +611,16 @@ impl Test {
                         }));
                     }
                 } else if code < 0 {
-                    return Err(JobFailure::ExecError(ExecError {
-                        stage: i,
-                        kind: ExecErrorKind::RuntimeError(strsignal(-code).map_or_else(
+                    let kind = match self.exit_code_map.get(&code) {
+                        Some(&verdict) => ExecErrorKind::MappedExitCode { code, verdict },
+                        None => ExecErrorKind::RuntimeError(strsignal(-code).map_or_else(
                             || format!("Runtime Error: signal {}", -code),
                             |x| format!("Runtime Error: {} (signal {})", x, -code),
                         )),
+                    };
+                    return Err(JobFailure::ExecError(ExecError {
+                        stage: i,
+                        kind,
                         output,
                     }));
                 }
@@ -270,23 +628,56 @@ impl Test {
 
             // Special case for the final step.
             if i == steps_len - 1 && !spj_enabled {
-                if let Some(expected) = self.expected.as_ref() {
-                    // * Actually there is a test that should not have passed,
-                    // * because the `.out` file is missing a `\n`.
-                    // * We trim the result here anyway...
-                    let got = EOF_PATTERN.replace_all(info.stdout.trim(), "\n");
-                    let expected = EOF_PATTERN.replace_all(expected.trim(), "\n");
-                    let (different, diff_str) = diff(&got, &expected);
-                    if different {
-                        return Err(JobFailure::OutputMismatch(OutputMismatch {
-                            diff: diff_str,
+                if let Some(scorer) = &self.scorer {
+                    let got = match &self.output_region {
+                        Some(region) => region.extract(&info.stdout),
+                        None => info.stdout.as_str().into(),
+                    };
+                    let (score, reason) = run_scorer(
+                        runner,
+                        scorer,
+                        variables,
+                        &got,
+                        self.expected.as_deref(),
+                        timeout,
+                    )
+                    .await?;
+                    details.output = output.clone();
+                    if score < 1.0 {
+                        return Err(JobFailure::ScorerRejected(ScorerFailure {
+                            score,
+                            reason,
                             output,
                         }));
                     }
+                } else if let Some(expected) = self.expected.as_ref() {
+                    let got = match &self.output_region {
+                        Some(region) => region.extract(&info.stdout),
+                        None => info.stdout.as_str().into(),
+                    };
+                    let cmp = compare_output(&got, expected, self.max_line_length);
+                    let matched = cmp.matched;
+                    let diff = cmp.diff.clone();
+                    details.report = Some(cmp);
+                    if !matched {
+                        // Second pass: see if only the formatting is wrong.
+                        let mismatch = OutputMismatch { diff, output };
+                        if self.presentation_error && tokens_match(&got, expected) {
+                            return Err(JobFailure::PresentationError(mismatch));
+                        }
+                        return Err(JobFailure::OutputMismatch(mismatch));
+                    }
+                }
+                if !self.should_fail {
+                    if let Some(diff) = self.stderr_mismatch(&info) {
+                        return Err(JobFailure::OutputMismatch(OutputMismatch { diff, output }));
+                    }
                 }
             }
         }
 
+        details.output = output.clone();
+
         // Handle special judge scoring, return the final result.
         // If special judge system is off, then the default return value should be `Ok(1.0)`.
         // TODO: Make `1.0` a variable.
@@ -309,15 +700,43 @@ impl Test {
             }
         } else if self.should_fail && !test_failed {
             // Tests that _should_ fail but didn't are considered malfunctioning.
-            Err(JobFailure::ShouldFail(ShouldFailFailure { output }))
+            Err(JobFailure::ShouldFail(ShouldFailFailure {
+                output,
+                reason: None,
+            }))
         } else {
             Ok(1.0)
         }
     }
 }
 
+/// Run a `before_each` or `after_each` command of a test. The error describes
+/// how the command failed.
+async fn run_hook(
+    runner: &(impl CommandRunner + Send),
+    cmd: &str,
+    variables: &IndexMap<String, String>,
+    timeout: Option<time::Duration>,
+) -> Result<(), String> {
+    let info = match timeout {
+        Some(timeout) => runner.run_timeout(cmd, variables, timeout).await,
+        None => runner.run(cmd, variables).await,
+    }
+    .map_err(|e| format!("`{}` failed to run: {}", cmd, e))?;
+    if info.ret_code != 0 {
+        return Err(format!(
+            "`{}` returned {}: {}",
+            cmd, info.ret_code, info.stderr
+        ));
+    }
+    Ok(())
+}
+
 pub type BuildResultChannel = UnboundedSender<BuildInfo>;
 
+/// Channel receiving the results of tests as they change.
+pub type TestResultChannel = UnboundedSender<(String, TestResult)>;
+
 impl Image {
     pub fn set_dockerfile_tag(&mut self, new_tag: String) -> &mut Self {
         if let Image::Dockerfile { tag, .. } = self {
@@ -345,7 +764,9 @@ impl Image {
         self
     }
 
-    /// Build (or pull) the [`Image`] to make it usable in Docker.
+    /// Build (or pull) the [`Image`] to make it usable in Docker. Base images
+    /// are pulled again according to the pull policy of `cfg`, with the
+    /// credentials of their registries.
     pub async fn build(
         &self,
         instance: bollard::Docker,
@@ -353,33 +774,97 @@ impl Image {
         cancel: CancellationTokenHandle,
         network: Option<&str>,
         cpu_shares: Option<f64>,
+        cfg: &DockerConfig,
     ) -> Result<(), BuildError> {
-        match &self {
-            Image::Prebuilt { tag } => instance
-                .create_image(
-                    Some(bollard::image::CreateImageOptions {
-                        from_image: tag.to_owned(),
+        let pull_policy = cfg.pull_policy;
+        // Decide whether `image` is pulled, and report it in the build log.
+        let decide_pull = |image: String| {
+            let instance = instance.clone();
+            let channel = partial_result_channel.clone();
+            async move {
+                let present = instance.inspect_image(&image).await.is_ok();
+                let pull =
+                    pull::should_pull(pull_policy, &image, present, pull::last_pulled(&image));
+                let msg = format!(
+                    "Pull policy `{}`: {} image `{}`\n",
+                    pull_policy,
+                    if pull { "pulling" } else { "using cached" },
+                    image
+                );
+                log::info!("{}", msg.trim_end());
+                if let Some(ch) = channel {
+                    let _ = ch.send(BuildInfo {
+                        stream: Some(msg),
                         ..Default::default()
-                    }),
-                    None,
-                    None,
-                )
-                .try_collect::<Vec<_>>()
-                .map_ok(drop)
-                .map_err(|e| {
-                    BuildError::ImagePullFailure(format!("Failed to pull image `{}`: {}", tag, e))
-                })
-                .with_cancel(cancel)
-                .await
-                .ok_or(BuildError::Cancelled)?,
+                    });
+                }
+                pull.then_some(image)
+            }
+        };
+
+        match &self {
+            Image::Prebuilt { tag } => {
+                if decide_pull(tag.clone()).await.is_some() {
+                    instance
+                        .create_image(
+                            Some(bollard::image::CreateImageOptions {
+                                from_image: tag.to_owned(),
+                                ..Default::default()
+                            }),
+                            None,
+                            cfg.credentials_for(tag),
+                        )
+                        .try_collect::<Vec<_>>()
+                        .map_ok(drop)
+                        .map_err(|e| {
+                            let msg = format!("Failed to pull image `{}`: {}", tag, e);
+                            if pull::is_unauthorized(&e) {
+                                BuildError::ImagePullUnauthorized(msg)
+                            } else {
+                                BuildError::ImagePullFailure(msg)
+                            }
+                        })
+                        .with_cancel(cancel)
+                        .await
+                        .ok_or(BuildError::Cancelled)??;
+                    pull::record_pull(tag);
+                }
+            }
 
-            Image::Dockerfile { tag, path, file } => {
+            Image::Dockerfile {
+                tag,
+                path,
+                file,
+                build_args,
+            } => {
                 // We set the CPU quota here by using a period of 100ms
                 let cpuquota = cpu_shares.map(|x| (x * 100_000f64).floor() as u64);
                 let cpuperiod = cpuquota.is_some().then(|| 100_000);
 
                 let ignore = ignore::gitignore::Gitignore::empty();
 
+                // Base images that are missing or due for a pull.
+                let dockerfile =
+                    path.join(file.as_deref().unwrap_or_else(|| "Dockerfile".as_ref()));
+                let bases = tokio::fs::read_to_string(&dockerfile)
+                    .await
+                    .map(|x| pull::dockerfile_base_images(&x))
+                    .unwrap_or_default();
+                let mut pulled = vec![];
+                for base in bases {
+                    pulled.extend(decide_pull(base).await);
+                }
+
+                let build_args = merge_build_args(build_args);
+                let msg = describe_build_args(&build_args);
+                log::info!("{}", msg.trim_end());
+                if let Some(ch) = partial_result_channel.as_ref() {
+                    let _ = ch.send(BuildInfo {
+                        stream: Some(msg),
+                        ..Default::default()
+                    });
+                }
+
                 // Launch a task for archiving.
                 let (tar_stream, archiving) = crate::util::tar::pack_as_tar(&path, ignore)
                     .map_err(|e| BuildError::FileTransferError(e.to_string()))?;
@@ -399,13 +884,12 @@ impl Image {
 
                             cpuperiod,
                             cpuquota,
-                            buildargs: [("CI", "true")]
-                                .iter()
-                                .map(|(k, v)| (k.to_string(), v.to_string()))
-                                .collect(),
+                            buildargs: build_args,
+                            labels: image_labels(),
+                            pull: !pulled.is_empty(),
                             ..Default::default()
                         },
-                        None,
+                        cfg.registry_credentials(),
                         // Freeze `path` as a tar archive.
                         Some(hyper::Body::wrap_stream(tar_stream)),
                     )
@@ -431,9 +915,10 @@ impl Image {
                     .map_err(|e| BuildError::Internal(e.to_string()))?
                     .map_err(|e: io::Error| BuildError::FileTransferError(e.to_string()))?;
 
-                Ok(())
+                pulled.iter().for_each(|x| pull::record_pull(x));
             }
         }
+        Ok(())
     }
 
     /// Remove the Image when finished.
@@ -486,11 +971,14 @@ pub struct TestSuite {
     /// The collection of commands to execute within each test case.
     pub exec: Vec<RawStep>,
 
+    /// Commands to execute once before running any test case.
+    pub build_commands: Vec<String>,
+
     /// Variables to be expanded at testing.
     ///
     /// Variables in this field are in the form of `{"$var": "dest"}`, which for example then
     /// expands `$var` inside test case `123` to `123.dest`.
-    pub vars: HashMap<String, String>,
+    pub vars: IndexMap<String, String>,
 
     /// Root folder of the [`TestSuite`] inside **this** machine.
     pub test_root: PathBuf,
@@ -503,9 +991,72 @@ pub struct TestSuite {
 
     /// Network options
     network: NetworkOptions,
+
+    /// Whether to distinguish presentation errors from wrong answers.
+    presentation_error: bool,
+
+    /// The region of the output to be compared.
+    output_region: Option<OutputRegion>,
+
+    /// Whether to run all steps of a test in a single script.
+    batch_commands: bool,
+
+    /// Commands run before and after every test.
+    before_each: Vec<String>,
+    after_each: Vec<String>,
+
+    /// Hostname of the test container.
+    hostname: Option<String>,
+
+    /// Service containers kept alive across all tests.
+    services: Vec<ServiceContainer>,
+
+    /// Command generating expected outputs, see
+    /// [`JudgerPublicConfig::generator`].
+    generator: Option<String>,
+
+    /// Command scoring outputs, see [`JudgerPublicConfig::scorer`].
+    scorer: Option<String>,
+
+    /// See [`JudgerPublicConfig::combined_output`].
+    combined_output: bool,
+
+    /// See [`JudgerPublicConfig::stderr_policy`].
+    stderr_policy: StderrPolicy,
+
+    /// See [`JudgerPublicConfig::parallel_tests`].
+    parallel_tests: Option<usize>,
+
+    /// See [`JudgerPublicConfig::max_line_length`].
+    max_line_length: usize,
+
+    /// See [`JudgerPublicConfig::max_output_size`].
+    max_output_size: usize,
+
+    /// See [`JudgerPublicConfig::exit_code_map`].
+    exit_code_map: Arc<HashMap<i32, TestResultKind>>,
+
+    /// Comparison reports of every test, collected when
+    /// [`TestSuiteOptions::report_all`] is set.
+    pub reports: HashMap<String, CompareReport>,
+
+    /// Network access attempts recorded while running, if the network is
+    /// audited.
+    pub network_attempts: Option<Vec<String>>,
+
+    /// Timing and output of every test that has run, in the order they run.
+    pub records: Vec<TestRecord>,
+
+    /// Time spent preparing the image and containers before running tests.
+    pub build_duration: Option<time::Duration>,
 }
 
 impl TestSuite {
+    /// Names of the tests in this [`TestSuite`], in the order they run.
+    pub fn test_order(&self) -> Vec<String> {
+        self.test_cases.iter().map(|x| x.name.clone()).collect()
+    }
+
     /// Push a [`TestCase`] to the current [`TestSuite`].
     pub fn add_case(&mut self, case: TestCase) {
         self.test_cases.push(case)
@@ -524,30 +1075,49 @@ impl TestSuite {
         let container_test_root = private_cfg.mapped_test_root_dir.clone();
         let test_root = private_cfg.test_root_dir.clone();
 
+        public_cfg.check_env().map_err(anyhow::Error::msg)?;
+        public_cfg
+            .check_exit_code_map()
+            .map_err(anyhow::Error::msg)?;
+        public_cfg
+            .check_test_time_limits()
+            .map_err(anyhow::Error::msg)?;
+        public_cfg.check_hostnames().map_err(anyhow::Error::msg)?;
+        if let Some(region) = &public_cfg.output_region {
+            region.check().map_err(anyhow::Error::msg)?;
+        }
+        for bind in public_cfg.binds.iter().flatten() {
+            bind.check().map_err(anyhow::Error::msg)?;
+        }
         let index = construct_case_index(&public_cfg);
 
-        let test_cases = futures::stream::iter(options.tests.clone().drain(..))
-            .map(|name| {
-                let case = index.get(&name).unwrap();
-                create_test_case(&public_cfg, &test_root, &container_test_root, case, name)
+        // Run tests in a stable order, or a reproducible random one.
+        let mut tests = expand_tests(&index, &options.tests, &test_root).await?;
+        tests.sort_by(|a, b| a.0.cmp(&b.0));
+        if let Some(seed) = options.shuffle_seed {
+            tests.shuffle(&mut StdRng::seed_from_u64(seed));
+        }
+
+        let test_cases = futures::stream::iter(tests)
+            .map(|(name, case, stem)| {
+                create_test_case(
+                    &public_cfg,
+                    &test_root,
+                    &container_test_root,
+                    case,
+                    name,
+                    stem,
+                )
             })
-            .buffer_unordered(16)
+            .buffered(16)
             .try_collect::<Vec<_>>()
             .await?;
 
         // Get command steps
-        let mut raw_steps = job_cfg
-            .run
-            .iter()
-            .map(|s| RawStep {
-                command: s.to_owned(),
-                is_user_command: true,
-            })
-            .chain(public_cfg.run.iter().map(|s| RawStep {
-                command: s.to_owned(),
-                is_user_command: false,
-            }))
-            .collect_vec();
+        let (build_commands, run_commands) = job_cfg.commands().map_err(anyhow::Error::msg)?;
+        let mut raw_steps = public_cfg
+            .merge_run_commands(&run_commands, !job_cfg.run.is_empty())
+            .map_err(anyhow::Error::msg)?;
 
         // Get ignored pattern.
         let copy_ignore = if let Some(file) = &public_cfg.test_ignore {
@@ -585,6 +1155,7 @@ impl TestSuite {
             test_cases,
             options,
             exec: raw_steps,
+            build_commands,
             vars: public_cfg.vars,
             binds: public_cfg.binds.map(|bs| {
                 bs.iter()
@@ -604,6 +1175,29 @@ impl TestSuite {
             test_root,
             container_test_root,
             network: public_cfg.network,
+            presentation_error: public_cfg.presentation_error,
+            output_region: public_cfg.output_region,
+            batch_commands: public_cfg.batch_commands,
+            before_each: public_cfg.before_each,
+            after_each: public_cfg.after_each,
+            hostname: sanitize_hostname(public_cfg.hostname.as_ref().unwrap_or(&public_cfg.name)),
+            services: public_cfg.services,
+            generator: public_cfg.generator,
+            scorer: public_cfg.scorer,
+            combined_output: public_cfg.combined_output,
+            stderr_policy: public_cfg.stderr_policy,
+            parallel_tests: public_cfg.parallel_tests,
+            max_line_length: public_cfg
+                .max_line_length
+                .unwrap_or(DEFAULT_MAX_LINE_LENGTH),
+            max_output_size: public_cfg
+                .max_output_size
+                .unwrap_or(DEFAULT_MAX_OUTPUT_SIZE),
+            exit_code_map: Arc::new(public_cfg.exit_code_map),
+            reports: HashMap::new(),
+            network_attempts: None,
+            records: vec![],
+            build_duration: None,
         })
     }
 
@@ -612,7 +1206,7 @@ impl TestSuite {
         instance: bollard::Docker,
         base_dir: PathBuf,
         build_result_channel: Option<BuildResultChannel>,
-        result_channel: Option<tokio::sync::mpsc::UnboundedSender<(String, TestResult)>>,
+        result_channel: Option<TestResultChannel>,
         upload_info: Option<Arc<ResultUploadConfig>>,
         cancellation_token: CancellationTokenHandle,
     ) -> anyhow::Result<HashMap<String, TestResult>> {
@@ -626,6 +1220,7 @@ impl TestSuite {
         } = self.options;
 
         log::trace!("{:08x}: started", rnd_id);
+        let build_start = time::Instant::now();
 
         // Take ownership of the `Image` instance stored in `Self`
         let mut image = self
@@ -641,6 +1236,8 @@ impl TestSuite {
             image,
             {
                 DockerCommandRunnerOptions {
+                    container_name: container_name(Some(&self.id)),
+                    hostname: self.hostname.clone(),
                     mem_limit,
                     build_image,
                     remove_image,
@@ -648,10 +1245,15 @@ impl TestSuite {
                     copies: self.copies.clone(),
                     cancellation_token: cancellation_token.clone(),
                     network_options: self.network.clone(),
+                    services: self.services.clone(),
+                    cfg: self.options.docker_config.clone(),
+                    combined_output: self.combined_output,
+                    output_budget: self.options.output_budget.clone(),
+                    max_output_size: self.max_output_size,
                     ..Default::default()
                 }
             },
-            build_result_channel,
+            build_result_channel.clone(),
         )
         .await?;
 
@@ -660,95 +1262,448 @@ impl TestSuite {
 
         log::trace!("{:08x}: runner created", rnd_id);
 
+        let build_res = run_build_commands(
+            &runner.as_build_user(),
+            &self.build_commands,
+            time_limit.map(std::time::Duration::from_millis),
+            build_result_channel.as_ref(),
+        )
+        .with_cancel(cancellation_token.clone())
+        .await
+        .unwrap_or(Err(BuildError::Cancelled));
+        if let Err(e) = build_res {
+            runner.kill().await;
+            return Err(e.into());
+        }
+        self.build_duration = Some(build_start.elapsed());
+
+        let parallel = match self.parallel_tests {
+            Some(n) if n > 1 && self.spj_env.is_none() => n,
+            _ => 1,
+        };
+        let result = if parallel > 1 {
+            self.run_parallel(
+                &runner,
+                parallel,
+                result_channel.as_ref(),
+                upload_info.as_ref(),
+                cancellation_token,
+            )
+            .await
+        } else {
+            self.run_sequential(
+                &runner,
+                rnd_id,
+                result_channel.as_ref(),
+                upload_info.as_ref(),
+                cancellation_token,
+            )
+            .await
+        };
+
+        self.network_attempts = runner.network_attempts().await;
+        runner.kill().await;
+
+        log::trace!("{:08x}: finished", rnd_id);
+
+        result
+    }
+
+    /// Run this suite like [`run`](Self::run), but directly on this machine
+    /// in `base_dir` with a [`TokioCommandRunner`], instead of in a Docker
+    /// container. No image is built, tests run one after another, and only
+    /// the time limit is enforced. Suites with binds or service containers
+    /// are rejected, since they only exist in containers.
+    ///
+    /// **This is insecure**, commands can do anything the current user can.
+    /// Only use it for debugging trusted suites and jobs locally.
+    pub async fn run_on_host(
+        &mut self,
+        base_dir: PathBuf,
+        cancellation_token: CancellationTokenHandle,
+    ) -> anyhow::Result<HashMap<String, TestResult>> {
+        if self.binds.as_ref().map_or(false, |x| !x.is_empty()) {
+            anyhow::bail!("Binds are not supported without Docker");
+        }
+        if !self.services.is_empty() {
+            anyhow::bail!("Service containers are not supported without Docker");
+        }
+        if self.options.mem_limit.is_some() {
+            log::warn!("Memory limit is not enforced without Docker");
+        }
+        let rnd_id = rand::random::<u32>();
+        let runner = TokioCommandRunner {
+            cwd: Some(base_dir),
+        };
+
+        let build_start = time::Instant::now();
+        run_build_commands(
+            &runner,
+            &self.build_commands,
+            self.options
+                .time_limit
+                .map(std::time::Duration::from_millis),
+            None,
+        )
+        .with_cancel(cancellation_token.clone())
+        .await
+        .unwrap_or(Err(BuildError::Cancelled))?;
+        self.build_duration = Some(build_start.elapsed());
+
+        self.run_sequential(&runner, rnd_id, None, None, cancellation_token)
+            .await
+    }
+
+    /// Run the tests of this suite one after another.
+    async fn run_sequential(
+        &mut self,
+        runner: &(impl CommandRunner + Send),
+        rnd_id: u32,
+        result_channel: Option<&TestResultChannel>,
+        upload_info: Option<&Arc<ResultUploadConfig>>,
+        cancellation_token: CancellationTokenHandle,
+    ) -> anyhow::Result<HashMap<String, TestResult>> {
         let mut result = HashMap::new();
+        let mut failed = false;
+        // Generated expected outputs, keyed by the input of the test.
+        let generated_out = std::sync::Mutex::new(HashMap::new());
+
+        for i in 0..self.test_cases.len() {
+            let case = &self.test_cases[i];
+            let name = case.name.clone();
+            if self.options.fail_fast && failed {
+                log::info!("{:08x}: skipped test: {}", rnd_id, name);
+                let res = bare_result(TestResultKind::Skipped);
+                result_channel.map(|ch| ch.send((name.clone(), res.clone())));
+                result.insert(name, res);
+                continue;
+            }
 
-        for case in &self.test_cases {
             log::info!(
                 "{:08x}: started test: {}, timeout {:?}",
                 rnd_id,
-                case.name,
-                time_limit
+                name,
+                self.time_limit_of(case)
             );
 
-            result_channel.as_ref().map(|ch| {
-                ch.send((
-                    case.name.clone(),
-                    TestResult {
-                        kind: TestResultKind::Running,
-                        score: None,
-                        result_file_id: None,
-                    },
-                ))
-            });
-            let mut t = Test::new();
-            t.should_fail = case.should_fail;
-            self.exec.iter().for_each(|step| {
-                t.add_step(Step::with_timeout(
-                    Capturable::new(step.command.clone()),
-                    time_limit.map(|n| std::time::Duration::from_secs(n as u64)),
-                    step.is_user_command,
-                ));
-            });
-            if let Some(out) = case.expected_out.as_deref() {
-                t.expected(out);
-            }
-
-            let replacer: HashMap<String, _> = self
-                .vars
-                .iter()
-                .map(|(var, ext)| {
-                    (var.to_owned(), {
-                        // Special case for `$stdout`:
-                        // These variables will point to files under `io_dir`,
-                        // while others to `src_dir`.
-                        let mut p = match var.as_ref() {
-                            "$stdout" => self.test_root.clone(),
-                            _ => self.container_test_root.clone(),
-                        };
-                        p.push(format!("{}.{}", &case.name, ext));
-                        p.to_slash_lossy()
-                    })
-                })
-                .collect();
+            result_channel.map(|ch| ch.send((name.clone(), bare_result(TestResultKind::Running))));
+            let mut t = self.new_test(case);
 
+            let files = self.case_files(case);
             if let Some(spj) = &mut self.spj_env {
                 if spj.features().case_init() {
-                    log::trace!("{:08x}: spj init {}", rnd_id, case.name);
-                    spj.spj_case_init(case, &replacer).await?;
+                    log::trace!("{:08x}: spj init {}", rnd_id, name);
+                    spj.spj_case_init(case, &files).await?;
                 }
             }
 
-            log::trace!("{:08x}: created test: {}", rnd_id, case.name);
+            log::trace!("{:08x}: created test: {}", rnd_id, name);
+
+            let variables = self.case_variables(case, files);
+            let generated = self
+                .generate_expected(
+                    &mut t,
+                    runner,
+                    case,
+                    &variables,
+                    &generated_out,
+                    cancellation_token.clone(),
+                )
+                .await;
+
+            let base_score = case.base_score;
+            let test_start = time::Instant::now();
+            let (res, details) = match generated {
+                Ok(()) => t
+                    .run_with_details(runner, &variables, self.spj_env.as_mut())
+                    .with_cancel(cancellation_token.clone())
+                    .await
+                    .unwrap_or_else(|| (Err(JobFailure::Cancelled), Default::default())),
+                Err(e) => (Err(e), Default::default()),
+            };
+            let usage = ResourceUsage::of(&details.output);
+            self.record_test(&name, test_start.elapsed(), details);
+            log::trace!("{:08x}: runned: {}", rnd_id, name);
+
+            let res =
+                report_result(&name, res, usage, base_score, upload_info, result_channel).await;
+            log::trace!("{:08x}: uploaded result: {}", rnd_id, name);
 
-            let res = t
-                .run(&runner, &replacer, self.spj_env.as_mut())
-                .with_cancel(cancellation_token.clone())
-                .await
-                .unwrap_or(Err(JobFailure::Cancelled));
-            log::trace!("{:08x}: runned: {}", rnd_id, case.name);
-
-            let (mut res, cache) = TestResult::from_result(res, case.base_score);
-            if let Some(cfg) = &upload_info {
-                if let Some(cache) = cache {
-                    let file = upload_test_result(cache, cfg.clone(), &case.name).await;
-                    res.result_file_id = file;
+            failed |= res.kind != TestResultKind::Accepted;
+            result.insert(name, res);
+        }
+
+        Ok(result)
+    }
+
+    /// Run the tests of this suite, `parallel` at a time, each in its own
+    /// working directory under [`PARALLEL_TEST_ROOT`]. Tests not started yet are
+    /// skipped after a failure with [`fail_fast`](TestSuiteOptions::fail_fast),
+    /// while running ones are left to finish.
+    async fn run_parallel(
+        &mut self,
+        runner: &DockerCommandRunner,
+        parallel: usize,
+        result_channel: Option<&TestResultChannel>,
+        upload_info: Option<&Arc<ResultUploadConfig>>,
+        cancellation_token: CancellationTokenHandle,
+    ) -> anyhow::Result<HashMap<String, TestResult>> {
+        let failed = AtomicBool::new(false);
+        let generated_out = std::sync::Mutex::new(HashMap::new());
+        let semaphore = tokio::sync::Semaphore::new(parallel);
+
+        let this = &*self;
+        let runs = this.test_cases.iter().enumerate().map(|(i, case)| {
+            let (failed, generated_out, semaphore) = (&failed, &generated_out, &semaphore);
+            let cancellation_token = cancellation_token.clone();
+            async move {
+                let _permit = semaphore.acquire().await;
+                if this.options.fail_fast && failed.load(Ordering::SeqCst) {
+                    let res = bare_result(TestResultKind::Skipped);
+                    result_channel.map(|ch| ch.send((case.name.clone(), res.clone())));
+                    return (res, None);
                 }
+                result_channel
+                    .map(|ch| ch.send((case.name.clone(), bare_result(TestResultKind::Running))));
+
+                let mut t = this.new_test(case);
+                let mut variables = this.case_variables(case, this.case_files(case));
+                let dir = format!("{}/{}", PARALLEL_TEST_ROOT, i);
+                variables.insert(TEST_DIR_ENV_VAR.into(), dir.clone());
+
+                let test_start = time::Instant::now();
+                let (res, details) = match create_test_dir(runner, &dir)
+                    .and_then(|_| {
+                        this.generate_expected(
+                            &mut t,
+                            runner,
+                            case,
+                            &variables,
+                            generated_out,
+                            cancellation_token.clone(),
+                        )
+                    })
+                    .await
+                {
+                    Ok(()) => t
+                        .run_with_details(runner, &variables, None)
+                        .with_cancel(cancellation_token)
+                        .await
+                        .unwrap_or_else(|| (Err(JobFailure::Cancelled), Default::default())),
+                    Err(e) => (Err(e), Default::default()),
+                };
+                let elapsed = test_start.elapsed();
+
+                let res = report_result(
+                    &case.name,
+                    res,
+                    ResourceUsage::of(&details.output),
+                    case.base_score,
+                    upload_info,
+                    result_channel,
+                )
+                .await;
+                if res.kind != TestResultKind::Accepted {
+                    failed.store(true, Ordering::SeqCst);
+                }
+                (res, Some((elapsed, details)))
             }
+        });
+        let outcomes = future::join_all(runs).await;
 
-            log::trace!("{:08x}: uploaded result: {}", rnd_id, case.name);
+        let mut result = HashMap::new();
+        for (i, (res, run)) in outcomes.into_iter().enumerate() {
+            let name = self.test_cases[i].name.clone();
+            if let Some((elapsed, details)) = run {
+                self.record_test(&name, elapsed, details);
+            }
+            result.insert(name, res);
+        }
+        Ok(result)
+    }
 
-            result_channel
-                .as_ref()
-                .map(|ch| ch.send((case.name.clone(), res.clone())));
+    /// Time limit of the commands of `case` in milliseconds, which is the
+    /// one of the test suite unless overridden for this test.
+    fn time_limit_of(&self, case: &TestCase) -> Option<u64> {
+        case.time_limit.or(self.options.time_limit)
+    }
 
-            result.insert(case.name.clone(), res);
+    /// A [`Test`] running the commands of this suite on `case`.
+    fn new_test(&self, case: &TestCase) -> Test {
+        let time_limit = self.time_limit_of(case);
+        let mut t = Test::new();
+        t.should_fail = case.should_fail;
+        if case.expected_exit_code.is_some() || case.expected_stderr.is_some() {
+            // Patterns are checked in `create_test_case`
+            let stderr = case
+                .expected_stderr
+                .as_deref()
+                .and_then(|x| regex::Regex::new(x).ok());
+            t.expected_failure(case.expected_exit_code, stderr);
         }
+        t.presentation_error(self.presentation_error);
+        t.output_region(self.output_region.clone());
+        t.batch(self.batch_commands);
+        t.scorer(self.scorer.clone());
+        t.hooks(self.before_each.clone(), self.after_each.clone());
+        self.exec.iter().for_each(|step| {
+            t.add_step(
+                Step::with_timeout(
+                    Capturable::new(step.command.clone()),
+                    time_limit.map(std::time::Duration::from_millis),
+                    step.is_user_command,
+                )
+                .set_allow_failure(step.allow_failure),
+            );
+        });
+        if let Some(out) = case.expected_out.as_deref() {
+            t.expected(out);
+        }
+        t.stderr_policy(self.stderr_policy, case.expected_stderr_out.clone());
+        t.max_line_length(self.max_line_length);
+        t.exit_code_map(self.exit_code_map.clone());
+        t
+    }
 
-        runner.kill().await;
+    /// Paths of the files of `case`, keyed by their variables in
+    /// [`vars`](Self::vars).
+    fn case_files(&self, case: &TestCase) -> IndexMap<String, String> {
+        self.vars
+            .iter()
+            .map(|(var, ext)| {
+                (var.to_owned(), {
+                    // Special case for `$stdout`:
+                    // These variables will point to files under `io_dir`,
+                    // while others to `src_dir`.
+                    let mut p = match var.as_ref() {
+                        "$stdout" => self.test_root.clone(),
+                        _ => self.container_test_root.clone(),
+                    };
+                    p.push(format!("{}.{}", case.stem(), ext));
+                    p.to_slash_lossy()
+                })
+            })
+            .collect()
+    }
 
-        log::trace!("{:08x}: finished", rnd_id);
+    /// Variables passed to the commands of `case`: the paths of its `files`,
+    /// its environment variables and the seed of the job.
+    fn case_variables(
+        &self,
+        case: &TestCase,
+        files: IndexMap<String, String>,
+    ) -> IndexMap<String, String> {
+        let mut variables = files;
+        variables.extend(case.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(seed) = self.options.seed {
+            variables.insert(SEED_ENV_VAR.into(), seed.to_string());
+        }
+        variables
+    }
 
-        Ok(result)
+    /// Set the expected output of `t` to the one generated for `case`, if it
+    /// should be. Outputs are generated once for each input, and kept in
+    /// `generated_out` keyed by the input.
+    async fn generate_expected(
+        &self,
+        t: &mut Test,
+        runner: &(impl CommandRunner + Send),
+        case: &TestCase,
+        variables: &IndexMap<String, String>,
+        generated_out: &std::sync::Mutex<HashMap<String, String>>,
+        cancellation_token: CancellationTokenHandle,
+    ) -> Result<(), JobFailure> {
+        let generator = match &self.generator {
+            Some(generator) if case.generate_out => generator,
+            _ => return Ok(()),
+        };
+        let input = variables.get("$stdin").unwrap_or(&case.name).clone();
+        let cached = generated_out.lock().unwrap().get(&input).cloned();
+        let out = match cached {
+            Some(out) => out,
+            None => {
+                let out = generate_expected_out(
+                    runner,
+                    generator,
+                    variables,
+                    self.time_limit_of(case)
+                        .map(std::time::Duration::from_millis),
+                )
+                .with_cancel(cancellation_token)
+                .await
+                .unwrap_or(Err(JobFailure::Cancelled))?;
+                generated_out.lock().unwrap().insert(input, out.clone());
+                out
+            }
+        };
+        t.expected(&out);
+        Ok(())
+    }
+
+    /// Keep the timing and output of the test `name` in
+    /// [`records`](Self::records), and its comparison report if asked to.
+    fn record_test(&mut self, name: &str, elapsed: time::Duration, details: TestRunDetails) {
+        self.records.push(TestRecord::new(
+            name.to_owned(),
+            elapsed,
+            &details.output,
+            self.options.record_output_len,
+        ));
+        if self.options.report_all {
+            if let Some(report) = details.report {
+                self.reports.insert(name.to_owned(), report);
+            }
+        }
+    }
+}
+
+/// A [`TestResult`] of `kind` without a score or output.
+fn bare_result(kind: TestResultKind) -> TestResult {
+    TestResult {
+        kind,
+        score: None,
+        result_file_id: None,
+        upload_failed: false,
+        usage: None,
+    }
+}
+
+/// The [`TestResult`] of the test `name` finished with `res` after using
+/// `usage`, sent to `result_channel`. The output of failed tests is uploaded
+/// if `upload_info` is given.
+async fn report_result(
+    name: &str,
+    res: Result<f64, JobFailure>,
+    usage: Option<ResourceUsage>,
+    base_score: f64,
+    upload_info: Option<&Arc<ResultUploadConfig>>,
+    result_channel: Option<&TestResultChannel>,
+) -> TestResult {
+    let (mut res, cache) = TestResult::from_result(res, base_score);
+    res.usage = usage;
+    if let (Some(cfg), Some(cache)) = (upload_info, cache) {
+        match upload_test_result(cache, cfg.clone(), name).await {
+            Ok(file) => res.result_file_id = Some(file),
+            Err(_) => res.upload_failed = true,
+        }
+    }
+    result_channel.map(|ch| ch.send((name.to_owned(), res.clone())));
+    res
+}
+
+/// Create the empty directory `dir` of a test run in parallel.
+async fn create_test_dir(runner: &DockerCommandRunner, dir: &str) -> Result<(), JobFailure> {
+    let cmd = format!("rm -rf {0} && mkdir -p {0}", dir);
+    match runner.run(&cmd, &IndexMap::new()).await {
+        Ok(info) if info.ret_code == 0 => Ok(()),
+        Ok(info) => Err(JobFailure::InternalError(format!(
+            "Failed to create test directory `{}`: {}",
+            dir, info.stderr
+        ))),
+        Err(e) => Err(JobFailure::InternalError(format!(
+            "Failed to create test directory `{}`: {}",
+            dir, e
+        ))),
     }
 }
 
@@ -762,20 +1717,21 @@ async fn create_test_case(
     container_test_root: &Path,
     case: &TestCaseDefinition,
     name: String,
+    file_stem: String,
 ) -> Result<TestCase> {
-    let replacer: HashMap<String, _> = public_cfg
+    let replacer: IndexMap<String, _> = public_cfg
         .vars
         .iter()
         .map(|(var, ext)| {
             (var.to_owned(), {
-                // Special case for `$stdout`:
+                // Special case for `$stdout` and `$stderr`:
                 // These variables will point to files under `io_dir`,
                 // while others to `src_dir`.
                 let p = match var.as_ref() {
-                    "$stdout" => test_root,
+                    "$stdout" | "$stderr" => test_root,
                     _ => container_test_root,
                 };
-                p.join(format!("{}.{}", name, ext)).to_slash_lossy()
+                p.join(format!("{}.{}", file_stem, ext)).to_slash_lossy()
             })
         })
         .collect();
@@ -783,38 +1739,219 @@ async fn create_test_case(
     // ? QUESTION: Now I'm reading `$stdout` in host, but the source file, etc. are handled in containers.
     // ? Is this desirable?
 
-    let expected_out = if case.has_out && !case.should_fail {
-        let stdout_path = replacer.get("$stdout").ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                "Output verification failed, no `$stdout` in dictionary",
+    if let Some(pattern) = &case.expected_stderr {
+        regex::Regex::new(pattern).map_err(|e| {
+            anyhow::anyhow!(
+                "Invalid expected stderr pattern of test case {:?}: {}",
+                case.name,
+                e
             )
         })?;
+    }
 
-        let mut expected_out = Vec::new();
-        let mut file = tokio::fs::File::open(stdout_path).await.map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                format!(
-                    "Output verification failed, failed to open `{:?}`: {}",
-                    stdout_path, e,
-                ),
-            )
-        })?;
-        file.read_to_end(&mut expected_out).await?;
-        Some(String::from_utf8_lossy(&expected_out).into_owned())
+    let generate_out = case.has_out && !case.should_fail && public_cfg.generator.is_some();
+    let expected_out = if case.has_out && !case.should_fail && !generate_out {
+        Some(read_expected_output(&replacer, "$stdout").await?)
+    } else {
+        None
+    };
+    let expected_stderr_out = if public_cfg.stderr_policy == StderrPolicy::Compare
+        && case.has_out
+        && !case.should_fail
+        && replacer.contains_key("$stderr")
+    {
+        Some(read_expected_output(&replacer, "$stderr").await?)
     } else {
         None
     };
 
+    let time_limit = public_cfg.test_time_limit(&name, case);
     Result::Ok(TestCase {
-        name: name.to_owned(),
+        file_stem: Some(file_stem).filter(|x| *x != name),
+        name,
         expected_out,
+        generate_out,
         should_fail: case.should_fail,
+        expected_exit_code: case.expected_exit_code,
+        expected_stderr: case.expected_stderr.clone(),
+        expected_stderr_out,
         base_score: case.base_score,
+        env: public_cfg.test_env(case),
+        time_limit,
     })
 }
 
+/// Read the expected output file the variable `var` points to.
+async fn read_expected_output(replacer: &IndexMap<String, String>, var: &str) -> Result<String> {
+    let path = replacer.get(var).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Output verification failed, no `{}` in dictionary", var),
+        )
+    })?;
+
+    let mut expected_out = Vec::new();
+    let mut file = tokio::fs::File::open(path).await.map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Output verification failed, failed to open `{:?}`: {}",
+                path, e,
+            ),
+        )
+    })?;
+    file.read_to_end(&mut expected_out).await?;
+    Ok(String::from_utf8_lossy(&expected_out).into_owned())
+}
+
+/// Run the `build` commands of a job once, sending their output into
+/// `channel` as part of the build log.
+async fn run_build_commands(
+    runner: &(impl CommandRunner + Send),
+    commands: &[String],
+    timeout: Option<time::Duration>,
+    channel: Option<&BuildResultChannel>,
+) -> Result<(), BuildError> {
+    let vars = IndexMap::new();
+    for cmd in commands {
+        let info = match timeout {
+            Some(timeout) => runner.run_timeout(cmd, &vars, timeout).await,
+            None => runner.run(cmd, &vars).await,
+        };
+        let info = info.map_err(|e| BuildError::BuildError {
+            error: format!("Failed to run build command `{}`: {}", cmd, e),
+            detail: None,
+        })?;
+        if let Some(ch) = channel {
+            let _ = ch.send(BuildInfo {
+                stream: Some(format!("$ {}\n{}", cmd, info.stdout)),
+                error: Some(info.stderr.clone()).filter(|x| !x.is_empty()),
+                ..Default::default()
+            });
+        }
+        if info.ret_code != 0 {
+            return Err(BuildError::BuildError {
+                error: format!(
+                    "Build command `{}` returned {}:\n{}",
+                    cmd, info.ret_code, info.stderr
+                ),
+                detail: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Run the expected output `generator` of a test with `runner`, and return its
+/// `stdout`. Failures of the generator are internal errors, since it is
+/// provided by the test suite.
+async fn generate_expected_out(
+    runner: &(impl CommandRunner + Send),
+    generator: &str,
+    variables: &IndexMap<String, String>,
+    timeout: Option<time::Duration>,
+) -> Result<String, JobFailure> {
+    let info = match timeout {
+        Some(timeout) => runner.run_timeout(generator, variables, timeout).await,
+        None => runner.run(generator, variables).await,
+    }
+    .map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut => JobFailure::InternalError(format!(
+            "Expected output generator timed out after {:?}",
+            timeout.unwrap_or_default()
+        )),
+        _ => JobFailure::InternalError(format!("Failed to run expected output generator: {}", e)),
+    })?;
+    if info.ret_code != 0 {
+        return Err(JobFailure::InternalError(format!(
+            "Expected output generator returned {}:\n{}",
+            info.ret_code, info.stderr
+        )));
+    }
+    Ok(info.stdout)
+}
+
+/// Run the `scorer` of a test with `runner` on the `actual` and `expected`
+/// outputs, and return the score it prints and its `stderr` if not empty.
+/// Failures of the scorer and scores out of `[0, 1]` are internal errors,
+/// since it is provided by the suite.
+async fn run_scorer(
+    runner: &(impl CommandRunner + Send),
+    scorer: &str,
+    variables: &IndexMap<String, String>,
+    actual: &str,
+    expected: Option<&str>,
+    timeout: Option<std::time::Duration>,
+) -> Result<(f64, Option<String>), JobFailure> {
+    // Outputs may be too large for environment variables, so they are passed
+    // in files.
+    let write_err = |e: io::Error| {
+        JobFailure::InternalError(format!("Failed to write outputs for scorer: {}", e))
+    };
+    let mut files = vec![];
+    let mut variables = variables.clone();
+    let actual_file = runner
+        .write_temp_file(actual.as_bytes())
+        .await
+        .map_err(write_err)?;
+    files.push(actual_file.clone());
+    variables.insert(SCORER_ACTUAL_VAR.into(), actual_file);
+    if let Some(expected) = expected {
+        let expected_file = runner.write_temp_file(expected.as_bytes()).await;
+        let expected_file = match expected_file {
+            Ok(file) => file,
+            Err(e) => {
+                remove_temp_files(runner, &files).await;
+                return Err(write_err(e));
+            }
+        };
+        files.push(expected_file.clone());
+        variables.insert(SCORER_EXPECTED_VAR.into(), expected_file);
+    }
+
+    let info = match timeout {
+        Some(timeout) => runner.run_timeout(scorer, &variables, timeout).await,
+        None => runner.run(scorer, &variables).await,
+    };
+    remove_temp_files(runner, &files).await;
+    let info = info.map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut => JobFailure::InternalError(format!(
+            "Scorer timed out after {:?}",
+            timeout.unwrap_or_default()
+        )),
+        _ => JobFailure::InternalError(format!("Failed to run scorer: {}", e)),
+    })?;
+    if info.ret_code != 0 {
+        return Err(JobFailure::InternalError(format!(
+            "Scorer returned {}:\n{}",
+            info.ret_code, info.stderr
+        )));
+    }
+    let score = info
+        .stdout
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|x| (0.0..=1.0).contains(x))
+        .ok_or_else(|| {
+            JobFailure::InternalError(format!(
+                "Scorer should print a score between 0 and 1, got {:?}",
+                info.stdout.trim()
+            ))
+        })?;
+    let reason = Some(info.stderr.trim().to_owned()).filter(|x| !x.is_empty());
+    Ok((score, reason))
+}
+
+/// Remove `files` written by `runner`, only logging failures.
+async fn remove_temp_files(runner: &(impl CommandRunner + Send), files: &[String]) {
+    for file in files {
+        if let Err(e) = runner.remove_temp_file(file).await {
+            log::warn!("Failed to remove temporary file {}: {}", file, e);
+        }
+    }
+}
+
 fn construct_case_index(pub_cfg: &JudgerPublicConfig) -> HashMap<String, &TestCaseDefinition> {
     pub_cfg
         .test_groups
@@ -823,3 +1960,46 @@ fn construct_case_index(pub_cfg: &JudgerPublicConfig) -> HashMap<String, &TestCa
         .map(|test| (test.name.clone(), test))
         .collect()
 }
+
+/// The test cases to run for the requested `tests`, with their names,
+/// definitions and file stems. Templates with
+/// [`inputs`](TestCaseDefinition::inputs) are expanded with the files under
+/// `test_root`; a single expanded test case like `name[01]` may be requested
+/// too.
+async fn expand_tests<'a>(
+    index: &HashMap<String, &'a TestCaseDefinition>,
+    tests: &[String],
+    test_root: &Path,
+) -> Result<Vec<(String, &'a TestCaseDefinition, String)>> {
+    let mut expanded = vec![];
+    for name in tests {
+        let (case, instance) = match index.get(name) {
+            Some(case) => (*case, None),
+            None => {
+                let template = name
+                    .strip_suffix(']')
+                    .and_then(|x| x.split_once('['))
+                    .and_then(|(base, _)| index.get(base))
+                    .filter(|case| case.inputs.is_some())
+                    .ok_or_else(|| anyhow::anyhow!("Unknown test case {:?}", name))?;
+                (*template, Some(name))
+            }
+        };
+        case.check_inputs().map_err(anyhow::Error::msg)?;
+        let cases = case
+            .expand(test_root)
+            .await
+            .with_context(|| format!("Failed to find the inputs of test case {:?}", case.name))?;
+        let found = expanded.len();
+        expanded.extend(
+            cases
+                .into_iter()
+                .filter(|(x, _)| instance.map_or(true, |name| x == name))
+                .map(|(name, stem)| (name, case, stem)),
+        );
+        if expanded.len() == found {
+            anyhow::bail!("Test case {:?} has no inputs", name);
+        }
+    }
+    Ok(expanded)
+}
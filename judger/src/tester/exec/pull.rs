@@ -0,0 +1,187 @@
+//! Deciding when base images are pulled again, see [`PullPolicy`].
+//!
+//! Mutable tags like `ubuntu:latest` resolve to whatever is cached on the
+//! host, so judgers pulling at different times may run different images.
+//! Images pinned by digest (`image@sha256:...`) never change, and are only
+//! pulled if not present.
+
+use crate::client::config::PullPolicy;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Interval between pulls of the same image under [`PullPolicy::Daily`].
+const DAILY_PULL_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When every image was last pulled by this judger. Images not pulled since
+/// the judger started are pulled again under [`PullPolicy::Daily`].
+static LAST_PULLED: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(Default::default);
+
+/// Whether `image` is pinned by its digest.
+pub fn is_digest_pinned(image: &str) -> bool {
+    image.contains('@')
+}
+
+/// Whether `image` should be pulled under `policy`. `present` tells if the
+/// image is in the local cache, and `last_pulled` how long ago it was last
+/// pulled, if known.
+pub fn should_pull(
+    policy: PullPolicy,
+    image: &str,
+    present: bool,
+    last_pulled: Option<Duration>,
+) -> bool {
+    if !present {
+        return true;
+    }
+    if is_digest_pinned(image) {
+        return false;
+    }
+    match policy {
+        PullPolicy::Always => true,
+        PullPolicy::IfNotPresent => false,
+        PullPolicy::Daily => match last_pulled {
+            Some(x) => x >= DAILY_PULL_INTERVAL,
+            None => true,
+        },
+    }
+}
+
+/// How long ago `image` was last pulled by this judger.
+pub fn last_pulled(image: &str) -> Option<Duration> {
+    LAST_PULLED.lock().unwrap().get(image).map(|x| x.elapsed())
+}
+
+/// Record that `image` has just been pulled.
+pub fn record_pull(image: &str) {
+    LAST_PULLED
+        .lock()
+        .unwrap()
+        .insert(image.to_owned(), Instant::now());
+}
+
+/// Host of Docker Hub, the registry of images without a registry host.
+pub const DOCKER_HUB_HOST: &str = "docker.io";
+
+/// Server address Docker expects in credentials of Docker Hub.
+pub const DOCKER_HUB_SERVER_ADDRESS: &str = "https://index.docker.io/v1/";
+
+/// `host`, or [`DOCKER_HUB_HOST`] if it is one of the other names of Docker
+/// Hub.
+pub fn normalize_registry_host(host: &str) -> &str {
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => DOCKER_HUB_HOST,
+        _ => host,
+    }
+}
+
+/// Host of the registry `image` is pulled from. Like Docker, the first
+/// component of the name is only a host if it contains `.` or `:`, or is
+/// `localhost`.
+pub fn registry_host(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':'].as_ref()) || host == "localhost" => {
+            normalize_registry_host(host)
+        }
+        _ => DOCKER_HUB_HOST,
+    }
+}
+
+/// Whether pulling an image failed because the registry rejected the
+/// credentials, or requires some.
+pub fn is_unauthorized(err: &bollard::errors::Error) -> bool {
+    if let bollard::errors::Error::DockerResponseServerError {
+        status_code: 401 | 403,
+        ..
+    } = err
+    {
+        return true;
+    }
+    let msg = err.to_string().to_lowercase();
+    msg.contains("unauthorized")
+        || msg.contains("authentication required")
+        || msg.contains("no basic auth credentials")
+        || msg.contains("incorrect username or password")
+}
+
+/// Base images of the stages in `dockerfile`, skipping `scratch`, earlier
+/// stages and images containing build arguments, which cannot be resolved
+/// here.
+pub fn dockerfile_base_images(dockerfile: &str) -> Vec<String> {
+    let mut stages = vec![];
+    let mut images = vec![];
+    for line in dockerfile.lines() {
+        let mut words = line.split_whitespace();
+        if !matches!(words.next(), Some(x) if x.eq_ignore_ascii_case("FROM")) {
+            continue;
+        }
+        let mut words = words.filter(|x| !x.starts_with("--"));
+        let image = match words.next() {
+            Some(image) => image,
+            None => continue,
+        };
+        if image != "scratch" && !image.contains('$') && !stages.iter().any(|x| x == image) {
+            images.push(image.to_owned());
+        }
+        if let (Some(as_), Some(name)) = (words.next(), words.next()) {
+            if as_.eq_ignore_ascii_case("AS") {
+                stages.push(name.to_owned());
+            }
+        }
+    }
+    images
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_should_pull() {
+        let day = Some(DAILY_PULL_INTERVAL);
+        let hour = Some(Duration::from_secs(3600));
+        let pinned = "ubuntu@sha256:0123";
+
+        assert!(should_pull(PullPolicy::IfNotPresent, "ubuntu", false, None));
+        assert!(!should_pull(PullPolicy::IfNotPresent, "ubuntu", true, None));
+        assert!(should_pull(PullPolicy::Always, "ubuntu", true, hour));
+        assert!(!should_pull(PullPolicy::Always, pinned, true, None));
+        assert!(should_pull(PullPolicy::Always, pinned, false, None));
+        assert!(should_pull(PullPolicy::Daily, "ubuntu", true, None));
+        assert!(should_pull(PullPolicy::Daily, "ubuntu", true, day));
+        assert!(!should_pull(PullPolicy::Daily, "ubuntu", true, hour));
+    }
+
+    #[test]
+    fn test_registry_host() {
+        assert_eq!(registry_host("ubuntu"), "docker.io");
+        assert_eq!(registry_host("library/ubuntu:20.04"), "docker.io");
+        assert_eq!(registry_host("index.docker.io/library/ubuntu"), "docker.io");
+        assert_eq!(registry_host("ghcr.io/owner/image:tag"), "ghcr.io");
+        assert_eq!(
+            registry_host("registry.example.com:5000/image@sha256:0123"),
+            "registry.example.com:5000"
+        );
+        assert_eq!(registry_host("localhost/image"), "localhost");
+        assert_eq!(registry_host("localhost:5000/image"), "localhost:5000");
+    }
+
+    #[test]
+    fn test_dockerfile_base_images() {
+        let dockerfile = "\
+            # FROM commented\n\
+            FROM --platform=linux/amd64 rust:latest AS build\n\
+            RUN cargo build\n\
+            from build\n\
+            FROM scratch\n\
+            FROM alpine@sha256:0123\n\
+            FROM ${BASE}\n";
+        assert_eq!(
+            dockerfile_base_images(dockerfile),
+            ["rust:latest", "alpine@sha256:0123"]
+        );
+    }
+}
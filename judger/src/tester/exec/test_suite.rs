@@ -15,6 +15,7 @@ fn golem_no_volume() -> Result<()> {
                 tag: image_name.to_owned(),
                 path: host_repo_dir,
                 file: None,
+                build_args: HashMap::new(),
             },
             &std::env::current_dir().unwrap(),
             JudgerPrivateConfig {
@@ -24,6 +25,7 @@ fn golem_no_volume() -> Result<()> {
             JudgerPublicConfig {
                 time_limit: None,
                 memory_limit: None,
+                test_time_limits: HashMap::new(),
                 name: "golem_no_volume".into(),
                 test_groups: {
                     [(
@@ -33,6 +35,10 @@ fn golem_no_volume() -> Result<()> {
                             should_fail: false,
                             has_out: true,
                             base_score: 1.0,
+                            env: IndexMap::new(),
+                            expected_exit_code: None,
+                            expected_stderr: None,
+                            inputs: None,
                         }],
                     )]
                     .iter()
@@ -50,26 +56,51 @@ fn golem_no_volume() -> Result<()> {
                 .collect(),
                 run: ["cat $stdin | python ./golem.py $bin"]
                     .iter()
-                    .map(|s| s.to_string())
+                    .map(|&s| s.into())
                     .collect(),
+                env: IndexMap::new(),
+                generator: None,
+                scorer: None,
 
                 mapped_dir: Bind {
                     from: PathBuf::from(r"../golem/src"),
                     to: PathBuf::from(r"../golem/src"),
+                    ..Default::default()
                 },
                 binds: None,
+                shared_binds: vec![],
                 special_judge_script: None,
                 network: NetworkOptions {
                     enable_running: false,
                     enable_build: false,
+                    audit_running: false,
+                    allowed_hosts: vec![],
                 },
                 test_ignore: None,
+                presentation_error: false,
+                output_region: None,
+                batch_commands: false,
+                before_each: vec![],
+                after_each: vec![],
+                combined_output: false,
+                stderr_policy: StderrPolicy::Ignore,
+                parallel_tests: None,
+                max_line_length: None,
+                max_output_size: None,
+                exit_code_map: Default::default(),
+                user_run: Default::default(),
+                required_files: vec![],
+                hostname: None,
+                services: vec![],
             },
             &JudgeTomlTestConfig {
                 // TODO: Refine interface
                 image: Image::Prebuilt { tag: "".into() },
                 build: None,
                 run: vec!["python ./golemc.py $src -o $bin".into()],
+                language: None,
+                source: None,
+                args: None,
             },
             TestSuiteOptions {
                 tests: ["succ"].iter().map(|s| s.to_string()).collect(),
@@ -77,6 +108,13 @@ fn golem_no_volume() -> Result<()> {
                 mem_limit: None,
                 build_image: true,
                 remove_image: true,
+                report_all: false,
+                shuffle_seed: None,
+                fail_fast: false,
+                record_output_len: None,
+                seed: None,
+                docker_config: Default::default(),
+                output_budget: None,
             },
         )
         .await?;
@@ -108,6 +146,7 @@ fn golem_with_volume() -> Result<()> {
                 tag: image_name.to_owned(),
                 path: host_repo_dir, // public: c# gives repo remote, rust clone and unzip
                 file: None,
+                build_args: HashMap::new(),
             },
             &std::env::current_dir().unwrap(),
             JudgerPrivateConfig {
@@ -117,6 +156,7 @@ fn golem_with_volume() -> Result<()> {
             JudgerPublicConfig {
                 time_limit: None,
                 memory_limit: None,
+                test_time_limits: HashMap::new(),
                 name: "golem".into(),
                 test_groups: {
                     [(
@@ -126,6 +166,10 @@ fn golem_with_volume() -> Result<()> {
                             should_fail: false,
                             has_out: true,
                             base_score: 1.0,
+                            env: IndexMap::new(),
+                            expected_exit_code: None,
+                            expected_stderr: None,
+                            inputs: None,
                         }],
                     )]
                     .iter()
@@ -143,18 +187,21 @@ fn golem_with_volume() -> Result<()> {
                 .collect(),
                 run: ["cat $stdin | python ./golem.py $bin"] // public
                     .iter()
-                    .map(|s| s.to_string())
+                    .map(|&s| s.into())
                     .collect(),
 
                 mapped_dir: Bind {
                     from: PathBuf::from(r"../golem/src"),
                     to: PathBuf::from(r"../golem/src"),
+                    ..Default::default()
                 },
                 binds: Some(vec![]),
                 special_judge_script: None,
                 network: NetworkOptions {
                     enable_running: false,
                     enable_build: false,
+                    audit_running: false,
+                    allowed_hosts: vec![],
                 },
                 ..Default::default()
             },
@@ -163,6 +210,9 @@ fn golem_with_volume() -> Result<()> {
                 image: Image::Prebuilt { tag: "".into() },
                 build: None,
                 run: vec!["python ./golemc.py $src -o $bin".into()],
+                language: None,
+                source: None,
+                args: None,
             },
             TestSuiteOptions {
                 tests: ["succ"].iter().map(|s| s.to_string()).collect(), // private
@@ -170,6 +220,13 @@ fn golem_with_volume() -> Result<()> {
                 mem_limit: None,                                         // private
                 build_image: true,                                       // private
                 remove_image: true,                                      // private
+                report_all: false,                                       // private
+                shuffle_seed: None,                                      // private
+                fail_fast: false,                                        // private
+                record_output_len: None,                                 // private
+                seed: None,                                              // private
+                docker_config: Default::default(),                       // private
+                output_budget: None,                                     // private
             },
         )
         .await?;
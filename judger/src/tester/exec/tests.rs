@@ -3,6 +3,172 @@ use super::*;
 use pretty_assertions::assert_eq as pretty_eq;
 use tokio_test::block_on;
 
+#[test]
+fn record_truncates_output() {
+    let info = ProcessInfo {
+        ret_code: 0,
+        is_user_command: true,
+        command: "cat".into(),
+        stdout: "αβγδ".into(),
+        stderr: "".into(),
+        peak_pids: None,
+        pids_limit: None,
+        wall_time_ms: None,
+        cpu_time_ms: None,
+        peak_memory: None,
+        combined: None,
+        reservation: Default::default(),
+    };
+    let duration = time::Duration::from_millis(1);
+
+    let kept = TestRecord::new("a".into(), duration, std::slice::from_ref(&info), Some(16));
+    assert!(!kept.truncated);
+    assert_eq!(kept.output, vec![info.clone()]);
+
+    // Truncated on a char boundary
+    let truncated = TestRecord::new("a".into(), duration, std::slice::from_ref(&info), Some(3));
+    assert!(truncated.truncated);
+    assert!(truncated.output[0].stdout.starts_with("α\n--- truncated"));
+
+    let dropped = TestRecord::new("a".into(), duration, &[info], None);
+    assert!(dropped.output.is_empty());
+}
+
+/// Process counts and resource usage differ between runs, so they are
+/// cleared before comparing.
+fn clear_usage<T>(mut res: Result<T, JobFailure>) -> Result<T, JobFailure> {
+    if let Err(JobFailure::ExecError(ExecError { output, .. }))
+    | Err(JobFailure::OutputMismatch(OutputMismatch { output, .. })) = &mut res
+    {
+        for info in output {
+            info.peak_pids = None;
+            info.pids_limit = None;
+            info.wall_time_ms = None;
+            info.cpu_time_ms = None;
+            info.peak_memory = None;
+        }
+    }
+    res
+}
+
+/// Pull images from a fake Docker daemon, which only serves
+/// `registry.example.com` images to clients sending the right credentials.
+#[tokio::test]
+async fn pull_with_registry_auth() {
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use std::{convert::Infallible, sync::Mutex};
+
+    type Sent = Arc<Mutex<Vec<bollard::auth::DockerCredentials>>>;
+    async fn respond(req: Request<Body>, sent: Sent) -> Result<Response<Body>, Infallible> {
+        let reply = |status, body: &str| {
+            Ok(Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_owned()))
+                .unwrap())
+        };
+        if !req.uri().path().ends_with("/images/create") {
+            return reply(StatusCode::NOT_FOUND, r#"{"message":"no such image"}"#);
+        }
+        let credentials = req
+            .headers()
+            .get("X-Registry-Auth")
+            .and_then(|x| base64::decode_config(x.as_bytes(), base64::URL_SAFE).ok())
+            .and_then(|x| serde_json::from_slice(&x).ok())
+            .unwrap_or_default();
+        let query = req.uri().query().unwrap_or_default().to_owned();
+        let authorized = matches!(
+            &credentials,
+            bollard::auth::DockerCredentials {
+                username: Some(user),
+                password: Some(pass),
+                ..
+            } if user == "user" && pass == "secret"
+        );
+        sent.lock().unwrap().push(credentials);
+        if !authorized {
+            reply(
+                StatusCode::UNAUTHORIZED,
+                r#"{"message":"unauthorized: authentication required"}"#,
+            )
+        } else if query.contains("missing") {
+            reply(StatusCode::NOT_FOUND, r#"{"message":"manifest unknown"}"#)
+        } else {
+            reply(StatusCode::OK, "{\"status\":\"Downloaded\"}\n")
+        }
+    }
+
+    let sent = Sent::default();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let make_svc = make_service_fn({
+        let sent = sent.clone();
+        move |_| {
+            let sent = sent.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| respond(req, sent.clone()))) }
+        }
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_svc));
+
+    let instance = bollard::Docker::connect_with_http(
+        &format!("http://{}", addr),
+        10,
+        bollard::API_DEFAULT_VERSION,
+    )
+    .unwrap();
+    let mut cfg = DockerConfig::default();
+    cfg.registry_auth.insert(
+        "registry.example.com".into(),
+        crate::client::config::RegistryAuth {
+            username: Some("user".into()),
+            password: Some("secret".into()),
+            identity_token: None,
+        },
+    );
+    let pull = |tag: &str| {
+        let image = Image::Prebuilt { tag: tag.into() };
+        let (instance, cfg) = (instance.clone(), &cfg);
+        async move {
+            let cancel = CancellationTokenHandle::new();
+            image.build(instance, None, cancel, None, None, cfg).await
+        }
+    };
+
+    pull("registry.example.com/private:latest").await.unwrap();
+    {
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].username.as_deref(), Some("user"));
+        assert_eq!(sent[0].password.as_deref(), Some("secret"));
+        assert_eq!(
+            sent[0].serveraddress.as_deref(),
+            Some("registry.example.com")
+        );
+    }
+
+    let res = pull("registry.example.com/missing:latest").await;
+    assert!(
+        matches!(res, Err(BuildError::ImagePullFailure(_))),
+        "{:?}",
+        res
+    );
+
+    // Credentials are only sent to their own registry
+    let res = pull("other.example.com/private:latest").await;
+    assert!(
+        matches!(res, Err(BuildError::ImagePullUnauthorized(_))),
+        "{:?}",
+        res
+    );
+    assert_eq!(sent.lock().unwrap()[2].password, None);
+
+    assert!(!format!("{:?}", cfg).contains("secret"));
+}
+
 #[cfg(unix)]
 mod tokio_runner {
     use super::*;
@@ -21,7 +187,9 @@ mod tokio_runner {
                 true,
             ));
             t.expected("Hello,\n");
-            let res = t.run(&TokioCommandRunner {}, &HashMap::new(), None).await;
+            let res = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
             assert!(matches!(dbg!(res), Ok(_)));
         })
     }
@@ -39,7 +207,9 @@ mod tokio_runner {
                 true,
             ));
             t.expected("Goodbye, world!");
-            let got = t.run(&TokioCommandRunner {}, &HashMap::new(), None).await;
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
             let expected: Result<f64, _> = Err(JobFailure::ExecError(ExecError {
                 stage: 1,
                 kind: ExecErrorKind::ReturnCodeCheckFailed,
@@ -50,6 +220,13 @@ mod tokio_runner {
                         stdout: "This does nothing.\n".into(),
                         stderr: "".into(),
                         is_user_command: true,
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                     ProcessInfo {
                         ret_code: 1,
@@ -57,10 +234,70 @@ mod tokio_runner {
                         stdout: "Hello, world!\n".into(),
                         stderr: "".into(),
                         is_user_command: true,
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                 ],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
+        })
+    }
+
+    #[test]
+    fn working_dir() {
+        block_on(async {
+            let dir = std::env::temp_dir().canonicalize().unwrap();
+            let runner = TokioCommandRunner {
+                cwd: Some(dir.clone()),
+            };
+            let mut t = Test::new();
+            t.add_step(Step::new(Capturable::new("pwd -P"), true));
+            t.expected(&format!("{}\n", dir.display()));
+            let res = t.run(&runner, &IndexMap::new(), None).await;
+            assert!(matches!(dbg!(res), Ok(_)));
+        })
+    }
+
+    #[test]
+    fn mapped_exit_code() {
+        block_on(async {
+            let mut t = Test::new();
+            t.add_step(Step::new(Capturable::new("exit 3"), true));
+            let map = [(3, TestResultKind::MemoryLimitExceeded)];
+            t.exit_code_map(Arc::new(map.iter().copied().collect()));
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            assert!(matches!(
+                got,
+                Err(JobFailure::ExecError(ExecError {
+                    kind: ExecErrorKind::MappedExitCode {
+                        code: 3,
+                        verdict: TestResultKind::MemoryLimitExceeded
+                    },
+                    ..
+                }))
+            ));
+
+            // Unmapped codes fail as usual
+            let mut t = Test::new();
+            t.add_step(Step::new(Capturable::new("exit 2"), true));
+            t.exit_code_map(Arc::new(map.iter().copied().collect()));
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            assert!(matches!(
+                got,
+                Err(JobFailure::ExecError(ExecError {
+                    kind: ExecErrorKind::ReturnCodeCheckFailed,
+                    ..
+                }))
+            ));
         })
     }
 
@@ -77,7 +314,9 @@ mod tokio_runner {
                 r#"{ sleep 0.1; kill $$; } & i=0; while [ "$i" -lt 4 ]; do echo $i; sleep 1; i=$(( i + 1 )); done"#
             ),true));
             t.expected("Hello,\nworld!\n");
-            let got = t.run(&TokioCommandRunner {}, &HashMap::new(), None).await;
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
             let expected: Result<f64, _> = Err(JobFailure::ExecError(ExecError {
                 stage: 1,
                 kind: ExecErrorKind::RuntimeError(
@@ -93,6 +332,13 @@ mod tokio_runner {
                         command: r"echo 'This does nothing.'".into(),
                         stdout: "This does nothing.\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                     ProcessInfo {
                         ret_code: -15,
@@ -100,10 +346,17 @@ mod tokio_runner {
                         command:r#"{ sleep 0.1; kill $$; } & i=0; while [ "$i" -lt 4 ]; do echo $i; sleep 1; i=$(( i + 1 )); done"#.into(),
                         stdout: "0\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                 ],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
         })
     }
 
@@ -120,7 +373,9 @@ mod tokio_runner {
                 true,
             ));
             t.expected("Hello,\nworld!");
-            let got = t.run(&TokioCommandRunner {}, &HashMap::new(), None).await;
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
             let expected: Result<f64, _> = Err(JobFailure::OutputMismatch(OutputMismatch {
                 diff: "+ Hello,\n  world!\n".into(),
                 output: vec![
@@ -130,6 +385,13 @@ mod tokio_runner {
                         command: r"echo 'This does nothing.'".into(),
                         stdout: "This does nothing.\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                     ProcessInfo {
                         ret_code: 0,
@@ -137,10 +399,261 @@ mod tokio_runner {
                         command: "echo 'Hello, world!' | awk '{print $2}'".into(),
                         stdout: "world!\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                 ],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
+        })
+    }
+
+    #[test]
+    fn presentation_error() {
+        block_on(async {
+            let make_test = |presentation_error| {
+                let mut t = Test::new();
+                t.add_step(Step::new(
+                    Capturable::new(r"printf 'Hello,  world!\n1 2\t3\n'"),
+                    true,
+                ));
+                t.expected("Hello, world!\n1 2 3\n")
+                    .presentation_error(presentation_error);
+                t
+            };
+
+            let got = make_test(true)
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            assert!(matches!(dbg!(got), Err(JobFailure::PresentationError(_))));
+
+            let got = make_test(false)
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            assert!(matches!(dbg!(got), Err(JobFailure::OutputMismatch(_))));
+        })
+    }
+
+    #[test]
+    fn presentation_error_wrong_tokens() {
+        block_on(async {
+            let mut t = Test::new();
+            t.add_step(Step::new(Capturable::new(r"printf '1  2 4\n'"), true));
+            t.expected("1 2 3\n").presentation_error(true);
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            assert!(matches!(dbg!(got), Err(JobFailure::OutputMismatch(_))));
+        })
+    }
+
+    #[test]
+    fn expected_failure() {
+        block_on(async {
+            let make_test = |exit_code, stderr: Option<&str>| {
+                let mut t = Test::new();
+                t.add_step(Step::new(
+                    Capturable::new("echo 'error: bad input' >&2; exit 3"),
+                    true,
+                ));
+                t.expected_failure(exit_code, stderr.map(|x| regex::Regex::new(x).unwrap()));
+                t
+            };
+            let run = |t: Test| async move {
+                t.run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                    .await
+            };
+
+            assert_eq!(run(make_test(None, None)).await, Ok(1.0));
+            assert_eq!(run(make_test(Some(3), Some("^error:"))).await, Ok(1.0));
+
+            let got = run(make_test(Some(2), None)).await;
+            assert!(matches!(
+                dbg!(got),
+                Err(JobFailure::ShouldFail(ShouldFailFailure {
+                    reason: Some(_),
+                    ..
+                }))
+            ));
+            let got = run(make_test(None, Some("overflow"))).await;
+            assert!(matches!(
+                dbg!(got),
+                Err(JobFailure::ShouldFail(ShouldFailFailure {
+                    reason: Some(_),
+                    ..
+                }))
+            ));
+
+            // Signals are still runtime errors
+            let mut t = Test::new();
+            t.add_step(Step::new(Capturable::new("kill -SEGV $$"), true));
+            t.expected_failure(None, None);
+            let got = run(t).await;
+            assert!(matches!(
+                dbg!(got),
+                Err(JobFailure::ExecError(ExecError {
+                    kind: ExecErrorKind::RuntimeError(_),
+                    ..
+                }))
+            ));
+        })
+    }
+
+    #[test]
+    fn before_and_after_each() {
+        block_on(async {
+            let dir =
+                std::env::temp_dir().join(format!("rurikawa-hooks-{}", rand::random::<u32>()));
+            let marker = dir.join("after");
+            let make_test = |before: &str, cmd: &str| {
+                let mut t = Test::new();
+                t.add_step(Step::new(Capturable::new(cmd), true));
+                t.hooks(
+                    vec![format!("mkdir -p {}", dir.display()), before.to_owned()],
+                    vec![format!("touch {}", marker.display())],
+                );
+                t
+            };
+            let run = |t: Test| async move {
+                t.run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                    .await
+            };
+
+            assert_eq!(run(make_test("true", "true")).await, Ok(1.0));
+
+            // Hooks run in their own shells, so they cannot change the
+            // environment of the test
+            let got = run(make_test(
+                "export HOOK_VAR=1; cd /",
+                r#"test -z "$HOOK_VAR" && test "$(pwd)" != /"#,
+            ))
+            .await;
+            assert_eq!(got, Ok(1.0));
+
+            // A failing `before_each` is a judger error, and skips the test
+            let got = run(make_test("false", "exit 1")).await;
+            assert!(matches!(dbg!(got), Err(JobFailure::InternalError(_))));
+
+            // `after_each` runs even if the test failed
+            let _ = std::fs::remove_file(&marker);
+            let got = run(make_test("true", "exit 1")).await;
+            let after_ran = marker.exists();
+            let _ = std::fs::remove_dir_all(&dir);
+            assert!(matches!(dbg!(got), Err(JobFailure::ExecError(_))));
+            assert!(after_ran);
+        })
+    }
+
+    #[test]
+    fn scorer() {
+        block_on(async {
+            let run = |cmd: &str, scorer: &str| {
+                let mut t = Test::new();
+                t.add_step(Step::new(Capturable::new(cmd), true));
+                t.expected("a b\n");
+                t.scorer(Some(scorer.to_owned()));
+                async move {
+                    t.run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                        .await
+                }
+            };
+            let score_by_diff = r#"sh -c 'if cmp -s "$RURIKAWA_ACTUAL" "$RURIKAWA_EXPECTED"; then echo 1; elif [ -s "$RURIKAWA_ACTUAL" ]; then echo 0.5; else echo 0; fi'"#;
+
+            assert_eq!(run("echo a b", score_by_diff).await, Ok(1.0));
+
+            let got = run("echo a c", score_by_diff).await;
+            match dbg!(got) {
+                Err(JobFailure::ScorerRejected(x)) => {
+                    assert_eq!(x.score, 0.5);
+                    assert_eq!(x.kind(), TestResultKind::PartialScore);
+                }
+                _ => panic!("expected a partial score"),
+            }
+
+            let got = run("true", score_by_diff).await;
+            assert!(
+                matches!(dbg!(got), Err(JobFailure::ScorerRejected(x)) if x.kind() == TestResultKind::WrongAnswer)
+            );
+
+            // Crashing scorers and invalid scores are judger errors
+            let got = run("echo a b", "sh -c 'exit 2'").await;
+            assert!(matches!(dbg!(got), Err(JobFailure::InternalError(_))));
+            let got = run("echo a b", "echo 1.5").await;
+            assert!(matches!(dbg!(got), Err(JobFailure::InternalError(_))));
+
+            // Outputs too large for environment variables
+            let mut t = Test::new();
+            t.add_step(Step::new(Capturable::new("yes a | head -c 300000"), true));
+            t.expected(&"a\n".repeat(150000));
+            t.scorer(Some(score_by_diff.to_owned()));
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            assert_eq!(got, Ok(1.0));
+        })
+    }
+
+    #[test]
+    fn stderr_policy() {
+        block_on(async {
+            let run = |policy, expected_stderr: Option<&str>| {
+                let mut t = Test::new();
+                t.add_step(Step::new(
+                    Capturable::new("echo out; echo 'warning: unused' >&2"),
+                    true,
+                ));
+                t.expected("out\n");
+                t.stderr_policy(policy, expected_stderr.map(|x| x.to_owned()));
+                async move {
+                    t.run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                        .await
+                }
+            };
+
+            assert_eq!(run(StderrPolicy::Ignore, None).await, Ok(1.0));
+
+            let got = run(StderrPolicy::FailIfNonempty, None).await;
+            assert!(matches!(dbg!(got), Err(JobFailure::OutputMismatch(_))));
+
+            let got = run(StderrPolicy::Compare, Some("warning: unused\n")).await;
+            assert_eq!(got, Ok(1.0));
+            let got = run(StderrPolicy::Compare, Some("")).await;
+            assert!(matches!(dbg!(got), Err(JobFailure::OutputMismatch(_))));
+        })
+    }
+
+    #[test]
+    fn batch_matches_separate_steps() {
+        block_on(async {
+            let make_test = |batch| {
+                let mut t = Test::new();
+                t.add_step(Step::new(Capturable::new("echo out; echo err >&2"), true));
+                t.add_step(Step::new(Capturable::new(r"printf 'no newline'"), false));
+                t.add_step(Step::new(
+                    Capturable::new("echo 'Hello, world!' && false"),
+                    true,
+                ));
+                t.add_step(Step::new(Capturable::new("echo unreachable"), true));
+                t.expected("unreachable\n").batch(batch);
+                t
+            };
+
+            let separate = make_test(false)
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            let batched = make_test(true)
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
+            assert!(matches!(
+                &separate,
+                Err(JobFailure::ExecError(ExecError { stage: 2, .. }))
+            ));
+            pretty_eq!(clear_usage(batched), clear_usage(separate));
         })
     }
 
@@ -157,7 +670,9 @@ mod tokio_runner {
                     .set_timeout(time::Duration::from_millis(100)),
             );
             t.expected("Hello,\nworld!\n");
-            let got = t.run(&TokioCommandRunner {}, &HashMap::new(), None).await;
+            let got = t
+                .run(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                .await;
             let expected: Result<f64, _> = Err(JobFailure::ExecError(ExecError {
                 stage: 1,
                 kind: ExecErrorKind::TimedOut,
@@ -167,9 +682,127 @@ mod tokio_runner {
                     command: r"echo 'This does nothing.'".into(),
                     stdout: "This does nothing.\n".into(),
                     stderr: "".into(),
+                    peak_pids: None,
+                    pids_limit: None,
+                    wall_time_ms: None,
+                    cpu_time_ms: None,
+                    peak_memory: None,
+                    combined: None,
+                    reservation: Default::default(),
                 }],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
+        })
+    }
+
+    #[test]
+    fn allow_failure() {
+        block_on(async {
+            let make_test = |allow_last: bool, batch: bool| {
+                let mut t = Test::new();
+                t.add_step(
+                    Step::new(Capturable::new("echo diagnostics; false"), false)
+                        .set_allow_failure(true),
+                );
+                t.add_step(
+                    Step::new(Capturable::new("echo 'Hello, world!'"), true)
+                        .set_allow_failure(allow_last),
+                );
+                t.expected("Hello, world!\n").batch(batch);
+                t
+            };
+            let run = |t: Test| async move {
+                t.run_with_details(&TokioCommandRunner::default(), &IndexMap::new(), None)
+                    .await
+            };
+
+            for batch in [false, true] {
+                let (res, details) = run(make_test(false, batch)).await;
+                assert_eq!(res, Ok(1.0));
+                // The failure is still recorded
+                assert_eq!(details.output[0].ret_code, 1);
+                assert_eq!(details.output[0].stdout, "diagnostics\n");
+            }
+
+            // Allowed failures count if every step allows failure
+            let (res, _) = run(make_test(true, false)).await;
+            assert!(
+                matches!(
+                    res,
+                    Err(JobFailure::ExecError(ExecError {
+                        stage: 0,
+                        kind: ExecErrorKind::ReturnCodeCheckFailed,
+                        ..
+                    }))
+                ),
+                "{:?}",
+                res
+            );
+        })
+    }
+
+    #[test]
+    fn resource_usage() {
+        block_on(async {
+            let info = TokioCommandRunner::default()
+                .run("sleep 1", &IndexMap::new())
+                .await
+                .unwrap();
+            assert!(info.wall_time_ms.unwrap() >= 1000, "{:?}", info);
+            assert!(info.cpu_time_ms.unwrap() < 1000, "{:?}", info);
+            assert!(info.peak_memory.unwrap() > 0, "{:?}", info);
+
+            let usage = ResourceUsage::of(&[info.clone(), info]).unwrap();
+            assert!(usage.wall_time_ms >= 2000, "{:?}", usage);
+            assert_eq!(ResourceUsage::of(&[]), None);
+        })
+    }
+
+    #[test]
+    fn timed_out_command_is_killed() {
+        block_on(async {
+            let marker =
+                std::env::temp_dir().join(format!("rurikawa-killed-{}", rand::random::<u32>()));
+            let cmd = format!("sleep 1; touch {}", marker.display());
+            let res = TokioCommandRunner::default()
+                .run_timeout(&cmd, &IndexMap::new(), time::Duration::from_millis(100))
+                .await;
+            assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+            tokio::time::sleep(time::Duration::from_millis(1500)).await;
+            assert!(!marker.exists());
+        })
+    }
+
+    #[test]
+    fn generated_expected_out() {
+        block_on(async {
+            let runner = TokioCommandRunner::default();
+            let variables = [("n".to_owned(), "3".to_owned())]
+                .iter()
+                .cloned()
+                .collect::<IndexMap<_, _>>();
+            let out = generate_expected_out(&runner, "seq $n", &variables, None)
+                .await
+                .unwrap();
+            assert_eq!(out, "1\n2\n3\n");
+
+            let failed = generate_expected_out(&runner, "echo oops >&2; false", &variables, None)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(&failed, JobFailure::InternalError(e) if e.contains("oops")),
+                "{:?}",
+                failed
+            );
+
+            let timed_out = generate_expected_out(
+                &runner,
+                "sleep 3",
+                &variables,
+                Some(time::Duration::from_millis(100)),
+            )
+            .await;
+            assert!(matches!(timed_out, Err(JobFailure::InternalError(_))));
         })
     }
 }
@@ -214,13 +847,92 @@ mod docker_runner {
                 true,
             ));
             t.expected("Hello,\n");
-            let res = t.run(&runner, &HashMap::new(), None).await;
+            let res = t.run(&runner, &IndexMap::new(), None).await;
             // Any Ok(_) represents accepted, just with different score.
             assert!(matches!(dbg!(res), Ok(_)));
             runner
         });
     }
 
+    #[test]
+    fn parallel_test_dirs() {
+        docker_run(|runner, _| async {
+            let run = |i: usize| {
+                let dir = format!("{}/{}", PARALLEL_TEST_ROOT, i);
+                let variables: IndexMap<_, _> =
+                    std::iter::once((TEST_DIR_ENV_VAR.to_owned(), dir.clone())).collect();
+                let runner = &runner;
+                async move {
+                    let created = runner
+                        .run(&format!("mkdir -p {}", dir), &IndexMap::new())
+                        .await
+                        .unwrap();
+                    assert_eq!(created.ret_code, 0, "{:?}", created);
+                    // Both tests write the same file name at the same time
+                    runner
+                        .run(
+                            &format!("echo {} > out; sleep 1; cat out; pwd", i),
+                            &variables,
+                        )
+                        .await
+                        .unwrap()
+                        .stdout
+                }
+            };
+            let (a, b) = futures::join!(run(0), run(1));
+            assert_eq!(a, format!("0\n{}/0\n", PARALLEL_TEST_ROOT));
+            assert_eq!(b, format!("1\n{}/1\n", PARALLEL_TEST_ROOT));
+            runner
+        });
+    }
+
+    #[test]
+    fn resource_usage() {
+        docker_run(|runner, _| async {
+            let info = runner.run("sleep 1", &IndexMap::new()).await.unwrap();
+            assert!(info.wall_time_ms.unwrap() >= 1000, "{:?}", info);
+            runner
+        });
+    }
+
+    #[test]
+    fn peak_pids() {
+        block_on(async {
+            let runner = DockerCommandRunner::try_new(
+                bollard::Docker::connect_with_local_defaults().unwrap(),
+                Image::Prebuilt {
+                    tag: "alpine:latest".to_owned(),
+                },
+                DockerCommandRunnerOptions {
+                    build_image: true,
+                    cfg: std::sync::Arc::new(crate::client::config::DockerConfig {
+                        pids_limit: Some(64),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Option::<BuildResultChannel>::None,
+            )
+            .await
+            .unwrap();
+
+            // Stats are sampled about once per second, so the processes are
+            // kept alive across a few samples
+            let info = runner
+                .run(
+                    "for i in 1 2 3 4; do sleep 3 & done; wait",
+                    &IndexMap::new(),
+                )
+                .await;
+            runner.kill().await;
+
+            let info = info.unwrap();
+            // The shell and the 4 `sleep`s
+            assert!(info.peak_pids.unwrap() >= 5, "{:?}", info);
+            assert_eq!(info.pids_limit, Some(64));
+        })
+    }
+
     #[test]
     fn error_code() {
         docker_run(|runner, mut t| async {
@@ -233,7 +945,7 @@ mod docker_runner {
                 true,
             ));
             t.expected("Hello,\nworld!\n");
-            let got = t.run(&runner, &HashMap::new(), None).await;
+            let got = t.run(&runner, &IndexMap::new(), None).await;
             let expected: Result<f64, _> = Err(JobFailure::ExecError(ExecError {
                 stage: 1,
                 kind: ExecErrorKind::ReturnCodeCheckFailed,
@@ -244,6 +956,13 @@ mod docker_runner {
                         stdout: "This does nothing.\n".into(),
                         stderr: "".into(),
                         is_user_command: true,
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                     ProcessInfo {
                         ret_code: 1,
@@ -251,10 +970,17 @@ mod docker_runner {
                         stdout: "Hello, world!\n".into(),
                         stderr: "".into(),
                         is_user_command: true,
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                 ],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
             runner
         })
     }
@@ -271,7 +997,7 @@ mod docker_runner {
                 r#"{ sleep 0.1; kill $$; } & i=0; while [ "$i" -lt 4 ]; do echo $i; sleep 1; i=$(( i + 1 )); done"#
             ),true));
             t.expected("Hello,\nworld!\n");
-            let got = t.run(&runner, &HashMap::new(), None).await;
+            let got = t.run(&runner, &IndexMap::new(), None).await;
             let expected: Result<f64, _> = Err(JobFailure::ExecError(ExecError {
                 stage: 1,
                 kind: ExecErrorKind::RuntimeError(
@@ -291,6 +1017,13 @@ mod docker_runner {
                         command: r"echo 'This does nothing.'".into(),
                         stdout: "This does nothing.\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                     ProcessInfo {
                         ret_code: -15,
@@ -298,10 +1031,17 @@ mod docker_runner {
                         command:r#"{ sleep 0.1; kill $$; } & i=0; while [ "$i" -lt 4 ]; do echo $i; sleep 1; i=$(( i + 1 )); done"#.into(),
                         stdout: "0\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                 ],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
             runner
         })
     }
@@ -318,7 +1058,7 @@ mod docker_runner {
                 true,
             ));
             t.expected("Hello,\nworld!");
-            let got = t.run(&runner, &HashMap::new(), None).await;
+            let got = t.run(&runner, &IndexMap::new(), None).await;
             let expected: Result<f64, _> = Err(JobFailure::OutputMismatch(OutputMismatch {
                 diff: "+ Hello,\n  world!\n".into(),
                 output: vec![
@@ -328,6 +1068,13 @@ mod docker_runner {
                         command: r"echo 'This does nothing.'".into(),
                         stdout: "This does nothing.\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                     ProcessInfo {
                         ret_code: 0,
@@ -335,10 +1082,17 @@ mod docker_runner {
                         command: "echo 'Hello, world!' | awk '{print $2}'".into(),
                         stdout: "world!\n".into(),
                         stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
                     },
                 ],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
             runner
         })
     }
@@ -355,20 +1109,299 @@ mod docker_runner {
                     .set_timeout(time::Duration::from_millis(100)),
             );
             t.expected("Hello,\nworld!\n");
-            let got = t.run(&runner, &HashMap::new(), None).await;
+            let got = t.run(&runner, &IndexMap::new(), None).await;
+            // The timed-out command is killed, and its output until then kept.
             let expected: Result<f64, _> = Err(JobFailure::ExecError(ExecError {
                 stage: 1,
                 kind: ExecErrorKind::TimedOut,
-                output: vec![ProcessInfo {
-                    ret_code: 0,
-                    is_user_command: true,
-                    command: r"echo 'This does nothing.'".into(),
-                    stdout: "This does nothing.\n".into(),
-                    stderr: "".into(),
-                }],
+                output: vec![
+                    ProcessInfo {
+                        ret_code: 0,
+                        is_user_command: true,
+                        command: r"echo 'This does nothing.'".into(),
+                        stdout: "This does nothing.\n".into(),
+                        stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
+                    },
+                    ProcessInfo {
+                        ret_code: -9,
+                        is_user_command: true,
+                        command: "echo 0; sleep 3; echo 1".into(),
+                        stdout: "0\n".into(),
+                        stderr: "".into(),
+                        peak_pids: None,
+                        pids_limit: None,
+                        wall_time_ms: None,
+                        cpu_time_ms: None,
+                        peak_memory: None,
+                        combined: None,
+                        reservation: Default::default(),
+                    },
+                ],
             }));
-            pretty_eq!(got, expected);
+            pretty_eq!(clear_usage(got), expected);
             runner
         })
     }
+
+    #[test]
+    fn build_args() {
+        block_on(async {
+            let root =
+                std::env::temp_dir().join(format!("rurikawa-build-args-{}", rand::random::<u32>()));
+            tokio::fs::create_dir_all(&root).await.unwrap();
+            tokio::fs::write(
+                root.join("Dockerfile"),
+                "FROM alpine:latest\nARG GREETING\nRUN echo \"greeting: $GREETING\"\n",
+            )
+            .await
+            .unwrap();
+
+            let tag = format!("rurikawa-build-args-{}", rand::random::<u32>());
+            let image = Image::Dockerfile {
+                tag: tag.clone(),
+                path: root.clone(),
+                file: None,
+                build_args: [("GREETING".to_owned(), "hello".to_owned())]
+                    .iter()
+                    .cloned()
+                    .collect(),
+            };
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let instance = bollard::Docker::connect_with_local_defaults().unwrap();
+            let res = image
+                .build(
+                    instance.clone(),
+                    Some(tx),
+                    CancellationTokenHandle::new(),
+                    None,
+                    None,
+                    &Default::default(),
+                )
+                .await;
+            let _ = tokio::fs::remove_dir_all(&root).await;
+            res.unwrap();
+            let _ = image.remove_image(instance).await;
+
+            let mut log = String::new();
+            while let Ok(info) = rx.try_recv() {
+                log.extend(info.stream);
+            }
+            assert!(
+                log.contains("Build args: CI=true GREETING=hello\n"),
+                "{}",
+                log
+            );
+            assert!(log.contains("greeting: hello"), "{}", log);
+        })
+    }
+
+    #[test]
+    fn read_only_bind() {
+        block_on(async {
+            let root =
+                std::env::temp_dir().join(format!("rurikawa-binds-{}", rand::random::<u32>()));
+            tokio::fs::create_dir_all(&root).await.unwrap();
+            tokio::fs::write(root.join("1.in"), "1\n").await.unwrap();
+
+            let binds = [
+                Bind {
+                    from: root.clone(),
+                    to: "/data".into(),
+                    ..Default::default()
+                },
+                Bind {
+                    to: "/scratch".into(),
+                    kind: BindKind::Tmpfs,
+                    size: Some(1 << 20),
+                    ..Default::default()
+                },
+            ];
+            let runner = DockerCommandRunner::try_new(
+                bollard::Docker::connect_with_local_defaults().unwrap(),
+                Image::Prebuilt {
+                    tag: "alpine:latest".to_owned(),
+                },
+                DockerCommandRunnerOptions {
+                    build_image: true,
+                    binds: Some(binds.iter().map(|x| x.to_mount()).collect()),
+                    ..Default::default()
+                },
+                Option::<BuildResultChannel>::None,
+            )
+            .await
+            .unwrap();
+
+            let read = runner.run("cat /data/1.in", &IndexMap::new()).await;
+            let write = runner.run("echo 2 > /data/1.in", &IndexMap::new()).await;
+            let scratch = runner.run("echo 2 > /scratch/1.in", &IndexMap::new()).await;
+            runner.kill().await;
+            let host_content = tokio::fs::read_to_string(root.join("1.in")).await;
+            let _ = tokio::fs::remove_dir_all(&root).await;
+
+            assert_eq!(read.unwrap().stdout, "1\n");
+            let write = write.unwrap();
+            assert_ne!(write.ret_code, 0);
+            assert!(write.stderr.contains("Read-only"), "{}", write.stderr);
+            assert_eq!(host_content.unwrap(), "1\n");
+            assert_eq!(scratch.unwrap().ret_code, 0);
+        })
+    }
+
+    #[test]
+    fn combined_output() {
+        block_on(async {
+            let runner = DockerCommandRunner::try_new(
+                bollard::Docker::connect_with_local_defaults().unwrap(),
+                Image::Prebuilt {
+                    tag: "alpine:latest".to_owned(),
+                },
+                DockerCommandRunnerOptions {
+                    build_image: true,
+                    combined_output: true,
+                    max_output_size: 16,
+                    ..Default::default()
+                },
+                Option::<BuildResultChannel>::None,
+            )
+            .await
+            .unwrap();
+
+            let interleaved = runner
+                .run(
+                    "echo out; sleep 0.2; echo err >&2; sleep 0.2; echo out",
+                    &IndexMap::new(),
+                )
+                .await;
+            let long = runner
+                .run("echo 0123456789; echo 0123456789 >&2", &IndexMap::new())
+                .await;
+            runner.kill().await;
+
+            let interleaved = interleaved.unwrap();
+            let frames = interleaved.combined.unwrap();
+            let streams = frames.iter().map(|x| x.stream).collect::<Vec<_>>();
+            assert_eq!(
+                streams,
+                [
+                    crate::tester::OutputStream::Stdout,
+                    crate::tester::OutputStream::Stderr,
+                    crate::tester::OutputStream::Stdout
+                ]
+            );
+            let text = frames.iter().map(|x| x.text.as_str()).collect::<String>();
+            assert_eq!(text, "out\nerr\nout\n");
+            assert!(frames.windows(2).all(|x| x[0].time_ms <= x[1].time_ms));
+
+            let long = long.unwrap();
+            assert_eq!(long.stdout, "0123456789\n");
+            let text = long
+                .combined
+                .unwrap()
+                .iter()
+                .map(|x| x.text.clone())
+                .collect::<String>();
+            assert!(text.starts_with("0123456789\n01234\n---"), "{}", text);
+        })
+    }
+
+    #[test]
+    fn allowed_hosts() {
+        block_on(async {
+            let runner = DockerCommandRunner::try_new(
+                bollard::Docker::connect_with_local_defaults().unwrap(),
+                Image::Prebuilt {
+                    tag: "alpine:latest".to_owned(),
+                },
+                DockerCommandRunnerOptions {
+                    build_image: true,
+                    network_options: NetworkOptions {
+                        enable_running: true,
+                        allowed_hosts: vec!["example.com:80".into()],
+                        ..Default::default()
+                    },
+                    cfg: Arc::new(DockerConfig {
+                        network_proxy_image: Some("alpine/socat:latest".into()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Option::<BuildResultChannel>::None,
+            )
+            .await
+            .unwrap();
+
+            let timeout = std::time::Duration::from_secs(10);
+            let allowed = runner
+                .run_timeout(
+                    "wget -q -O /dev/null http://example.com/",
+                    &IndexMap::new(),
+                    timeout,
+                )
+                .await;
+            let blocked = runner
+                .run_timeout(
+                    "wget -q -O /dev/null http://example.org/",
+                    &IndexMap::new(),
+                    timeout,
+                )
+                .await;
+            runner.kill().await;
+
+            assert_eq!(allowed.unwrap().ret_code, 0);
+            // Either times out or fails to connect at all
+            assert!(
+                !matches!(&blocked, Ok(info) if info.ret_code == 0),
+                "{:?}",
+                blocked
+            );
+        })
+    }
+
+    #[test]
+    fn network_audit() {
+        block_on(async {
+            let runner = DockerCommandRunner::try_new(
+                bollard::Docker::connect_with_local_defaults().unwrap(),
+                Image::Prebuilt {
+                    tag: "alpine:latest".to_owned(),
+                },
+                DockerCommandRunnerOptions {
+                    build_image: true,
+                    network_options: NetworkOptions {
+                        audit_running: true,
+                        ..Default::default()
+                    },
+                    cfg: Arc::new(DockerConfig {
+                        // Any image that keeps running gets an address
+                        network_audit_image: Some("nginx:alpine".into()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Option::<BuildResultChannel>::None,
+            )
+            .await
+            .unwrap();
+
+            // Proxy-aware traffic is routed to the address of the sidecar
+            let proxy = runner.run("echo \"$http_proxy\"", &IndexMap::new()).await;
+            let attempts = runner.network_attempts().await;
+            runner.kill().await;
+
+            let proxy = proxy.unwrap().stdout;
+            assert!(
+                proxy.starts_with("http://") && !proxy.starts_with("http://:"),
+                "{}",
+                proxy
+            );
+            assert!(attempts.is_some());
+        })
+    }
 }
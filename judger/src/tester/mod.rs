@@ -1,9 +1,12 @@
+pub mod compare;
 pub mod exec;
 pub mod model;
 pub mod runner;
 pub mod spj;
 pub mod utils;
+pub mod validate;
 
+use crate::util::mem_budget::SharedReservation;
 use err_derive::Error;
 use rquickjs::IntoJsByRef;
 use serde::{Deserialize, Serialize};
@@ -13,6 +16,12 @@ pub enum ExecErrorKind {
     RuntimeError(String),
     ReturnCodeCheckFailed,
     TimedOut,
+    /// A command exited with a code mapped to `verdict` in
+    /// [`JudgerPublicConfig::exit_code_map`](model::JudgerPublicConfig::exit_code_map).
+    MappedExitCode {
+        code: i32,
+        verdict: crate::client::model::TestResultKind,
+    },
 }
 
 /// The result returned by running a subprocess.
@@ -23,6 +32,60 @@ pub struct ProcessInfo {
     pub command: String,
     pub stdout: String,
     pub stderr: String,
+    /// The peak number of processes (including threads) in the container
+    /// while running this command. Sampled from Docker stats about once per
+    /// second, so short-lived processes may be missed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[quickjs(skip)]
+    pub peak_pids: Option<u64>,
+    /// The process limit of the container, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[quickjs(skip)]
+    pub pids_limit: Option<u64>,
+    /// Time from starting the command until it returned, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[quickjs(skip)]
+    pub wall_time_ms: Option<u64>,
+    /// CPU time (user and system) spent by the command, in milliseconds.
+    /// In containers this is the CPU time of the whole container, sampled
+    /// from Docker stats like [`peak_pids`](Self::peak_pids).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[quickjs(skip)]
+    pub cpu_time_ms: Option<u64>,
+    /// The peak memory usage of the command in bytes. In containers this is
+    /// the memory usage of the whole container, sampled like
+    /// [`peak_pids`](Self::peak_pids).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[quickjs(skip)]
+    pub peak_memory: Option<u64>,
+    /// `stdout` and `stderr` interleaved in the order they arrived, if
+    /// [`JudgerPublicConfig::combined_output`](model::JudgerPublicConfig::combined_output)
+    /// is set. Truncated at the same length as each of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[quickjs(skip)]
+    pub combined: Option<Vec<OutputFrame>>,
+    /// Memory of [`ClientConfig::output_memory_limit`](crate::client::config::ClientConfig::output_memory_limit)
+    /// taken by the output, given back once every copy of it is dropped.
+    #[serde(skip)]
+    #[quickjs(skip)]
+    pub reservation: SharedReservation,
+}
+
+/// The stream an [`OutputFrame`] is written to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A chunk of output of a command.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct OutputFrame {
+    /// Milliseconds since the command started.
+    pub time_ms: u64,
+    pub stream: OutputStream,
+    pub text: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -38,6 +101,28 @@ pub struct SpjFailure {
     pub output: Vec<ProcessInfo>,
 }
 
+/// A test given a score lower than 1 by the scorer of the suite.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ScorerFailure {
+    /// The score in `[0, 1)`.
+    pub score: f64,
+    /// The `stderr` of the scorer, if any.
+    pub reason: Option<String>,
+    pub output: Vec<ProcessInfo>,
+}
+
+impl ScorerFailure {
+    /// `WrongAnswer` for a score of 0, `PartialScore` otherwise.
+    pub fn kind(&self) -> crate::client::model::TestResultKind {
+        use crate::client::model::TestResultKind;
+        if self.score > 0.0 {
+            TestResultKind::PartialScore
+        } else {
+            TestResultKind::WrongAnswer
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Error)]
 #[error(
     display = "Execution error in stage {}: {:?};\noutputs: {:?}",
@@ -54,11 +139,16 @@ pub struct ExecError {
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct ShouldFailFailure {
     pub output: Vec<ProcessInfo>,
+    /// Why the failure is not the expected one, if a command did fail.
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum BuildError {
     ImagePullFailure(String),
+    /// The registry rejected the credentials of the image, or requires some.
+    ImagePullUnauthorized(String),
     FileTransferError(String),
     BuildError {
         error: String,
@@ -76,10 +166,14 @@ impl std::fmt::Display for BuildError {
 
 impl std::error::Error for BuildError {}
 
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum JobFailure {
     OutputMismatch(OutputMismatch),
     SpjWrongAnswer(SpjFailure),
+    /// The scorer of the suite gave a score lower than 1.
+    ScorerRejected(ScorerFailure),
+    /// The output only differs from the expected one in whitespaces.
+    PresentationError(OutputMismatch),
     ExecError(ExecError),
     InternalError(String),
     ShouldFail(ShouldFailFailure),
@@ -95,6 +189,18 @@ impl std::fmt::Display for JobFailure {
 impl std::error::Error for JobFailure {}
 
 impl JobFailure {
+    /// Output of the commands run before the failure.
+    pub fn output(&self) -> &[ProcessInfo] {
+        match self {
+            JobFailure::OutputMismatch(x) | JobFailure::PresentationError(x) => &x.output,
+            JobFailure::SpjWrongAnswer(x) => &x.output,
+            JobFailure::ScorerRejected(x) => &x.output,
+            JobFailure::ExecError(x) => &x.output,
+            JobFailure::ShouldFail(x) => &x.output,
+            JobFailure::InternalError(_) | JobFailure::Cancelled => &[],
+        }
+    }
+
     /// Make a new `InternalError`, the lazy way.
     pub fn internal_err_from<D>(error: D) -> JobFailure
     where
@@ -1,7 +1,15 @@
+use super::{compare::OutputRegion, runner::sanitize_hostname};
+use crate::{
+    client::{config::DockerConfig, model::TestResultKind},
+    util::{mem_budget::MemoryBudget, path_security::assert_child_path},
+};
 use anyhow::Result;
-use bollard::models::Mount;
+use bollard::models::{Mount, MountTmpfsOptions, MountTypeEnum};
+use indexmap::IndexMap;
+use itertools::Itertools;
 use names::{Generator, Name};
 use path_absolutize::Absolutize;
+use path_slash::PathBufExt;
 use rquickjs::{FromJs, IntoJsByRef};
 use serde::{self, Deserialize, Serialize};
 use std::{
@@ -9,37 +17,145 @@ use std::{
     path::{Path, PathBuf},
     str::FromStr,
     string::String,
+    sync::Arc,
 };
 
+/// Kinds of mounts of the container.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum BindKind {
+    /// A path in the host machine.
+    Bind,
+    /// A Docker volume, named by `from`. Only volumes allowed by the judger
+    /// can be mounted, see
+    /// [`allowed_volumes`](crate::client::config::DockerConfig::allowed_volumes).
+    Volume,
+    /// An empty in-memory filesystem.
+    Tmpfs,
+}
+
+impl Default for BindKind {
+    fn default() -> Self {
+        BindKind::Bind
+    }
+}
+
 /// A Host-to-container volume binding for the container.
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Bind {
-    /// Absolute/Relative `from` path (in the host machine).
+    /// Path in the host machine relative to the test suite, or the name of
+    /// the volume. Unused by tmpfs mounts.
+    #[serde(default)]
     pub from: PathBuf,
     /// Absolute `to` path (in the container).
     pub to: PathBuf,
-    // Note: Removed readonly option here, since all binds should be readonly
-    // for security reasons.
+    #[serde(default, rename = "type")]
+    pub kind: BindKind,
+    /// Whether the container cannot write to this mount. Defaults to `true`,
+    /// so that submissions cannot tamper with test data, except for tmpfs
+    /// mounts. Binds of host paths are always read-only for security reasons.
+    #[serde(default)]
+    pub read_only: Option<bool>,
+    /// Size limit of a tmpfs mount in bytes, written with units like
+    /// `"64MiB"`. Unlimited if not set.
+    #[serde(default, with = "crate::util::units::size")]
+    pub size: Option<u64>,
+    /// Whether this binds shared data resolved from a [`SharedBind`], whose
+    /// absolute host path comes from the judger rather than the test suite.
+    #[serde(skip)]
+    pub shared: bool,
 }
 
 impl Bind {
     pub fn canonicalize(&mut self, base: &Path) {
-        self.from = canonical_join(base, &self.from)
+        if self.kind == BindKind::Bind {
+            self.from = canonical_join(base, &self.from)
+        }
+    }
+
+    /// Whether the container cannot write to this mount, see
+    /// [`read_only`](Self::read_only).
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.unwrap_or(self.kind != BindKind::Tmpfs)
+    }
+
+    /// Check that this mount can be made: host paths are bound read-only and
+    /// stay inside the test suite unless they are shared data, volumes are
+    /// named, and only tmpfs mounts have a size.
+    pub fn check(&self) -> Result<(), String> {
+        if !self.to.is_absolute() {
+            return Err(format!(
+                "Mount target {} is not an absolute path",
+                self.to.display()
+            ));
+        }
+        if self.kind != BindKind::Tmpfs && self.size.is_some() {
+            return Err(format!(
+                "Mount at {} has a size, which is only supported by tmpfs mounts",
+                self.to.display()
+            ));
+        }
+        match self.kind {
+            BindKind::Bind if !self.is_read_only() => {
+                Err(format!("Bind of {} must be read-only", self.from.display()))
+            }
+            BindKind::Bind if !self.shared => assert_child_path(&self.from).map_err(|e| {
+                format!(
+                    "Bind source must be inside the test suite, use `sharedBinds` for \
+                     shared data: {}",
+                    e
+                )
+            }),
+            BindKind::Volume if !is_valid_volume_name(&self.from.to_string_lossy()) => Err(
+                format!("Invalid volume name {:?}", self.from.to_string_lossy()),
+            ),
+            _ => Ok(()),
+        }
     }
 
     pub fn to_mount(&self) -> Mount {
+        let (typ, source) = match self.kind {
+            BindKind::Bind => (MountTypeEnum::BIND, Some(self.from.display().to_string())),
+            BindKind::Volume => (MountTypeEnum::VOLUME, Some(self.from.display().to_string())),
+            BindKind::Tmpfs => (MountTypeEnum::TMPFS, None),
+        };
         Mount {
             target: Some(self.to.display().to_string()),
-            source: Some(self.from.display().to_string()),
-            typ: Some(bollard::models::MountTypeEnum::BIND),
-            // all binds should be readonly for security reasons.
-            read_only: Some(true),
+            source,
+            typ: Some(typ),
+            read_only: Some(self.is_read_only()),
+            tmpfs_options: self.size.map(|size| MountTmpfsOptions {
+                size_bytes: Some(size as i64),
+                ..Default::default()
+            }),
             ..Default::default()
         }
     }
 }
 
+/// Whether `name` is a valid name of a Docker volume.
+fn is_valid_volume_name(name: &str) -> bool {
+    name.len() >= 2
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// A read-only binding of operator-provided shared data into the container.
+///
+/// The host path of the data is looked up by `name` in the allowlist of the
+/// judger, so test suites cannot mount arbitrary host paths.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedBind {
+    /// Name of the shared data in the allowlist of the judger.
+    pub name: String,
+    /// Absolute path in the container.
+    pub to: PathBuf,
+}
+
 /// Join a `relative` path onto a `base` path and canonicalize the result.
 pub fn canonical_join(base: impl AsRef<Path>, relative: impl AsRef<Path>) -> PathBuf {
     base.as_ref()
@@ -68,9 +184,351 @@ pub enum Image {
         /// Path of the dockerfile itself, relative to the context directory.
         /// Leaving this value to None means using the default dockerfile: `path/Dockerfile`.
         file: Option<PathBuf>,
+        /// Build-time variables (`ARG`s) passed to the dockerfile.
+        #[serde(default)]
+        build_args: HashMap<String, String>,
     },
 }
 
+/// Prefixes of well-known access tokens.
+const TOKEN_PREFIXES: &[&str] = &[
+    "ghp_",
+    "gho_",
+    "ghs_",
+    "github_pat_",
+    "glpat-",
+    "sk-",
+    "xox",
+    "AKIA",
+];
+
+/// Parts of arg names suggesting that the arg holds a secret.
+const SECRET_NAME_PARTS: &[&str] = &["TOKEN", "SECRET", "PASSWORD", "PASSWD", "CREDENTIAL", "KEY"];
+
+/// Check the given Docker build args. Arg names must consist of ASCII
+/// letters, digits and underscores, and must not start with a digit.
+///
+/// Build args are persisted in the image history, so they should never carry
+/// secrets. Returns the names of args that look like they do.
+pub fn check_build_args(args: &HashMap<String, String>) -> Result<Vec<&str>, String> {
+    let mut suspicious = vec![];
+    for (name, value) in args {
+        if !is_valid_var_name(name) {
+            return Err(format!("Invalid build arg name: {:?}", name));
+        }
+
+        if looks_like_secret(name, value) {
+            suspicious.push(name.as_str());
+        }
+    }
+    suspicious.sort_unstable();
+    Ok(suspicious)
+}
+
+fn looks_like_secret(name: &str, value: &str) -> bool {
+    let upper_name = name.to_ascii_uppercase();
+    SECRET_NAME_PARTS.iter().any(|x| upper_name.contains(x))
+        || TOKEN_PREFIXES.iter().any(|x| value.starts_with(x))
+}
+
+/// Build args passed to every dockerfile, unless overridden by the ones of
+/// the image.
+pub const DEFAULT_BUILD_ARGS: &[(&str, &str)] = &[("CI", "true")];
+
+/// `args` merged over [`DEFAULT_BUILD_ARGS`].
+pub fn merge_build_args(args: &HashMap<String, String>) -> HashMap<String, String> {
+    DEFAULT_BUILD_ARGS
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .chain(args.iter().map(|(k, v)| (k.clone(), v.clone())))
+        .collect()
+}
+
+/// A line listing `args` for the build log, sorted by name. Values of args
+/// that look like secrets are hidden.
+pub fn describe_build_args(args: &HashMap<String, String>) -> String {
+    let args = args
+        .iter()
+        .sorted()
+        .map(|(k, v)| {
+            if looks_like_secret(k, v) {
+                format!("{}=***", k)
+            } else {
+                format!("{}={}", k, v)
+            }
+        })
+        .join(" ");
+    format!("Build args: {}\n", args)
+}
+
+/// Whether `name` is a valid name of a variable in `sh`: ASCII letters,
+/// digits and underscores, not starting with a digit.
+fn is_valid_var_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .map(|c| c.is_ascii_alphabetic() || c == '_')
+        .unwrap_or(false)
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Name of the environment variable holding the seed of the job, see
+/// [`TestSuiteOptions::seed`].
+pub const SEED_ENV_VAR: &str = "RURIKAWA_SEED";
+
+/// Name of the environment variable holding the directory of a test run in
+/// parallel with others, see [`JudgerPublicConfig::parallel_tests`].
+pub const TEST_DIR_ENV_VAR: &str = "RURIKAWA_TEST_DIR";
+
+/// Directory in the test container holding the directories of tests run in
+/// parallel.
+pub const PARALLEL_TEST_ROOT: &str = "/tmp/rurikawa-tests";
+
+/// Environment variable holding the path of a file with the expected output,
+/// for the scorer of a suite. Unset if the test has no expected output.
+pub const SCORER_EXPECTED_VAR: &str = "RURIKAWA_EXPECTED";
+
+/// Environment variable holding the path of a file with the output of the
+/// test, for the scorer of a suite.
+pub const SCORER_ACTUAL_VAR: &str = "RURIKAWA_ACTUAL";
+
+/// Prefixes of environment variable names reserved for the judger.
+const RESERVED_ENV_PREFIXES: &[&str] = &["RURIKAWA_", "__rurikawa"];
+
+impl JudgerPublicConfig {
+    /// Environment variables of `case`, i.e. the ones of the test suite
+    /// overridden by the ones of the test case.
+    pub fn test_env(&self, case: &TestCaseDefinition) -> IndexMap<String, String> {
+        let mut env = self.env.clone();
+        env.extend(case.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        env
+    }
+
+    /// Check that [`USER_RUN_MARKER`] appears at most once in `run`.
+    pub fn check_run(&self) -> Result<(), String> {
+        if self
+            .run
+            .iter()
+            .filter(|x| x.command() == USER_RUN_MARKER)
+            .count()
+            > 1
+        {
+            return Err(format!(
+                "`{}` must appear at most once in `run`",
+                USER_RUN_MARKER
+            ));
+        }
+        Ok(())
+    }
+
+    /// The commands run in every test: the `run` commands of the test suite,
+    /// with the `user_run` commands of the submission placed at
+    /// [`USER_RUN_MARKER`], or before them if there's no marker. Commands
+    /// written in `judge.toml` (`user_run_explicit`) replace the ones of the
+    /// test suite instead under [`UserRunPolicy::Override`].
+    pub fn merge_run_commands(
+        &self,
+        user_run: &[String],
+        user_run_explicit: bool,
+    ) -> Result<Vec<RawStep>, String> {
+        self.check_run()?;
+        let user_steps = || {
+            user_run.iter().map(|x| RawStep {
+                command: x.clone(),
+                is_user_command: true,
+                allow_failure: false,
+            })
+        };
+        if self.user_run == UserRunPolicy::Override && user_run_explicit {
+            return Ok(user_steps().collect());
+        }
+
+        let mut steps = vec![];
+        if !self.run.iter().any(|x| x.command() == USER_RUN_MARKER) {
+            steps.extend(user_steps());
+        }
+        for cmd in &self.run {
+            if cmd.command() == USER_RUN_MARKER {
+                steps.extend(user_steps());
+            } else {
+                steps.push(RawStep {
+                    command: cmd.command().to_owned(),
+                    is_user_command: false,
+                    allow_failure: cmd.allow_failure(),
+                });
+            }
+        }
+        Ok(steps)
+    }
+
+    /// Check the environment variables of the test suite and every test case.
+    /// Names must be valid, and must not collide with `vars` (which are
+    /// passed as environment variables too) or names reserved for the judger.
+    pub fn check_env(&self) -> Result<(), String> {
+        let suite = self.env.keys().map(|name| (name, None));
+        let cases = self
+            .test_groups
+            .values()
+            .flatten()
+            .flat_map(|case| case.env.keys().map(move |name| (name, Some(&case.name))));
+        for (name, case) in suite.chain(cases) {
+            let location = case
+                .map(|x| format!("test case {:?}", x))
+                .unwrap_or_else(|| "test suite".into());
+            if !is_valid_var_name(name) {
+                return Err(format!(
+                    "Invalid environment variable name {:?} in {}",
+                    name, location
+                ));
+            }
+            if RESERVED_ENV_PREFIXES.iter().any(|x| name.starts_with(x)) {
+                return Err(format!(
+                    "Environment variable {:?} in {} uses a name reserved for the judger",
+                    name, location
+                ));
+            }
+            if self
+                .vars
+                .keys()
+                .any(|var| var.trim_start_matches('$') == name)
+            {
+                return Err(format!(
+                    "Environment variable {:?} in {} collides with a variable in `vars`",
+                    name, location
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `exit_code_map` only maps non-zero codes to verdicts of
+    /// failed tests.
+    pub fn check_exit_code_map(&self) -> Result<(), String> {
+        for (code, verdict) in self.exit_code_map.iter().sorted_by_key(|x| x.0) {
+            if *code == 0 {
+                return Err("Exit code 0 cannot be mapped to a verdict".into());
+            }
+            if !is_failure_verdict(*verdict) {
+                return Err(format!(
+                    "Exit code {} cannot be mapped to {:?}, which is not a verdict of a failed test",
+                    code, verdict
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every key of `test_time_limits` names a test case
+    /// definition, or a test expanded from a templated one.
+    pub fn check_test_time_limits(&self) -> Result<(), String> {
+        let cases = self.test_groups.values().flatten().collect::<Vec<_>>();
+        for name in self.test_time_limits.keys().sorted() {
+            let known = cases.iter().any(|case| {
+                *name == case.name
+                    || (case.inputs.is_some()
+                        && name
+                            .strip_prefix(case.name.as_str())
+                            .map_or(false, |x| x.starts_with('[') && x.ends_with(']')))
+            });
+            if !known {
+                return Err(format!(
+                    "Time limit is set for test {:?}, which is not defined",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `hostname` and the names of `services`, which are used as
+    /// hostnames, are valid hostnames.
+    pub fn check_hostnames(&self) -> Result<(), String> {
+        let names = self
+            .hostname
+            .iter()
+            .map(|x| ("hostname", x))
+            .chain(self.services.iter().map(|x| ("service name", &x.name)));
+        for (kind, name) in names {
+            if sanitize_hostname(name).as_ref() != Some(name) {
+                return Err(format!(
+                    "Invalid {} {:?}: only lowercase letters, digits and `-` are allowed, \
+                     not at either end, up to 63 characters",
+                    kind, name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Time limit of the test `name` expanded from `case` in milliseconds, if
+    /// overridden in `test_time_limits`.
+    pub fn test_time_limit(&self, name: &str, case: &TestCaseDefinition) -> Option<u64> {
+        self.test_time_limits
+            .get(name)
+            .or_else(|| self.test_time_limits.get(&case.name))
+            .copied()
+    }
+
+    /// Add `shared_binds` into `binds`, looking up their host paths in the
+    /// allowlist `host_paths`. Unknown names are rejected.
+    pub fn resolve_shared_binds(
+        &mut self,
+        host_paths: &HashMap<String, PathBuf>,
+    ) -> Result<(), String> {
+        for bind in &self.shared_binds {
+            let from = host_paths.get(&bind.name).ok_or_else(|| {
+                format!(
+                    "Shared data {:?} is not provided by this judger (available: {})",
+                    bind.name,
+                    host_paths.keys().sorted().join(", ")
+                )
+            })?;
+            if !from.is_absolute() {
+                return Err(format!(
+                    "Host path of shared data {:?} must be absolute, got {}",
+                    bind.name,
+                    from.display()
+                ));
+            }
+            self.binds.get_or_insert_with(Vec::new).push(Bind {
+                from: from.clone(),
+                to: bind.to.clone(),
+                shared: true,
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that every volume mounted by [`binds`](Self::binds) is in the
+    /// `allowed` list of the judger.
+    pub fn check_volumes(&self, allowed: &[String]) -> Result<(), String> {
+        for bind in self.binds.iter().flatten() {
+            let name = bind.from.to_string_lossy();
+            if bind.kind == BindKind::Volume && !allowed.iter().any(|x| *x == name) {
+                return Err(format!(
+                    "Volume {:?} is not allowed by this judger (allowed: {})",
+                    name,
+                    allowed.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `kind` is a final verdict of a failed test.
+fn is_failure_verdict(kind: TestResultKind) -> bool {
+    !matches!(
+        kind,
+        TestResultKind::Accepted
+            | TestResultKind::PartialScore
+            | TestResultKind::NotRan
+            | TestResultKind::Waiting
+            | TestResultKind::Running
+            | TestResultKind::Skipped
+    )
+}
+
 fn random_tag() -> String {
     Generator::with_naming(Name::Plain).next().unwrap()
 }
@@ -86,6 +544,105 @@ pub struct TestCaseDefinition {
     /// Baseline score for this test case
     #[serde(default = "default_base_score")]
     pub base_score: f64,
+
+    /// Environment variables of this test case, overriding the ones of the
+    /// test suite.
+    #[serde(skip_serializing_if = "IndexMap::is_empty")]
+    pub env: IndexMap<String, String>,
+
+    /// The exit code the failing command must return. Any non-zero exit code
+    /// is accepted if not set. Implies `should_fail`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_exit_code: Option<i32>,
+
+    /// A regular expression the `stderr` of the failing command must match.
+    /// Implies `should_fail`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_stderr: Option<String>,
+
+    /// A glob of input files relative to the test root, like `data/*.in`,
+    /// making this test case a template. It expands into one test case for
+    /// every matching file, named after the file without its extension:
+    /// `data/01.in` becomes `name[01]`, reading its files from `data/01.*`.
+    /// Only the file name may contain wildcards (`*` and `?`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inputs: Option<String>,
+}
+
+impl TestCaseDefinition {
+    /// The directory of [`inputs`](Self::inputs) and a pattern matching the
+    /// names of input files in it, if this test case is a template.
+    fn inputs_pattern(&self) -> Result<Option<(PathBuf, regex::Regex)>, String> {
+        let inputs = match &self.inputs {
+            Some(inputs) => inputs,
+            None => return Ok(None),
+        };
+        let invalid = |reason: &str| {
+            format!(
+                "Invalid inputs {:?} of test case {:?}: {}",
+                inputs, self.name, reason
+            )
+        };
+        let path = Path::new(inputs);
+        let file_name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or_else(|| invalid("no file name"))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if dir.to_string_lossy().contains(|c| c == '*' || c == '?') {
+            return Err(invalid("only the file name may contain wildcards"));
+        }
+        assert_child_path(dir).map_err(|e| invalid(&e.to_string()))?;
+
+        let pattern = file_name
+            .split('*')
+            .map(|part| part.split('?').map(regex::escape).join("."))
+            .join(".*");
+        let pattern = regex::Regex::new(&format!("^{}$", pattern)).unwrap();
+        Ok(Some((dir.to_owned(), pattern)))
+    }
+
+    /// Check the [`inputs`](Self::inputs) of this test case, if any.
+    pub fn check_inputs(&self) -> Result<(), String> {
+        self.inputs_pattern().map(|_| ())
+    }
+
+    /// Names and file stems (paths of their files relative to `test_root`,
+    /// without extension) of the test cases this definition expands into,
+    /// sorted by name. A test case without [`inputs`](Self::inputs) expands
+    /// into itself.
+    pub async fn expand(&self, test_root: &Path) -> std::io::Result<Vec<(String, String)>> {
+        let (dir, pattern) = match self
+            .inputs_pattern()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
+        {
+            Some(x) => x,
+            None => return Ok(vec![(self.name.clone(), self.name.clone())]),
+        };
+
+        let mut entries = tokio::fs::read_dir(test_root.join(&dir)).await?;
+        let mut cases = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(x) if pattern.is_match(x) => x,
+                _ => continue,
+            };
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            let stem = match Path::new(file_name).file_stem().and_then(|x| x.to_str()) {
+                Some(stem) => stem,
+                None => continue,
+            };
+            cases.push((
+                format!("{}[{}]", self.name, stem),
+                dir.join(stem).to_slash_lossy(),
+            ));
+        }
+        cases.sort();
+        Ok(cases)
+    }
 }
 
 impl FromStr for TestCaseDefinition {
@@ -97,6 +654,10 @@ impl FromStr for TestCaseDefinition {
             should_fail: false,
             has_out: true,
             base_score: 1.0,
+            env: IndexMap::new(),
+            expected_exit_code: None,
+            expected_stderr: None,
+            inputs: None,
         })
     }
 }
@@ -107,8 +668,24 @@ impl FromStr for TestCaseDefinition {
 #[serde(rename_all = "camelCase")]
 #[quickjs(rename_all = "camelCase")]
 pub struct JudgerPublicConfig {
-    pub time_limit: Option<i32>,
-    pub memory_limit: Option<i32>,
+    /// Time limit of every command, in milliseconds. Written with units in
+    /// the config, e.g. `"2s"` or `"500ms"`, see [`units`](crate::util::units).
+    /// Bare numbers are read as seconds, which is deprecated.
+    #[serde(default, with = "crate::util::units::time_limit")]
+    pub time_limit: Option<u64>,
+    /// Memory limit of the test container, in bytes. Written with units in
+    /// the config, e.g. `"256MiB"`. Bare numbers are read as bytes, which is
+    /// deprecated.
+    #[serde(default, with = "crate::util::units::memory_limit")]
+    pub memory_limit: Option<u64>,
+    /// Time limits of specific tests, overriding
+    /// [`time_limit`](Self::time_limit) and written like it. Keyed by the name
+    /// of a test case definition, which for a templated definition `a`
+    /// applies to all the tests it expands to, or by the name of one of them
+    /// like `a[01]`.
+    #[serde(default, with = "crate::util::units::time_limits")]
+    #[quickjs(skip)]
+    pub test_time_limits: HashMap<String, u64>,
     pub name: String,
     pub test_groups: HashMap<String, Vec<TestCaseDefinition>>,
 
@@ -116,10 +693,49 @@ pub struct JudgerPublicConfig {
     /// (`$src`, `$bin`, `$stdin`, `$stdout`, etc...).
     /// For example: `"$src" => "go"`.
     #[serde(default)]
-    pub vars: HashMap<String, String>,
+    pub vars: IndexMap<String, String>,
 
     /// Sequence of commands necessary to perform an IO check.
-    pub run: Vec<String>,
+    ///
+    /// The `run` commands of the submission are merged into these according
+    /// to [`user_run`](Self::user_run), see
+    /// [`merge_run_commands`](Self::merge_run_commands). Commands written as
+    /// tables may be allowed to fail, see [`RunCommand`].
+    pub run: Vec<RunCommand>,
+
+    /// Whether the `run` commands of submissions may replace the ones of the
+    /// test suite, or are only merged into them. Defaults to merging.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub user_run: UserRunPolicy,
+
+    /// Environment variables of all commands run in tests. Test cases may
+    /// override them with their own `env`. Names must not collide with
+    /// `vars` or names reserved for the judger.
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+
+    /// Command generating the expected output of a test, run in the test
+    /// container before the test with the same variables. Its `stdout` is
+    /// compared against the one of the test instead of the `$stdout` file.
+    /// The generator is not isolated from the submission: it runs in the
+    /// same container after the submission is built, so a submission can
+    /// tamper with it. Don't use it where tests must withstand hostile
+    /// submissions. Its failure is reported as a judger error.
+    #[serde(default)]
+    pub generator: Option<String>,
+
+    /// Command scoring the output of every test, run in the test container
+    /// after the test with the same variables, plus the paths of files with
+    /// the expected output in [`SCORER_EXPECTED_VAR`] and the actual one in
+    /// [`SCORER_ACTUAL_VAR`]. It should print a score in `[0, 1]` to
+    /// `stdout`: 1 is accepted, 0 is a wrong answer and anything in between a
+    /// partial score. It replaces the comparison of outputs, but not special
+    /// judgers. Its failure is reported as a judger error. Since it runs next
+    /// to the submission, the submission may tamper with it, so it must not
+    /// be relied upon against malicious submissions.
+    #[serde(default)]
+    pub scorer: Option<String>,
 
     /// The path of test root directory to be mapped inside test container
     #[quickjs(skip)]
@@ -129,12 +745,18 @@ pub struct JudgerPublicConfig {
     #[quickjs(skip)]
     pub test_ignore: Option<PathBuf>,
 
-    /// `host-src:container-dest` volume bindings for the container. **Binds are
-    /// always readonly for security reasons.**
-    /// For details see [here](https://docs.rs/bollard/0.7.2/bollard/service/struct.HostConfig.html#structfield.binds).
+    /// Mounts of host paths, volumes and tmpfs filesystems for the container.
+    /// Mounts are read-only by default, and **binds of host paths are always
+    /// read-only for security reasons.** See [`Bind`].
     #[quickjs(skip)]
     pub binds: Option<Vec<Bind>>,
 
+    /// Read-only bindings of shared data provided by the judger operator,
+    /// referenced by name. See [`SharedBind`].
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub shared_binds: Vec<SharedBind>,
+
     /// Path to the special judger script.
     ///
     /// The special judger script should be a valid JS script with specified
@@ -144,6 +766,145 @@ pub struct JudgerPublicConfig {
     /// Network options applied to this config
     #[serde(default)]
     pub network: NetworkOptions,
+
+    /// Report `PresentationError` instead of `WrongAnswer` when the output
+    /// only differs from the expected one in whitespaces. Defaults to false.
+    #[serde(default)]
+    pub presentation_error: bool,
+
+    /// Compare only this region of the output against the expected output.
+    /// Applies to all comparison modes, but not to special judgers, which
+    /// receive the whole output.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub output_region: Option<OutputRegion>,
+
+    /// Run all commands of a test in a single `sh` script instead of one
+    /// Docker exec per command. This saves the round-trips of creating and
+    /// inspecting execs, which dominate the run time of suites with many
+    /// trivial commands, at the cost of weaker isolation between commands.
+    /// Time limits apply to the whole script instead. Defaults to false.
+    #[serde(default)]
+    pub batch_commands: bool,
+
+    /// Commands run in the test container before every test, e.g. to set up
+    /// files it needs. A failing command fails the test as a judger error.
+    ///
+    /// Like every command, each one runs in its own shell, so `export`, `cd`
+    /// or `source` in it does not affect the test. Use
+    /// [`env`](Self::env) to set environment variables instead.
+    #[serde(default)]
+    pub before_each: Vec<String>,
+
+    /// Commands run in the test container after every test, even if the test
+    /// failed. Their failures are only logged.
+    #[serde(default)]
+    pub after_each: Vec<String>,
+
+    /// How the `stderr` of the last command affects the verdict. Defaults to
+    /// ignoring it.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub stderr_policy: StderrPolicy,
+
+    /// Also capture `stdout` and `stderr` of commands interleaved in the
+    /// order they arrived, with timestamps, and include it in test results.
+    /// Useful for debugging interactive or networked tests. Not available
+    /// with `batch_commands`. Defaults to false.
+    #[serde(default)]
+    pub combined_output: bool,
+
+    /// Number of tests to run concurrently in the test container. Tests are
+    /// run one after another by default.
+    ///
+    /// **Only enable this for stateless tests.** Each test runs in its own
+    /// empty working directory, which is also in [`TEST_DIR_ENV_VAR`], so
+    /// files of the image must be referred to with absolute paths. Tests
+    /// share the container, so the memory limit applies to all running tests
+    /// together. A test timing out only stops its own processes. Ignored with
+    /// a special judger, which runs tests one at a time.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub parallel_tests: Option<usize>,
+
+    /// Maximum length of a line of output compared against the expected
+    /// output, in bytes. Longer lines fail the test as "output line too long"
+    /// without being diffed, unless the expected output has lines as long.
+    /// Defaults to [`DEFAULT_MAX_LINE_LENGTH`](super::compare::DEFAULT_MAX_LINE_LENGTH).
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub max_line_length: Option<usize>,
+
+    /// Files the submission must contain, relative to the folder of its
+    /// `judge.toml`, as gitignore-style patterns. Jobs missing any of them
+    /// fail with a compile error before anything is built. See
+    /// [`find_missing_required_files`](crate::fs::find_missing_required_files).
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub required_files: Vec<RequiredFile>,
+
+    /// Maximum length of the `stdout` and `stderr` of every command, in
+    /// bytes. Longer output is truncated. Defaults to
+    /// [`DEFAULT_MAX_OUTPUT_SIZE`](super::runner::DEFAULT_MAX_OUTPUT_SIZE).
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub max_output_size: Option<usize>,
+
+    /// Verdicts of tests whose commands exit with specific non-zero codes,
+    /// e.g. `{ "3": "MemoryLimitExceeded" }` for a sandbox reporting memory
+    /// exhaustion with exit code 3. Codes above 128 mean the command was killed
+    /// by a signal, and are written as negative signal numbers, e.g. `-9` for
+    /// `SIGKILL`. Unmapped codes fail
+    /// the test as usual. Ignored for tests expected to fail and under a
+    /// special judger.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub exit_code_map: HashMap<i32, TestResultKind>,
+
+    /// Hostname of the test container, made of lowercase letters, digits and
+    /// `-`. Defaults to one derived from the name of the test suite, with
+    /// characters not allowed in hostnames replaced by `-`, so that it stays
+    /// the same across runs.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub hostname: Option<String>,
+
+    /// Auxiliary service containers started before all tests and kept alive
+    /// across the whole test suite.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub services: Vec<ServiceContainer>,
+}
+
+/// An auxiliary container (e.g. a database) that tests can connect to.
+///
+/// Service containers are connected to the internal network of the test
+/// container, and are reachable from tests with their names as hostnames.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceContainer {
+    /// Name of this service, also used as its hostname, so it must be a
+    /// valid hostname, see [`JudgerPublicConfig::check_hostnames`].
+    pub name: String,
+    /// Tag of the image to run.
+    pub image: String,
+    /// Environment variables in the form of `KEY=value`.
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Entrypoint to run instead of the default one of the image.
+    pub entrypoint: Option<Vec<String>>,
+    /// Command to run instead of the default one of the image.
+    pub cmd: Option<Vec<String>>,
+    /// A shell command to be run inside the service container to probe its
+    /// readiness. Tests begin only after it returns 0.
+    pub ready_command: Option<String>,
+    /// Seconds to wait until the service is ready. Defaults to 30.
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout: u64,
+}
+
+fn default_ready_timeout() -> u64 {
+    30
 }
 
 /// Network options for judge containers.
@@ -157,6 +918,26 @@ pub struct NetworkOptions {
     /// Disable networking when building. Defaults to be false.
     #[serde(default = "return_true")]
     pub enable_build: bool,
+    /// Record network access attempts while running, and report them in the
+    /// result. Attempts are still blocked. Has no effect if `enable_running`
+    /// is set. Defaults to be false.
+    ///
+    /// Requires a network audit sidecar image configured on the judger, or
+    /// else network access is simply blocked.
+    #[serde(default)]
+    pub audit_running: bool,
+    /// Destinations reachable while running, as `host:port` pairs, e.g.
+    /// `api.example.com:443`. Every other destination is blocked. Only has
+    /// effect if `enable_running` is set, and allows all destinations if
+    /// empty. Defaults to be empty.
+    ///
+    /// Hosts must be names rather than IP addresses, as they are redirected
+    /// to forwarding sidecars through `/etc/hosts`. Requires a network proxy
+    /// sidecar image configured on the judger, or else network access is
+    /// simply blocked.
+    #[serde(default)]
+    #[quickjs(skip)]
+    pub allowed_hosts: Vec<String>,
 }
 
 impl Default for NetworkOptions {
@@ -164,6 +945,8 @@ impl Default for NetworkOptions {
         NetworkOptions {
             enable_running: false,
             enable_build: true,
+            audit_running: false,
+            allowed_hosts: vec![],
         }
     }
 }
@@ -172,6 +955,45 @@ impl NetworkOptions {
     pub fn use_network(&self) -> bool {
         !(self.enable_build && self.enable_running)
     }
+
+    /// Whether network access while running is limited to
+    /// [`allowed_hosts`](Self::allowed_hosts).
+    pub fn restricts_egress(&self) -> bool {
+        self.enable_running && !self.allowed_hosts.is_empty()
+    }
+
+    /// Parse [`allowed_hosts`](Self::allowed_hosts) into `(host, port)`
+    /// pairs.
+    pub fn parse_allowed_hosts(&self) -> Result<Vec<(&str, u16)>, String> {
+        self.allowed_hosts
+            .iter()
+            .map(|x| parse_allowed_host(x))
+            .collect()
+    }
+}
+
+/// Parse a `host:port` pair of [`NetworkOptions::allowed_hosts`].
+fn parse_allowed_host(s: &str) -> Result<(&str, u16), String> {
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Allowed host {:?} is not in the form of `host:port`", s))?;
+    let port = port
+        .parse::<u16>()
+        .ok()
+        .filter(|x| *x != 0)
+        .ok_or_else(|| format!("Invalid port of allowed host {:?}", s))?;
+    let is_name = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-'))
+        && host.chars().any(|c| c.is_ascii_alphabetic());
+    if !is_name {
+        return Err(format!(
+            "Allowed host {:?} must be a host name, not an IP address",
+            s
+        ));
+    }
+    Ok((host, port))
 }
 
 /// A wrapper for a unix command [`String`] to be used in special judge scripts.
@@ -180,6 +1002,10 @@ impl NetworkOptions {
 pub struct RawStep {
     pub command: String,
     pub is_user_command: bool,
+    /// Whether the test goes on when this command returns non-zero, as if
+    /// it succeeded. Ignored if every step of the test allows failure.
+    #[quickjs(default)]
+    pub allow_failure: bool,
 }
 
 /// Judger's private config, specific to a host machine.
@@ -192,20 +1018,166 @@ pub struct JudgerPrivateConfig {
     pub mapped_test_root_dir: PathBuf,
 }
 
+/// A file required in submissions, see [`JudgerPublicConfig::required_files`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RequiredFile {
+    /// A file or folder matching this pattern must exist.
+    One(String),
+    /// At least one file or folder matching any of these patterns must exist.
+    AnyOf {
+        #[serde(rename = "anyOf")]
+        any_of: Vec<String>,
+    },
+}
+
+impl RequiredFile {
+    pub fn patterns(&self) -> &[String] {
+        match self {
+            RequiredFile::One(x) => std::slice::from_ref(x),
+            RequiredFile::AnyOf { any_of } => any_of,
+        }
+    }
+
+    /// Description of this requirement shown to users when it's not met.
+    pub fn describe(&self) -> String {
+        match self {
+            RequiredFile::One(x) => format!("`{}`", x),
+            RequiredFile::AnyOf { any_of } => format!(
+                "one of {}",
+                any_of.iter().map(|x| format!("`{}`", x)).join(", ")
+            ),
+        }
+    }
+}
+
+/// Entry of [`JudgerPublicConfig::run`] marking where the `run` commands of
+/// the submission are placed.
+pub const USER_RUN_MARKER: &str = "@user";
+
+/// A command in [`JudgerPublicConfig::run`], written either as a string or
+/// as a table with options, e.g.
+/// `{ "command": "env", "allowFailure": true }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RunCommand {
+    Plain(String),
+    Detailed {
+        command: String,
+        /// See [`RawStep::allow_failure`].
+        #[serde(default, rename = "allowFailure")]
+        allow_failure: bool,
+    },
+}
+
+impl RunCommand {
+    pub fn command(&self) -> &str {
+        match self {
+            RunCommand::Plain(x) | RunCommand::Detailed { command: x, .. } => x,
+        }
+    }
+
+    pub fn allow_failure(&self) -> bool {
+        match self {
+            RunCommand::Plain(_) => false,
+            RunCommand::Detailed { allow_failure, .. } => *allow_failure,
+        }
+    }
+}
+
+impl From<&str> for RunCommand {
+    fn from(x: &str) -> Self {
+        RunCommand::Plain(x.to_owned())
+    }
+}
+
+/// Special judge scripts see commands as plain strings.
+impl<'js> rquickjs::IntoJs<'js> for &RunCommand {
+    fn into_js(self, ctx: rquickjs::Ctx<'js>) -> rquickjs::Result<rquickjs::Value<'js>> {
+        rquickjs::IntoJs::into_js(self.command(), ctx)
+    }
+}
+
+/// How the `run` commands of a submission are merged with the ones of the test
+/// suite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserRunPolicy {
+    /// The commands of the submission are placed at [`USER_RUN_MARKER`], or
+    /// before all commands of the test suite if there's no marker.
+    #[default]
+    Merge,
+    /// Commands written in `judge.toml` replace the ones of the test suite.
+    /// Commands from language profiles are still merged.
+    Override,
+}
+
+/// How the `stderr` of the last command of a test affects its verdict.
+/// Tests expected to fail are not affected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StderrPolicy {
+    /// `stderr` does not affect the verdict.
+    #[default]
+    Ignore,
+    /// Any output to `stderr` is a wrong answer.
+    FailIfNonempty,
+    /// `stderr` is compared against the `$stderr` file of the test like
+    /// `stdout`. Tests without a `$stderr` variable are not affected.
+    Compare,
+}
+
 /// The public representation of a test.
 #[derive(Serialize, Deserialize, Debug, Clone, IntoJsByRef)]
 #[quickjs(rename_all = "camelCase")]
 pub struct TestCase {
-    /// File name of the test case.
+    /// Name of the test case.
     pub name: String,
+    /// Path of the files of the test case relative to the test root, without
+    /// extension. Defaults to [`name`](Self::name).
+    #[serde(default)]
+    pub file_stem: Option<String>,
     /// Expected `stdout` of the last command.
     pub expected_out: Option<String>,
+    /// Whether the expected `stdout` should be produced by the generator of
+    /// the test suite instead.
+    #[serde(default)]
+    pub generate_out: bool,
     /// Should this test case fail
     pub should_fail: bool,
+    /// See [`TestCaseDefinition::expected_exit_code`].
+    #[serde(default)]
+    pub expected_exit_code: Option<i32>,
+    /// See [`TestCaseDefinition::expected_stderr`].
+    #[serde(default)]
+    pub expected_stderr: Option<String>,
+
+    /// Expected `stderr` of the last command, under
+    /// [`StderrPolicy::Compare`].
+    #[serde(default)]
+    pub expected_stderr_out: Option<String>,
 
     /// Baseline score for this test case
     #[serde(default = "default_base_score")]
     pub base_score: f64,
+
+    /// Environment variables of this test case, with the ones of the test
+    /// suite merged in.
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+
+    /// Time limit of every command of this test case, in milliseconds,
+    /// overriding the one of the test suite.
+    #[serde(default)]
+    pub time_limit: Option<u64>,
+}
+
+impl TestCase {
+    /// Path of the files of this test case relative to the test root, without
+    /// extension.
+    pub fn stem(&self) -> &str {
+        self.file_stem.as_deref().unwrap_or(&self.name)
+    }
 }
 
 fn default_base_score() -> f64 {
@@ -218,8 +1190,8 @@ fn default_base_score() -> f64 {
 pub struct TestSuiteOptions {
     /// File names of tests.
     pub tests: Vec<String>,
-    /// Time limit of a step, in seconds.
-    pub time_limit: Option<usize>,
+    /// Time limit of a step, in milliseconds.
+    pub time_limit: Option<u64>,
     // TODO: Use this field.
     /// Memory limit of the contrainer, in bytes.
     pub mem_limit: Option<usize>,
@@ -227,6 +1199,37 @@ pub struct TestSuiteOptions {
     pub build_image: bool,
     /// If the image needs to be removed after run.
     pub remove_image: bool,
+    /// If the output comparison of every test should be reported, even for
+    /// accepted tests.
+    #[serde(default)]
+    pub report_all: bool,
+    /// Tests are run in the order of their names. If this seed is set, they
+    /// are shuffled with it instead.
+    #[serde(default)]
+    pub shuffle_seed: Option<u64>,
+    /// Stop at the first test not accepted, reporting the remaining tests as
+    /// [`Skipped`](crate::client::model::TestResultKind::Skipped). All tests
+    /// are run by default.
+    #[serde(default)]
+    pub fail_fast: bool,
+    /// Keep the output of every test in
+    /// [`TestSuite::records`](super::exec::TestSuite::records), truncating
+    /// each stream of each command to this many bytes. Outputs are not kept
+    /// if not set.
+    #[serde(default)]
+    pub record_output_len: Option<usize>,
+    /// Seed for randomized tests, passed to every command as the environment
+    /// variable [`SEED_ENV_VAR`]. Running a job with the same seed again
+    /// reproduces its random inputs.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Resource limits and other options of Docker containers.
+    #[serde(skip)]
+    pub docker_config: Arc<DockerConfig>,
+    /// Budget the captured output of commands is taken from. Output is not
+    /// accounted if not set.
+    #[serde(skip)]
+    pub output_budget: Option<Arc<MemoryBudget>>,
 }
 
 impl Default for TestSuiteOptions {
@@ -237,6 +1240,13 @@ impl Default for TestSuiteOptions {
             mem_limit: None,
             build_image: false,
             remove_image: false,
+            report_all: false,
+            shuffle_seed: None,
+            fail_fast: false,
+            record_output_len: None,
+            seed: None,
+            docker_config: Default::default(),
+            output_budget: None,
         }
     }
 }
@@ -271,6 +1281,10 @@ mod de {
         ShouldFail,
         HasOut,
         BaseScore,
+        Env,
+        ExpectedExitCode,
+        ExpectedStderr,
+        Inputs,
     }
 
     struct TestCaseVisitor;
@@ -297,6 +1311,10 @@ mod de {
             let mut should_fail = None;
             let mut has_out = None;
             let mut base_score = None;
+            let mut env = None;
+            let mut expected_exit_code = None;
+            let mut expected_stderr = None;
+            let mut inputs = None;
 
             while let Some(key) = map.next_key::<TestCaseFields>()? {
                 match key {
@@ -304,19 +1322,30 @@ mod de {
                     TestCaseFields::ShouldFail => set_field!(should_fail, map),
                     TestCaseFields::HasOut => set_field!(has_out, map),
                     TestCaseFields::BaseScore => set_field!(base_score, map),
+                    TestCaseFields::Env => set_field!(env, map),
+                    TestCaseFields::ExpectedExitCode => set_field!(expected_exit_code, map),
+                    TestCaseFields::ExpectedStderr => set_field!(expected_stderr, map),
+                    TestCaseFields::Inputs => set_field!(inputs, map),
                 }
             }
 
             let name = check_field!(name);
-            let should_fail = should_fail.unwrap_or(false);
+            let should_fail = should_fail.unwrap_or(false)
+                || expected_exit_code.is_some()
+                || expected_stderr.is_some();
             let has_out = has_out.unwrap_or(true);
             let base_score = base_score.unwrap_or(1.0);
+            let env = env.unwrap_or_default();
 
             Ok(TestCaseDefinition {
                 name,
                 should_fail,
                 has_out,
                 base_score,
+                env,
+                expected_exit_code,
+                expected_stderr,
+                inputs,
             })
         }
     }
@@ -334,3 +1363,365 @@ mod de {
 const fn return_true() -> bool {
     true
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_build_args() {
+        let args = |xs: &[(&str, &str)]| {
+            xs.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let ok = args(&[("RUST_VERSION", "1.55"), ("_flag", "1")]);
+        assert_eq!(check_build_args(&ok), Ok(vec![]));
+
+        let secret = args(&[("GH_TOKEN", "abc"), ("VERSION", "ghp_0123456789abcdef")]);
+        assert_eq!(check_build_args(&secret), Ok(vec!["GH_TOKEN", "VERSION"]));
+
+        assert!(check_build_args(&args(&[("1ARG", "1")])).is_err());
+        assert!(check_build_args(&args(&[("MY-ARG", "1")])).is_err());
+        assert!(check_build_args(&args(&[("", "1")])).is_err());
+
+        let merged = merge_build_args(&args(&[("CI", "false"), ("RUST_VERSION", "1.55")]));
+        assert_eq!(merged, args(&[("CI", "false"), ("RUST_VERSION", "1.55")]));
+        let merged = merge_build_args(&args(&[("GH_TOKEN", "abc")]));
+        assert_eq!(merged, args(&[("CI", "true"), ("GH_TOKEN", "abc")]));
+        assert_eq!(
+            describe_build_args(&merged),
+            "Build args: CI=true GH_TOKEN=***\n"
+        );
+    }
+
+    #[test]
+    fn test_env() {
+        let cfg = serde_json::from_value::<JudgerPublicConfig>(serde_json::json!({
+            "name": "env",
+            "testGroups": {
+                "default": [
+                    "plain",
+                    { "name": "seeded", "env": { "SEED": "42" } },
+                ],
+            },
+            "vars": { "$stdin": "in" },
+            "run": [],
+            "mappedDir": { "from": ".", "to": "/src" },
+            "env": { "SEED": "0", "MODE": "test" },
+        }))
+        .unwrap();
+        assert_eq!(cfg.check_env(), Ok(()));
+
+        let cases = &cfg.test_groups["default"];
+        let plain = cfg.test_env(&cases[0]);
+        let seeded = cfg.test_env(&cases[1]);
+        assert_eq!(plain["SEED"], "0");
+        assert_eq!(seeded["SEED"], "42");
+        assert_eq!(seeded["MODE"], "test");
+
+        let with_env = |name: &str| {
+            let mut cfg = cfg.clone();
+            cfg.env.insert(name.into(), "1".into());
+            cfg.check_env()
+        };
+        assert!(with_env("stdin").is_err());
+        assert!(with_env("RURIKAWA_TOKEN").is_err());
+        assert!(with_env("1X").is_err());
+    }
+
+    #[test]
+    fn test_exit_code_map() {
+        let cfg = |map: serde_json::Value| {
+            serde_json::from_value::<JudgerPublicConfig>(serde_json::json!({
+                "name": "exit-code-map",
+                "testGroups": {},
+                "vars": {},
+                "run": [],
+                "mappedDir": { "from": ".", "to": "/src" },
+                "exitCodeMap": map,
+            }))
+            .unwrap()
+        };
+        let ok = cfg(serde_json::json!({ "2": "RuntimeError", "-9": "MemoryLimitExceeded" }));
+        assert_eq!(ok.exit_code_map[&2], TestResultKind::RuntimeError);
+        assert_eq!(ok.exit_code_map[&-9], TestResultKind::MemoryLimitExceeded);
+        assert_eq!(ok.check_exit_code_map(), Ok(()));
+
+        assert!(cfg(serde_json::json!({ "0": "WrongAnswer" }))
+            .check_exit_code_map()
+            .is_err());
+        assert!(cfg(serde_json::json!({ "1": "Accepted" }))
+            .check_exit_code_map()
+            .is_err());
+    }
+
+    #[test]
+    fn test_test_time_limits() {
+        let cfg = |limits: serde_json::Value| {
+            serde_json::from_value::<JudgerPublicConfig>(serde_json::json!({
+                "name": "time-limits",
+                "testGroups": {
+                    "default": ["plain", { "name": "data", "inputs": "data/*.in" }],
+                },
+                "vars": {},
+                "run": [],
+                "mappedDir": { "from": ".", "to": "/src" },
+                "timeLimit": "1s",
+                "testTimeLimits": limits,
+            }))
+            .unwrap()
+        };
+        let ok = cfg(serde_json::json!({ "plain": "100ms", "data": "2s", "data[01]": "3s" }));
+        assert_eq!(ok.check_test_time_limits(), Ok(()));
+        let cases = &ok.test_groups["default"];
+        assert_eq!(ok.test_time_limit("plain", &cases[0]), Some(100));
+        assert_eq!(ok.test_time_limit("data[01]", &cases[1]), Some(3000));
+        assert_eq!(ok.test_time_limit("data[02]", &cases[1]), Some(2000));
+        assert_eq!(
+            cfg(serde_json::json!({})).test_time_limit("plain", &cases[0]),
+            None
+        );
+
+        assert!(cfg(serde_json::json!({ "missing": "1s" }))
+            .check_test_time_limits()
+            .is_err());
+        assert!(cfg(serde_json::json!({ "plain[01]": "1s" }))
+            .check_test_time_limits()
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_hostnames() {
+        let cfg = |hostname: Option<&str>, service: &str| JudgerPublicConfig {
+            hostname: hostname.map(Into::into),
+            services: vec![ServiceContainer {
+                name: service.into(),
+                image: "redis".into(),
+                env: vec![],
+                entrypoint: None,
+                cmd: None,
+                ready_command: None,
+                ready_timeout: default_ready_timeout(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(cfg(None, "db").check_hostnames(), Ok(()));
+        assert_eq!(cfg(Some("judge-1"), "db-2").check_hostnames(), Ok(()));
+        assert!(cfg(Some("Judge"), "db").check_hostnames().is_err());
+        assert!(cfg(Some("-judge"), "db").check_hostnames().is_err());
+        assert!(cfg(None, "db;rm -rf").check_hostnames().is_err());
+        assert!(cfg(None, "").check_hostnames().is_err());
+        assert!(cfg(None, &"a".repeat(64)).check_hostnames().is_err());
+    }
+
+    #[test]
+    fn test_merge_run_commands() {
+        let cfg = |run: &[&str], user_run: &str| {
+            serde_json::from_value::<JudgerPublicConfig>(serde_json::json!({
+                "name": "run",
+                "testGroups": {},
+                "run": run,
+                "userRun": user_run,
+                "mappedDir": { "from": ".", "to": "/src" },
+            }))
+            .unwrap()
+        };
+        let user = vec!["./program".to_owned()];
+        let merged = |cfg: &JudgerPublicConfig, explicit: bool| {
+            cfg.merge_run_commands(&user, explicit).map(|steps| {
+                steps
+                    .into_iter()
+                    .map(|x| (x.command, x.is_user_command))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let plain = cfg(&["diff out $stdout"], "merge");
+        assert_eq!(
+            merged(&plain, true).unwrap(),
+            vec![
+                ("./program".to_owned(), true),
+                ("diff out $stdout".to_owned(), false)
+            ]
+        );
+
+        let marked = cfg(&["setup", USER_RUN_MARKER, "check"], "merge");
+        assert_eq!(
+            merged(&marked, true).unwrap(),
+            vec![
+                ("setup".to_owned(), false),
+                ("./program".to_owned(), true),
+                ("check".to_owned(), false)
+            ]
+        );
+
+        let overridable = cfg(&["setup", USER_RUN_MARKER, "check"], "override");
+        assert_eq!(
+            merged(&overridable, true).unwrap(),
+            vec![("./program".to_owned(), true)]
+        );
+        assert_eq!(merged(&overridable, false), merged(&marked, false));
+
+        assert!(merged(&cfg(&[USER_RUN_MARKER, USER_RUN_MARKER], "merge"), true).is_err());
+
+        let detailed = serde_json::from_value::<JudgerPublicConfig>(serde_json::json!({
+            "name": "run",
+            "testGroups": {},
+            "run": [{ "command": "env", "allowFailure": true }, "check"],
+            "mappedDir": { "from": ".", "to": "/src" },
+        }))
+        .unwrap();
+        let steps = detailed.merge_run_commands(&user, true).unwrap();
+        assert_eq!(
+            steps
+                .iter()
+                .map(|x| (x.command.as_str(), x.allow_failure))
+                .collect::<Vec<_>>(),
+            vec![("./program", false), ("env", true), ("check", false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expand_inputs() {
+        let root = std::env::temp_dir().join(format!("rurikawa-inputs-{}", rand::random::<u32>()));
+        tokio::fs::create_dir_all(root.join("data")).await.unwrap();
+        for file in &["02.in", "01.in", "01.out", "notes.txt"] {
+            tokio::fs::write(root.join("data").join(file), "")
+                .await
+                .unwrap();
+        }
+
+        let case = serde_json::from_value::<TestCaseDefinition>(serde_json::json!({
+            "name": "add",
+            "inputs": "data/*.in",
+        }))
+        .unwrap();
+        let plain = "plain".parse::<TestCaseDefinition>().unwrap();
+        let expanded = case.expand(&root).await;
+        let plain_expanded = plain.expand(&root).await;
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(
+            expanded.unwrap(),
+            vec![
+                ("add[01]".to_owned(), "data/01".to_owned()),
+                ("add[02]".to_owned(), "data/02".to_owned()),
+            ]
+        );
+        assert_eq!(
+            plain_expanded.unwrap(),
+            vec![("plain".to_owned(), "plain".to_owned())]
+        );
+
+        let invalid = |inputs: &str| TestCaseDefinition {
+            inputs: Some(inputs.into()),
+            ..plain.clone()
+        };
+        assert!(invalid("data/?.in").check_inputs().is_ok());
+        assert!(invalid("*/01.in").check_inputs().is_err());
+        assert!(invalid("../data/*.in").check_inputs().is_err());
+    }
+
+    #[test]
+    fn test_binds() {
+        let bind = |x: serde_json::Value| serde_json::from_value::<Bind>(x).unwrap();
+        let data = bind(serde_json::json!({ "from": "data", "to": "/data" }));
+        assert_eq!(data.kind, BindKind::Bind);
+        assert!(data.is_read_only());
+        assert_eq!(data.check(), Ok(()));
+        assert_eq!(data.to_mount().read_only, Some(true));
+
+        let scratch =
+            bind(serde_json::json!({ "type": "tmpfs", "to": "/scratch", "size": "64MiB" }));
+        assert!(!scratch.is_read_only());
+        assert_eq!(scratch.check(), Ok(()));
+        let mount = scratch.to_mount();
+        assert_eq!(mount.typ, Some(MountTypeEnum::TMPFS));
+        assert_eq!(mount.source, None);
+        assert_eq!(mount.tmpfs_options.unwrap().size_bytes, Some(64 << 20));
+
+        let cache = bind(serde_json::json!({ "type": "volume", "from": "cache", "to": "/cache" }));
+        assert!(cache.is_read_only());
+        assert_eq!(cache.check(), Ok(()));
+        assert_eq!(cache.to_mount().typ, Some(MountTypeEnum::VOLUME));
+
+        let writable =
+            bind(serde_json::json!({ "from": "data", "to": "/data", "readOnly": false }));
+        assert!(writable.check().is_err());
+        let sized = bind(serde_json::json!({ "from": "data", "to": "/data", "size": "1MiB" }));
+        assert!(sized.check().is_err());
+        let relative = bind(serde_json::json!({ "type": "tmpfs", "to": "scratch" }));
+        assert!(relative.check().is_err());
+        let unnamed = bind(serde_json::json!({ "type": "volume", "to": "/cache" }));
+        assert!(unnamed.check().is_err());
+        // Host paths outside the suite can only be mounted as shared data
+        let absolute = bind(serde_json::json!({ "from": "/etc", "to": "/data" }));
+        assert!(absolute.check().is_err());
+        let escaping = bind(serde_json::json!({ "from": "data/../..", "to": "/data" }));
+        assert!(escaping.check().is_err());
+
+        let cfg = serde_json::from_value::<JudgerPublicConfig>(serde_json::json!({
+            "name": "volumes",
+            "testGroups": {},
+            "run": [],
+            "mappedDir": { "from": ".", "to": "/src" },
+            "binds": [
+                { "from": "data", "to": "/data" },
+                { "type": "volume", "from": "cache", "to": "/cache" },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(cfg.check_volumes(&["cache".into()]), Ok(()));
+        assert!(cfg.check_volumes(&[]).is_err());
+        assert!(cfg.check_volumes(&["other".into()]).is_err());
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let network = |hosts: &[&str]| NetworkOptions {
+            enable_running: true,
+            allowed_hosts: hosts.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(!network(&[]).restricts_egress());
+        let allowed = network(&["api.example.com:443", "mock-api:8080"]);
+        assert!(allowed.restricts_egress());
+        assert_eq!(
+            allowed.parse_allowed_hosts(),
+            Ok(vec![("api.example.com", 443), ("mock-api", 8080)])
+        );
+        assert!(network(&["example.com"]).parse_allowed_hosts().is_err());
+        assert!(network(&["example.com:0"]).parse_allowed_hosts().is_err());
+        assert!(network(&["10.0.0.1:80"]).parse_allowed_hosts().is_err());
+        assert!(network(&["[::1]:80"]).parse_allowed_hosts().is_err());
+        assert!(network(&["a;rm -rf /:80"]).parse_allowed_hosts().is_err());
+    }
+
+    #[test]
+    fn test_resolve_shared_binds() {
+        let mut cfg = serde_json::from_value::<JudgerPublicConfig>(serde_json::json!({
+            "name": "shared",
+            "testGroups": {},
+            "run": [],
+            "mappedDir": { "from": ".", "to": "/src" },
+            "sharedBinds": [{ "name": "imagenet", "to": "/data" }],
+        }))
+        .unwrap();
+
+        let mut host_paths = HashMap::new();
+        assert!(cfg.clone().resolve_shared_binds(&host_paths).is_err());
+        host_paths.insert("imagenet".to_owned(), PathBuf::from("datasets/imagenet"));
+        assert!(cfg.clone().resolve_shared_binds(&host_paths).is_err());
+
+        let host_path = std::env::temp_dir().join("imagenet");
+        host_paths.insert("imagenet".to_owned(), host_path.clone());
+        cfg.resolve_shared_binds(&host_paths).unwrap();
+        let binds = cfg.binds.unwrap();
+        assert_eq!(binds.len(), 1);
+        assert_eq!(binds[0].from, host_path);
+        assert_eq!(binds[0].to, PathBuf::from("/data"));
+        assert_eq!(binds[0].check(), Ok(()));
+    }
+}
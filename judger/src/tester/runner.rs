@@ -1,40 +1,123 @@
-use super::{exec::BuildResultChannel, model::*, utils::convert_code, JobFailure, ProcessInfo};
-use crate::{client::config::DockerConfig, prelude::*, sh};
+use super::{
+    exec::BuildResultChannel, model::*, utils::convert_code, JobFailure, OutputFrame, OutputStream,
+    ProcessInfo,
+};
+use crate::{client::config::DockerConfig, prelude::*, sh, util::mem_budget::MemoryBudget};
 use anyhow::Result;
 use async_trait::async_trait;
 use bollard::{
-    container::UploadToContainerOptions, exec::StartExecResults, models::Mount,
-    network::ConnectNetworkOptions, Docker,
+    container::UploadToContainerOptions,
+    exec::StartExecResults,
+    models::{HostConfig, ImageSummary, Mount, Network, ThrottleDevice},
+    network::ConnectNetworkOptions,
+    Docker,
 };
 use drop_bomb::DropBomb;
 use futures::prelude::*;
+use indexmap::IndexMap;
+use itertools::Itertools;
 use names::{Generator, Name};
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
 use std::{
-    collections::HashMap, default::Default, io, path::PathBuf, process::ExitStatus, sync::Arc,
+    collections::HashMap,
+    default::Default,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    process::{Command, ExitStatus, Output},
+    sync::Arc,
 };
-use tokio::process::Command;
 
 /// An evaluation environment for commands.
 #[async_trait]
-pub trait CommandRunner {
+pub trait CommandRunner: Sync {
     /// Evaluate a command string with the given variables to replace.
     /// The command should be supplied with Unix Shell style.
-    async fn run(&self, cmd: &str, variables: &HashMap<String, String>)
-        -> PopenResult<ProcessInfo>;
+    async fn run(
+        &self,
+        cmd: &str,
+        variables: &IndexMap<String, String>,
+    ) -> PopenResult<ProcessInfo>;
+
+    /// Evaluate a command like [`run`](Self::run), but stop it if it does not
+    /// finish within `timeout`.
+    ///
+    /// A stopped command results in an error of kind
+    /// [`io::ErrorKind::TimedOut`], which carries a [`TimedOutOutput`] if the
+    /// output of the command before it stopped is available.
+    async fn run_timeout(
+        &self,
+        cmd: &str,
+        variables: &IndexMap<String, String>,
+        timeout: std::time::Duration,
+    ) -> PopenResult<ProcessInfo> {
+        tokio::time::timeout(timeout, self.run(cmd, variables))
+            .await
+            .map_err(|_| timed_out_err(timeout, None))?
+    }
+
+    /// Write `content` into a new temporary file readable by commands, and
+    /// return its path as seen by them. Used to pass data too large for
+    /// command line arguments or environment variables. The file should be
+    /// removed with [`remove_temp_file`](Self::remove_temp_file).
+    async fn write_temp_file(&self, content: &[u8]) -> io::Result<String>;
+
+    /// Remove a file created by [`write_temp_file`](Self::write_temp_file).
+    async fn remove_temp_file(&self, _path: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Name of a new temporary file for [`CommandRunner::write_temp_file`].
+fn temp_file_name() -> String {
+    format!("rurikawa-{:016x}", rand::random::<u64>())
+}
+
+/// Output of a command captured before it was stopped for timing out.
+#[derive(Debug)]
+pub struct TimedOutOutput(pub ProcessInfo);
+
+impl std::fmt::Display for TimedOutOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command `{}` timed out", self.0.command)
+    }
+}
+
+impl std::error::Error for TimedOutOutput {}
+
+/// Get the output captured before the command timed out from the error
+/// returned by [`CommandRunner::run_timeout`], if any.
+pub fn timed_out_output(err: &io::Error) -> Option<&ProcessInfo> {
+    err.get_ref()?
+        .downcast_ref::<TimedOutOutput>()
+        .map(|x| &x.0)
+}
+
+fn timed_out_err(timeout: std::time::Duration, output: Option<ProcessInfo>) -> io::Error {
+    match output {
+        Some(info) => io::Error::new(io::ErrorKind::TimedOut, TimedOutOutput(info)),
+        None => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Popen capture timed out at {}s", timeout.as_secs_f64()),
+        ),
+    }
 }
 
 /// A *local* command evaluation environment.
 /// This is used generally for local testing purposes.
-pub struct TokioCommandRunner {}
+#[derive(Debug, Default)]
+pub struct TokioCommandRunner {
+    /// Working directory of commands. Defaults to the current directory.
+    pub cwd: Option<PathBuf>,
+}
 
 #[async_trait]
 impl CommandRunner for TokioCommandRunner {
     async fn run(
         &self,
         cmd_str: &str,
-        variables: &HashMap<String, String>,
+        variables: &IndexMap<String, String>,
     ) -> PopenResult<ProcessInfo> {
         let cmd: Vec<String> = sh!(cmd_str);
 
@@ -47,16 +130,24 @@ impl CommandRunner for TokioCommandRunner {
 
         let mut command = Command::new(car);
         command.args(cdr);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
 
         for (k, v) in variables {
             command.env(k, v);
         }
 
-        let std::process::Output {
-            status,
-            stdout,
-            stderr,
-        } = command.output().await?;
+        let start = std::time::Instant::now();
+        let (
+            Output {
+                status,
+                stdout,
+                stderr,
+            },
+            usage,
+        ) = output_with_usage(command).await?;
+        let wall_time_ms = start.elapsed().as_millis() as u64;
 
         let ret_code = ret_code_from_exit_status(status);
         let ret_code = convert_code(ret_code);
@@ -67,8 +158,219 @@ impl CommandRunner for TokioCommandRunner {
             stdout: String::from_utf8_lossy(&stdout).into_owned(),
             stderr: String::from_utf8_lossy(&stderr).into_owned(),
             ret_code,
+            peak_pids: None,
+            pids_limit: None,
+            wall_time_ms: Some(wall_time_ms),
+            cpu_time_ms: usage.cpu_time_ms,
+            peak_memory: usage.peak_memory,
+            combined: None,
+            reservation: Default::default(),
         })
     }
+
+    async fn write_temp_file(&self, content: &[u8]) -> io::Result<String> {
+        let path = std::env::temp_dir().join(temp_file_name());
+        tokio::fs::write(&path, content).await?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    async fn remove_temp_file(&self, path: &str) -> io::Result<()> {
+        tokio::fs::remove_file(path).await
+    }
+}
+
+/// Resource usage of a finished child process.
+#[derive(Debug, Default)]
+struct ChildUsage {
+    /// User and system CPU time in milliseconds.
+    cpu_time_ms: Option<u64>,
+    /// Peak resident set size in bytes.
+    peak_memory: Option<u64>,
+}
+
+/// Run `command` like [`tokio::process::Command::output`], waiting for it
+/// with `wait4` to also get its resource usage.
+///
+/// The command runs in its own process group, which is killed if the
+/// returned future is dropped before the command finishes, so that cancelled
+/// commands do not keep running or hold the blocking thread reading them.
+#[cfg(unix)]
+async fn output_with_usage(mut command: Command) -> io::Result<(Output, ChildUsage)> {
+    use nix::libc;
+    use std::{os::unix::process::CommandExt, process::Stdio, sync::Mutex};
+
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    let mut child = command.spawn()?;
+    let pid = child.id() as libc::pid_t;
+    let reaped = Arc::new(Mutex::new(false));
+    let _guard = KillGroupOnDrop {
+        pid,
+        reaped: reaped.clone(),
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let (stdout, stderr) = read_both(stdout, stderr);
+
+        // Wait for the child to exit without reaping it, so that the guard
+        // can still kill its process group until it is reaped below.
+        let mut info = std::mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+        retry_interrupted(|| unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                info.as_mut_ptr(),
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        })?;
+
+        // `Child::wait` does not report the resource usage, so the child is
+        // reaped directly instead.
+        let mut status = 0;
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+        {
+            let mut reaped = reaped.lock().unwrap();
+            retry_interrupted(|| unsafe { libc::wait4(pid, &mut status, 0, usage.as_mut_ptr()) })?;
+            *reaped = true;
+        }
+        let stdout = stdout?;
+        let stderr = stderr?;
+
+        // Filled in by `wait4` above
+        let usage = unsafe { usage.assume_init() };
+        let ms = |t: libc::timeval| t.tv_sec as u64 * 1000 + t.tv_usec as u64 / 1000;
+        // `ru_maxrss` is in bytes on macOS and in kilobytes elsewhere
+        let max_rss = usage.ru_maxrss.max(0) as u64;
+        let peak_memory = if cfg!(target_os = "macos") {
+            max_rss
+        } else {
+            max_rss * 1024
+        };
+        let output = Output {
+            status: ExitStatus::from_raw(status),
+            stdout,
+            stderr,
+        };
+        let usage = ChildUsage {
+            cpu_time_ms: Some(ms(usage.ru_utime) + ms(usage.ru_stime)),
+            peak_memory: Some(peak_memory),
+        };
+        Ok((output, usage))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Kills the process group led by `pid` when dropped, unless the process has
+/// already been reaped and its id may have been reused.
+#[cfg(unix)]
+struct KillGroupOnDrop {
+    pid: nix::libc::pid_t,
+    reaped: Arc<std::sync::Mutex<bool>>,
+}
+
+#[cfg(unix)]
+impl Drop for KillGroupOnDrop {
+    fn drop(&mut self) {
+        let reaped = self.reaped.lock().unwrap_or_else(|e| e.into_inner());
+        if !*reaped {
+            unsafe { nix::libc::kill(-self.pid, nix::libc::SIGKILL) };
+        }
+    }
+}
+
+/// Call `f` until it does not fail with `EINTR`.
+#[cfg(unix)]
+fn retry_interrupted(mut f: impl FnMut() -> nix::libc::c_int) -> io::Result<()> {
+    while f() == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Read the stdout and stderr of a child to their ends on the current
+/// thread, polling both so that neither pipe fills up and blocks the child.
+#[cfg(unix)]
+fn read_both(
+    stdout: Option<std::process::ChildStdout>,
+    stderr: Option<std::process::ChildStderr>,
+) -> (io::Result<Vec<u8>>, io::Result<Vec<u8>>) {
+    use nix::libc;
+    use std::{
+        fs::File,
+        io::Read,
+        os::unix::io::{AsRawFd, OwnedFd},
+    };
+
+    let mut pipes = [
+        stdout.map(|x| File::from(OwnedFd::from(x))),
+        stderr.map(|x| File::from(OwnedFd::from(x))),
+    ];
+    let mut outputs = [Ok(vec![]), Ok(vec![])];
+    let mut chunk = [0u8; 8192];
+    loop {
+        let mut fds = pipes
+            .iter()
+            .flatten()
+            .map(|x| libc::pollfd {
+                fd: x.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+        if fds.is_empty() {
+            break;
+        }
+        if let Err(e) =
+            retry_interrupted(|| unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) })
+        {
+            for (pipe, output) in pipes.iter_mut().zip(&mut outputs) {
+                if pipe.take().is_some() {
+                    *output = Err(io::Error::new(e.kind(), e.to_string()));
+                }
+            }
+            break;
+        }
+        for (pipe, output) in pipes.iter_mut().zip(&mut outputs) {
+            let file = match pipe {
+                Some(file) => file,
+                None => continue,
+            };
+            let fd = file.as_raw_fd();
+            if !fds.iter().any(|x| x.fd == fd && x.revents != 0) {
+                continue;
+            }
+            match file.read(&mut chunk) {
+                Ok(0) => *pipe = None,
+                Ok(n) => {
+                    if let Ok(buf) = output {
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    *pipe = None;
+                    *output = Err(e);
+                }
+            }
+        }
+    }
+    let [stdout, stderr] = outputs;
+    (stdout, stderr)
+}
+
+#[cfg(not(unix))]
+async fn output_with_usage(command: Command) -> io::Result<(Output, ChildUsage)> {
+    let output = tokio::process::Command::from(command).output().await?;
+    Ok((output, ChildUsage::default()))
 }
 
 #[cfg(windows)]
@@ -90,6 +392,7 @@ fn ret_code_from_exit_status(status: ExitStatus) -> i32 {
 /// - Every `DockerCommandRunner` instance includes a `DropBomb`,
 ///     which prevents `drop`ping without explicitly using `self.kill()`.
 /// - When the instance is directly `drop`ped, a runtime panic will occur.
+/// - Service containers are owned by the runner and only removed by `self.kill()`.
 pub struct DockerCommandRunner {
     /// The image to be used.
     image: Image,
@@ -99,14 +402,323 @@ pub struct DockerCommandRunner {
     options: DockerCommandRunnerOptions,
     /// Intermediate images created by this runner.
     pub intermediate_images: Vec<String>,
+    /// Names of service containers created by this runner.
+    service_containers: Vec<String>,
+    /// Name of the sidecar container recording network access attempts, if
+    /// the network is audited.
+    audit_container: Option<String>,
     /// A bomb that must be defused. Prevents drops without explicit kills.
     bomb: DropBomb,
 }
 
+/// Maximum length of generated container names. Docker accepts longer names,
+/// but container names are also used as prefixes of service container names.
+const MAX_CONTAINER_NAME_LEN: usize = 64;
+
+/// Maximum length of a hostname label, as per RFC 1123.
+const MAX_HOSTNAME_LEN: usize = 63;
+
+/// Length of the unique suffix of container names, see [`container_name`].
+const CONTAINER_NAME_SUFFIX_LEN: usize = 14;
+
+/// Number of names tried when creating a container before giving up on name
+/// conflicts.
+const MAX_CONTAINER_NAME_ATTEMPTS: usize = 3;
+
+/// Generate a random container name. If `id` is given (e.g. the job id), it is
+/// included in the name, so that containers can be told apart in `docker ps`.
+///
+/// The name ends with a [`FlowSnake`] to keep it unique under high
+/// concurrency, since the readable word before it comes from a small
+/// namespace. Characters not accepted by Docker are replaced with `-`.
+pub fn container_name(id: Option<&str>) -> String {
+    let mut names = Generator::with_naming(Name::Numbered);
+    let word = format!("{}_{}", names.next().unwrap(), FlowSnake::generate());
+    match id {
+        Some(id) => {
+            let max_id_len = MAX_CONTAINER_NAME_LEN - "rurikawa__".len() - word.len();
+            let id = id
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+                    _ => '-',
+                })
+                .take(max_id_len)
+                .collect::<String>();
+            format!("rurikawa_{}_{}", id, word)
+        }
+        None => format!("rurikawa_{}", word),
+    }
+}
+
+/// Replace the unique suffix of a name generated by [`container_name`], for
+/// retrying after a name conflict.
+fn rename_container(name: &str) -> String {
+    let prefix = name
+        .len()
+        .checked_sub(CONTAINER_NAME_SUFFIX_LEN)
+        .and_then(|x| name.get(..x))
+        .unwrap_or(name);
+    format!("{}{}", prefix, FlowSnake::generate())
+}
+
+/// Label of every image built or committed, and every network created by
+/// the judger.
+pub const IMAGE_LABEL: &str = "rurikawa.judger";
+
+/// Images younger than this are never pruned, as they may be about to be
+/// used by a job that has just built them.
+const IMAGE_PRUNE_MIN_AGE: i64 = 30 * 60;
+
+/// Labels to be attached to images built or committed by the judger.
+pub fn image_labels() -> HashMap<String, String> {
+    std::iter::once((IMAGE_LABEL.to_owned(), "true".to_owned())).collect()
+}
+
+/// Images built or committed by the judger.
+pub async fn judger_images(instance: &Docker) -> Result<Vec<ImageSummary>, bollard::errors::Error> {
+    instance
+        .list_images(Some(bollard::image::ListImagesOptions {
+            filters: std::iter::once(("label", vec![IMAGE_LABEL])).collect(),
+            ..Default::default()
+        }))
+        .await
+}
+
+/// Total size of `images` in bytes. Layers shared between images are counted
+/// once for each image, so this overestimates the actual disk usage.
+fn images_size(images: &[ImageSummary]) -> u64 {
+    images.iter().map(|x| x.size.max(0) as u64).sum()
+}
+
+/// Select the oldest of `images` to be removed, until the size of the
+/// remaining ones is at most `limit` bytes. Images created after
+/// `now - IMAGE_PRUNE_MIN_AGE` are kept.
+fn images_to_prune(images: &[ImageSummary], limit: u64, now: i64) -> Vec<&ImageSummary> {
+    let mut usage = images_size(images);
+    images
+        .iter()
+        .filter(|x| x.created <= now - IMAGE_PRUNE_MIN_AGE)
+        .sorted_by_key(|x| x.created)
+        .take_while(|x| {
+            let over = usage > limit;
+            usage = usage.saturating_sub(x.size.max(0) as u64);
+            over
+        })
+        .collect()
+}
+
+/// Remove the oldest images built or committed by the judger until their
+/// disk usage is at most `limit` bytes. Images used by containers cannot be
+/// removed and are skipped. Returns the disk usage afterwards.
+pub async fn prune_images(instance: &Docker, limit: u64) -> Result<u64, bollard::errors::Error> {
+    let images = judger_images(instance).await?;
+    let now = chrono::Utc::now().timestamp();
+    let mut usage = images_size(&images);
+    for image in images_to_prune(&images, limit, now) {
+        let names = if image.repo_tags.is_empty() {
+            vec![image.id.clone()]
+        } else {
+            image.repo_tags.clone()
+        };
+        let mut removed = true;
+        for name in &names {
+            if let Err(e) = instance.remove_image(name, None, None).await {
+                log::warn!("Failed to prune image {}: {}", name, e);
+                removed = false;
+            }
+        }
+        if removed {
+            log::info!("Pruned image {} ({} bytes)", names.join(", "), image.size);
+            usage = usage.saturating_sub(image.size.max(0) as u64);
+        }
+    }
+    Ok(usage)
+}
+
+/// Networks younger than this are never pruned, as they may belong to a
+/// runner of another judger sharing the Docker daemon that is starting up.
+const NETWORK_PRUNE_MIN_AGE: i64 = 10 * 60;
+
+/// Select the networks in `networks` left behind by runners that were not
+/// killed properly, i.e. internal networks named like containers of the
+/// judger or labelled with [`IMAGE_LABEL`], created before
+/// `now - NETWORK_PRUNE_MIN_AGE`.
+fn networks_to_prune(networks: &[Network], now: i64) -> Vec<&Network> {
+    networks
+        .iter()
+        .filter(|x| {
+            let labelled = x
+                .labels
+                .as_ref()
+                .map_or(false, |labels| labels.contains_key(IMAGE_LABEL));
+            let named = x
+                .name
+                .as_deref()
+                .map_or(false, |x| x.starts_with("rurikawa_"))
+                && x.internal == Some(true);
+            labelled || named
+        })
+        .filter(|x| {
+            x.created
+                .as_deref()
+                .and_then(|x| chrono::DateTime::parse_from_rfc3339(x).ok())
+                .map_or(false, |x| x.timestamp() <= now - NETWORK_PRUNE_MIN_AGE)
+        })
+        .collect()
+}
+
+/// Remove the networks left behind by runners of crashed judgers, see
+/// [`networks_to_prune`]. Networks still in use cannot be removed and are
+/// skipped. Returns the number of networks removed.
+pub async fn prune_networks(instance: &Docker) -> Result<usize, bollard::errors::Error> {
+    let networks = instance
+        .list_networks(Some(bollard::network::ListNetworksOptions {
+            filters: std::iter::once(("name", vec!["rurikawa_"])).collect(),
+        }))
+        .await?;
+    let now = chrono::Utc::now().timestamp();
+    let mut removed = 0;
+    for network in networks_to_prune(&networks, now) {
+        let id = match network.id.as_deref().or(network.name.as_deref()) {
+            Some(id) => id,
+            None => continue,
+        };
+        match instance.remove_network(id).await {
+            Ok(_) => {
+                log::info!("Pruned network {}", network.name.as_deref().unwrap_or(id));
+                removed += 1;
+            }
+            Err(e) => log::debug!("Failed to prune network {}: {}", id, e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Whether `err` means the Docker daemon has run out of subnets for new
+/// networks.
+fn is_address_pool_exhausted(err: &bollard::errors::Error) -> bool {
+    let msg = err.to_string();
+    msg.contains("fully subnetted") || msg.contains("non-overlapping IPv4 address pool")
+}
+
+/// Whether the Docker daemon enforces the memory limits set on test
+/// containers. Docker silently ignores them on hosts without memory or swap
+/// accounting, e.g. with cgroup v1 without `swapaccount=1`, or in some
+/// rootless setups.
+pub async fn memory_limits_enforceable(instance: &Docker) -> Result<bool, bollard::errors::Error> {
+    let info = instance.info().await?;
+    Ok(info.memory_limit.unwrap_or(false) && info.swap_limit.unwrap_or(false))
+}
+
+/// Whether tests would run as root on the host, given the security options of
+/// the Docker daemon and `cfg`.
+///
+/// Root in a container is host root unless the daemon is rootless or remaps
+/// user namespaces (and `cfg` does not opt out with `userns_mode = "host"`).
+/// Tests run as root if no run user is configured, since images run as root
+/// by default.
+pub fn container_root_is_host_root(security_options: &[String], cfg: &DockerConfig) -> bool {
+    let has_option = |name: &str| {
+        security_options
+            .iter()
+            .any(|x| x.split(',').any(|kv| kv == format!("name={}", name)))
+    };
+    let remapped = has_option("rootless")
+        || (has_option("userns") && cfg.userns_mode.as_deref() != Some("host"));
+    if remapped {
+        return false;
+    }
+    match cfg.run_user() {
+        None => true,
+        Some(user) => {
+            let name = user.split(':').next().unwrap_or_default();
+            name == "root" || name == "0"
+        }
+    }
+}
+
+/// Check with the Docker daemon whether tests would run as root on the host.
+/// See [`container_root_is_host_root`].
+pub async fn runs_as_host_root(
+    instance: &Docker,
+    cfg: &DockerConfig,
+) -> Result<bool, bollard::errors::Error> {
+    let info = instance.info().await?;
+    Ok(container_root_is_host_root(
+        &info.security_options.unwrap_or_default(),
+        cfg,
+    ))
+}
+
+/// Convert `s` into a valid hostname, i.e. a lowercase RFC 1123 label. Returns
+/// `None` if nothing is left after the conversion.
+pub fn sanitize_hostname(s: &str) -> Option<String> {
+    let hostname = s
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9') => c,
+            _ => '-',
+        })
+        .collect::<String>();
+    let hostname = hostname.trim_matches('-');
+    let hostname = hostname[..hostname.len().min(MAX_HOSTNAME_LEN)].trim_end_matches('-');
+    if hostname.is_empty() {
+        None
+    } else {
+        Some(hostname.to_owned())
+    }
+}
+
+/// Name of the network audit sidecar, also used as its hostname.
+const NETWORK_AUDIT_SERVICE: &str = "rurikawa-audit";
+
+/// Name prefix of the sidecars forwarding connections to allowed hosts.
+const NETWORK_PROXY_SERVICE: &str = "rurikawa-egress";
+
+/// Port of the HTTP proxy served by the network audit sidecar.
+pub const NETWORK_AUDIT_PROXY_PORT: u16 = 3128;
+
+/// Environment variables directing proxy-aware programs to the network audit
+/// sidecar at `ip`.
+fn network_audit_env(ip: &str) -> Vec<String> {
+    let proxy = format!("http://{}:{}", ip, NETWORK_AUDIT_PROXY_PORT);
+    ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"]
+        .iter()
+        .map(|var| format!("{}={}", var, proxy))
+        .collect()
+}
+
+/// Convert the limits in `map` into a list of throttled devices.
+fn throttle_devices(map: &HashMap<String, i64>) -> Option<Vec<ThrottleDevice>> {
+    if map.is_empty() {
+        return None;
+    }
+    let devices = map
+        .iter()
+        .map(|(path, rate)| ThrottleDevice {
+            path: Some(path.clone()),
+            rate: Some(*rate),
+        })
+        .collect();
+    Some(devices)
+}
+
+/// Apply the block IO limits in `cfg` onto `host_config`.
+fn apply_blkio_limits(cfg: &DockerConfig, host_config: &mut HostConfig) {
+    host_config.blkio_weight = cfg.blkio_weight;
+    host_config.blkio_device_read_bps = throttle_devices(&cfg.blkio_device_read_bps);
+    host_config.blkio_device_write_bps = throttle_devices(&cfg.blkio_device_write_bps);
+    host_config.blkio_device_read_iops = throttle_devices(&cfg.blkio_device_read_iops);
+    host_config.blkio_device_write_iops = throttle_devices(&cfg.blkio_device_write_iops);
+}
+
 /// The options while creating a `DockerCommandRunner`.
 pub struct DockerCommandRunnerOptions {
     /// Name assigned to the container.
     pub container_name: String,
+    /// Hostname of the container. Defaults to the container ID if not set.
+    pub hostname: Option<String>,
     /// Memory limit of the container.
     pub mem_limit: Option<usize>,
     /// If the image needs to be pulled/built before run.
@@ -128,15 +740,26 @@ pub struct DockerCommandRunnerOptions {
     pub network_options: NetworkOptions,
     /// Network ID of this command runner
     pub network_name: Option<String>,
+    /// Service containers to be started before running any command
+    pub services: Vec<ServiceContainer>,
     /// Predefined configurations, e.g. CPU shares
     pub cfg: Arc<DockerConfig>,
+    /// If interleaved, timestamped output is captured besides `stdout` and
+    /// `stderr`.
+    pub combined_output: bool,
+    /// Budget the captured output of commands is taken from, shared by all
+    /// running jobs.
+    pub output_budget: Option<Arc<MemoryBudget>>,
+    /// Maximum length of the `stdout` and `stderr` of a command, in bytes.
+    /// Longer output is truncated.
+    pub max_output_size: usize,
 }
 
 impl Default for DockerCommandRunnerOptions {
     fn default() -> Self {
-        let mut names = Generator::with_naming(Name::Numbered);
         DockerCommandRunnerOptions {
-            container_name: format!("rurikawa_{}", names.next().unwrap()),
+            container_name: container_name(None),
+            hostname: None,
             mem_limit: None,
             build_image: false,
             remove_image: false,
@@ -146,8 +769,12 @@ impl Default for DockerCommandRunnerOptions {
             cancellation_token: Default::default(),
             network_options: Default::default(),
             network_name: None,
+            services: vec![],
             cfg: Default::default(),
             copy_ignore: vec![],
+            combined_output: false,
+            output_budget: None,
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
         }
     }
 }
@@ -172,6 +799,8 @@ impl DockerCommandRunner {
             instance,
             options,
             intermediate_images: vec![],
+            service_containers: vec![],
+            audit_container: None,
             bomb: DropBomb::new(
                 "DockerCommandRunner must be explicitly killed to prevent stranding contrainers",
             ),
@@ -185,7 +814,7 @@ impl DockerCommandRunner {
                 match $res {
                     Ok(val) => val,
                     Err(err) => {
-                        r.kill().await;
+                        r.abort().await;
                         return Err(err.into());
                     }
                 }
@@ -196,27 +825,59 @@ impl DockerCommandRunner {
 
         log::info!("container {}: started building", r.options.container_name);
 
+        // Service containers are only reachable through the internal network.
+        let use_services = !r.options.services.is_empty();
+        let use_network_running = r.options.network_options.enable_running || use_services;
+        // Audited containers are connected to the internal network only.
+        let audit_network =
+            r.options.network_options.audit_running && !r.options.network_options.enable_running;
+        // So are containers only allowed to reach some hosts, through sidecars
+        // forwarding connections to them.
+        let restrict_egress = r.options.network_options.restricts_egress();
+
         // Spin up a network for later use
-        r.options.network_name =
-            if r.options.network_options.use_network() && r.options.network_name.is_none() {
-                try_or_kill!(
-                    r.instance
-                        .create_network(bollard::network::CreateNetworkOptions {
-                            name: r.options.container_name.as_str(),
-                            check_duplicate: false,
-                            driver: "bridge",
-                            internal: true,
-                            ..Default::default()
-                        })
-                        .await
-                )
-                .id
-            } else {
-                None
+        r.options.network_name = if (r.options.network_options.use_network()
+            || use_services
+            || audit_network
+            || restrict_egress)
+            && r.options.network_name.is_none()
+        {
+            let create_network = || {
+                r.instance
+                    .create_network(bollard::network::CreateNetworkOptions {
+                        name: r.options.container_name.as_str(),
+                        check_duplicate: false,
+                        driver: "bridge",
+                        internal: true,
+                        labels: std::iter::once((IMAGE_LABEL, "true")).collect(),
+                        ..Default::default()
+                    })
             };
+            let mut res = create_network().await;
+            // Networks leaked by crashed judgers may use up all subnets
+            if let Err(e) = &res {
+                if is_address_pool_exhausted(e) {
+                    log::warn!("No subnet left for a new network, pruning stale networks");
+                    match prune_networks(&r.instance).await {
+                        Ok(n) => log::info!("Pruned {} stale networks", n),
+                        Err(e) => log::warn!("Failed to prune networks: {}", e),
+                    }
+                    res = create_network().await;
+                }
+            }
+            try_or_kill!(res).id
+        } else {
+            None
+        };
 
-        // Build the image.
+        // Build the image, making room for it first.
         if r.options.build_image {
+            if let Some(limit) = r.options.cfg.image_disk_limit {
+                match prune_images(&r.instance, limit).await {
+                    Ok(usage) => log::info!("Images built by judger use {} bytes", usage),
+                    Err(e) => log::warn!("Failed to prune images: {}", e),
+                }
+            }
             try_or_kill!(
                 r.image
                     .build(
@@ -228,7 +889,8 @@ impl DockerCommandRunner {
                             .enable_build
                             .then(|| r.options.network_name.as_deref())
                             .flatten(),
-                        r.options.cfg.build_cpu_share
+                        r.options.cfg.build_cpu_share,
+                        &r.options.cfg,
                     )
                     .await
             )
@@ -266,6 +928,7 @@ impl DockerCommandRunner {
                         open_stdin: Some(true),
                         attach_stdin: Some(true),
                         entrypoint: Some(vec!["sh".into()]),
+                        user: r.options.cfg.build_user(),
 
                         // We don't need network if we're just copying files
                         network_disabled: Some(true),
@@ -279,10 +942,10 @@ impl DockerCommandRunner {
             // Ensure every early return comes with an explicit kill.
             if create_res.is_none() {
                 // TODO: Cleanup
-                r.kill().await;
+                r.abort().await;
                 return Err(JobFailure::Cancelled.into());
             } else if let Err(e) = create_res.unwrap() {
-                r.kill().await;
+                r.abort().await;
                 return Err(JobFailure::internal_err_from(format!(
                     "Failed to create container `{}`: {}",
                     &container_name, e
@@ -363,14 +1026,17 @@ impl DockerCommandRunner {
                             repo: after_copy_image_name.clone(),
                             ..Default::default()
                         },
-                        bollard::container::Config::<String>::default(),
+                        bollard::container::Config::<String> {
+                            labels: Some(image_labels()),
+                            ..Default::default()
+                        },
                     )
                     .await
             );
 
-            if r.options.record_intermediate_images {
-                r.intermediate_images.push(after_copy_image_name.clone());
-            }
+            // Images with copied data are only used by this runner, so they are
+            // always recorded for removal.
+            r.intermediate_images.push(after_copy_image_name.clone());
             image_name = after_copy_image_name;
 
             try_or_kill!(r.instance.stop_container(&container_name, None).await);
@@ -381,49 +1047,118 @@ impl DockerCommandRunner {
             try_or_kill!(r.instance.remove_container(&container_name, None).await);
         }
 
+        // Start the network audit sidecar before the container using it.
+        let audit_ip = if audit_network {
+            let cancel = r.options.cancellation_token.clone();
+            match r.start_network_audit().with_cancel(cancel).await {
+                Some(ip) => ip,
+                None => {
+                    r.abort().await;
+                    return Err(JobFailure::Cancelled.into());
+                }
+            }
+        } else {
+            None
+        };
+
+        // Start the sidecars of allowed hosts before the container using them.
+        let extra_hosts = if restrict_egress {
+            let cancel = r.options.cancellation_token.clone();
+            let network_options = r.options.network_options.clone();
+            let allowed = try_or_kill!(network_options
+                .parse_allowed_hosts()
+                .map_err(JobFailure::internal_err_from));
+            match r.start_egress_proxies(&allowed).with_cancel(cancel).await {
+                Some(hosts) => Some(hosts),
+                None => {
+                    r.abort().await;
+                    return Err(JobFailure::Cancelled.into());
+                }
+            }
+        } else {
+            None
+        };
+        // Containers attached to the internal network only
+        let internal_only = audit_ip.is_some() || restrict_egress;
+
         log::trace!("container {}: creating", r.options.container_name);
 
-        // Create a container
-        try_or_kill!(r
-            .instance
-            .create_container(
-                Some(bollard::container::CreateContainerOptions {
-                    name: r.options.container_name.clone(),
-                }),
-                bollard::container::Config {
-                    image: Some(image_name),
-                    attach_stdin: Some(true),
-                    attach_stdout: Some(true),
-                    attach_stderr: Some(true),
-                    tty: Some(true),
-                    // set docker user
-                    user: r.options.cfg.docker_user.clone(),
-                    host_config: Some(bollard::service::HostConfig {
-                        mounts: r.options.binds.clone(),
-                        // set memory limits
-                        memory_swap: r.options.mem_limit.map(|n| n as i64),
-                        // set cpu limits
-                        nano_cpus: r.options.cfg.run_cpu_share.map(|x| (x * 1e9) as i64),
-                        ..Default::default()
-                    }),
-                    entrypoint: Some(vec!["sh".into()]),
-                    // Set network availability
-                    network_disabled: Some(!r.options.network_options.enable_running),
+        // Create a container, picking another name if the name is taken.
+        let config = bollard::container::Config {
+            image: Some(image_name),
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(true),
+            hostname: r.options.hostname.clone(),
+            // tests run as the unprivileged user
+            user: r.options.cfg.run_user(),
+            // route proxy-aware traffic to the audit sidecar
+            env: audit_ip.as_deref().map(network_audit_env),
+            host_config: Some({
+                let mut host_config = bollard::service::HostConfig {
+                    mounts: r.options.binds.clone(),
+                    // only attach audited or restricted containers to the
+                    // internal network, and resolve names with the sidecars
+                    network_mode: internal_only
+                        .then(|| r.options.network_name.clone())
+                        .flatten(),
+                    dns: audit_ip.clone().map(|ip| vec![ip]),
+                    extra_hosts,
+                    // set memory limits
+                    memory_swap: r.options.mem_limit.map(|n| n as i64),
+                    // set cpu limits
+                    nano_cpus: r.options.cfg.run_cpu_share.map(|x| (x * 1e9) as i64),
+                    // set process limits
+                    pids_limit: r.options.cfg.pids_limit,
+                    userns_mode: r.options.cfg.userns_mode.clone(),
                     ..Default::default()
-                },
-            )
-            .await
-            .map_err(|e| {
-                JobFailure::internal_err_from(format!(
+                };
+                // set block io limits
+                apply_blkio_limits(&r.options.cfg, &mut host_config);
+                host_config
+            }),
+            entrypoint: Some(vec!["sh".into()]),
+            // Set network availability
+            network_disabled: Some(!use_network_running && audit_ip.is_none()),
+            ..Default::default()
+        };
+        let mut attempts = 1;
+        loop {
+            let res = r
+                .instance
+                .create_container(
+                    Some(bollard::container::CreateContainerOptions {
+                        name: r.options.container_name.clone(),
+                    }),
+                    config.clone(),
+                )
+                .await;
+            match res {
+                Ok(_) => break,
+                Err(bollard::errors::Error::DockerResponseConflictError { .. })
+                    if attempts < MAX_CONTAINER_NAME_ATTEMPTS =>
+                {
+                    attempts += 1;
+                    let name = rename_container(&r.options.container_name);
+                    log::warn!(
+                        "container {}: name is taken, retrying as {}",
+                        r.options.container_name,
+                        name
+                    );
+                    r.options.container_name = name;
+                }
+                Err(e) => try_or_kill!(Err(JobFailure::internal_err_from(format!(
                     "Failed to create container `{}`: {}",
                     &r.options.container_name, e
-                ))
-            }));
+                )))),
+            }
+        }
 
         let container_name = &r.options.container_name;
 
         // Connect to network
-        if r.options.network_options.enable_running {
+        if use_network_running && !internal_only {
             let res = r
                 .instance
                 .connect_network(
@@ -461,9 +1196,315 @@ impl DockerCommandRunner {
             }),);
 
         log::trace!("container {}: launched", r.options.container_name);
+
+        // Start service containers
+        let cancel = r.options.cancellation_token.clone();
+        for service in r.options.services.clone() {
+            let res = r
+                .start_service(&service)
+                .with_cancel(cancel.clone())
+                .await
+                .unwrap_or_else(|| Err(JobFailure::Cancelled.into()));
+            try_or_kill!(res);
+        }
+
         Ok(r)
     }
 
+    /// Start the sidecar recording network access attempts, and return its IP
+    /// address in the internal network.
+    ///
+    /// Returns `None` if the sidecar is not configured or fails to start, in
+    /// which case the container is run with all network access blocked.
+    async fn start_network_audit(&mut self) -> Option<String> {
+        let image = match &self.options.cfg.network_audit_image {
+            Some(image) => image.clone(),
+            None => {
+                log::warn!(
+                    "container {}: no network audit image configured, blocking network access without auditing",
+                    self.options.container_name
+                );
+                return None;
+            }
+        };
+        let service = ServiceContainer {
+            name: NETWORK_AUDIT_SERVICE.into(),
+            image,
+            env: vec![],
+            entrypoint: None,
+            cmd: None,
+            ready_command: None,
+            ready_timeout: 0,
+        };
+
+        let res = async {
+            let container_name = self.start_service(&service).await?;
+            let ip = self.internal_ip(&container_name).await?;
+            Ok::<_, anyhow::Error>((container_name, ip))
+        }
+        .await;
+
+        match res {
+            Ok((container_name, ip)) => {
+                self.audit_container = Some(container_name);
+                Some(ip)
+            }
+            Err(e) => {
+                log::warn!(
+                    "container {}: failed to start network audit, blocking network access without auditing: {}",
+                    self.options.container_name,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Start a sidecar forwarding connections to each host in `allowed`, and
+    /// return `host:ip` entries redirecting the hosts to their sidecars.
+    ///
+    /// Hosts whose sidecar is not configured or fails to start are left out,
+    /// and are blocked like any other destination.
+    async fn start_egress_proxies(&mut self, allowed: &[(&str, u16)]) -> Vec<String> {
+        let image = match &self.options.cfg.network_proxy_image {
+            Some(image) => image.clone(),
+            None => {
+                log::warn!(
+                    "container {}: no network proxy image configured, blocking network access to allowed hosts",
+                    self.options.container_name
+                );
+                return vec![];
+            }
+        };
+
+        let mut ports = IndexMap::<&str, Vec<u16>>::new();
+        for &(host, port) in allowed {
+            ports.entry(host).or_default().push(port);
+        }
+
+        let mut extra_hosts = vec![];
+        for (i, (host, ports)) in ports.into_iter().enumerate() {
+            // Hosts and ports are checked when parsed, so they are safe to
+            // put in a shell script.
+            let script = ports
+                .iter()
+                .map(|port| {
+                    format!(
+                        "socat TCP-LISTEN:{0},fork,reuseaddr TCP:{1}:{0} &",
+                        port, host
+                    )
+                })
+                .chain(std::iter::once("wait".to_owned()))
+                .join(" ");
+            let service = ServiceContainer {
+                name: format!("{}-{}", NETWORK_PROXY_SERVICE, i),
+                image: image.clone(),
+                env: vec![],
+                entrypoint: Some(vec!["sh".into(), "-c".into()]),
+                cmd: Some(vec![script]),
+                ready_command: None,
+                ready_timeout: 0,
+            };
+
+            let res = async {
+                let container_name = self.start_service(&service).await?;
+                // The internal network has no route outside, so the sidecar
+                // reaches the host through the default bridge network.
+                self.instance
+                    .connect_network(
+                        "bridge",
+                        ConnectNetworkOptions {
+                            container: container_name.clone(),
+                            endpoint_config: Default::default(),
+                        },
+                    )
+                    .await?;
+                self.internal_ip(&container_name).await
+            }
+            .await;
+
+            match res {
+                Ok(ip) => extra_hosts.push(format!("{}:{}", host, ip)),
+                Err(e) => log::warn!(
+                    "container {}: failed to start network proxy for {}, blocking network access to it: {}",
+                    self.options.container_name,
+                    host,
+                    e
+                ),
+            }
+        }
+        extra_hosts
+    }
+
+    /// IP address of the container `container_name` in the internal network.
+    async fn internal_ip(&self, container_name: &str) -> Result<String> {
+        let info = self
+            .instance
+            .inspect_container(container_name, None)
+            .await?;
+        // Networks are keyed by name here, but only the ID is kept
+        let network_id = self.options.network_name.as_deref();
+        let ip = info
+            .network_settings
+            .and_then(|x| x.networks)
+            .and_then(|x| {
+                x.into_iter()
+                    .map(|(_, x)| x)
+                    .find(|x| x.network_id.is_some() && x.network_id.as_deref() == network_id)
+            })
+            .and_then(|x| x.ip_address)
+            .filter(|x| !x.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!("sidecar `{}` has no address in network", container_name)
+            })?;
+        Ok(ip)
+    }
+
+    /// Network access attempts recorded by the audit sidecar so far, one per
+    /// line of its output. Returns `None` if the network is not audited.
+    pub async fn network_attempts(&self) -> Option<Vec<String>> {
+        let container_name = self.audit_container.as_ref()?;
+        let logs = self
+            .instance
+            .logs(
+                container_name,
+                Some(bollard::container::LogsOptions {
+                    stdout: true,
+                    tail: "all",
+                    ..Default::default()
+                }),
+            )
+            .try_collect::<Vec<_>>()
+            .await;
+        let logs = match logs {
+            Ok(logs) => logs,
+            Err(e) => {
+                log::warn!(
+                    "container {}: failed to read network audit log: {}",
+                    self.options.container_name,
+                    e
+                );
+                return None;
+            }
+        };
+
+        let output = logs
+            .into_iter()
+            .map(|x| String::from_utf8_lossy(&x.into_bytes()).into_owned())
+            .collect::<String>();
+        Some(
+            output
+                .lines()
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_owned())
+                .collect(),
+        )
+    }
+
+    /// Start a service container, and wait until it's ready. Returns the name
+    /// of the service container.
+    async fn start_service(&mut self, service: &ServiceContainer) -> Result<String> {
+        let container_name = format!("{}-svc-{}", self.options.container_name, service.name);
+        let network_name = self.options.network_name.clone().unwrap();
+        log::info!(
+            "container {}: starting service {}",
+            self.options.container_name,
+            container_name
+        );
+
+        if self.options.build_image {
+            Image::Prebuilt {
+                tag: service.image.clone(),
+            }
+            .build(
+                self.instance.clone(),
+                None,
+                self.options.cancellation_token.clone(),
+                None,
+                None,
+                &self.options.cfg,
+            )
+            .await?;
+        }
+
+        self.instance
+            .create_container(
+                Some(bollard::container::CreateContainerOptions {
+                    name: container_name.clone(),
+                }),
+                bollard::container::Config {
+                    image: Some(service.image.clone()),
+                    env: Some(service.env.clone()),
+                    entrypoint: service.entrypoint.clone(),
+                    cmd: service.cmd.clone(),
+                    host_config: Some(bollard::service::HostConfig {
+                        network_mode: Some(network_name.clone()),
+                        ..Default::default()
+                    }),
+                    networking_config: Some(bollard::container::NetworkingConfig {
+                        endpoints_config: vec![(
+                            network_name,
+                            bollard::models::EndpointSettings {
+                                aliases: Some(vec![service.name.clone()]),
+                                ..Default::default()
+                            },
+                        )]
+                        .into_iter()
+                        .collect(),
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| {
+                JobFailure::internal_err_from(format!(
+                    "Failed to create service container `{}`: {}",
+                    &container_name, e
+                ))
+            })?;
+        // Record the container right after creation, so `kill()` removes it
+        // even if it fails to start.
+        self.service_containers.push(container_name.clone());
+
+        self.instance
+            .start_container::<String>(&container_name, None)
+            .await
+            .map_err(|e| {
+                JobFailure::internal_err_from(format!(
+                    "Failed to start service container `{}`: {}",
+                    &container_name, e
+                ))
+            })?;
+
+        // Probe readiness
+        if let Some(cmd) = &service.ready_command {
+            let deadline =
+                tokio::time::Instant::now() + std::time::Duration::from_secs(service.ready_timeout);
+            loop {
+                let res = self
+                    .exec_in(&container_name, cmd, &IndexMap::new(), None, None)
+                    .await;
+                match res {
+                    Ok(info) if info.ret_code == 0 => break,
+                    Ok(_) => {}
+                    Err(e) => log::trace!("service {}: probe failed: {}", container_name, e),
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(JobFailure::internal_err_from(format!(
+                        "Service `{}` is not ready after {} seconds",
+                        service.name, service.ready_timeout
+                    ))
+                    .into());
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            }
+        }
+
+        log::info!("service {}: ready", container_name);
+        Ok(container_name)
+    }
+
     /// Kill the `DockerCommandRunner` instance.
     ///
     /// This includes:
@@ -471,7 +1512,18 @@ impl DockerCommandRunner {
     /// - Stopping & removing the container.
     /// - Removing all the intermediate images (only if `self.options.remove_image` is set to `true`).
     // ! WARNING: When implementing this function, we should explicitly drop the returned values because we have no way to fail.
-    pub async fn kill(mut self) {
+    pub async fn kill(self) {
+        let remove_images = self.options.remove_image;
+        self.kill_inner(remove_images).await
+    }
+
+    /// Kill the `DockerCommandRunner` instance after it failed to start, like
+    /// [`kill`](Self::kill), but always removing the intermediate images.
+    async fn abort(self) {
+        self.kill_inner(true).await
+    }
+
+    async fn kill_inner(mut self, remove_images: bool) {
         // Defuse the bomb.
         self.bomb.defuse();
 
@@ -502,14 +1554,28 @@ impl DockerCommandRunner {
             )
             .await;
 
+        // Remove service containers, which are still connected to the network
+        for service in &self.service_containers {
+            let _res = self
+                .instance
+                .remove_container(
+                    service,
+                    Some(bollard::container::RemoveContainerOptions {
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await;
+        }
+
         // Remove the dedicated network
         if let Some(network) = &self.options.network_name {
             let _res = self.instance.remove_network(&network).await;
         }
 
-        // Remove the image.
-        if self.options.remove_image {
-            for image in &self.intermediate_images {
+        // Remove the images, the latest first as it may be based on earlier ones.
+        if remove_images {
+            for image in self.intermediate_images.iter().rev() {
                 let _res = self
                     .instance
                     .remove_image(
@@ -525,24 +1591,300 @@ impl DockerCommandRunner {
     }
 }
 
-// 100kB
-// TODO: user-configurable output size
-static MAX_CONSOLE_FILE_SIZE: usize = 100 * 1024;
+/// Default maximum length of the `stdout` and `stderr` of a command, in
+/// bytes (100kB).
+pub const DEFAULT_MAX_OUTPUT_SIZE: usize = 100 * 1024;
+
+/// Marker appended to output truncated at its maximum length.
+const OUTPUT_TRUNCATED_MARKER: &str = "\n--- ERROR: Max output length exceeded";
+
+/// Append `msg` to `out`, truncating it at `limit` bytes (at a character
+/// boundary) and appending [`OUTPUT_TRUNCATED_MARKER`] if it would grow
+/// longer. Returns whether the output is truncated.
+fn push_output(out: &mut String, msg: &str, limit: usize) -> bool {
+    let room = limit.saturating_sub(out.len());
+    if msg.len() <= room {
+        out.push_str(msg);
+        return false;
+    }
+    let mut end = room;
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    out.push_str(&msg[..end]);
+    out.push_str(OUTPUT_TRUNCATED_MARKER);
+    true
+}
+
+/// Append `frame` to `frames`, truncating its text like [`push_output`] so
+/// that the text of all frames, whose length is kept in `len`, is at most
+/// `limit` bytes. Frames are dropped once truncated. Returns whether the
+/// output is truncated.
+fn push_frame(
+    frames: &mut Vec<OutputFrame>,
+    len: &mut usize,
+    mut frame: OutputFrame,
+    limit: usize,
+) -> bool {
+    // Only the truncation marker can take the length over the limit
+    if *len > limit {
+        return true;
+    }
+    let text = std::mem::take(&mut frame.text);
+    let truncated = push_output(&mut frame.text, &text, limit - *len);
+    *len += frame.text.len();
+    frames.push(frame);
+    truncated
+}
+
+/// Maximum time to wait for the output budget before truncating the output of
+/// a command.
+const OUTPUT_BUDGET_WAIT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Time to wait for the output of a command to end after it is killed.
+const KILL_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[async_trait]
 impl CommandRunner for DockerCommandRunner {
     async fn run(
         &self,
         cmd: &str,
-        variables: &HashMap<String, String>,
+        variables: &IndexMap<String, String>,
     ) -> PopenResult<ProcessInfo> {
-        let container_name = &self.options.container_name;
+        self.exec_in(&self.options.container_name, cmd, variables, None, None)
+            .await
+    }
+
+    async fn run_timeout(
+        &self,
+        cmd: &str,
+        variables: &IndexMap<String, String>,
+        timeout: std::time::Duration,
+    ) -> PopenResult<ProcessInfo> {
+        self.exec_in(
+            &self.options.container_name,
+            cmd,
+            variables,
+            Some(timeout),
+            None,
+        )
+        .await
+    }
+
+    /// Files are written under `/tmp` of the container, and removed with it.
+    async fn write_temp_file(&self, content: &[u8]) -> io::Result<String> {
+        let name = temp_file_name();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut archive = tar::Builder::new(vec![]);
+        archive.append_data(&mut header, &name, content)?;
+        let archive = archive.into_inner()?;
+
+        self.instance
+            .upload_to_container(
+                &self.options.container_name,
+                Some(UploadToContainerOptions {
+                    path: "/tmp",
+                    ..Default::default()
+                }),
+                archive.into(),
+            )
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(format!("/tmp/{}", name))
+    }
+}
+
+/// A [`DockerCommandRunner`] running commands as the build user instead of
+/// the user of the test container. See [`DockerConfig::build_user`].
+pub struct BuildUserRunner<'a>(&'a DockerCommandRunner);
 
+#[async_trait]
+impl CommandRunner for BuildUserRunner<'_> {
+    async fn run(
+        &self,
+        cmd: &str,
+        variables: &IndexMap<String, String>,
+    ) -> PopenResult<ProcessInfo> {
+        let user = self.0.options.cfg.build_user();
+        self.0
+            .exec_in(
+                &self.0.options.container_name,
+                cmd,
+                variables,
+                None,
+                user.as_deref(),
+            )
+            .await
+    }
+
+    async fn run_timeout(
+        &self,
+        cmd: &str,
+        variables: &IndexMap<String, String>,
+        timeout: std::time::Duration,
+    ) -> PopenResult<ProcessInfo> {
+        let user = self.0.options.cfg.build_user();
+        self.0
+            .exec_in(
+                &self.0.options.container_name,
+                cmd,
+                variables,
+                Some(timeout),
+                user.as_deref(),
+            )
+            .await
+    }
+
+    async fn write_temp_file(&self, content: &[u8]) -> io::Result<String> {
+        self.0.write_temp_file(content).await
+    }
+}
+
+impl DockerCommandRunner {
+    /// This runner, running commands as the build user.
+    pub fn as_build_user(&self) -> BuildUserRunner<'_> {
+        BuildUserRunner(self)
+    }
+
+    /// Stop all processes in `container_name` except its init process, while
+    /// `run` keeps collecting the output of the command being stopped. If
+    /// `test_dir` is given, only processes of the test run in it are stopped,
+    /// see [`signal_processes`](Self::signal_processes).
+    ///
+    /// Processes are sent `SIGTERM` first to let them flush their output and
+    /// exit cleanly, and `SIGKILL` if `run` does not finish within
+    /// [`DockerConfig::kill_grace_period_ms`].
+    async fn stop_processes<F: Future>(
+        &self,
+        container_name: &str,
+        test_dir: Option<&str>,
+        mut run: Pin<&mut F>,
+    ) {
+        let grace = std::time::Duration::from_millis(self.options.cfg.kill_grace_period_ms);
+        if !grace.is_zero() {
+            self.signal_processes(container_name, test_dir, "TERM")
+                .await;
+            if tokio::time::timeout(grace, run.as_mut()).await.is_ok() {
+                return;
+            }
+        }
+        self.signal_processes(container_name, test_dir, "KILL")
+            .await;
+        if tokio::time::timeout(KILL_DRAIN_TIMEOUT, run).await.is_err() {
+            log::warn!(
+                "container {}: output did not end after killing all processes",
+                container_name
+            );
+        }
+    }
+
+    /// Send `signal` to all processes in `container_name` except its init
+    /// process.
+    ///
+    /// If `test_dir` is given, only processes with [`TEST_DIR_ENV_VAR`] set to
+    /// it are signaled, leaving other tests running in parallel alone.
+    /// Processes clearing their environment are not found this way.
+    async fn signal_processes(&self, container_name: &str, test_dir: Option<&str>, signal: &str) {
+        let cmd = match test_dir {
+            Some(dir) => format!(
+                "for p in /proc/[0-9]*; do \
+                    grep -qsxzF '{}={}' $p/environ && kill -{} ${{p#/proc/}}; \
+                done; true",
+                TEST_DIR_ENV_VAR, dir, signal
+            ),
+            // `kill -1` never signals the calling shell itself
+            None => format!("kill -{} -1", signal),
+        };
+        let res = async {
+            let exec = self
+                .instance
+                .create_exec(
+                    container_name,
+                    bollard::exec::CreateExecOptions {
+                        cmd: Some(vec!["sh", "-c", &cmd]),
+                        attach_stdout: Some(true),
+                        attach_stderr: Some(true),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            let res = self
+                .instance
+                .start_exec(
+                    &exec.id,
+                    Some(bollard::exec::StartExecOptions { detach: false }),
+                )
+                .await?;
+            if let StartExecResults::Attached { mut output, .. } = res {
+                while output.next().await.is_some() {}
+            }
+            Ok::<_, bollard::errors::Error>(())
+        };
+        if let Err(e) = res.await {
+            log::warn!(
+                "container {}: failed to send SIG{} to processes: {}",
+                container_name,
+                signal,
+                e
+            );
+        }
+    }
+
+    /// Exit code of the finished Docker Exec `exec_id`, inspecting it again up
+    /// to [`DockerConfig::inspect_exec_retries`] times if the daemon reports
+    /// none. An exec without an exit code is an error of the daemon, not of
+    /// the command.
+    async fn exec_exit_code(&self, exec_id: &str) -> io::Result<i64> {
+        let interval =
+            std::time::Duration::from_millis(self.options.cfg.inspect_exec_retry_interval_ms);
+        let mut attempt = 0;
+        loop {
+            let inspect_res = self.instance.inspect_exec(exec_id).await.map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Failed to inspect Docker Exec: {:?}", e),
+                )
+            })?;
+            if let Some(code) = inspect_res.exit_code {
+                return Ok(code);
+            }
+            log::warn!(
+                "exec {}: no exit code (attempt {}): {:?}",
+                exec_id,
+                attempt + 1,
+                inspect_res
+            );
+            if attempt >= self.options.cfg.inspect_exec_retries {
+                return Err(io::Error::other(format!(
+                    "Docker Exec has no exit code after {} attempts",
+                    attempt + 1
+                )));
+            }
+            attempt += 1;
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Run a command inside the given container managed by this runner, as
+    /// `user` if given or the user of the container otherwise.
+    ///
+    /// If the command does not finish within `timeout`, all processes in the
+    /// container are stopped with [`stop_processes`](Self::stop_processes),
+    /// and the output captured until then is returned in a
+    /// [`TimedOutOutput`] error.
+    async fn exec_in(
+        &self,
+        container_name: &str,
+        cmd: &str,
+        variables: &IndexMap<String, String>,
+        timeout: Option<std::time::Duration>,
+        user: Option<&str>,
+    ) -> PopenResult<ProcessInfo> {
         // Create a Docker Exec
-        let env = variables
-            .iter()
-            .map(|(k, v)| format!("{}={}", k.trim_start_matches('$'), v))
-            .collect::<Vec<_>>();
+        let env = exec_env(variables);
 
         let message = self
             .instance
@@ -553,6 +1895,9 @@ impl CommandRunner for DockerCommandRunner {
                     attach_stdout: Some(true),
                     attach_stderr: Some(true),
                     env: Some(env.iter().map(|x| x.as_str()).collect()),
+                    user,
+                    // tests run in parallel get their own working directories
+                    working_dir: variables.get(TEST_DIR_ENV_VAR).map(|x| x.as_str()),
                     ..Default::default()
                 },
             )
@@ -581,51 +1926,356 @@ impl CommandRunner for DockerCommandRunner {
 
         let mut stdout = String::new();
         let mut stderr = String::new();
+        let mut combined = self.options.combined_output.then(Vec::new);
+        let mut combined_len = 0;
+        let start = std::time::Instant::now();
+        // Given back once every copy of the output is dropped
+        let mut reservation = self.options.output_budget.as_ref().map(|x| x.reserve());
 
-        while let Some(msg) = start_res.next().await {
-            use bollard::container::LogOutput;
-            let msg = msg.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            match msg {
-                LogOutput::StdOut { message } => {
-                    let msg = String::from_utf8_lossy(&message);
-                    stdout.push_str(&msg);
-                    if stdout.len() >= MAX_CONSOLE_FILE_SIZE {
-                        stdout.push_str("\n--- ERROR: Max output length exceeded");
+        let read_output = async {
+            while let Some(msg) = start_res.next().await {
+                use bollard::container::LogOutput;
+                let msg = msg.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                if let Some(reservation) = &mut reservation {
+                    // Reading is paused while waiting, holding the command
+                    // back once its pipes are full.
+                    let size = match &msg {
+                        LogOutput::StdOut { message } | LogOutput::StdErr { message } => {
+                            message.len() * if combined.is_some() { 2 } else { 1 }
+                        }
+                        _ => 0,
+                    };
+                    if !reservation.grow(size, OUTPUT_BUDGET_WAIT).await {
+                        let out = match msg {
+                            LogOutput::StdErr { .. } => &mut stderr,
+                            _ => &mut stdout,
+                        };
+                        out.push_str("\n--- ERROR: Judger output memory exhausted");
                         break;
                     }
                 }
-                LogOutput::StdErr { message } => {
-                    let msg = String::from_utf8_lossy(&message);
-                    stderr.push_str(&msg);
-                    if stderr.len() >= MAX_CONSOLE_FILE_SIZE {
-                        stderr.push_str("\n--- ERROR: Max output length exceeded");
-                        break;
+                let frame = match &msg {
+                    LogOutput::StdOut { message } => Some((OutputStream::Stdout, message)),
+                    LogOutput::StdErr { message } => Some((OutputStream::Stderr, message)),
+                    _ => None,
+                };
+                if let (Some(frames), Some((stream, message))) = (&mut combined, frame) {
+                    let frame = OutputFrame {
+                        time_ms: start.elapsed().as_millis() as u64,
+                        stream,
+                        text: String::from_utf8_lossy(message).into_owned(),
+                    };
+                    let limit = self.options.max_output_size;
+                    push_frame(frames, &mut combined_len, frame, limit);
+                }
+                match msg {
+                    LogOutput::StdOut { message } => {
+                        let msg = String::from_utf8_lossy(&message);
+                        if push_output(&mut stdout, &msg, self.options.max_output_size) {
+                            break;
+                        }
                     }
+                    LogOutput::StdErr { message } => {
+                        let msg = String::from_utf8_lossy(&message);
+                        if push_output(&mut stderr, &msg, self.options.max_output_size) {
+                            break;
+                        }
+                    }
+                    _ => (),
                 }
-                _ => (),
             }
-        }
+            Ok::<_, io::Error>(())
+        };
 
+        // Sample the process count and resource usage of the container while
+        // the command runs.
+        let mut peak_pids = None;
+        let mut pids_limit = None;
+        let mut peak_memory = None;
+        let mut cpu_usage = None;
+        let watch_pids = self
+            .instance
+            .stats(
+                container_name,
+                Some(bollard::container::StatsOptions {
+                    stream: true,
+                    one_shot: false,
+                }),
+            )
+            .try_for_each(|stats| {
+                if let Some(current) = stats.pids_stats.current {
+                    peak_pids = Some(peak_pids.map_or(current, |x: u64| x.max(current)));
+                }
+                // Docker reports 0 or `u64::MAX` when there's no limit
+                pids_limit = stats.pids_stats.limit.filter(|x| *x != 0 && *x != u64::MAX);
+                if let Some(current) = stats.memory_stats.usage {
+                    peak_memory = Some(peak_memory.map_or(current, |x: u64| x.max(current)));
+                }
+                // Total CPU time of the container in nanoseconds, at the first
+                // and the latest sample. A single sample measures nothing.
+                let total = stats.cpu_stats.cpu_usage.total_usage;
+                cpu_usage =
+                    Some(cpu_usage.map_or((total, None), |(first, _)| (first, Some(total))));
+                future::ok(())
+            });
+
+        let timed_out = {
+            tokio::pin!(read_output);
+            let run = async {
+                tokio::select! {
+                    res = &mut read_output => res,
+                    // Stats stream ended early; keep reading the output
+                    _ = watch_pids => (&mut read_output).await,
+                }
+            };
+            tokio::pin!(run);
+            match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, &mut run).await {
+                    Ok(res) => res.map(|_| false)?,
+                    Err(_) => {
+                        let test_dir = variables.get(TEST_DIR_ENV_VAR).map(|x| x.as_str());
+                        self.stop_processes(container_name, test_dir, run.as_mut())
+                            .await;
+                        true
+                    }
+                },
+                None => run.await.map(|_| false)?,
+            }
+        };
+
+        let wall_time_ms = start.elapsed().as_millis() as u64;
         drop(start_res);
 
         // Use inspect_exec to get exit code.
-        let inspect_res = self.instance.inspect_exec(&message.id).await.map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to inspect Docker Exec: {:?}", e),
-            )
-        })?;
-        let ret_code = inspect_res
-            .exit_code
-            .map(|x| convert_code(x as i32))
-            .unwrap_or(-1);
+        let ret_code = convert_code(self.exec_exit_code(&message.id).await? as i32);
 
-        Ok(ProcessInfo {
+        let info = ProcessInfo {
             command: cmd.into(),
             is_user_command: false,
             stdout,
             stderr,
             ret_code,
-        })
+            peak_pids,
+            pids_limit,
+            wall_time_ms: Some(wall_time_ms),
+            cpu_time_ms: cpu_usage
+                .and_then(|(first, last)| Some(last?.saturating_sub(first) / 1_000_000)),
+            peak_memory,
+            combined,
+            reservation: reservation.map(Into::into).unwrap_or_default(),
+        };
+        match timeout {
+            Some(timeout) if timed_out => Err(timed_out_err(timeout, Some(info))),
+            _ => Ok(info),
+        }
+    }
+}
+
+/// Environment of a Docker Exec setting `variables`, with the leading `$`
+/// of their names removed. Variables are set in the order they were inserted,
+/// so repeated runs get identical environments.
+fn exec_env(variables: &IndexMap<String, String>) -> Vec<String> {
+    variables
+        .iter()
+        .map(|(k, v)| format!("{}={}", k.trim_start_matches('$'), v))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exec_env_order() {
+        let mut variables = IndexMap::new();
+        for (k, v) in [
+            ("$stdin", "1.in"),
+            ("PATH", "/a"),
+            ("$bin", "1.out"),
+            ("A", ""),
+        ] {
+            variables.insert(k.to_owned(), v.to_owned());
+        }
+        variables.insert("PATH".into(), "/b".into());
+        assert_eq!(
+            exec_env(&variables),
+            ["stdin=1.in", "PATH=/b", "bin=1.out", "A="]
+        );
+    }
+
+    #[test]
+    fn test_container_names() {
+        let name = container_name(Some("job/1 é"));
+        assert!(name.starts_with("rurikawa_job-1--_"));
+        assert!(name.len() <= MAX_CONTAINER_NAME_LEN);
+
+        let name = container_name(Some(&"x".repeat(200)));
+        assert!(name.len() <= MAX_CONTAINER_NAME_LEN);
+        assert_ne!(container_name(Some("job")), container_name(Some("job")));
+
+        let renamed = rename_container(&name);
+        assert_ne!(renamed, name);
+        assert_eq!(renamed.len(), name.len());
+        assert_eq!(
+            renamed[..name.len() - CONTAINER_NAME_SUFFIX_LEN],
+            name[..name.len() - CONTAINER_NAME_SUFFIX_LEN]
+        );
+
+        assert_eq!(
+            sanitize_hostname("-My Test_Suite-").as_deref(),
+            Some("my-test-suite")
+        );
+        assert_eq!(sanitize_hostname(&"a".repeat(100)).unwrap().len(), 63);
+        assert_eq!(sanitize_hostname("__"), None);
+    }
+
+    #[test]
+    fn test_container_root_is_host_root() {
+        let options = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let mut cfg = DockerConfig::default();
+
+        assert!(container_root_is_host_root(
+            &options(&["name=seccomp,profile=default"]),
+            &cfg
+        ));
+        assert!(!container_root_is_host_root(
+            &options(&["name=rootless"]),
+            &cfg
+        ));
+        assert!(!container_root_is_host_root(
+            &options(&["name=userns"]),
+            &cfg
+        ));
+
+        cfg.userns_mode = Some("host".into());
+        assert!(container_root_is_host_root(
+            &options(&["name=userns"]),
+            &cfg
+        ));
+
+        cfg.docker_user = Some("1000:1000".into());
+        assert!(!container_root_is_host_root(&[], &cfg));
+        cfg.run_user = Some("0:0".into());
+        assert!(container_root_is_host_root(&[], &cfg));
+    }
+
+    #[test]
+    fn test_images_to_prune() {
+        let image = |id: &str, created, size| ImageSummary {
+            id: id.into(),
+            created,
+            size,
+            ..Default::default()
+        };
+        let now = 100_000;
+        let images = [
+            image("new", now, 300),
+            image("old", now - 3 * IMAGE_PRUNE_MIN_AGE, 200),
+            image("older", now - 4 * IMAGE_PRUNE_MIN_AGE, 100),
+            image("mid", now - 2 * IMAGE_PRUNE_MIN_AGE, 400),
+        ];
+        let pruned = |limit| {
+            images_to_prune(&images, limit, now)
+                .iter()
+                .map(|x| x.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pruned(1000), Vec::<&str>::new());
+        assert_eq!(pruned(950), ["older"]);
+        assert_eq!(pruned(700), ["older", "old"]);
+        // Images too new are never pruned
+        assert_eq!(pruned(0), ["older", "old", "mid"]);
+    }
+
+    #[test]
+    fn test_push_output_truncates_at_limit() {
+        let mut out = String::new();
+        assert!(!push_output(&mut out, "12345", 10));
+        assert!(!push_output(&mut out, "67890", 10));
+        assert_eq!(out, "1234567890");
+        assert!(push_output(&mut out, "x", 10));
+        assert_eq!(out, format!("1234567890{}", OUTPUT_TRUNCATED_MARKER));
+
+        let mut out = String::new();
+        assert!(push_output(&mut out, "123456789012", 10));
+        assert_eq!(out, format!("1234567890{}", OUTPUT_TRUNCATED_MARKER));
+
+        // Multi-byte characters are not split
+        let mut out = String::new();
+        assert!(push_output(&mut out, "123456789é", 10));
+        assert_eq!(out, format!("123456789{}", OUTPUT_TRUNCATED_MARKER));
+    }
+
+    #[test]
+    fn test_push_frame_truncates_at_limit() {
+        let frame = |stream, text: &str| OutputFrame {
+            time_ms: 0,
+            stream,
+            text: text.into(),
+        };
+        let mut frames = vec![];
+        let mut len = 0;
+        let mut push = |x| push_frame(&mut frames, &mut len, x, 10);
+        assert!(!push(frame(OutputStream::Stdout, "12345")));
+        assert!(push(frame(OutputStream::Stderr, "678901")));
+        // Frames after the truncated one are dropped
+        assert!(push(frame(OutputStream::Stdout, "x")));
+        assert_eq!(
+            frames,
+            [
+                frame(OutputStream::Stdout, "12345"),
+                frame(
+                    OutputStream::Stderr,
+                    &format!("67890{}", OUTPUT_TRUNCATED_MARKER)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_networks_to_prune() {
+        let now = 1_600_000_000;
+        let created = |age: i64| {
+            chrono::DateTime::<chrono::Utc>::from_utc(
+                chrono::NaiveDateTime::from_timestamp(now - age, 0),
+                chrono::Utc,
+            )
+            .to_rfc3339()
+        };
+        let network = |name: &str, internal, labelled, age| Network {
+            name: Some(name.into()),
+            internal: Some(internal),
+            labels: Some(
+                labelled
+                    .then(|| (IMAGE_LABEL.to_owned(), "true".to_owned()))
+                    .into_iter()
+                    .collect(),
+            ),
+            created: Some(created(age)),
+            ..Default::default()
+        };
+        let networks = [
+            network("rurikawa_leaked", true, true, 2 * NETWORK_PRUNE_MIN_AGE),
+            network(
+                "rurikawa_unlabelled",
+                true,
+                false,
+                2 * NETWORK_PRUNE_MIN_AGE,
+            ),
+            network("rurikawa_starting", true, true, 10),
+            network("rurikawa_user", false, false, 2 * NETWORK_PRUNE_MIN_AGE),
+            network("bridge", false, false, 2 * NETWORK_PRUNE_MIN_AGE),
+        ];
+        let pruned = networks_to_prune(&networks, now)
+            .iter()
+            .map(|x| x.name.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(pruned, ["rurikawa_leaked", "rurikawa_unlabelled"]);
+
+        let exhausted = bollard::errors::Error::DockerResponseServerError {
+            status_code: 400,
+            message: "all predefined address pools have been fully subnetted".into(),
+        };
+        assert!(is_address_pool_exhausted(&exhausted));
     }
 }
@@ -8,11 +8,9 @@ use super::{
     ProcessInfo,
 };
 use anyhow::Context as AnyhowCtx;
+use indexmap::IndexMap;
 use rquickjs::{Context, FromJs, Function, Promise, Runtime};
-use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
-};
+use std::path::{Path, PathBuf};
 use tokio::{runtime::Handle, task::JoinHandle};
 
 pub const SPJ_INIT_FN: &str = "specialJudgeInit";
@@ -127,7 +125,7 @@ impl SpjEnvironment {
     pub async fn spj_case_init(
         &self,
         case: &TestCase,
-        mappings: &HashMap<String, String>,
+        mappings: &IndexMap<String, String>,
     ) -> anyhow::Result<()> {
         run_promise_like!(self.ctx, SPJ_CASE_INIT_FN, (case, mappings), |x| x).map_err(|e| e.into())
     }
@@ -369,6 +367,7 @@ mod binding {
 #[cfg(test)]
 mod test {
     use crate::tester::model::{Bind, JudgerPublicConfig};
+    use indexmap::IndexMap;
     use std::{collections::HashMap, path::PathBuf};
 
     #[tokio::test]
@@ -378,29 +377,55 @@ mod test {
             return new Promise((res, rej)=>{
                 console.log('hi')
                 console.log('hi there')
+                if (config.timeLimit !== 2000 || config.memoryLimit !== 268435456) {
+                    rej('limits are not visible')
+                }
                 res()
             })
         }
         ";
         let mut spj = super::SpjEnvironment::new().unwrap();
         let config = JudgerPublicConfig {
-            time_limit: None,
-            memory_limit: None,
+            time_limit: Some(2000),
+            memory_limit: Some(256 << 20),
+            test_time_limits: HashMap::new(),
             name: "golem".into(),
             test_groups: HashMap::new(),
-            vars: HashMap::new(),
+            vars: IndexMap::new(),
             run: vec![],
+            env: IndexMap::new(),
+            generator: None,
+            scorer: None,
             test_ignore: None,
             mapped_dir: Bind {
                 from: PathBuf::from(r"../golem/src"),
                 to: PathBuf::from(r"/golem/src"),
+                ..Default::default()
             },
             binds: Some(vec![]),
+            shared_binds: vec![],
             special_judge_script: None,
             network: super::super::model::NetworkOptions {
                 enable_running: true,
                 enable_build: true,
+                audit_running: false,
+                allowed_hosts: vec![],
             },
+            presentation_error: false,
+            output_region: None,
+            batch_commands: false,
+            before_each: vec![],
+            after_each: vec![],
+            combined_output: false,
+            stderr_policy: Default::default(),
+            parallel_tests: None,
+            max_line_length: None,
+            max_output_size: None,
+            exit_code_map: Default::default(),
+            user_run: Default::default(),
+            required_files: vec![],
+            hostname: None,
+            services: vec![],
         };
 
         spj.load_script(script).unwrap();
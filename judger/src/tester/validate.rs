@@ -0,0 +1,394 @@
+//! Integrity checks for downloaded test suites.
+//!
+//! A test suite package is only useful if every file its `testconf.json`
+//! references is actually shipped within it. These checks are run once a
+//! suite is extracted, so that a broken package is reported as a whole
+//! instead of failing test by test in the middle of a job.
+
+use super::model::{canonical_join, check_build_args, Image, JudgerPublicConfig, StderrPolicy};
+use crate::{
+    config::JudgeToml,
+    util::path_security::{assert_child_path, assert_no_symlink_in_path},
+};
+use itertools::Itertools;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// Variables whose expanded paths must exist inside the test root of a suite.
+///
+/// Other variables (like `$bin`) may point to files generated during the job,
+/// so they are not checked.
+pub const SUITE_FILE_VARS: &[&str] = &["$stdin", "$stdout", "$stderr"];
+
+/// Problems found in a test suite by [`lint_suite`].
+#[derive(Debug, Clone, Default)]
+pub struct SuiteProblems {
+    /// Problems that make jobs of the suite fail.
+    pub errors: Vec<String>,
+    /// Options without effect, or likely not doing what was intended.
+    pub warnings: Vec<String>,
+}
+
+impl SuiteProblems {
+    /// Whether jobs of the suite would fail because of these problems.
+    pub fn is_fatal(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.warnings.is_empty()
+    }
+
+    pub fn extend(&mut self, other: SuiteProblems) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+    }
+}
+
+/// Returns all files referenced by `cfg` that cannot be found inside
+/// `suite_root`, including the mapped directory if it is not a directory.
+/// An empty list means the suite is complete.
+pub async fn find_missing_suite_files(
+    cfg: &JudgerPublicConfig,
+    suite_root: &Path,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut required = vec![];
+    let mut missing = vec![];
+
+    // Mounting anything else would only fail later with confusing container
+    // errors
+    let test_root = canonical_join(suite_root, &cfg.mapped_dir.from);
+    match tokio::fs::metadata(&test_root).await {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => missing.push(test_root.clone()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => missing.push(test_root.clone()),
+        Err(e) => return Err(e),
+    }
+
+    if let Some(script) = &cfg.special_judge_script {
+        required.push(suite_root.join(script));
+    }
+    if let Some(ignore) = &cfg.test_ignore {
+        required.push(suite_root.join(ignore));
+    }
+
+    for case in cfg.test_groups.values().flatten() {
+        let stems = match case.expand(&test_root).await {
+            Ok(cases) => cases.into_iter().map(|(_, stem)| stem).collect_vec(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                missing.push(test_root.join(case.inputs.as_deref().unwrap_or(&case.name)));
+                continue;
+            }
+            // Invalid inputs are reported by `lint_suite`
+            Err(_) => continue,
+        };
+        for var in SUITE_FILE_VARS {
+            let ext = match cfg.vars.get(*var) {
+                Some(ext) => ext,
+                None => continue,
+            };
+            // Tests without an output file (or those expected to fail)
+            // never read their `$stdout`, nor do suites generating it.
+            if *var == "$stdout" && (!case.has_out || case.should_fail || cfg.generator.is_some()) {
+                continue;
+            }
+            // Expected `stderr` is only read if it is compared.
+            if *var == "$stderr"
+                && (cfg.stderr_policy != StderrPolicy::Compare || !case.has_out || case.should_fail)
+            {
+                continue;
+            }
+            for stem in &stems {
+                required.push(test_root.join(format!("{}.{}", stem, ext)));
+            }
+        }
+    }
+
+    for path in required {
+        match tokio::fs::metadata(&path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => missing.push(path),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(missing)
+}
+
+/// Check `cfg` of the suite at `suite_root` for every problem that can be
+/// found without running it, returning a description of each problem. This
+/// includes missing files, unsafe paths and invalid options.
+pub async fn lint_suite(
+    cfg: &JudgerPublicConfig,
+    suite_root: &Path,
+) -> std::io::Result<SuiteProblems> {
+    let mut problems = vec![];
+    let mut warnings = vec![];
+
+    let mut paths = vec![("mapped directory", cfg.mapped_dir.from.as_path())];
+    if let Some(script) = &cfg.special_judge_script {
+        paths.push(("special judge script", Path::new(script)));
+    }
+    if let Some(ignore) = &cfg.test_ignore {
+        paths.push(("ignore file", ignore.as_path()));
+    }
+    for bind in cfg.binds.iter().flatten() {
+        // Also checks that bound host paths stay inside the suite
+        if let Err(e) = bind.check() {
+            problems.push(e);
+        }
+    }
+    for (what, path) in paths {
+        if let Err(e) = assert_child_path(path) {
+            problems.push(format!("Unsafe {}: {}", what, e));
+        }
+    }
+    if !cfg.mapped_dir.to.is_absolute() {
+        problems.push(format!(
+            "Mapped directory target {} is not an absolute path",
+            cfg.mapped_dir.to.display()
+        ));
+    }
+    let test_root = canonical_join(suite_root, &cfg.mapped_dir.from);
+    if let Err(e) = assert_no_symlink_in_path(&test_root).await {
+        problems.push(format!("Unsafe mapped directory: {}", e));
+    }
+
+    let mut names = HashSet::new();
+    for case in cfg.test_groups.values().flatten() {
+        if !names.insert(case.name.as_str()) {
+            problems.push(format!(
+                "Test case {:?} is defined more than once",
+                case.name
+            ));
+        }
+        if let Err(e) = case.check_inputs() {
+            problems.push(e);
+        }
+        if let Some(pattern) = &case.expected_stderr {
+            if let Err(e) = regex::Regex::new(pattern) {
+                problems.push(format!(
+                    "Invalid expected stderr pattern of test case {:?}: {}",
+                    case.name, e
+                ));
+            }
+        }
+    }
+    if let Err(e) = cfg.check_env() {
+        problems.push(e);
+    }
+    if let Err(e) = cfg.check_run() {
+        problems.push(e);
+    }
+    if let Err(e) = cfg.check_exit_code_map() {
+        problems.push(e);
+    }
+    if let Err(e) = cfg.check_test_time_limits() {
+        problems.push(e);
+    }
+    if let Err(e) = cfg.check_hostnames() {
+        problems.push(e);
+    }
+    for pattern in cfg.required_files.iter().flat_map(|x| x.patterns()) {
+        if let Err(e) = ignore::gitignore::GitignoreBuilder::new("").add_line(None, pattern) {
+            problems.push(format!(
+                "Invalid required file pattern {:?}: {}",
+                pattern, e
+            ));
+        }
+    }
+    if let Some(region) = &cfg.output_region {
+        if let Err(e) = region.check() {
+            problems.push(e);
+        }
+    }
+    if let Err(e) = cfg.network.parse_allowed_hosts() {
+        problems.push(e);
+    }
+    if !cfg.network.allowed_hosts.is_empty() && !cfg.network.enable_running {
+        warnings.push(
+            "`network.allowedHosts` has no effect unless `network.enableRunning` is set".to_owned(),
+        );
+    }
+    if cfg.parallel_tests == Some(0) {
+        problems.push("`parallel_tests` must be at least 1".to_owned());
+    }
+    if cfg.parallel_tests.map_or(false, |n| n > 1) && cfg.special_judge_script.is_some() {
+        warnings.push(
+            "`parallel_tests` is ignored with a special judger, tests will run one at a time"
+                .to_owned(),
+        );
+    }
+
+    for path in find_missing_suite_files(cfg, suite_root).await? {
+        problems.push(format!("Missing file {}", path.display()));
+    }
+    Ok(SuiteProblems {
+        errors: problems,
+        warnings,
+    })
+}
+
+/// Check the jobs in `judge`, found in `job_root`, as far as they matter to
+/// the suite named `suite_name`: the job of the suite must exist, and every
+/// job must have valid commands and a Dockerfile inside `job_root`.
+pub async fn lint_judge_toml(
+    judge: &JudgeToml,
+    suite_name: &str,
+    job_root: &Path,
+) -> std::io::Result<SuiteProblems> {
+    let mut problems = SuiteProblems::default();
+    if !judge.jobs.contains_key(suite_name) {
+        problems.errors.push(format!(
+            "No job named {:?} in `judge.toml`, found {}",
+            suite_name,
+            judge
+                .jobs
+                .keys()
+                .sorted()
+                .map(|x| format!("{:?}", x))
+                .join(", ")
+        ));
+    }
+
+    for (name, job) in judge.jobs.iter().sorted_by_key(|x| x.0) {
+        if let Err(e) = job.commands() {
+            problems.errors.push(format!("Job {:?}: {}", name, e));
+        }
+        let (path, file, build_args) = match &job.image {
+            Image::Dockerfile {
+                path,
+                file,
+                build_args,
+                ..
+            } => (path, file, build_args),
+            Image::Prebuilt { .. } => continue,
+        };
+        match check_build_args(build_args) {
+            Ok(suspicious) => {
+                for arg in suspicious {
+                    problems.warnings.push(format!(
+                        "Job {:?}: build arg `{}` looks like a secret",
+                        name, arg
+                    ));
+                }
+            }
+            Err(e) => problems.errors.push(format!("Job {:?}: {}", name, e)),
+        }
+
+        let dockerfile = path.join(file.as_deref().unwrap_or_else(|| "Dockerfile".as_ref()));
+        let mut safe = true;
+        for (what, path) in [("context directory", path), ("Dockerfile", &dockerfile)] {
+            if let Err(e) = assert_child_path(path) {
+                problems
+                    .errors
+                    .push(format!("Job {:?}: unsafe {}: {}", name, what, e));
+                safe = false;
+            }
+        }
+        if !safe {
+            continue;
+        }
+        if let Err(e) = assert_no_symlink_in_path(&job_root.join(&dockerfile)).await {
+            problems
+                .errors
+                .push(format!("Job {:?}: unsafe Dockerfile: {}", name, e));
+            continue;
+        }
+        match tokio::fs::metadata(job_root.join(&dockerfile)).await {
+            Ok(meta) if meta.is_file() => {}
+            Ok(_) => problems.errors.push(format!(
+                "Job {:?}: Dockerfile {} is not a file",
+                name,
+                dockerfile.display()
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => problems.errors.push(format!(
+                "Job {:?}: missing Dockerfile {}",
+                name,
+                dockerfile.display()
+            )),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tester::model::{Bind, TestCaseDefinition};
+
+    #[tokio::test]
+    async fn test_find_missing_suite_files() {
+        let root =
+            std::env::temp_dir().join(format!("rurikawa-validate-{}", rand::random::<u32>()));
+        tokio::fs::create_dir_all(root.join("tests")).await.unwrap();
+        tokio::fs::write(root.join("tests/a.in"), "1")
+            .await
+            .unwrap();
+        tokio::fs::write(root.join("tests/a.out"), "1")
+            .await
+            .unwrap();
+        tokio::fs::write(root.join("tests/b.in"), "2")
+            .await
+            .unwrap();
+
+        let cfg = JudgerPublicConfig {
+            test_groups: [(
+                "default".to_owned(),
+                vec![
+                    "a".parse().unwrap(),
+                    "b".parse::<TestCaseDefinition>().unwrap(),
+                ],
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+            vars: [("$stdin", "in"), ("$stdout", "out")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            mapped_dir: Bind {
+                from: PathBuf::from("tests"),
+                to: PathBuf::from("/tests"),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let missing = find_missing_suite_files(&cfg, &root).await.unwrap();
+
+        let mut generated_cfg = cfg.clone();
+        generated_cfg.generator = Some("./reference < $stdin".into());
+        let generated_missing = find_missing_suite_files(&generated_cfg, &root)
+            .await
+            .unwrap();
+
+        let mut file_cfg = cfg.clone();
+        file_cfg.mapped_dir.from = PathBuf::from("tests/a.in");
+        let file_mapped_dir = find_missing_suite_files(&file_cfg, &root).await.unwrap();
+        let mut missing_cfg = cfg.clone();
+        missing_cfg.mapped_dir.from = PathBuf::from("data");
+        let missing_mapped_dir = find_missing_suite_files(&missing_cfg, &root).await.unwrap();
+
+        let mut unsafe_cfg = cfg.clone();
+        unsafe_cfg.mapped_dir.from = PathBuf::from("../tests");
+        unsafe_cfg.special_judge_script = Some("/etc/passwd".into());
+        let problems = lint_suite(&unsafe_cfg, &root.join("tests")).await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&root).await;
+
+        assert_eq!(missing, vec![root.join("tests/b.out")]);
+        assert!(generated_missing.is_empty(), "{:?}", generated_missing);
+        assert!(file_mapped_dir.contains(&root.join("tests/a.in")));
+        assert!(missing_mapped_dir.contains(&root.join("data")));
+        assert!(problems
+            .errors
+            .iter()
+            .any(|x| x.starts_with("Unsafe mapped directory")));
+        assert!(problems
+            .errors
+            .iter()
+            .any(|x| x.starts_with("Unsafe special judge script")));
+    }
+}
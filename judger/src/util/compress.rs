@@ -0,0 +1,50 @@
+//! Compression of uploaded test results, see
+//! [`ClientConfig::upload_compression`](crate::client::config::ClientConfig::upload_compression).
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+/// A compression of uploaded data, named after its `Content-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadCompression {
+    Gzip,
+    /// Requires the `zstd` feature.
+    Zstd,
+}
+
+impl UploadCompression {
+    /// Value of the `Content-Encoding` header of data compressed this way.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            UploadCompression::Gzip => "gzip",
+            UploadCompression::Zstd => "zstd",
+        }
+    }
+
+    /// Whether this judger is built with support of this compression.
+    pub fn is_available(self) -> bool {
+        match self {
+            UploadCompression::Gzip => true,
+            UploadCompression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            UploadCompression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            UploadCompression::Zstd => zstd::stream::encode_all(data, 0),
+            #[cfg(not(feature = "zstd"))]
+            UploadCompression::Zstd => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "zstd compression requires the `zstd` feature",
+            )),
+        }
+    }
+}
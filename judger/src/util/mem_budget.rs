@@ -0,0 +1,168 @@
+//! Accounting of memory used by buffers of the judger itself, shared by all
+//! running jobs.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// A global limit on the number of bytes buffered by the judger.
+///
+/// Memory is taken from the budget through [`Reservation`]s, which give it
+/// back when dropped.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    released: Notify,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Maximum number of bytes in this budget.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Number of bytes currently reserved.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Whether at least 90% of the budget is reserved.
+    pub fn is_near_limit(&self) -> bool {
+        self.used() >= self.limit - self.limit / 10
+    }
+
+    /// Create an empty reservation of this budget.
+    pub fn reserve(self: &Arc<Self>) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            size: 0,
+        }
+    }
+
+    fn try_take(&self, size: usize) -> bool {
+        let limit = self.limit;
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(size).filter(|x| *x <= limit)
+            })
+            .is_ok()
+    }
+
+    fn give_back(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::SeqCst);
+        self.released.notify_waiters();
+    }
+}
+
+/// Bytes taken from a [`MemoryBudget`], given back when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    size: usize,
+}
+
+impl Reservation {
+    /// Number of bytes in this reservation.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Take `size` more bytes from the budget, waiting at most `wait` for
+    /// other reservations to be given back if the budget is exhausted.
+    /// Returns `false` if the bytes could not be taken in time.
+    pub async fn grow(&mut self, size: usize, wait: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // Created before checking, so that bytes given back in between
+            // are not missed.
+            let released = self.budget.released.notified();
+            if self.budget.try_take(size) {
+                self.size += size;
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return false;
+            }
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.size > 0 {
+            self.budget.give_back(self.size);
+        }
+    }
+}
+
+/// A [`Reservation`] shared by all copies of the data it accounts for, given
+/// back when the last copy is dropped. Compares equal to any other, so that
+/// it does not affect the comparison of the data.
+#[derive(Debug, Clone, Default)]
+pub struct SharedReservation(Option<Arc<Reservation>>);
+
+impl From<Reservation> for SharedReservation {
+    fn from(reservation: Reservation) -> Self {
+        SharedReservation(Some(Arc::new(reservation)))
+    }
+}
+
+impl PartialEq for SharedReservation {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for SharedReservation {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let mut a = budget.reserve();
+        assert!(a.grow(60, Duration::ZERO).await);
+
+        // Shared reservations are given back with their last copy
+        let mut shared = budget.reserve();
+        assert!(shared.grow(10, Duration::ZERO).await);
+        let shared = SharedReservation::from(shared);
+        let copy = shared.clone();
+        drop(shared);
+        assert_eq!(budget.used(), 70);
+        drop(copy);
+        assert_eq!(budget.used(), 60);
+        assert!(!budget.is_near_limit());
+
+        let mut b = budget.reserve();
+        assert!(!b.grow(60, Duration::from_millis(10)).await);
+        assert!(b.grow(35, Duration::ZERO).await);
+        assert!(budget.is_near_limit());
+
+        let waiting = tokio::spawn(async move {
+            let mut c = budget.reserve();
+            let res = c.grow(50, Duration::from_secs(5)).await;
+            (res, budget.used())
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(a);
+        assert_eq!(waiting.await.unwrap(), (true, 85));
+        assert_eq!(b.size(), 35);
+    }
+}
@@ -1,4 +1,7 @@
+pub mod compress;
+pub mod mem_budget;
 pub mod path_security;
 mod string_or_struct;
 pub use string_or_struct::*;
 pub mod tar;
+pub mod units;
@@ -0,0 +1,334 @@
+//! Resource limits in human-friendly units, e.g. `"2s"` or `"256MiB"`.
+//!
+//! Time limits are kept in milliseconds and memory limits in bytes. Both are
+//! serialized as strings with units, so that reading them back yields the
+//! same values.
+
+use serde::{de, ser::SerializeMap, Deserialize, Deserializer, Serializer};
+use std::collections::HashMap;
+
+/// Smallest accepted time limit, in milliseconds.
+pub const MIN_TIME_LIMIT_MS: u64 = 1;
+/// Largest accepted time limit (a day), in milliseconds.
+pub const MAX_TIME_LIMIT_MS: u64 = 24 * 60 * 60 * 1000;
+/// Smallest accepted memory limit in bytes, the smallest one Docker accepts.
+pub const MIN_MEMORY_LIMIT: u64 = 6 * 1024 * 1024;
+/// Largest accepted memory limit (1 TiB), in bytes.
+pub const MAX_MEMORY_LIMIT: u64 = 1 << 40;
+
+const TIME_UNITS: &[(&str, u64)] = &[
+    ("ms", 1),
+    ("s", 1000),
+    ("min", 60 * 1000),
+    ("m", 60 * 1000),
+    ("h", 60 * 60 * 1000),
+];
+
+const MEMORY_UNITS: &[(&str, u64)] = &[
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+    ("KB", 1000),
+    ("MB", 1000 * 1000),
+    ("GB", 1000 * 1000 * 1000),
+    ("K", 1 << 10),
+    ("M", 1 << 20),
+    ("G", 1 << 30),
+    ("B", 1),
+];
+
+/// Parse `s` as a number followed by one of `units`, which are tried in
+/// order. Fractions are rounded to the nearest whole base unit.
+fn parse_with_units(s: &str, units: &[(&str, u64)], what: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, scale) = units
+        .iter()
+        .find_map(|(unit, scale)| s.strip_suffix(unit).map(|x| (x.trim_end(), *scale)))
+        .ok_or_else(|| {
+            format!(
+                "Invalid {} {:?}: expected a number followed by one of {}",
+                what,
+                s,
+                units.iter().map(|x| x.0).collect::<Vec<_>>().join(", ")
+            )
+        })?;
+    let number = number
+        .parse::<f64>()
+        .ok()
+        .filter(|x| x.is_finite() && *x >= 0.0)
+        .ok_or_else(|| format!("Invalid {} {:?}: not a non-negative number", what, s))?;
+    Ok((number * scale as f64).round() as u64)
+}
+
+/// Parse a duration like `"2s"`, `"1.5s"` or `"500ms"` into milliseconds.
+/// Accepted units are `ms`, `s`, `min` (or `m`) and `h`.
+pub fn parse_duration_ms(s: &str) -> Result<u64, String> {
+    parse_with_units(s, TIME_UNITS, "duration")
+}
+
+/// Parse a size like `"256MiB"` or `"1GB"` into bytes. Accepted units are
+/// `B`, `KB`, `MB`, `GB` (powers of 1000) and `KiB`, `MiB`, `GiB` (powers of
+/// 1024). `K`, `M` and `G` are powers of 1024, like in Docker.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    parse_with_units(s, MEMORY_UNITS, "size")
+}
+
+/// Format `ms` with the largest of `ms`, `s`, `min` and `h` that divides it.
+pub fn format_duration_ms(ms: u64) -> String {
+    format_with_units(
+        ms,
+        &[("h", 60 * 60 * 1000), ("min", 60 * 1000), ("s", 1000)],
+        "ms",
+    )
+}
+
+/// Format `bytes` with the largest of `B`, `KiB`, `MiB` and `GiB` that
+/// divides it.
+pub fn format_bytes(bytes: u64) -> String {
+    format_with_units(
+        bytes,
+        &[("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)],
+        "B",
+    )
+}
+
+fn format_with_units(value: u64, units: &[(&str, u64)], base: &str) -> String {
+    units
+        .iter()
+        .find(|(_, scale)| value != 0 && value % scale == 0)
+        .map(|(unit, scale)| format!("{}{}", value / scale, unit))
+        .unwrap_or_else(|| format!("{}{}", value, base))
+}
+
+/// A limit as written in a config: a string with units, or a bare number in
+/// the legacy unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawLimit {
+    Number(u64),
+    String(String),
+}
+
+fn check_range<E: de::Error>(
+    value: u64,
+    min: u64,
+    max: u64,
+    format: fn(u64) -> String,
+    what: &str,
+) -> Result<u64, E> {
+    if value < min || value > max {
+        return Err(E::custom(format!(
+            "{} {} is out of range, it must be between {} and {}",
+            what,
+            format(value),
+            format(min),
+            format(max)
+        )));
+    }
+    Ok(value)
+}
+
+/// Read a time limit in milliseconds out of `raw`.
+fn time_limit_ms<E: de::Error>(raw: RawLimit) -> Result<u64, E> {
+    let ms = match raw {
+        RawLimit::String(s) => parse_duration_ms(&s).map_err(E::custom)?,
+        RawLimit::Number(secs) => {
+            tracing::warn!(
+                "Time limit without unit is deprecated, write \"{}s\" instead",
+                secs
+            );
+            secs.saturating_mul(1000)
+        }
+    };
+    check_range(
+        ms,
+        MIN_TIME_LIMIT_MS,
+        MAX_TIME_LIMIT_MS,
+        format_duration_ms,
+        "Time limit",
+    )
+}
+
+/// (De)serialization of optional time limits in milliseconds. Bare numbers
+/// are read as seconds, which is deprecated.
+pub mod time_limit {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(ms) => serializer.serialize_str(&format_duration_ms(*ms)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        Option::<RawLimit>::deserialize(deserializer)?
+            .map(time_limit_ms)
+            .transpose()
+    }
+}
+
+/// (De)serialization of maps of time limits in milliseconds, like
+/// [`time_limit`].
+pub mod time_limits {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &HashMap<String, u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(value.len()))?;
+        for (k, ms) in value {
+            map.serialize_entry(k, &format_duration_ms(*ms))?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, u64>, D::Error> {
+        HashMap::<String, RawLimit>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(k, raw)| time_limit_ms(raw).map(|ms| (k, ms)))
+            .collect()
+    }
+}
+
+/// (De)serialization of optional memory limits in bytes. Bare numbers are
+/// read as bytes, which is deprecated, and are not range checked like they
+/// never were.
+pub mod memory_limit {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(bytes) => serializer.serialize_str(&format_bytes(*bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        let bytes = match Option::<RawLimit>::deserialize(deserializer)? {
+            None => return Ok(None),
+            Some(RawLimit::String(s)) => parse_bytes(&s).map_err(de::Error::custom)?,
+            Some(RawLimit::Number(bytes)) => {
+                tracing::warn!(
+                    "Memory limit without unit is deprecated, write \"{}\" instead",
+                    format_bytes(bytes)
+                );
+                return Ok(Some(bytes));
+            }
+        };
+        check_range(
+            bytes,
+            MIN_MEMORY_LIMIT,
+            MAX_MEMORY_LIMIT,
+            format_bytes,
+            "Memory limit",
+        )
+        .map(Some)
+    }
+}
+
+/// (De)serialization of optional sizes in bytes, written like memory limits.
+/// Bare numbers are read as bytes.
+pub mod size {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        memory_limit::serialize(value, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        match Option::<RawLimit>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(RawLimit::String(s)) => parse_bytes(&s).map(Some).map_err(de::Error::custom),
+            Some(RawLimit::Number(bytes)) => Ok(Some(bytes)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(serde::Serialize, Deserialize, Debug, PartialEq)]
+    struct Limits {
+        #[serde(default, with = "time_limit")]
+        time: Option<u64>,
+        #[serde(default, with = "memory_limit")]
+        memory: Option<u64>,
+        #[serde(default, with = "time_limits")]
+        times: HashMap<String, u64>,
+    }
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(parse_duration_ms("2s"), Ok(2000));
+        assert_eq!(parse_duration_ms("1.5 s"), Ok(1500));
+        assert_eq!(parse_duration_ms("500ms"), Ok(500));
+        assert_eq!(parse_duration_ms("2min"), Ok(120_000));
+        assert!(parse_duration_ms("2").is_err());
+        assert!(parse_duration_ms("-1s").is_err());
+
+        assert_eq!(parse_bytes("256MiB"), Ok(256 << 20));
+        assert_eq!(parse_bytes("1GB"), Ok(1_000_000_000));
+        assert_eq!(parse_bytes("512M"), Ok(512 << 20));
+        assert!(parse_bytes("256 megabytes").is_err());
+
+        assert_eq!(format_duration_ms(1500), "1500ms");
+        assert_eq!(format_duration_ms(120_000), "2min");
+        assert_eq!(format_bytes(256 << 20), "256MiB");
+        assert_eq!(format_bytes(1_000_000_000), "1000000000B");
+    }
+
+    #[test]
+    fn test_limits_serde() {
+        let parse = |x| serde_json::from_value::<Limits>(x);
+        let limits = parse(serde_json::json!({ "time": "2s", "memory": "256MiB" })).unwrap();
+        assert_eq!(
+            limits,
+            Limits {
+                time: Some(2000),
+                memory: Some(256 << 20),
+                times: HashMap::new(),
+            }
+        );
+        let round_trip = parse(serde_json::to_value(&limits).unwrap()).unwrap();
+        assert_eq!(round_trip, limits);
+
+        // Legacy bare numbers
+        let legacy = parse(serde_json::json!({ "time": 2, "memory": 268435456 })).unwrap();
+        assert_eq!(legacy, limits);
+        let legacy = parse(serde_json::json!({ "memory": 1024 })).unwrap();
+        assert_eq!(legacy.memory, Some(1024));
+        assert_eq!(
+            parse(serde_json::json!({})).unwrap(),
+            Limits {
+                time: None,
+                memory: None,
+                times: HashMap::new(),
+            }
+        );
+
+        let times = parse(serde_json::json!({ "times": { "a": "50ms", "b": 3 } })).unwrap();
+        assert_eq!(times.times["a"], 50);
+        assert_eq!(times.times["b"], 3000);
+        let round_trip = parse(serde_json::to_value(&times).unwrap()).unwrap();
+        assert_eq!(round_trip, times);
+        assert!(parse(serde_json::json!({ "times": { "a": "2000min" } })).is_err());
+
+        // Off by a factor of 1000 or 1024
+        assert!(parse(serde_json::json!({ "time": "2000s" })).is_ok());
+        assert!(parse(serde_json::json!({ "time": "2000min" })).is_err());
+        assert!(parse(serde_json::json!({ "memory": "256KiB" })).is_err());
+        assert!(parse(serde_json::json!({ "memory": "256GiB" })).is_ok());
+        assert!(parse(serde_json::json!({ "memory": "256TB" })).is_err());
+    }
+}
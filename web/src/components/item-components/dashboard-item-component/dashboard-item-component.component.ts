@@ -48,6 +48,10 @@ export class DashboardItemComponentComponent implements OnInit {
     if (this.item.job === undefined) {
       return 'N/A';
     } else if (this.item.job.stage !== 'Finished') {
+      let progress = this.item.job.progress;
+      if (this.item.job.stage === 'Running' && progress != null) {
+        return `Running ${progress.finished}/${progress.total}`;
+      }
       return this.item.job.stage;
     } else if (this.item.job.resultKind !== 'Accepted') {
       return this.item.job.resultKind;
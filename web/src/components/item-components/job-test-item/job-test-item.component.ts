@@ -44,6 +44,12 @@ export class JobTestItemComponent implements OnInit {
         return 'WA';
       case 'ShouldFail':
         return 'SFE';
+      case 'PresentationError':
+        return 'PE';
+      case 'PartialScore':
+        return 'PS';
+      case 'Skipped':
+        return 'SK';
     }
   }
 
@@ -27,8 +27,14 @@ export function dashboardTypeToSlider(item: TestResultKind): SliderItemKind {
       return 'warn';
     case 'ShouldFail':
       return 'error';
+    case 'PresentationError':
+      return 'error';
+    case 'PartialScore':
+      return 'warn';
     case 'Waiting':
       return 'disable';
+    case 'Skipped':
+      return 'cancel';
     default:
       return item;
   }
@@ -104,15 +110,28 @@ export type TestResultKind =
   | 'TimeLimitExceeded'
   | 'MemoryLimitExceeded'
   | 'ShouldFail'
+  | 'PresentationError'
+  | 'PartialScore'
   | 'NotRan'
   | 'Waiting'
   | 'Running'
+  | 'Skipped'
   | 'OtherError';
 
 export interface TestResult {
   kind: TestResultKind;
   score?: number;
   resultFileId: string | undefined;
+  uploadFailed?: boolean;
+  /** Resources used by the commands of this test, if they have run */
+  usage?: ResourceUsage;
+}
+
+export interface ResourceUsage {
+  wallTimeMs: number;
+  cpuTimeMs?: number;
+  /** Peak memory usage in bytes */
+  peakMemory?: number;
 }
 
 export interface Job {
@@ -124,10 +143,21 @@ export interface Job {
   testSuite: string;
   tests: string[];
   stage: JobStage;
+  /** Progress through the test list, only available while running */
+  progress?: TestProgress;
   resultKind: JobResultKind;
   resultMessage?: string;
   buildOutputFile?: string;
   results: { [key: string]: TestResult };
+  /** Network access attempts recorded while running, if audited */
+  networkAttempts?: string[];
+  /** The seed randomized tests were run with, as reported by the judger */
+  seed?: number;
+}
+
+export interface TestProgress {
+  finished: number;
+  total: number;
 }
 
 export interface ProcessInfo {
@@ -135,6 +165,21 @@ export interface ProcessInfo {
   command: string;
   stdout: string;
   stderr: string;
+  peak_pids?: number;
+  pids_limit?: number;
+  wall_time_ms?: number;
+  cpu_time_ms?: number;
+  /** Peak memory usage in bytes */
+  peak_memory?: number;
+  /** Interleaved stdout and stderr, if enabled by the test suite */
+  combined?: OutputFrame[];
+}
+
+export interface OutputFrame {
+  /** Milliseconds since the command started */
+  time_ms: number;
+  stream: 'stdout' | 'stderr';
+  text: string;
 }
 
 export interface FailedTestcaseOutput {
@@ -88,11 +88,35 @@ export interface NewJobMessage {
   ref?: string;
   testSuite: string;
   tests: string[];
+  /** Credentials for cloning the repo if it's private */
+  credentials?: GitCredentials;
+}
+
+export interface GitCredentials {
+  username?: string;
+  password: string;
 }
 
 export interface JobBuildOutput {
   output?: string;
   error?: string;
+  /** Dockerfile steps of the build in the order they started */
+  stages?: BuildStageMark[];
+}
+
+/** The start of a Dockerfile step in the build output of a job */
+export interface BuildStageMark {
+  seq?: number;
+  /** Milliseconds since the build started */
+  elapsedMs?: number;
+  stage: BuildStage;
+}
+
+/** A step of a Dockerfile build, as in `Step 2/5 : RUN make` */
+export interface BuildStage {
+  step: number;
+  total: number;
+  instruction: string;
 }
 
 export interface JudgerStatus {
@@ -1,4 +1,10 @@
-import { Job, JobStage, JobResultKind, TestResult } from './job-items';
+import {
+  Job,
+  JobStage,
+  JobResultKind,
+  TestResult,
+  TestProgress,
+} from './job-items';
 import { Dictionary } from 'lodash';
 
 export type ServerMessageKind =
@@ -23,6 +29,7 @@ export interface JobStatusUpdateMsg extends WsApiMsg {
   jobId: string;
   buildOutputFile?: string;
   stage?: JobStage;
+  progress?: TestProgress;
   jobResult?: JobResultKind;
   testResult?: Dictionary<TestResult>;
 }
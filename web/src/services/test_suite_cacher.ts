@@ -109,6 +109,9 @@ export class TestSuiteAndJobCache {
       if (msg.stage !== undefined) {
         job.stage = msg.stage;
       }
+      if (msg.progress !== undefined) {
+        job.progress = msg.progress;
+      }
       if (msg.testResult !== undefined) {
         Object.assign(job.results, msg.testResult);
       }